@@ -13,7 +13,10 @@ fn main() {
     let btf =
         BtfTypes::from_file("/sys/kernel/btf/vmlinux").expect("Failed to parse sysfs BTF file.");
     let mut compiler = Compiler::create(&btf);
-    compiler.compile(prog).unwrap();
+    if let Err(error) = compiler.compile(prog) {
+        eprint!("{}", error.diagnostic(prog));
+        std::process::exit(1);
+    }
 
     for ins in compiler.get_instructions() {
         println!("{}", ins);
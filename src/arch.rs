@@ -0,0 +1,96 @@
+use std::fmt;
+
+/// The CPU architecture a program is compiled for. Programs are portable across
+/// architectures, except for the layout of `struct pt_regs`, which decides where
+/// kprobes find the arguments and return value of the function they probe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+/// The names of all architectures, including aliases.
+const ARCH_NAMES: &[(&str, Arch)] = &[
+    ("x86_64", Arch::X86_64),
+    ("aarch64", Arch::Aarch64),
+    ("arm64", Arch::Aarch64),
+];
+
+/// The `pt_regs` members holding the first six arguments of a function on x86_64,
+/// as passed by the System V calling convention.
+const X86_64_ARGUMENTS: [&str; 6] = ["di", "si", "dx", "cx", "r8", "r9"];
+
+impl Arch {
+    /// Returns the architecture the compiler itself was built for, or x86_64 if
+    /// BPF programs can't run on it.
+    pub fn host() -> Self {
+        match std::env::consts::ARCH {
+            "aarch64" => Self::Aarch64,
+            _ => Self::X86_64,
+        }
+    }
+
+    /// Returns an architecture from its name, e.g. `x86_64` or `arm64`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the architecture.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Arch;
+    ///
+    /// assert_eq!(Arch::from_name("arm64"), Some(Arch::Aarch64));
+    /// ```
+    pub fn from_name(name: &str) -> Option<Self> {
+        ARCH_NAMES
+            .iter()
+            .find(|(arch_name, _)| *arch_name == name)
+            .map(|(_, arch)| *arch)
+    }
+
+    /// Returns the path from `struct pt_regs` to the register holding an argument
+    /// of the probed function: the members to access, followed by the element of
+    /// the last one if it's an array. Returns `None` if the argument isn't passed
+    /// in a register.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the argument, starting at 0.
+    pub fn argument_register(
+        &self,
+        index: usize,
+    ) -> Option<(&'static [&'static str], Option<u32>)> {
+        match self {
+            Self::X86_64 => X86_64_ARGUMENTS
+                .get(index)
+                .map(|member| (std::slice::from_ref(member), None)),
+            Self::Aarch64 if index < 8 => Some((&["user_regs", "regs"], Some(index as u32))),
+            Self::Aarch64 => None,
+        }
+    }
+
+    /// Returns the path from `struct pt_regs` to the register holding the return
+    /// value of the probed function, in the same form as `argument_register`.
+    pub fn return_register(&self) -> (&'static [&'static str], Option<u32>) {
+        match self {
+            Self::X86_64 => (&["ax"], None),
+            Self::Aarch64 => (&["user_regs", "regs"], Some(0)),
+        }
+    }
+}
+
+impl Default for Arch {
+    fn default() -> Self {
+        Self::host()
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::X86_64 => write!(f, "x86_64"),
+            Self::Aarch64 => write!(f, "aarch64"),
+        }
+    }
+}
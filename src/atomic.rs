@@ -0,0 +1,49 @@
+use bpf_ins::{ArithmeticOperation, Instruction, MemoryOpSize, Register};
+
+/// Opcode class for stores from a register.
+const CLASS_STORE_REGISTER: u8 = 0x03;
+
+/// Memory mode for atomic operations.
+const MODE_ATOMIC: u8 = 0xc0;
+
+/// Creates an atomic read-modify-write of memory: `lock *(size *)(dst + offset) <op>= src`.
+/// `bpf-ins` doesn't provide constructors for atomic operations, so they are built
+/// from their raw encoding. Returns `None` if the operation or size has no atomic
+/// variant.
+///
+/// # Arguments
+///
+/// * `op` - The operation to perform, one of add, and, or and xor.
+/// * `dst` - The register holding the address of the memory to modify.
+/// * `offset` - The offset from `dst` of the memory to modify.
+/// * `src` - The register holding the operand.
+/// * `size` - The size of the memory to modify, either a word or a double word.
+pub fn atomic(
+    op: ArithmeticOperation,
+    dst: Register,
+    offset: i16,
+    src: Register,
+    size: MemoryOpSize,
+) -> Option<Instruction> {
+    let size = match size {
+        MemoryOpSize::Word => 0x00,
+        MemoryOpSize::DoubleWord => 0x18,
+        _ => return None,
+    };
+
+    let imm: u64 = match op {
+        ArithmeticOperation::Add => 0x00,
+        ArithmeticOperation::Or => 0x40,
+        ArithmeticOperation::And => 0x50,
+        ArithmeticOperation::Xor => 0xa0,
+        _ => return None,
+    };
+
+    let raw = (CLASS_STORE_REGISTER | MODE_ATOMIC | size) as u64
+        | (dst.as_num() as u64) << 8
+        | (src.as_num() as u64) << 12
+        | (offset as u16 as u64) << 16
+        | imm << 32;
+
+    Instruction::decode(&[raw]).ok()
+}
@@ -1,45 +1,159 @@
+use crate::arch::Arch;
+use crate::atomic::atomic;
+use crate::debuginfo::{sorted_members, BtfWriter, FuncInfo, MapBtf, ProgramBtf};
+use crate::diagnostic::closest_match;
+use crate::elf::{function_name, write_object, ObjectFunction, ObjectMap};
+use crate::error::{CompileError, Result, Span};
+use crate::format::{parse_format, unescape, Conversion, ConversionKind, StringError};
 use crate::helpers::Helpers;
+use crate::ir::{Block, BlockId, Function, Inst, Op, Width};
+use crate::jump;
 use crate::optimizer::optimize;
+use crate::pass::{OptLevel, Pass, PassManager, PassStats};
+use crate::program::{AttachPoint, ProgramType};
+use crate::regalloc::allocate;
+use crate::relocation::{relocate, CoreRelocation, FieldAccess, RelocationError, RelocationKind};
+use crate::verifier::{verify, MapSizes, RegType};
 
-use anyhow::{bail, Context, Result};
-use bpf_ins::{Instruction, MemoryOpLoadType, Register};
-use btf::types::{QualifiedType, Type};
+use bpf_ins::{
+    ArithmeticOperation, Instruction, JumpOperation, MemoryOpLoadType, MemoryOpSize, Register,
+};
+use btf::types::{Array, Integer, QualifiedType, Struct, Type};
 use btf::BtfTypes;
-use peginator::PegParser;
-use peginator_macro::peginate;
+use peginator::{PegParser, PegPosition};
 
 use std::collections::HashMap;
-use std::str::FromStr;
+use std::ops::Range;
 
-peginate!(
-    "
+/// The default maximum number of iterations a loop may run for.
+const DEFAULT_MAX_LOOP_ITERATIONS: u32 = 1024;
+
+/// The most copies of a `while` loop's body that are emitted when unrolling it.
+/// The number of iterations isn't known, so there's one copy for each iteration
+/// up to the maximum, which has to be lowered to at most this many.
+const MAX_UNROLLED_WHILE_ITERATIONS: u32 = 32;
+
+/// The size of the stack, shared by the frames of nested calls.
+const STACK_SIZE: u32 = 512;
+
+/// The maximum number of nested calls, counting the program itself.
+const MAX_CALL_FRAMES: usize = 8;
+
+/// The variable kprobes keep their context in when the script doesn't name it.
+/// It isn't a valid identifier, so it can't clash with the script's variables.
+const CONTEXT_VARIABLE: &str = "(context)";
+
+/// The target of a jump whose destination hasn't been emitted yet.
+const PENDING: BlockId = BlockId(usize::MAX);
+
+mod grammar {
+    use peginator_macro::peginate;
+
+    peginate!(
+        "
 @export
-ScriptDef = input:InputLine {NewLine exprs:Expression}$;
+ScriptDef = {functions:FunctionDef NewLine} input:InputLine {NewLine exprs:Expression}$;
 
-InputLine = 'fn' '(' [args:TypedArgument {',' args:TypedArgument}] ')';
-TypedArgument = name:Ident ':' type_name:TypeDecl;
+@position
+InputLine = header:ProgramHeader [params:Parameters] | params:Parameters;
+Parameters = FnKeyword '(' [args:TypedArgument {',' args:TypedArgument}] ')';
+@position
+@no_skip_ws
+ProgramHeader = !FnKeyword kind:Ident {':' target:AttachTarget};
+@position
+FunctionDef = FnKeyword name:Ident '(' [args:TypedArgument {',' args:TypedArgument}] ')'
+    ['->' return_type:TypeDecl] body:Block;
+@position
+TypedArgument = name:Ident [':' type_name:TypeDecl];
+@position
 TypeDecl = [is_ref:ReferencePrefix] name:Ident;
 
-Expression = @:Assignment | @:FunctionCall | @:Return;
+@position
+Expression = @:If | @:For | @:While | @:MapDecl | @:Delete | @:Return | @:Assignment | @:FunctionCall;
 
-Assignment = left:LValue [':' type_name:TypeDecl] '=' right:RValue;
+Block = '{' {NewLine exprs:Expression} NewLine '}';
+@position
+If = IfKeyword condition:Condition then:Block [ElseKeyword else_block:*ElseBlock];
+ElseBlock = @:If | @:Block;
+@position
+For = ForKeyword var:Ident InKeyword start:Immediate '..' end:Immediate body:Block;
+@position
+While = WhileKeyword condition:Condition body:Block;
+
+@position
+MapDecl = MapKeyword name:Ident ':' kind:Ident '<' key:TypeDecl ',' value:TypeDecl '>'
+    ['[' max_entries:Immediate ']'];
+@position
+Delete = DeleteKeyword target:LValue;
+
+@position
+Assignment = left:LValue [':' type_name:TypeDecl] op:AssignOperator right:RValue;
+@position
 FunctionCall = name:Ident '(' [args:RValue {',' args:RValue}] ')';
-Return = 'return' [value:RValue];
+@position
+Return = ReturnKeyword [value:RValue];
 
-Condition = left:LValue WhiteSpace op:Comparator WhiteSpace right:RValue;
+Condition = left:RValue WhiteSpace op:Comparator WhiteSpace right:RValue;
 
-RValue = @:FunctionCall | @:Immediate | @:LValue;
+RValue = first:Operand {rest:BinaryTail};
+BinaryTail = op:BinaryOperator operand:Operand;
+Operand = @:Unary | @:Group | @:FunctionCall | @:Immediate | @:StringLiteral | @:LValue;
+Unary = op:UnaryOperator operand:*Operand;
+Group = '(' value:*RValue ')';
+@position
 LValue = [prefix:Prefix] name:Ident {derefs:DeReference};
 
 DeReference = @:MemberAccess | @:ArrayIndex;
 
+@position
 MemberAccess = '.' name:Ident;
-ArrayIndex = '[' element:Immediate ']';
+@position
+ArrayIndex = '[' element:RValue ']';
+
+@string
+@no_skip_ws
+Immediate = ('0x' | '0X') {'0'..'9' | 'a'..'f' | 'A'..'F'}+ | {'0'..'9'}+;
 
+@position
+@no_skip_ws
+StringLiteral = '\"' value:StringText '\"';
 @string
-Immediate = {'0'..'9'}+;
+@no_skip_ws
+StringText = {'\\\\' char | !'\"' !'\\n' char};
+
+BinaryOperator = @:ShiftLeft | @:ShiftRight | @:Add | @:Subtract | @:Multiply | @:Divide | @:Modulo
+    | @:BitAnd | @:BitXor | @:BitOr;
+ShiftLeft = '<<';
+ShiftRight = '>>';
+Add = '+';
+Subtract = '-';
+Multiply = '*';
+Divide = '/';
+Modulo = '%';
+BitAnd = '&';
+BitXor = '^';
+BitOr = '|';
 
-Comparator = @:Equals | @:NotEquals | @:LessThan | @:GreaterThan | @:LessOrEqual | @:GreaterOrEqual;
+AssignOperator = @:ShiftLeftAssign | @:ShiftRightAssign | @:AddAssign | @:SubtractAssign
+    | @:MultiplyAssign | @:DivideAssign | @:ModuloAssign | @:BitAndAssign | @:BitXorAssign
+    | @:BitOrAssign | @:Assign;
+ShiftLeftAssign = '<<=';
+ShiftRightAssign = '>>=';
+AddAssign = '+=';
+SubtractAssign = '-=';
+MultiplyAssign = '*=';
+DivideAssign = '/=';
+ModuloAssign = '%=';
+BitAndAssign = '&=';
+BitXorAssign = '^=';
+BitOrAssign = '|=';
+Assign = '=';
+
+UnaryOperator = @:Negate | @:Invert;
+Negate = '-';
+Invert = '~';
+
+Comparator = @:Equals | @:NotEquals | @:LessOrEqual | @:GreaterOrEqual | @:LessThan | @:GreaterThan;
 Equals = '==';
 NotEquals = '!=';
 LessThan = '<';
@@ -53,7 +167,33 @@ Prefix = @:ReferencePrefix | @:DeReferencePrefix;
 
 @string
 @no_skip_ws
-Ident = {'a'..'z' | 'A'..'Z' | '_' | '0'..'9'}+;
+Ident = {IdentChar}+;
+
+@string
+@no_skip_ws
+AttachTarget = {IdentChar | '.' | '-' | '/'}+;
+
+@char
+IdentChar = 'a'..'z' | 'A'..'Z' | '_' | '0'..'9';
+
+@no_skip_ws
+FnKeyword = 'fn' !IdentChar;
+@no_skip_ws
+IfKeyword = 'if' !IdentChar;
+@no_skip_ws
+ElseKeyword = 'else' !IdentChar;
+@no_skip_ws
+ForKeyword = 'for' !IdentChar;
+@no_skip_ws
+InKeyword = 'in' !IdentChar;
+@no_skip_ws
+WhileKeyword = 'while' !IdentChar;
+@no_skip_ws
+ReturnKeyword = 'return' !IdentChar;
+@no_skip_ws
+MapKeyword = 'map' !IdentChar;
+@no_skip_ws
+DeleteKeyword = 'delete' !IdentChar;
 
 @string
 @no_skip_ws
@@ -63,12 +203,308 @@ WhiteSpace = {' ' | '\t'};
 @no_skip_ws
 NewLine = {'\r' | '\n' | '\r\n'};
 "
-);
+    );
+}
+
+use grammar::*;
+
+/// Returns whether every path through a list of expressions ends in a return.
+fn always_returns(exprs: &[Expression]) -> bool {
+    match exprs.last() {
+        Some(Expression::Return(_)) => true,
+        Some(Expression::If(stmt)) => if_always_returns(stmt),
+        _ => false,
+    }
+}
+
+fn if_always_returns(stmt: &If) -> bool {
+    let else_returns = match stmt.else_block.as_deref() {
+        None => false,
+        Some(ElseBlock::If(else_if)) => if_always_returns(else_if),
+        Some(ElseBlock::Block(block)) => always_returns(&block.exprs),
+    };
+
+    else_returns && always_returns(&stmt.then.exprs)
+}
+
+/// Returns the kind of relocation a field information intrinsic evaluates to, e.g.
+/// `field_exists(task.pid)`.
+fn field_info_kind(name: &str) -> Option<RelocationKind> {
+    match name {
+        "field_offset" => Some(RelocationKind::FieldByteOffset),
+        "field_size" => Some(RelocationKind::FieldByteSize),
+        "field_exists" => Some(RelocationKind::FieldExists),
+        "field_signed" => Some(RelocationKind::FieldSigned),
+        _ => None,
+    }
+}
+
+/// Returns an unsigned integer type of the given size, used for loop counters
+/// and helper return values.
+fn unsigned_type(size: u32) -> QualifiedType {
+    QualifiedType {
+        base_type: Type::Integer(Integer {
+            id: 0,
+            name: String::from(""),
+            size,
+            bits: (size * 8) as u8,
+            is_signed: false,
+            is_char: false,
+            is_bool: false,
+            offset: 0,
+        }),
+        ..Default::default()
+    }
+}
+
+/// Returns the type of a string literal, a pointer to `char`.
+fn char_pointer_type() -> QualifiedType {
+    let mut char_type = unsigned_type(1);
+    if let Type::Integer(int) = &mut char_type.base_type {
+        int.is_char = true;
+    }
+
+    QualifiedType {
+        num_refs: 1,
+        ..char_type
+    }
+}
+
+/// Returns whether two types resolved from the type library are the same type.
+fn same_type(a: &QualifiedType, b: &QualifiedType) -> bool {
+    a.num_refs == b.num_refs
+        && a.get_size() == b.get_size()
+        && a.base_type.get_id() == b.base_type.get_id()
+}
+
+/// Returns the memory operation size used to load or store a value of `size` bytes.
+fn get_memory_size(size: u32) -> Option<MemoryOpSize> {
+    match size {
+        1 => Some(MemoryOpSize::Byte),
+        2 => Some(MemoryOpSize::HalfWord),
+        4 => Some(MemoryOpSize::Word),
+        8 => Some(MemoryOpSize::DoubleWord),
+        _ => None,
+    }
+}
+
+/// The kinds of maps that can be declared, by name, along with their BPF map type.
+const MAP_KINDS: &[(&str, u32)] = &[
+    ("hash", 1),
+    ("array", 2),
+    ("percpu_hash", 5),
+    ("percpu_array", 6),
+    ("lru_hash", 9),
+    ("lru_percpu_hash", 10),
+];
+
+/// Number of entries of a map declared without a size. Only used when the map is
+/// created by a loader from an object file.
+const DEFAULT_MAX_ENTRIES: u32 = 1024;
+
+/// Update flag for `map_update_elem` that creates or replaces an element.
+const BPF_ANY: i32 = 0;
+
+/// Update flag for `map_update_elem` that only creates an element, failing with
+/// `-EEXIST` if it already exists.
+const BPF_NOEXIST: i32 = 1;
+
+/// The error `map_update_elem` returns when `BPF_NOEXIST` finds an element.
+const EEXIST: i32 = 17;
+
+/// Binary operators, in a form that's easier to work with than the parsed
+/// `BinaryOperator`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    BitAnd,
+    BitXor,
+    BitOr,
+}
+
+impl Operator {
+    fn from_parsed(op: &BinaryOperator) -> Self {
+        match op {
+            BinaryOperator::ShiftLeft(_) => Self::ShiftLeft,
+            BinaryOperator::ShiftRight(_) => Self::ShiftRight,
+            BinaryOperator::Add(_) => Self::Add,
+            BinaryOperator::Subtract(_) => Self::Subtract,
+            BinaryOperator::Multiply(_) => Self::Multiply,
+            BinaryOperator::Divide(_) => Self::Divide,
+            BinaryOperator::Modulo(_) => Self::Modulo,
+            BinaryOperator::BitAnd(_) => Self::BitAnd,
+            BinaryOperator::BitXor(_) => Self::BitXor,
+            BinaryOperator::BitOr(_) => Self::BitOr,
+        }
+    }
+
+    /// Returns the operator applied by a compound assignment, or `None` for a
+    /// plain assignment.
+    fn from_assignment(op: &AssignOperator) -> Option<Self> {
+        Some(match op {
+            AssignOperator::ShiftLeftAssign(_) => Self::ShiftLeft,
+            AssignOperator::ShiftRightAssign(_) => Self::ShiftRight,
+            AssignOperator::AddAssign(_) => Self::Add,
+            AssignOperator::SubtractAssign(_) => Self::Subtract,
+            AssignOperator::MultiplyAssign(_) => Self::Multiply,
+            AssignOperator::DivideAssign(_) => Self::Divide,
+            AssignOperator::ModuloAssign(_) => Self::Modulo,
+            AssignOperator::BitAndAssign(_) => Self::BitAnd,
+            AssignOperator::BitXorAssign(_) => Self::BitXor,
+            AssignOperator::BitOrAssign(_) => Self::BitOr,
+            AssignOperator::Assign(_) => return None,
+        })
+    }
+
+    /// Operator precedence, following C. Higher binds tighter.
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Multiply | Self::Divide | Self::Modulo => 5,
+            Self::Add | Self::Subtract => 4,
+            Self::ShiftLeft | Self::ShiftRight => 3,
+            Self::BitAnd => 2,
+            Self::BitXor => 1,
+            Self::BitOr => 0,
+        }
+    }
+
+    fn get_operation(&self, is_signed: bool) -> ArithmeticOperation {
+        match self {
+            Self::ShiftLeft => ArithmeticOperation::Lhs,
+            Self::ShiftRight if is_signed => ArithmeticOperation::Ash,
+            Self::ShiftRight => ArithmeticOperation::Rhs,
+            Self::Add => ArithmeticOperation::Add,
+            Self::Subtract => ArithmeticOperation::Sub,
+            Self::Multiply => ArithmeticOperation::Mul,
+            Self::Divide => ArithmeticOperation::Div,
+            Self::Modulo => ArithmeticOperation::Mod,
+            Self::BitAnd => ArithmeticOperation::And,
+            Self::BitXor => ArithmeticOperation::Xor,
+            Self::BitOr => ArithmeticOperation::Or,
+        }
+    }
+}
+
+/// An arithmetic expression tree, built from the flat operand/operator list
+/// produced by the parser.
+enum Expr<'a> {
+    Operand(&'a Operand),
+    Binary(Operator, Box<Expr<'a>>, Box<Expr<'a>>),
+}
+
+impl<'a> Expr<'a> {
+    /// Builds an expression tree from an `RValue` using precedence climbing.
+    fn from_rvalue(rval: &'a RValue) -> Self {
+        fn reduce<'a>(operands: &mut Vec<Expr<'a>>, ops: &mut Vec<Operator>) {
+            let op = ops.pop().expect("operator stack is never empty here");
+            let right = operands.pop().expect("operand stack is never empty here");
+            let left = operands.pop().expect("operand stack is never empty here");
+            operands.push(Expr::Binary(op, Box::new(left), Box::new(right)));
+        }
+
+        let mut operands = vec![Expr::Operand(&rval.first)];
+        let mut ops: Vec<Operator> = vec![];
+        for tail in &rval.rest {
+            let op = Operator::from_parsed(&tail.op);
+            while matches!(ops.last(), Some(top) if top.precedence() >= op.precedence()) {
+                reduce(&mut operands, &mut ops);
+            }
+
+            ops.push(op);
+            operands.push(Expr::Operand(&tail.operand));
+        }
+
+        while !ops.is_empty() {
+            reduce(&mut operands, &mut ops);
+        }
+
+        operands.pop().expect("expression always has an operand")
+    }
+
+    /// Returns the expression's value if it's an immediate that fits in the 32-bit
+    /// immediate field of an instruction.
+    fn as_imm32(&self) -> Option<i32> {
+        match self {
+            Expr::Operand(Operand::Immediate(imm)) => parse_integer(imm)?.try_into().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl RValue {
+    /// Returns the single operand of this rvalue, if it doesn't contain any
+    /// binary operators.
+    fn as_operand(&self) -> Option<&Operand> {
+        if self.rest.is_empty() {
+            Some(&self.first)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the size and signedness of an integer type narrower than 64 bits.
+fn narrow_integer(value_type: &QualifiedType) -> Option<(u32, bool)> {
+    match &value_type.base_type {
+        _ if value_type.is_pointer() => None,
+        Type::Integer(int) if int.size < 8 => Some((int.size, int.is_signed)),
+        _ => None,
+    }
+}
+
+/// Parses a decimal or hexadecimal integer literal.
+fn parse_integer(s: &str) -> Option<i128> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => i128::from_str_radix(hex, 16).ok(),
+        None => s.parse::<i128>().ok(),
+    }
+}
+
+/// Returns the type of a binary expression given the types of its operands. A
+/// `void` type represents an untyped literal, which takes on the type of the
+/// other operand.
+fn get_arithmetic_type(op: Operator, left: &QualifiedType, right: &QualifiedType) -> QualifiedType {
+    if matches!(op, Operator::ShiftLeft | Operator::ShiftRight) {
+        return left.clone();
+    }
+
+    match (&left.base_type, &right.base_type) {
+        (Type::Void, _) if !left.is_pointer() => right.clone(),
+        (_, Type::Void) if !right.is_pointer() => left.clone(),
+        _ if left.is_pointer() => left.clone(),
+        _ if right.is_pointer() => right.clone(),
+        (Type::Integer(l), Type::Integer(r)) => {
+            if l.size > r.size || (l.size == r.size && !l.is_signed) {
+                left.clone()
+            } else {
+                right.clone()
+            }
+        }
+        _ => left.clone(),
+    }
+}
 
 #[derive(Clone, Copy)]
 enum VariableLocation {
-    SpecialImmediate(u32),
+    SpecialImmediate(i64),
     Stack(i16),
+    Map(usize),
+}
+
+#[derive(Clone)]
+struct MapInfo {
+    pub name: String,
+    pub fd: u32,
+    pub map_type: u32,
+    pub max_entries: u32,
+    pub key_type: QualifiedType,
+    pub value_type: QualifiedType,
 }
 
 #[derive(Clone)]
@@ -77,12 +513,79 @@ struct VariableInfo {
     pub location: VariableLocation,
 }
 
+/// A run of member accesses and constant array indices, resolved by
+/// `get_field_access`.
+struct FieldAccessRun {
+    /// The number of dereferences in the run.
+    count: usize,
+    /// The combined offset of the dereferences.
+    offset: u32,
+    /// The type of the accessed field.
+    field_type: QualifiedType,
+    /// The accesses that describe the run in a CO-RE relocation.
+    accesses: Vec<FieldAccess>,
+}
+
+/// A value captured from Rust that's copied onto the stack when the program starts.
+#[derive(Clone)]
+struct CapturedData {
+    pub name: String,
+    pub var_type: QualifiedType,
+    pub bytes: Vec<u8>,
+}
+
+/// A function defined by the script, which the program calls with pseudo-calls.
+#[derive(Clone)]
+struct FunctionInfo {
+    pub name: String,
+    pub params: Vec<(String, QualifiedType)>,
+    pub return_type: Option<QualifiedType>,
+}
+
+/// A function of the program compiled on its own, before the functions are laid
+/// out one after the other and the calls between them are patched.
+struct CompiledFunction {
+    ir: Function,
+    instructions: Vec<Instruction>,
+    lines: Vec<(usize, Range<usize>)>,
+    relocations: Vec<CoreRelocation>,
+    args: Vec<(String, QualifiedType)>,
+    stack_size: u32,
+    /// The functions it calls, by index, and where the calls are in the script.
+    calls: Vec<(usize, Range<usize>)>,
+}
+
 pub struct Compiler<'a> {
     types: &'a BtfTypes,
     variables: HashMap<String, VariableInfo>,
+    maps: Vec<MapInfo>,
+    captures: Vec<CapturedData>,
+    ir: Function,
     instructions: Vec<Instruction>,
     stack: u32,
-    expr_num: u32,
+    stack_objects: Vec<(i16, u32)>,
+    source: String,
+    position: Range<usize>,
+    args: Vec<(String, QualifiedType)>,
+    locals: Vec<(i16, QualifiedType)>,
+    lines: Vec<(usize, Range<usize>)>,
+    relocations: Vec<CoreRelocation>,
+    unroll_loops: bool,
+    max_loop_iterations: u32,
+    loop_ranges: HashMap<String, Range<u32>>,
+    signed_division: bool,
+    out_of_bounds: Vec<BlockId>,
+    core_relocations: bool,
+    passes: PassManager,
+    pass_report: Vec<PassStats>,
+    functions: Vec<FunctionInfo>,
+    current_function: Option<usize>,
+    calls: Vec<(usize, Range<usize>)>,
+    subprograms: Vec<(usize, usize)>,
+    attach: Option<AttachPoint>,
+    traced_args: Vec<(String, QualifiedType, usize)>,
+    arch: Arch,
+    context: Option<String>,
 }
 
 impl<'a> Compiler<'a> {
@@ -104,18 +607,276 @@ impl<'a> Compiler<'a> {
         Self {
             types,
             variables: HashMap::new(),
+            maps: vec![],
+            captures: vec![],
+            ir: Function::default(),
             instructions: vec![],
             stack: 0,
-            expr_num: 1,
+            stack_objects: vec![],
+            source: String::new(),
+            position: 0..0,
+            args: vec![],
+            locals: vec![],
+            lines: vec![],
+            relocations: vec![],
+            unroll_loops: false,
+            max_loop_iterations: DEFAULT_MAX_LOOP_ITERATIONS,
+            loop_ranges: HashMap::new(),
+            signed_division: false,
+            out_of_bounds: vec![],
+            core_relocations: false,
+            passes: PassManager::default(),
+            pass_report: vec![],
+            functions: vec![],
+            current_function: None,
+            calls: vec![],
+            subprograms: vec![],
+            attach: None,
+            traced_args: vec![],
+            arch: Arch::default(),
+            context: None,
         }
     }
 
+    /// Controls whether loops are fully unrolled instead of being compiled to
+    /// backward jumps. Kernels older than 5.3 reject programs containing
+    /// backward jumps, so this must be enabled when targeting them.
+    ///
+    /// `while` loops are unrolled into one copy for each iteration they may run
+    /// for, so they fail to compile unless `set_max_loop_iterations` lowers the
+    /// limit to 32 or less.
+    ///
+    /// # Arguments
+    ///
+    /// * `unroll` - Whether to unroll loops.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.set_unroll_loops(true);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         for i in 0..4 {
+    ///             get_current_pid_tgid()
+    ///         }
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn set_unroll_loops(&mut self, unroll: bool) {
+        self.unroll_loops = unroll;
+    }
+
+    /// Controls whether `/` and `%` on signed integers are compiled to the signed
+    /// division instructions of the v4 instruction set. Kernels older than 6.6
+    /// reject them, so dividing signed integers fails to compile unless this is
+    /// enabled. Unsigned division works on every kernel.
+    ///
+    /// # Arguments
+    ///
+    /// * `enable` - Whether to emit signed division instructions.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("s32", 4, true).expect("Failed to add s32 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.compile(r#"
+    ///     fn(a: s32)
+    ///         return a / 2
+    /// "#).expect_err("Signed division should be disabled.");
+    ///
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.set_signed_division(true);
+    /// compiler.compile(r#"
+    ///     fn(a: s32)
+    ///         return a / 2
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn set_signed_division(&mut self, enable: bool) {
+        self.signed_division = enable;
+    }
+
+    /// Sets the architecture the program is compiled for, which decides how the
+    /// `arg0` to `arg5` and `retval` builtins of kprobes are read from
+    /// `struct pt_regs`. Defaults to the architecture the compiler runs on.
+    ///
+    /// # Arguments
+    ///
+    /// * `arch` - The target architecture.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Arch, Compiler};
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u64", 8, false).expect("Failed to add u64 type.");
+    /// btf.add_struct("pt_regs", &[("si", "u64"), ("di", "u64")])
+    ///     .expect("Failed to add pt_regs type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.set_target_arch(Arch::X86_64);
+    /// compiler.compile(r#"
+    ///     kprobe:do_sys_openat2
+    ///         return arg1
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn set_target_arch(&mut self, arch: Arch) {
+        self.arch = arch;
+    }
+
+    /// Sets the maximum number of iterations any loop may run for. `for` loops
+    /// with a larger range fail to compile, and `while` loops stop once the limit
+    /// is reached so the verifier can prove they terminate.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - The maximum number of iterations.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.set_max_loop_iterations(2);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         for i in 0..4 {
+    ///             get_current_pid_tgid()
+    ///         }
+    /// "#).expect_err("Loop should be too long.");
+    /// ```
+    pub fn set_max_loop_iterations(&mut self, max: u32) {
+        self.max_loop_iterations = max;
+    }
+
+    /// Controls whether accesses to memory described by the type library (e.g.
+    /// kernel structs reached through pointers) are compiled with CO-RE
+    /// relocations, so the program can be adapted to kernels whose struct layouts
+    /// differ from the ones it was compiled against. The relocations are returned
+    /// by `get_core_relocations` and written to the program's BTF.
+    ///
+    /// Relocated accesses always emit their offset, even when it's zero, and the
+    /// `field_exists`, `field_size`, `field_signed` and `field_offset` intrinsics
+    /// are relocated to describe the field on the target kernel.
+    ///
+    /// # Arguments
+    ///
+    /// * `enable` - Whether to emit CO-RE relocations.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// btf.add_struct("file", &[("flags", "u32"), ("mode", "u32")])
+    ///     .expect("Failed to add file type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.set_core_relocations(true);
+    /// compiler.compile(r#"
+    ///     fn(f: &file)
+    ///         mode: u32 = f.mode
+    ///         return mode
+    /// "#).expect("Failed to compile.");
+    /// let relocation = &compiler.get_core_relocations()[0];
+    /// assert_eq!(relocation.access_string(), "0:1");
+    /// ```
+    pub fn set_core_relocations(&mut self, enable: bool) {
+        self.core_relocations = enable;
+    }
+
+    /// Sets how much programs are optimized. Defaults to `OptLevel::O2`, while
+    /// `OptLevel::O0` emits the instructions exactly as they were generated, which
+    /// helps telling whether a bug is in code generation or in an optimization.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The optimization level.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::pass::OptLevel;
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.set_opt_level(OptLevel::O0);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         a: u32 = 1
+    ///         return a
+    /// "#).expect("Failed to compile.");
+    /// assert!(compiler.get_pass_report().is_empty());
+    /// ```
+    pub fn set_opt_level(&mut self, level: OptLevel) {
+        self.passes.set_level(level);
+    }
+
+    /// Adds a custom optimization pass over the IR, which runs after the built-in
+    /// passes at every optimization level.
+    ///
+    /// # Arguments
+    ///
+    /// * `pass` - The pass to add.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::ir::{Function, Op, Value};
+    /// use bpf_script::pass::Pass;
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// /// Replaces every constant that's moved into a register with 0.
+    /// struct ReturnZero;
+    ///
+    /// impl Pass for ReturnZero {
+    ///     fn name(&self) -> &str {
+    ///         "return-zero"
+    ///     }
+    ///
+    ///     fn run(&mut self, function: &mut Function) -> bool {
+    ///         let mut changed = false;
+    ///         for block in &mut function.blocks {
+    ///             for inst in &mut block.insts {
+    ///                 if let Op::Move { src: src @ Value::Immediate(_), .. } = &mut inst.op {
+    ///                     changed |= *src != Value::Immediate(0);
+    ///                     *src = Value::Immediate(0);
+    ///                 }
+    ///             }
+    ///         }
+    ///         changed
+    ///     }
+    /// }
+    ///
+    /// let btf = BtfTypes::default();
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.add_pass(ReturnZero);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return 5
+    /// "#).expect("Failed to compile.");
+    /// assert!(compiler.get_pass_report().iter().any(|p| p.name == "return-zero" && p.fired()));
+    /// ```
+    pub fn add_pass(&mut self, pass: impl Pass + 'static) {
+        self.passes.add_pass(pass);
+    }
+
     /// Used to capture variables from the outer scope into the BPF
     /// program being compiled. This is mostly used to capture map
     /// identifers to pass to BPF helpers and for other integer values
-    /// that need to be captured. In the future, this will be extended
-    /// to capture arbitrary types making sharing between Rust and BPF
-    /// more seamless.
+    /// that need to be captured. The value is typed as a signed 64-bit
+    /// integer, `capture_int` captures an integer with a different type.
     ///
     /// # Arguments
     ///
@@ -138,27 +899,245 @@ impl<'a> Compiler<'a> {
     pub fn capture(&mut self, name: &str, value: i64) {
         let info = VariableInfo {
             var_type: QualifiedType::int::<i64>(),
-            location: VariableLocation::SpecialImmediate(value as u32),
+            location: VariableLocation::SpecialImmediate(value),
         };
+        self.captures.retain(|capture| capture.name != name);
         self.variables.insert(name.to_string(), info);
     }
 
-    /// Helper function for resolving a type by id and printing an error
-    /// with line information, if it's not found.
-    fn resolve_type_by_id(&mut self, id: u32) -> Result<QualifiedType> {
-        if let Some(t) = self.types.resolve_type_by_id(id) {
-            return Ok(t);
-        }
-
-        bail!(
-            "[Line {}] Bad BTF database: type id \"{}\" not found.",
-            self.expr_num,
-            id
-        );
+    /// Captures an integer from the outer scope with a type from the BTF type
+    /// library, e.g. so that comparisons against it are unsigned. Like `capture`,
+    /// the full 64-bit value is loaded wherever the variable is used.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the variable when referenced from the script.
+    /// `type_name` - The name of an integer type, e.g. `u64`.
+    /// `value` - The value of the variable, it must fit in the given type.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u64", 8, false).expect("Failed to add u64 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.capture_int("threshold", "u64", 0x1_0000_0000).expect("Failed to capture.");
+    /// compiler.compile(r#"
+    ///     fn(a: u64)
+    ///         if a > threshold {
+    ///             return 1
+    ///         }
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn capture_int(&mut self, name: &str, type_name: &str, value: i64) -> Result<()> {
+        let var_type = self.resolve_capture_type(type_name)?;
+        let integer = match &var_type.base_type {
+            Type::Integer(integer) if !var_type.is_pointer() => integer,
+            _ => {
+                return Err(CompileError::InvalidCapture {
+                    name: type_name.to_string(),
+                    reason: "Captured integers must have an integer type".to_string(),
+                    suggestion: None,
+                });
+            }
+        };
+
+        let bits = integer.size * 8;
+        let fits = match (integer.is_signed, bits) {
+            (_, 64) => true,
+            (true, bits) => (-(1i64 << (bits - 1))..(1i64 << (bits - 1))).contains(&value),
+            (false, bits) => (0..(1i64 << bits)).contains(&value),
+        };
+        if !fits {
+            return Err(CompileError::InvalidCapture {
+                name: value.to_string(),
+                reason: format!("Captured value doesn't fit in \"{}\"", type_name),
+                suggestion: None,
+            });
+        }
+
+        let info = VariableInfo {
+            var_type,
+            location: VariableLocation::SpecialImmediate(value),
+        };
+        self.captures.retain(|capture| capture.name != name);
+        self.variables.insert(name.to_string(), info);
+        Ok(())
+    }
+
+    /// Captures a byte string from the outer scope. The bytes are copied onto
+    /// the stack when the program starts and can be indexed, or passed to helpers
+    /// by reference, as a `char` array. The BTF type library must contain `char`.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the variable when referenced from the script.
+    /// `bytes` - The contents of the variable.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("char", 1, true).expect("Failed to add char type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.capture_bytes("comm", b"bash\0").expect("Failed to capture.");
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return comm[0]
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn capture_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        let char_type = self.resolve_capture_type("char")?;
+        let len = bytes.len() as u32;
+        let var_type = QualifiedType {
+            base_type: Type::Array(Array {
+                id: 0,
+                size: len,
+                element_type: char_type.base_type.get_id().unwrap_or_default(),
+                index_type: 0,
+                num_elements: len,
+            }),
+            ..Default::default()
+        };
+
+        self.add_captured_data(name, var_type, bytes)
+    }
+
+    /// Captures a struct from the outer scope, e.g. configuration shared between
+    /// Rust and the program. `bytes` holds the struct as it's laid out in memory;
+    /// it's copied onto the stack when the program starts and its members can then
+    /// be accessed like those of any other struct.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the variable when referenced from the script.
+    /// `type_name` - The name of the struct type in the BTF type library.
+    /// `bytes` - The contents of the struct, exactly the size of the type.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// btf.add_struct("filter", &[("pid", "u32"), ("uid", "u32")])
+    ///     .expect("Failed to add struct.");
+    /// let mut compiler = Compiler::create(&btf);
+    ///
+    /// let mut filter = vec![];
+    /// filter.extend_from_slice(&1234u32.to_ne_bytes());
+    /// filter.extend_from_slice(&1000u32.to_ne_bytes());
+    /// compiler.capture_struct("config", "filter", &filter).expect("Failed to capture.");
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return config.uid
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn capture_struct(&mut self, name: &str, type_name: &str, bytes: &[u8]) -> Result<()> {
+        let var_type = self.resolve_capture_type(type_name)?;
+        if var_type.is_pointer() || !matches!(var_type.base_type, Type::Struct(_)) {
+            return Err(CompileError::InvalidCapture {
+                name: type_name.to_string(),
+                reason: "Captured structs must have a struct type".to_string(),
+                suggestion: None,
+            });
+        }
+
+        if var_type.get_size() as usize != bytes.len() {
+            return Err(CompileError::InvalidCapture {
+                name: type_name.to_string(),
+                reason: format!(
+                    "Captured {} bytes for a {} byte type",
+                    bytes.len(),
+                    var_type.get_size()
+                ),
+                suggestion: None,
+            });
+        }
+
+        self.add_captured_data(name, var_type, bytes)
+    }
+
+    /// Resolves the type of a captured value.
+    fn resolve_capture_type(&self, type_name: &str) -> Result<QualifiedType> {
+        if let Some(t) = self.types.resolve_type_by_name(type_name) {
+            return Ok(t);
+        }
+
+        Err(CompileError::InvalidCapture {
+            name: type_name.to_string(),
+            reason: "No type found with this name".to_string(),
+            suggestion: closest_match(type_name, self.types.iter().map(|t| t.get_name())),
+        })
+    }
+
+    /// Records a captured value for the prologue to copy onto the stack.
+    fn add_captured_data(
+        &mut self,
+        name: &str,
+        var_type: QualifiedType,
+        bytes: &[u8],
+    ) -> Result<()> {
+        if bytes.len() > 512 {
+            return Err(CompileError::InvalidCapture {
+                name: name.to_string(),
+                reason: format!(
+                    "Captured {} bytes, more than the 512 bytes of stack",
+                    bytes.len()
+                ),
+                suggestion: None,
+            });
+        }
+
+        self.variables.remove(name);
+        self.captures.retain(|capture| capture.name != name);
+        self.captures.push(CapturedData {
+            name: name.to_string(),
+            var_type,
+            bytes: bytes.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Returns the span of a byte range of the script being compiled.
+    fn span(&self, range: &Range<usize>) -> Span {
+        Span::from_range(&self.source, range)
+    }
+
+    /// Returns the span of an identifier within a byte range, e.g. the name of a
+    /// variable without its prefix and dereferences.
+    fn ident_span(&self, range: &Range<usize>, name: &str) -> Span {
+        match self.source.get(range.clone()).and_then(|s| s.find(name)) {
+            Some(off) => self.span(&(range.start + off..range.start + off + name.len())),
+            None => self.span(range),
+        }
+    }
+
+    /// Returns the span of the statement currently being compiled.
+    fn current_span(&self) -> Span {
+        self.span(&self.position)
+    }
+
+    /// Helper function for resolving a type by id and returning an error with
+    /// location information, if it's not found.
+    fn resolve_type_by_id(&mut self, id: u32) -> Result<QualifiedType> {
+        if let Some(t) = self.types.resolve_type_by_id(id) {
+            return Ok(t);
+        }
+
+        Err(CompileError::MissingTypeId {
+            span: self.current_span(),
+            type_id: id,
+        })
     }
 
-    /// Helper function for resolving a type by `TypeDecl` and printing an error
-    /// with line information, if it's not found.
+    /// Helper function for resolving a type by `TypeDecl` and returning an error
+    /// with location information, if it's not found.
     fn resolve_type_by_decl(&mut self, decl: &TypeDecl) -> Result<QualifiedType> {
         if let Some(mut t) = self.types.resolve_type_by_name(&decl.name) {
             if matches!(decl.is_ref, Some(ReferencePrefix)) {
@@ -167,35 +1146,154 @@ impl<'a> Compiler<'a> {
             return Ok(t);
         }
 
-        bail!(
-            "[Line {}] No type found with the name \"{}\".",
-            self.expr_num,
-            decl.name
-        );
+        Err(CompileError::UnknownType {
+            span: self.ident_span(&decl.position, &decl.name),
+            name: decl.name.clone(),
+            suggestion: closest_match(&decl.name, self.types.iter().map(|t| t.get_name())),
+        })
     }
 
-    /// Helper function for finding a scoped variable by name and printing an error
-    /// with line information, if it's not found.
+    /// Helper function for finding a scoped variable by name and returning an error
+    /// with location information, if it's not found.
     fn get_variable_by_name(&mut self, name: &str) -> Result<VariableInfo> {
         if let Some(info) = self.variables.get(name) {
             return Ok(info.clone());
         }
 
-        bail!(
-            "[Line {}] No variable with the name \"{}\".",
-            self.expr_num,
-            name
-        );
+        Err(CompileError::UnknownVariable {
+            span: self.current_span(),
+            name: name.to_string(),
+            suggestion: closest_match(
+                name,
+                self.variables
+                    .keys()
+                    .map(|k| k.as_str())
+                    .filter(|k| *k != CONTEXT_VARIABLE),
+            ),
+        })
+    }
+
+    /// Helper function for finding the variable an lvalue refers to, the error
+    /// points at the lvalue rather than the whole statement.
+    fn get_variable_by_lvalue(&mut self, lval: &LValue) -> Result<VariableInfo> {
+        match self.get_variable_by_name(&lval.name) {
+            Err(CompileError::UnknownVariable {
+                name, suggestion, ..
+            }) => Err(CompileError::UnknownVariable {
+                span: self.ident_span(&lval.position, &lval.name),
+                name,
+                suggestion,
+            }),
+            result => result,
+        }
     }
 
-    /// Helper function for parsing an immediate value and printin an error with line
-    /// information, if it's not found.
-    fn parse_immediate<T: FromStr>(&mut self, s: &str) -> Result<T> {
-        if let Ok(imm) = s.parse::<T>() {
+    /// Helper function for parsing an immediate value and returning an error with
+    /// location information, if it's not valid.
+    fn parse_immediate<T: TryFrom<i128>>(&mut self, s: &str) -> Result<T> {
+        if let Some(imm) = parse_integer(s).and_then(|imm| imm.try_into().ok()) {
             return Ok(imm);
         }
 
-        bail!("[Line {}] Bad immediate value \"{}\".", self.expr_num, s);
+        Err(CompileError::InvalidImmediate {
+            span: self.current_span(),
+            value: s.to_string(),
+        })
+    }
+
+    /// Parses an immediate that's loaded into a 64-bit register. Both signed and
+    /// unsigned 64-bit values are accepted, the latter are reinterpreted as signed.
+    fn parse_immediate64(&mut self, s: &str) -> Result<i64> {
+        match self.parse_immediate::<i64>(s) {
+            Ok(imm) => Ok(imm),
+            Err(_) => Ok(self.parse_immediate::<u64>(s)? as i64),
+        }
+    }
+
+    /// Returns an error if an integer literal can't be represented by the type of
+    /// the expression it's used in, e.g. `x + 0x1_0000_0000` when `x` is 32-bit.
+    fn check_literal_fits(&mut self, expr: &Expr, value_type: &QualifiedType) -> Result<()> {
+        let imm_str = match expr {
+            Expr::Operand(Operand::Immediate(imm_str)) => imm_str,
+            _ => return Ok(()),
+        };
+
+        let bits = match &value_type.base_type {
+            Type::Integer(int) if !value_type.is_pointer() && int.size < 8 => int.size * 8,
+            _ => return Ok(()),
+        };
+
+        /*
+         * Literals are untyped, so anything that's valid as either a signed or an
+         * unsigned value of the right size is accepted.
+         */
+        let imm = self.parse_immediate::<i64>(imm_str)?;
+        if !(-(1i64 << (bits - 1))..(1i64 << bits)).contains(&imm) {
+            return Err(CompileError::InvalidImmediate {
+                span: self.current_span(),
+                value: imm_str.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Appends an instruction to the block being emitted, attributed to the
+    /// statement being compiled.
+    fn emit(&mut self, op: Op) {
+        let inst = Inst {
+            op,
+            source: self.position.clone(),
+            relocation: None,
+        };
+        self.current_block().insts.push(inst);
+    }
+
+    /// Appends an instruction that a CO-RE relocation applies to.
+    fn emit_relocated(&mut self, op: Op, relocation: CoreRelocation) {
+        self.relocations.push(relocation);
+        self.emit(op);
+        let relocation = Some(self.relocations.len() - 1);
+        if let Some(inst) = self.current_block().insts.last_mut() {
+            inst.relocation = relocation;
+        }
+    }
+
+    /// Returns the block instructions are being emitted into.
+    fn current_block(&mut self) -> &mut Block {
+        if self.ir.blocks.is_empty() {
+            self.ir.blocks.push(Block::default());
+        }
+
+        let last = self.ir.blocks.len() - 1;
+        &mut self.ir.blocks[last]
+    }
+
+    /// Returns the id the next block will get, i.e. the block that follows the
+    /// one being emitted.
+    fn next_block(&self) -> BlockId {
+        BlockId(self.ir.blocks.len().max(1))
+    }
+
+    /// Ends the block being emitted with a terminator and starts the next one.
+    /// Returns the block that was ended, so that a branch to a target that isn't
+    /// known yet can be patched with `patch_jump`.
+    fn emit_terminator(&mut self, op: Op) -> BlockId {
+        self.emit(op);
+        let block = BlockId(self.ir.blocks.len() - 1);
+        self.ir.blocks.push(Block::default());
+        block
+    }
+
+    /// Returns the block the next instruction is emitted into, making sure it's
+    /// at the start of a block so it can be jumped to.
+    fn start_block(&mut self) -> BlockId {
+        if !self.current_block().insts.is_empty() {
+            let next = self.next_block();
+            self.emit_terminator(Op::Jump { target: next });
+        }
+
+        BlockId(self.ir.blocks.len() - 1)
     }
 
     /// Get the current stack offset.
@@ -204,17 +1302,24 @@ impl<'a> Compiler<'a> {
     }
 
     /// Push the stack value by a given size and return the new offset. Verifies the
-    /// new location doesn't overflow the stack and returns and error with line information,
-    /// if it does.
+    /// new location doesn't overflow the stack and returns and error with location
+    /// information, if it does.
     fn push_stack(&mut self, sz: u32) -> Result<i16> {
-        if self.stack + sz > 512 {
-            bail!(
-                "[Line {}] Stack size exceeded 512 bytes with this assignment.",
-                self.expr_num
-            );
+        /*
+         * The verifier rejects misaligned stack accesses, so values are aligned to
+         * their size, up to 8 bytes.
+         */
+        let align = sz.clamp(1, 8).next_power_of_two();
+        let stack = (self.stack + sz).div_ceil(align) * align;
+        if stack > 512 {
+            return Err(CompileError::StackOverflow {
+                span: self.current_span(),
+                size: stack,
+            });
         }
 
-        self.stack += sz;
+        self.stack = stack;
+        self.stack_objects.push((self.get_stack(), sz));
         Ok(self.get_stack())
     }
 
@@ -230,37 +1335,114 @@ impl<'a> Compiler<'a> {
             | value << 56;
         let mut remaining = size;
         for _ in 0..size / 8 {
-            self.instructions
-                .push(Instruction::store64(Register::R10, offset, v64));
+            self.emit(Op::store(
+                MemoryOpSize::DoubleWord,
+                Register::R10,
+                offset,
+                v64,
+            ));
             remaining -= 8;
             offset += 8;
         }
         size = remaining;
 
         for _ in 0..size / 4 {
-            self.instructions
-                .push(Instruction::store32(Register::R10, offset, v64 as i32));
+            self.emit(Op::store(
+                MemoryOpSize::Word,
+                Register::R10,
+                offset,
+                v64 as i32,
+            ));
             remaining -= 4;
             offset += 4;
         }
         size = remaining;
 
         for _ in 0..size / 2 {
-            self.instructions
-                .push(Instruction::store16(Register::R10, offset, v64 as i16));
+            self.emit(Op::store(
+                MemoryOpSize::HalfWord,
+                Register::R10,
+                offset,
+                v64 as i16,
+            ));
             remaining -= 2;
             offset += 2;
         }
         size = remaining;
 
         for _ in 0..size {
-            self.instructions
-                .push(Instruction::store8(Register::R10, offset, v64 as i8));
-            remaining -= 1;
+            self.emit(Op::store(
+                MemoryOpSize::Byte,
+                Register::R10,
+                offset,
+                v64 as i8,
+            ));
             offset += 1;
         }
     }
 
+    /// Copies a byte string onto the stack at `offset` using immediate stores.
+    fn emit_init_bytes(&mut self, mut offset: i16, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            /*
+             * 64-bit stores sign-extend a 32-bit immediate, so they're only used when
+             * the value survives that, otherwise both halves are stored separately.
+             */
+            let value = i64::from_ne_bytes(chunk.try_into().unwrap());
+            match i32::try_from(value) {
+                Ok(value) => {
+                    self.emit(Op::store(
+                        MemoryOpSize::DoubleWord,
+                        Register::R10,
+                        offset,
+                        value,
+                    ));
+                }
+                Err(_) => {
+                    for (i, half) in chunk.chunks_exact(4).enumerate() {
+                        let value = i32::from_ne_bytes(half.try_into().unwrap());
+                        self.emit(Op::store(
+                            MemoryOpSize::Word,
+                            Register::R10,
+                            offset + i as i16 * 4,
+                            value,
+                        ));
+                    }
+                }
+            }
+            offset += 8;
+        }
+
+        let mut rest = chunks.remainder();
+        while !rest.is_empty() {
+            let size = match rest.len() {
+                4.. => 4,
+                2.. => 2,
+                _ => 1,
+            };
+            let (chunk, remainder) = rest.split_at(size);
+            let ins = match size {
+                4 => Op::store(
+                    MemoryOpSize::Word,
+                    Register::R10,
+                    offset,
+                    i32::from_ne_bytes(chunk.try_into().unwrap()),
+                ),
+                2 => Op::store(
+                    MemoryOpSize::HalfWord,
+                    Register::R10,
+                    offset,
+                    i16::from_ne_bytes(chunk.try_into().unwrap()),
+                ),
+                _ => Op::store(MemoryOpSize::Byte, Register::R10, offset, chunk[0] as i8),
+            };
+            self.emit(ins);
+            offset += size as i16;
+            rest = remainder;
+        }
+    }
+
     fn emit_push_immediate(
         &mut self,
         imm_str: &str,
@@ -272,10 +1454,10 @@ impl<'a> Compiler<'a> {
             Type::Struct(st) => (st.size, false),
             Type::Void => (8, false),
             _ => {
-                bail!(
-                    "[Line {}] Can only assign immediates to integer/inferred types.",
-                    self.expr_num
-                );
+                return Err(CompileError::InvalidType {
+                    span: self.current_span(),
+                    reason: "Can only assign immediates to integer/inferred types",
+                });
             }
         };
 
@@ -287,50 +1469,62 @@ impl<'a> Compiler<'a> {
         let new_type = match (sz, is_signed) {
             (1, false) => {
                 let imm = self.parse_immediate::<u8>(imm_str)?;
-                self.instructions
-                    .push(Instruction::store8(Register::R10, offset, imm as i8));
+                self.emit(Op::store(
+                    MemoryOpSize::Byte,
+                    Register::R10,
+                    offset,
+                    imm as i8,
+                ));
                 QualifiedType::int::<u8>()
             }
             (1, true) => {
                 let imm = self.parse_immediate::<i8>(imm_str)?;
-                self.instructions
-                    .push(Instruction::store8(Register::R10, offset, imm));
+                self.emit(Op::store(MemoryOpSize::Byte, Register::R10, offset, imm));
                 QualifiedType::int::<i8>()
             }
             (2, false) => {
                 let imm = self.parse_immediate::<u16>(imm_str)?;
-                self.instructions
-                    .push(Instruction::store16(Register::R10, offset, imm as i16));
+                self.emit(Op::store(
+                    MemoryOpSize::HalfWord,
+                    Register::R10,
+                    offset,
+                    imm as i16,
+                ));
                 QualifiedType::int::<u16>()
             }
             (2, true) => {
                 let imm = self.parse_immediate::<i16>(imm_str)?;
-                self.instructions
-                    .push(Instruction::store16(Register::R10, offset, imm));
+                self.emit(Op::store(
+                    MemoryOpSize::HalfWord,
+                    Register::R10,
+                    offset,
+                    imm,
+                ));
                 QualifiedType::int::<i16>()
             }
             (4, false) => {
                 let imm = self.parse_immediate::<u32>(imm_str)?;
-                self.instructions
-                    .push(Instruction::store32(Register::R10, offset, imm as i32));
+                self.emit(Op::store(
+                    MemoryOpSize::Word,
+                    Register::R10,
+                    offset,
+                    imm as i32,
+                ));
                 QualifiedType::int::<u32>()
             }
             (4, true) => {
                 let imm = self.parse_immediate::<i32>(imm_str)?;
-                self.instructions
-                    .push(Instruction::store32(Register::R10, offset, imm));
+                self.emit(Op::store(MemoryOpSize::Word, Register::R10, offset, imm));
                 QualifiedType::int::<i32>()
             }
             (8, false) => {
                 let imm = self.parse_immediate::<u64>(imm_str)?;
-                self.instructions
-                    .push(Instruction::store64(Register::R10, offset, imm as i64));
+                self.emit_init_bytes(offset, &imm.to_ne_bytes());
                 QualifiedType::int::<u64>()
             }
             (8, true) => {
                 let imm = self.parse_immediate::<i64>(imm_str)?;
-                self.instructions
-                    .push(Instruction::store64(Register::R10, offset, imm));
+                self.emit_init_bytes(offset, &imm.to_ne_bytes());
                 QualifiedType::int::<i64>()
             }
             (size, _) => {
@@ -340,9 +1534,37 @@ impl<'a> Compiler<'a> {
             }
         };
 
+        /*
+         * The integer type is only used when the type was inferred, otherwise the
+         * declared type (which may be a small struct) is kept.
+         */
+        let new_type = match cast_type.base_type {
+            Type::Void => new_type,
+            _ => cast_type.clone(),
+        };
+
         Ok((offset, new_type))
     }
 
+    /// Returns an error about part of a string literal, e.g. a bad escape sequence.
+    fn string_error(&self, literal: &StringLiteral, (range, reason): StringError) -> CompileError {
+        let start = literal.position.start + 1;
+        CompileError::InvalidString {
+            span: self.span(&(start + range.start..start + range.end)),
+            value: literal.value[range].to_string(),
+            reason,
+        }
+    }
+
+    /// Copies a string literal onto the stack, NUL terminated, and returns its
+    /// offset and size.
+    fn emit_push_string(&mut self, literal: &StringLiteral) -> Result<(i16, u32)> {
+        let bytes = unescape(&literal.value).map_err(|error| self.string_error(literal, error))?;
+        let offset = self.push_stack(bytes.len() as u32)?;
+        self.emit_init_bytes(offset, &bytes);
+        Ok((offset, bytes.len() as u32))
+    }
+
     fn emit_push_register(&mut self, reg: Register, offset: Option<i16>) -> Result<i16> {
         let offset = if let Some(offset) = offset {
             offset
@@ -350,8 +1572,12 @@ impl<'a> Compiler<'a> {
             self.push_stack(8)?
         };
 
-        self.instructions
-            .push(Instruction::storex64(Register::R10, offset, reg));
+        self.emit(Op::store(
+            MemoryOpSize::DoubleWord,
+            Register::R10,
+            offset,
+            reg,
+        ));
         Ok(offset)
     }
 
@@ -364,18 +1590,11 @@ impl<'a> Compiler<'a> {
         /*
          * probe_read_kernel(stack + offset, cast_type.get_size(), reg)
          */
-        self.instructions
-            .push(Instruction::movx64(Register::R1, Register::R10));
-        self.instructions
-            .push(Instruction::add64(Register::R1, offset.into()));
-        self.instructions.push(Instruction::mov64(
-            Register::R2,
-            cast_type.get_size() as i32,
-        ));
-        self.instructions
-            .push(Instruction::movx64(Register::R3, reg));
-        self.instructions
-            .push(Instruction::call(Helpers::ProbeReadKernel as u32));
+        self.emit(Op::mov64(Register::R1, Register::R10));
+        self.emit(Op::add64(Register::R1, offset));
+        self.emit(Op::mov64(Register::R2, cast_type.get_size() as i32));
+        self.emit(Op::mov64(Register::R3, reg));
+        self.emit(Op::call(Helpers::ProbeReadKernel));
     }
 
     fn emit_push_lvalue(
@@ -386,9 +1605,12 @@ impl<'a> Compiler<'a> {
     ) -> Result<(i16, QualifiedType)> {
         /*
          * This emits instructions to set R6 to a pointer to the lvalue, the type
-         * of the lvalue is returned by the function into `var_type`.
+         * of the lvalue is returned by the function into `var_type`. Out of bounds
+         * elements are read from NULL, which fails and zeroes the destination.
          */
+        let first = self.out_of_bounds.len();
         let var_type = self.emit_set_register_to_lvalue_addr(Register::R6, lval)?;
+        self.emit_out_of_bounds_zero(Register::R6, first);
 
         /*
          * If the cast type is `void` we "deduce" the type to be the type of the lvalue.
@@ -403,10 +1625,11 @@ impl<'a> Compiler<'a> {
          * The effective type must match the type of the lvalue in size.
          */
         if real_type.get_size() != var_type.get_size() {
-            bail!(
-                "[Line {}] Cannot assign two types of different sizes.",
-                self.expr_num
-            );
+            return Err(CompileError::SizeMismatch {
+                span: self.span(&lval.position),
+                expected: real_type.get_size(),
+                found: var_type.get_size(),
+            });
         }
 
         /*
@@ -423,15 +1646,19 @@ impl<'a> Compiler<'a> {
         match lval.prefix {
             None => self.emit_deref_register_to_stack(Register::R6, &real_type, offset),
             Some(Prefix::DeReferencePrefix(_)) => {
-                bail!(
-                    "[Line {}] Dereferencing is no currently implemented.",
-                    self.expr_num
-                );
+                return Err(CompileError::Unsupported {
+                    span: self.span(&lval.position),
+                    feature: "Dereferencing",
+                });
             }
             Some(Prefix::ReferencePrefix(_)) => {
                 real_type.num_refs += 1;
-                self.instructions
-                    .push(Instruction::storex64(Register::R10, offset, Register::R6));
+                self.emit(Op::store(
+                    MemoryOpSize::DoubleWord,
+                    Register::R10,
+                    offset,
+                    Register::R6,
+                ));
             }
         }
 
@@ -444,431 +1671,2938 @@ impl<'a> Compiler<'a> {
         cast_type: &QualifiedType,
         use_offset: Option<i16>,
     ) -> Result<(i16, QualifiedType)> {
-        match rval {
-            RValue::Immediate(imm_str) => self.emit_push_immediate(imm_str, cast_type, use_offset),
-            RValue::LValue(lval) => self.emit_push_lvalue(lval, cast_type, use_offset),
-            RValue::FunctionCall(call) => {
+        let expr = Expr::from_rvalue(rval);
+        let operand = match rval.as_operand() {
+            Some(operand) => operand,
+            None => return self.emit_push_expression(&expr, cast_type, use_offset),
+        };
+
+        match operand {
+            Operand::Immediate(imm_str) => self.emit_push_immediate(imm_str, cast_type, use_offset),
+            Operand::StringLiteral(literal) => Err(CompileError::InvalidType {
+                span: self.span(&literal.position),
+                reason: "String literals can only be passed to functions",
+            }),
+            Operand::LValue(lval) if self.get_map_access(lval)?.is_some() => {
+                self.emit_push_expression(&expr, cast_type, use_offset)
+            }
+            Operand::LValue(lval) => self.emit_push_lvalue(lval, cast_type, use_offset),
+            Operand::Unary(_) | Operand::Group(_) => {
+                self.emit_push_expression(&expr, cast_type, use_offset)
+            }
+            Operand::FunctionCall(call) if self.function_index(&call.name).is_some() => {
+                self.emit_push_expression(&expr, cast_type, use_offset)
+            }
+            Operand::FunctionCall(call) => {
                 if let Type::Integer(integer) = &cast_type.base_type {
                     if integer.size != 8 {
-                        bail!(
-                            "[Line {}] Cannot store function return value in non-64-bit integer.",
-                            self.expr_num
-                        );
+                        return Err(CompileError::SizeMismatch {
+                            span: self.span(&call.position),
+                            expected: integer.size,
+                            found: 8,
+                        });
                     }
 
                     self.emit_call(call)?;
                     let offset = self.emit_push_register(Register::R0, use_offset)?;
                     Ok((offset, cast_type.clone()))
                 } else {
-                    bail!(
-                        "[Line {}] Cannot store function return in non-integer type.",
-                        self.expr_num
-                    );
+                    Err(CompileError::InvalidType {
+                        span: self.span(&call.position),
+                        reason: "Cannot store function return in non-integer type",
+                    })
                 }
             }
         }
     }
 
-    fn get_member_access(
+    /// Evaluates an arithmetic expression and stores the result on the stack.
+    fn emit_push_expression(
         &mut self,
-        qtype: &QualifiedType,
-        name: &str,
-    ) -> Result<(u32, QualifiedType)> {
-        if let Type::Struct(st) = &qtype.base_type {
-            let member = st.members.get(name).context(format!(
-                "[Line {}] Member \"{}\" doesn't exist.",
-                self.expr_num, name
-            ))?;
+        expr: &Expr,
+        cast_type: &QualifiedType,
+        use_offset: Option<i16>,
+    ) -> Result<(i16, QualifiedType)> {
+        let expr_type = self.emit_set_register_from_expr(Register::R6, expr, None)?;
+        let expr_type_size = expr_type.get_size();
 
-            if member.offset % 8 != 0 {
-                bail!(
-                    "[Line {}] Bitfield accesses aren't supported.",
-                    self.expr_num
-                );
+        /*
+         * If the cast type is `void` the type is deduced from the expression, untyped
+         * literals default to a signed 64-bit integer.
+         */
+        let real_type = match (&cast_type.base_type, &expr_type.base_type) {
+            (Type::Void, Type::Void) if !expr_type.is_pointer() => QualifiedType::int::<i64>(),
+            (Type::Void, _) => expr_type.clone(),
+            _ => cast_type.clone(),
+        };
+
+        let is_scalar = real_type.is_pointer() || matches!(real_type.base_type, Type::Integer(_));
+        let size = match get_memory_size(real_type.get_size()) {
+            Some(size) if is_scalar => size,
+            _ => {
+                return Err(CompileError::InvalidType {
+                    span: self.current_span(),
+                    reason: "Can only assign expressions to integer types",
+                });
             }
+        };
 
-            let member_type = self.resolve_type_by_id(member.type_id)?;
+        let offset = match use_offset {
+            Some(off) => off,
+            None => self.push_stack(real_type.get_size())?,
+        };
 
-            Ok((member.offset / 8, member_type))
-        } else {
-            bail!(
-                "[Line {}] Tried to get member on non-struct type.",
-                self.expr_num
-            )
+        if real_type.get_size() > expr_type_size {
+            self.emit_sign_extend(Register::R6, &expr_type);
         }
+        self.emit(Op::store(size, Register::R10, offset, Register::R6));
+        Ok((offset, real_type))
     }
 
-    fn get_array_index(
+    /// Resolves a run of member accesses and constant array indices starting from a
+    /// named struct, stopping before runtime indices and after pointers, which have
+    /// to be loaded before they can be followed. Returns `None` if there's nothing
+    /// to resolve.
+    fn get_field_access(
         &mut self,
         qtype: &QualifiedType,
-        index: &str,
-    ) -> Result<(u32, QualifiedType)> {
-        let index = self.parse_immediate::<u32>(index)?;
-        if let Type::Array(ar) = &qtype.base_type {
-            if index > ar.num_elements {
-                bail!(
-                    "[Line {}] Tried to access array index {} when array size is {}.",
-                    self.expr_num,
-                    index,
-                    ar.num_elements
-                );
-            }
-            let element_type = self.resolve_type_by_id(ar.element_type)?;
-            let offset = element_type.get_size() * index;
-            Ok((offset, element_type))
-        } else {
-            bail!(
-                "[Line {}] Tried to get member on non-struct type.",
-                self.expr_num
-            )
+        derefs: &[DeReference],
+    ) -> Result<Option<FieldAccessRun>> {
+        if !matches!(&qtype.base_type, Type::Struct(st) if !st.name.is_empty()) {
+            return Ok(None);
         }
-    }
 
-    fn get_assign_offset(
-        &mut self,
-        qtype: &QualifiedType,
-        derefs: &[DeReference],
-    ) -> Result<(i16, QualifiedType)> {
+        let mut count = 0;
         let mut offset = 0;
         let mut cur_type = qtype.clone();
-        for deref in derefs.iter() {
-            if cur_type.is_pointer() {
-                bail!(
-                    "[Line {}] Indirect assignments aren't supported.",
-                    self.expr_num
-                );
+        let mut accesses = vec![];
+        for deref in derefs {
+            if count > 0 && cur_type.is_pointer() {
+                break;
             }
 
-            let (off, ty) = match deref {
-                DeReference::MemberAccess(ma) => self.get_member_access(&cur_type, &ma.name)?,
-                DeReference::ArrayIndex(ai) => self.get_array_index(&cur_type, &ai.element)?,
+            /*
+             * Members of anonymous structs and unions are reached through the
+             * anonymous member, which relocations have to record too.
+             */
+            let path = match deref {
+                DeReference::MemberAccess(ma) => self.get_member_path(&cur_type, ma)?,
+                DeReference::ArrayIndex(ai) => match self.get_constant_index(&ai.element)? {
+                    Some(index) => {
+                        let (off, next_type) = self.get_array_index(&cur_type, ai)?;
+                        vec![(off, next_type, FieldAccess::Element(index))]
+                    }
+                    None => break,
+                },
             };
 
-            offset += off;
-            cur_type = ty;
+            for (off, next_type, access) in path {
+                offset += off;
+                cur_type = next_type;
+                accesses.push(access);
+            }
+            count += 1;
         }
 
-        Ok((offset.try_into()?, cur_type))
-    }
-
-    fn emit_assign(&mut self, assign: &Assignment) -> Result<()> {
-        let mut new_variable = true;
-        let (cast_type, use_offset) =
-            if let Ok(info) = &self.get_variable_by_name(&assign.left.name) {
-                if assign.type_name.is_some() {
-                    bail!(
-                        "[Line {}] Can't re-type \"{}\" after first assignment.",
-                        self.expr_num,
-                        assign.left.name
-                    );
-                } else if let VariableLocation::Stack(off) = info.location {
-                    let (rel_off, offset_type) =
-                        self.get_assign_offset(&info.var_type, &assign.left.derefs)?;
-                    new_variable = false;
-                    (offset_type, Some(off + rel_off))
-                } else {
-                    bail!(
-                        "[Line {}] Variable \"{}\" cannot be re-assigned.",
-                        self.expr_num,
-                        assign.left.name
-                    );
-                }
-            } else if let Some(type_name) = &assign.type_name {
-                let assign_type = self.resolve_type_by_decl(type_name)?;
-                (assign_type, None)
-            } else {
-                (Default::default(), None)
-            };
+        if count == 0 {
+            return Ok(None);
+        }
 
-        let (offset, new_type) = self.emit_push_rvalue(&assign.right, &cast_type, use_offset)?;
+        Ok(Some(FieldAccessRun {
+            count,
+            offset,
+            field_type: cur_type,
+            accesses,
+        }))
+    }
 
-        if new_variable {
-            self.variables.insert(
-                assign.left.name.clone(),
-                VariableInfo {
-                    var_type: new_type,
-                    location: VariableLocation::Stack(offset),
-                },
-            );
+    fn get_member_access(
+        &mut self,
+        qtype: &QualifiedType,
+        member_access: &MemberAccess,
+    ) -> Result<(u32, QualifiedType)> {
+        let mut offset = 0;
+        let mut member_type = QualifiedType::default();
+        for (off, next_type, _) in self.get_member_path(qtype, member_access)? {
+            offset += off;
+            member_type = next_type;
         }
 
-        Ok(())
+        Ok((offset, member_type))
     }
 
-    fn emit_deref_member_access(
+    /// Resolves a member access to the members leading to it from `qtype`, each
+    /// with its offset in bytes from the previous one, its type and how it's
+    /// recorded in relocations. Members of anonymous structs and unions are
+    /// accessed as if they belonged to the enclosing type, as in C, so those take
+    /// more than one step.
+    fn get_member_path(
         &mut self,
-        reg: Register,
         qtype: &QualifiedType,
         member_access: &MemberAccess,
-    ) -> Result<QualifiedType> {
-        let (offset, member_type) = self.get_member_access(qtype, &member_access.name)?;
-        if offset > 0 {
-            self.instructions
-                .push(Instruction::add64(reg, offset as i32));
+    ) -> Result<Vec<(u32, QualifiedType, FieldAccess)>> {
+        let name = &member_access.name;
+        let mut cur = match &qtype.base_type {
+            Type::Struct(_) | Type::Union(_) => qtype.base_type.clone(),
+            _ => {
+                return Err(CompileError::NotAStruct {
+                    span: self.span(&member_access.position),
+                    name: name.clone(),
+                })
+            }
+        };
+
+        /*
+         * The type library keeps anonymous members under an empty name, so only
+         * one anonymous member of each type can be looked into.
+         */
+        let mut path = vec![];
+        let mut visible = vec![];
+        let mut hidden = false;
+        while let Type::Struct(st) | Type::Union(st) = &cur {
+            hidden |= self.has_hidden_members(st, matches!(cur, Type::Union(_)))?;
+            visible.extend(st.members.keys().filter(|k| !k.is_empty()).cloned());
+            let (member, is_anonymous) = match st.members.get(name) {
+                Some(member) => (member.clone(), false),
+                None => match st.members.get("") {
+                    Some(member) => (member.clone(), true),
+                    None => break,
+                },
+            };
+
+            if member.offset % 8 != 0 {
+                return Err(CompileError::BitfieldUnsupported {
+                    span: self.span(&member_access.position),
+                    name: name.clone(),
+                });
+            }
+
+            let member_type = self.resolve_type_by_id(member.type_id)?;
+            let index = sorted_members(st)
+                .iter()
+                .position(|m| m.name == member.name)
+                .unwrap_or_default();
+            let access = FieldAccess::Member {
+                name: member.name.clone(),
+                index: index as u32,
+            };
+            path.push((member.offset / 8, member_type.clone(), access));
+            if !is_anonymous {
+                return Ok(path);
+            }
+
+            if member_type.is_pointer() {
+                break;
+            }
+            cur = member_type.base_type;
         }
-        Ok(member_type)
+
+        let span = self.ident_span(&member_access.position, name);
+        if hidden {
+            return Err(CompileError::Unsupported {
+                span,
+                feature: "Looking up members of types with several anonymous members",
+            });
+        }
+
+        Err(CompileError::UnknownMember {
+            span,
+            name: name.clone(),
+            suggestion: closest_match(name, visible.iter().map(|k| k.as_str())),
+        })
     }
 
-    fn emit_deref_array_index(
+    /// Returns whether a struct or union has members the type library lost. All
+    /// anonymous members share the empty name, so only the last one is kept, and
+    /// the others show up as space the remaining members don't account for: a gap
+    /// between members, or at the end, at least as large as a member's alignment,
+    /// which is more than padding can take.
+    ///
+    /// # Arguments
+    ///
+    /// * `st` - The struct or union.
+    /// * `is_union` - Whether it's a union, whose members all start at offset 0.
+    fn has_hidden_members(&mut self, st: &Struct, is_union: bool) -> Result<bool> {
+        let mut end = 0;
+        let mut largest = 0;
+        let mut max_align = 1;
+        for member in sorted_members(st) {
+            let size = self.resolve_type_by_id(member.type_id)?.get_size();
+            let align = 1 << size.clamp(1, 8).ilog2();
+            if !is_union && (member.offset / 8).saturating_sub(end) >= align {
+                return Ok(true);
+            }
+
+            let bits = match member.bitfield_size {
+                0 => size * 8,
+                bits => bits,
+            };
+            end = end.max((member.offset + bits).div_ceil(8));
+            largest = largest.max(size);
+            max_align = max_align.max(align);
+        }
+
+        let used = if is_union { largest } else { end };
+        Ok(st.size.saturating_sub(used) >= max_align)
+    }
+
+    /// Returns the value of an array index if it's known at compile time, either
+    /// because it's an immediate or because it names a special immediate variable
+    /// (e.g. the counter of an unrolled loop).
+    fn get_constant_index(&mut self, index: &RValue) -> Result<Option<u32>> {
+        match index.as_operand() {
+            Some(Operand::Immediate(imm)) => Ok(Some(self.parse_immediate::<u32>(imm)?)),
+            Some(Operand::LValue(lval)) if lval.prefix.is_none() && lval.derefs.is_empty() => {
+                match self.get_variable_by_lvalue(lval)?.location {
+                    VariableLocation::SpecialImmediate(v) => match u32::try_from(v) {
+                        Ok(v) => Ok(Some(v)),
+                        Err(_) => Err(CompileError::InvalidImmediate {
+                            span: self.span(&lval.position),
+                            value: v.to_string(),
+                        }),
+                    },
+                    _ => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn get_array_index(
         &mut self,
-        reg: Register,
         qtype: &QualifiedType,
         array_index: &ArrayIndex,
-    ) -> Result<QualifiedType> {
-        let (offset, element_type) = self.get_array_index(qtype, &array_index.element)?;
-        if offset > 0 {
-            self.instructions
-                .push(Instruction::add64(reg, offset as i32));
+    ) -> Result<(u32, QualifiedType)> {
+        let index = match self.get_constant_index(&array_index.element)? {
+            Some(index) => index,
+            None => {
+                return Err(CompileError::NonConstantIndex {
+                    span: self.span(&array_index.position),
+                });
+            }
+        };
+
+        if let Type::Array(ar) = &qtype.base_type {
+            /*
+             * Arrays without elements are flexible array members, which can be
+             * indexed past their end.
+             */
+            if index >= ar.num_elements && ar.num_elements != 0 {
+                return Err(CompileError::IndexOutOfBounds {
+                    span: self.span(&array_index.position),
+                    index,
+                    len: ar.num_elements,
+                });
+            }
+            let element_type = self.resolve_type_by_id(ar.element_type)?;
+            let offset = element_type.get_size() * index;
+            Ok((offset, element_type))
+        } else {
+            Err(CompileError::InvalidType {
+                span: self.span(&array_index.position),
+                reason: "Tried to index a non-array type",
+            })
         }
-        Ok(element_type)
     }
 
-    fn emit_apply_derefs_to_reg(
+    fn get_assign_offset(
         &mut self,
-        reg: Register,
-        var_type: &QualifiedType,
+        qtype: &QualifiedType,
         derefs: &[DeReference],
-    ) -> Result<QualifiedType> {
-        if derefs.is_empty() {
-            return Ok(var_type.clone());
+    ) -> Result<(i16, QualifiedType)> {
+        let mut offset = 0;
+        let mut cur_type = qtype.clone();
+        for deref in derefs.iter() {
+            if cur_type.is_pointer() {
+                return Err(CompileError::Unsupported {
+                    span: self.current_span(),
+                    feature: "Indirect assignment",
+                });
+            }
+
+            let (off, ty) = match deref {
+                DeReference::MemberAccess(ma) => self.get_member_access(&cur_type, ma)?,
+                DeReference::ArrayIndex(ai) => self.get_array_index(&cur_type, ai)?,
+            };
+
+            offset += off;
+            cur_type = ty;
         }
 
-        if var_type.is_pointer() {
-            self.instructions.push(Instruction::loadx64(reg, reg, 0));
+        match offset.try_into() {
+            Ok(offset) => Ok((offset, cur_type)),
+            Err(_) => Err(CompileError::InvalidType {
+                span: self.current_span(),
+                reason: "Assignment offset is out of range",
+            }),
         }
+    }
 
-        let next_type = match &derefs[0] {
-            DeReference::MemberAccess(ma) => self.emit_deref_member_access(reg, var_type, ma)?,
-            DeReference::ArrayIndex(ai) => self.emit_deref_array_index(reg, var_type, ai)?,
+    fn emit_assign(&mut self, assign: &Assignment) -> Result<()> {
+        if let Some(access) = self.get_map_access(&assign.left)? {
+            return self.emit_map_assign(assign, access);
+        }
+
+        if let Some(op) = Operator::from_assignment(&assign.op) {
+            return self.emit_compound_assign(assign, op);
+        }
+
+        let mut new_variable = true;
+        let (cast_type, use_offset) =
+            if let Ok(info) = &self.get_variable_by_name(&assign.left.name) {
+                if let Some(type_name) = &assign.type_name {
+                    return Err(CompileError::RetypedVariable {
+                        span: self.span(&type_name.position),
+                        name: assign.left.name.clone(),
+                    });
+                } else if let VariableLocation::Stack(off) = info.location {
+                    let (rel_off, offset_type) =
+                        self.get_assign_offset(&info.var_type, &assign.left.derefs)?;
+                    new_variable = false;
+                    (offset_type, Some(off + rel_off))
+                } else {
+                    return Err(CompileError::ImmutableVariable {
+                        span: self.span(&assign.left.position),
+                        name: assign.left.name.clone(),
+                    });
+                }
+            } else if let Some(type_name) = &assign.type_name {
+                let assign_type = self.resolve_type_by_decl(type_name)?;
+                (assign_type, None)
+            } else {
+                (Default::default(), None)
+            };
+
+        let (offset, new_type) = self.emit_push_rvalue(&assign.right, &cast_type, use_offset)?;
+
+        if new_variable {
+            self.variables.insert(
+                assign.left.name.clone(),
+                VariableInfo {
+                    var_type: new_type,
+                    location: VariableLocation::Stack(offset),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Emits a compound assignment, e.g. `a += b`, to an existing stack variable
+    /// by evaluating it as `a = a + (b)`.
+    fn emit_compound_assign(&mut self, assign: &Assignment, op: Operator) -> Result<()> {
+        let info = self.get_variable_by_lvalue(&assign.left)?;
+        if let Some(type_name) = &assign.type_name {
+            return Err(CompileError::RetypedVariable {
+                span: self.span(&type_name.position),
+                name: assign.left.name.clone(),
+            });
+        }
+
+        let offset = match info.location {
+            VariableLocation::Stack(offset) => offset,
+            _ => {
+                return Err(CompileError::ImmutableVariable {
+                    span: self.span(&assign.left.position),
+                    name: assign.left.name.clone(),
+                });
+            }
         };
 
-        self.emit_apply_derefs_to_reg(reg, &next_type, &derefs[1..])
+        let (rel_off, target_type) = self.get_assign_offset(&info.var_type, &assign.left.derefs)?;
+        let left = Operand::LValue(assign.left.clone());
+        let expr = Expr::Binary(
+            op,
+            Box::new(Expr::Operand(&left)),
+            Box::new(Expr::from_rvalue(&assign.right)),
+        );
+
+        self.emit_push_expression(&expr, &target_type, Some(offset + rel_off))?;
+        Ok(())
     }
 
-    fn emit_set_register_to_lvalue_addr(
+    /// Declares a map. The map's file descriptor must already have been captured
+    /// under the same name, the declaration gives it a key and value type so it
+    /// can be indexed.
+    fn emit_map_decl(&mut self, decl: &MapDecl) -> Result<()> {
+        let span = self.ident_span(&decl.position, &decl.name);
+        let map_type = match MAP_KINDS.iter().find(|(kind, _)| *kind == decl.kind) {
+            Some((_, map_type)) => *map_type,
+            None => {
+                return Err(CompileError::InvalidMap {
+                    span: self.ident_span(&decl.position, &decl.kind),
+                    name: decl.kind.clone(),
+                    reason: "Unknown map kind",
+                });
+            }
+        };
+
+        let key_type = self.resolve_type_by_decl(&decl.key)?;
+        let value_type = self.resolve_type_by_decl(&decl.value)?;
+        if key_type.get_size() == 0 || value_type.get_size() == 0 {
+            return Err(CompileError::InvalidMap {
+                span,
+                name: decl.name.clone(),
+                reason: "Map keys and values can't be empty",
+            });
+        }
+
+        /*
+         * Array maps are always indexed by a 32-bit integer.
+         */
+        let is_array = matches!(map_type, 2 | 6);
+        let is_int_key = !key_type.is_pointer() && matches!(key_type.base_type, Type::Integer(_));
+        if is_array && (!is_int_key || key_type.get_size() != 4) {
+            return Err(CompileError::InvalidMap {
+                span,
+                name: decl.name.clone(),
+                reason: "Array maps must have a 4 byte integer key",
+            });
+        }
+
+        let max_entries = match &decl.max_entries {
+            Some(max_entries) => self.parse_immediate::<u32>(max_entries)?,
+            None => DEFAULT_MAX_ENTRIES,
+        };
+        if max_entries == 0 {
+            return Err(CompileError::InvalidMap {
+                span,
+                name: decl.name.clone(),
+                reason: "Maps must have at least one entry",
+            });
+        }
+
+        let fd = match self.variables.get(&decl.name).map(|info| info.location) {
+            Some(VariableLocation::SpecialImmediate(fd)) if u32::try_from(fd).is_ok() => fd as u32,
+            _ => {
+                return Err(CompileError::InvalidMap {
+                    span,
+                    name: decl.name.clone(),
+                    reason: "Maps must be captured before being declared",
+                });
+            }
+        };
+
+        /*
+         * Functions that use the same map each declare it, which is fine as long as
+         * the declarations agree.
+         */
+        let existing = self.maps.iter().position(|map| {
+            map.name == decl.name
+                && map.fd == fd
+                && map.map_type == map_type
+                && map.max_entries == max_entries
+                && same_type(&map.key_type, &key_type)
+                && same_type(&map.value_type, &value_type)
+        });
+        if let Some(index) = existing {
+            self.variables.insert(
+                decl.name.clone(),
+                VariableInfo {
+                    var_type: value_type,
+                    location: VariableLocation::Map(index),
+                },
+            );
+            return Ok(());
+        }
+
+        /*
+         * Object files identify the map an instruction references by its fd, so
+         * they have to be unique even when they're only placeholders.
+         */
+        if self.maps.iter().any(|map| map.fd == fd) {
+            return Err(CompileError::InvalidMap {
+                span,
+                name: decl.name.clone(),
+                reason: "Maps must be captured with distinct file descriptors",
+            });
+        }
+
+        self.maps.push(MapInfo {
+            name: decl.name.clone(),
+            fd,
+            map_type,
+            max_entries,
+            key_type,
+            value_type: value_type.clone(),
+        });
+        self.variables.insert(
+            decl.name.clone(),
+            VariableInfo {
+                var_type: value_type,
+                location: VariableLocation::Map(self.maps.len() - 1),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// If an lvalue indexes into a map, returns the map, the key, and any
+    /// dereferences applied to the value.
+    fn get_map_access<'b>(
         &mut self,
-        reg: Register,
-        lval: &LValue,
-    ) -> Result<QualifiedType> {
-        let info = self.get_variable_by_name(&lval.name)?;
+        lval: &'b LValue,
+    ) -> Result<Option<(MapInfo, &'b RValue, &'b [DeReference])>> {
+        let map = match self.variables.get(&lval.name).map(|info| info.location) {
+            Some(VariableLocation::Map(index)) => self.maps[index].clone(),
+            _ => return Ok(None),
+        };
 
-        match info.location {
-            VariableLocation::SpecialImmediate(_) => {
-                bail!(
-                    "[Line {}] Cannot assign a value to a special immediate variable.",
-                    self.expr_num,
-                );
+        let key = match lval.derefs.first() {
+            None => return Ok(None),
+            Some(DeReference::ArrayIndex(ai)) if lval.prefix.is_none() => &ai.element,
+            Some(_) => {
+                return Err(CompileError::InvalidMap {
+                    span: self.span(&lval.position),
+                    name: lval.name.clone(),
+                    reason: "Maps can only be indexed by a key",
+                });
             }
-            VariableLocation::Stack(o) => {
-                self.instructions
-                    .push(Instruction::movx64(reg, Register::R10));
-                self.instructions.push(Instruction::add64(reg, o.into()));
+        };
+
+        Ok(Some((map, key, &lval.derefs[1..])))
+    }
+
+    /// Evaluates a map key or value into a new buffer on the stack and returns its
+    /// offset. Structs can't be evaluated into a register, so they have to name a
+    /// stack variable which is then used in place.
+    fn emit_map_buffer(
+        &mut self,
+        reg: Register,
+        rval: &RValue,
+        buffer_type: &QualifiedType,
+    ) -> Result<i16> {
+        let is_scalar =
+            buffer_type.is_pointer() || matches!(buffer_type.base_type, Type::Integer(_));
+        if let (true, Some(size)) = (is_scalar, get_memory_size(buffer_type.get_size())) {
+            self.emit_set_register_from_rvalue(reg, rval, None)?;
+            let offset = self.push_stack(buffer_type.get_size())?;
+            self.emit(Op::store(size, Register::R10, offset, reg));
+            return Ok(offset);
+        }
+
+        if let Some(Operand::LValue(lval)) = rval.as_operand() {
+            let info = self.get_variable_by_lvalue(lval)?;
+            if let (None, VariableLocation::Stack(offset)) = (&lval.prefix, info.location) {
+                let (rel_off, var_type) = self.get_assign_offset(&info.var_type, &lval.derefs)?;
+                if var_type.get_size() != buffer_type.get_size() {
+                    return Err(CompileError::SizeMismatch {
+                        span: self.span(&lval.position),
+                        expected: buffer_type.get_size(),
+                        found: var_type.get_size(),
+                    });
+                }
+
+                return Ok(offset + rel_off);
             }
         }
 
-        self.emit_apply_derefs_to_reg(reg, &info.var_type, &lval.derefs)
+        Err(CompileError::InvalidType {
+            span: self.current_span(),
+            reason: "Struct map keys and values must be stack variables",
+        })
     }
 
-    fn emit_set_register_from_lvalue(
+    /// Sets R1 to the map and R2 to a pointer to the key at `key_offset` on the stack.
+    fn emit_map_key_args(&mut self, map: &MapInfo, key_offset: i16) {
+        self.emit(Op::LoadImmediate {
+            dst: Register::R1,
+            imm: map.fd.into(),
+            load_type: MemoryOpLoadType::Map,
+        });
+        self.emit(Op::mov64(Register::R2, Register::R10));
+        self.emit(Op::add64(Register::R2, key_offset));
+    }
+
+    /// Emits a `map_update_elem` call that stores the value at `value_offset` on the
+    /// stack under the key at `key_offset`, with `flags` being `BPF_ANY` or
+    /// `BPF_NOEXIST`.
+    fn emit_map_update(&mut self, map: &MapInfo, key_offset: i16, value_offset: i16, flags: i32) {
+        self.emit_map_key_args(map, key_offset);
+        self.emit(Op::mov64(Register::R3, Register::R10));
+        self.emit(Op::add64(Register::R3, value_offset));
+        self.emit(Op::mov64(Register::R4, flags));
+        self.emit(Op::call(Helpers::MapUpdateElem));
+    }
+
+    /// Ends the block with a branch taken when a map lookup returned null, to be
+    /// patched with `patch_jump`.
+    fn emit_null_check(&mut self) -> BlockId {
+        let otherwise = self.next_block();
+        self.emit_terminator(Op::Branch {
+            op: JumpOperation::IfEqual,
+            lhs: Register::R0,
+            rhs: 0.into(),
+            then: PENDING,
+            otherwise,
+        })
+    }
+
+    /// Sets a register to the value stored in a map, or zero if the key isn't
+    /// present.
+    fn emit_map_read(
         &mut self,
         reg: Register,
         lval: &LValue,
-        load_type: Option<MemoryOpLoadType>,
-    ) -> Result<()> {
-        let info = self.get_variable_by_name(&lval.name)?;
-        if let VariableLocation::SpecialImmediate(v) = info.location {
-            if !lval.derefs.is_empty() {
-                bail!(
-                    "[Line {}] Cannot dereference a special immediate variable.",
-                    self.expr_num,
-                );
-            }
+        access: (MapInfo, &RValue, &[DeReference]),
+    ) -> Result<QualifiedType> {
+        let (map, key, derefs) = access;
+        let key_offset = self.emit_map_buffer(reg, key, &map.key_type)?;
+        self.emit_map_key_args(&map, key_offset);
+        self.emit(Op::call(Helpers::MapLookupElem));
 
-            let load_type = load_type.unwrap_or(MemoryOpLoadType::Void);
-            self.instructions
-                .push(Instruction::loadtype(reg, v.into(), load_type));
-            return Ok(());
+        /*
+         * if r0 == 0 goto null
+         * reg = *(r0 + derefs)
+         * goto done
+         * null:
+         *   reg = 0
+         * done:
+         */
+        let null_jump = self.emit_null_check();
+        if reg != Register::R0 {
+            self.emit(Op::mov64(reg, Register::R0));
         }
 
-        let var_type = self.emit_set_register_to_lvalue_addr(reg, lval)?;
+        let first = self.out_of_bounds.len();
+        let value_type = self.emit_apply_derefs_to_reg(reg, &map.value_type, derefs, false)?;
+        let is_scalar = value_type.is_pointer() || matches!(value_type.base_type, Type::Integer(_));
+        let size = match get_memory_size(value_type.get_size()) {
+            Some(size) if is_scalar => size,
+            _ => {
+                return Err(CompileError::InvalidType {
+                    span: self.span(&lval.position),
+                    reason: "Only integer map values can be read",
+                });
+            }
+        };
+        self.emit(Op::load(size, reg, reg, 0));
 
         /*
-         * the register is already holding a pointer to the lvalue so, if a reference
-         * was specified, nothing else needs to be done.
+         * Out of bounds indices into the value read 0 like missing elements. R0 is
+         * already zero when the lookup failed, so there's nothing to do when it's
+         * the destination and no index was checked.
          */
-        if matches!(lval.prefix, Some(Prefix::ReferencePrefix(_))) {
-            return Ok(());
+        let out_of_bounds = self.out_of_bounds.split_off(first);
+        if reg == Register::R0 && out_of_bounds.is_empty() {
+            self.patch_jump(null_jump);
+        } else {
+            let done_jump = self.emit_terminator(Op::Jump { target: PENDING });
+            self.patch_jump(null_jump);
+            for out_of_bounds in out_of_bounds {
+                self.patch_jump(out_of_bounds);
+            }
+            self.emit(Op::mov64(reg, 0));
+            self.patch_jump(done_jump);
         }
 
-        /*
-         * register is pointing to a value of type `var_type`, load it into the register,
-         * if it fits.
-         */
-        match var_type.get_size() {
-            1 => self.instructions.push(Instruction::loadx8(reg, reg, 0)),
-            2 => self.instructions.push(Instruction::loadx16(reg, reg, 0)),
-            4 => self.instructions.push(Instruction::loadx32(reg, reg, 0)),
-            8 => self.instructions.push(Instruction::loadx64(reg, reg, 0)),
+        Ok(value_type)
+    }
+
+    /// Emits an assignment to a map element, e.g. `counts[key] = 1` or
+    /// `counts[key] += 1`.
+    fn emit_map_assign(
+        &mut self,
+        assign: &Assignment,
+        access: (MapInfo, &RValue, &[DeReference]),
+    ) -> Result<()> {
+        let (map, key, derefs) = access;
+        if !derefs.is_empty() {
+            return Err(CompileError::Unsupported {
+                span: self.span(&assign.left.position),
+                feature: "Assigning to part of a map value",
+            });
+        }
+
+        if let Some(type_name) = &assign.type_name {
+            return Err(CompileError::RetypedVariable {
+                span: self.span(&type_name.position),
+                name: assign.left.name.clone(),
+            });
+        }
+
+        let op = match Operator::from_assignment(&assign.op) {
+            Some(op) => op,
+            None => {
+                let key_offset = self.emit_map_buffer(Register::R6, key, &map.key_type)?;
+                let value_offset =
+                    self.emit_map_buffer(Register::R6, &assign.right, &map.value_type)?;
+                self.emit_map_update(&map, key_offset, value_offset, BPF_ANY);
+                return Ok(());
+            }
+        };
+
+        let value_type = map.value_type.clone();
+        let size = match get_memory_size(value_type.get_size()) {
+            Some(size) if matches!(value_type.base_type, Type::Integer(_)) => size,
             _ => {
-                bail!(
-                    "[Line {}] Variable too large to be passed in a register.",
-                    self.expr_num,
-                );
+                return Err(CompileError::InvalidType {
+                    span: self.span(&assign.left.position),
+                    reason: "Compound assignments to maps need an integer value",
+                });
             }
+        };
+
+        /*
+         * The operand is kept in R7 across the helper calls. Subtraction is done by
+         * adding the negated operand so that it can be performed atomically.
+         */
+        let mut op = op;
+        self.emit_set_register_from_rvalue(Register::R7, &assign.right, None)?;
+        if op == Operator::Subtract {
+            self.emit(Op::alu(
+                ArithmeticOperation::Neg,
+                Width::Bits64,
+                Register::R7,
+                0,
+            ));
+            op = Operator::Add;
         }
 
+        let key_offset = self.emit_map_buffer(Register::R6, key, &map.key_type)?;
+        self.emit_map_key_args(&map, key_offset);
+        self.emit(Op::call(Helpers::MapLookupElem));
+
         /*
-         * the register is now holding `var_type`. if another dereference was requested
-         * then make sure the type being held by the register is a pointer.
+         * if r0 == 0 goto insert
+         * *r0 <op>= r7
+         * goto done
+         * insert:
+         *   value = 0 <op> r7
+         *   r0 = map_update_elem(map, &key, &value, BPF_NOEXIST)
+         *   if r0 != -EEXIST goto done
+         *   r0 = map_lookup_elem(map, &key)
+         *   if r0 == 0 goto done
+         *   *r0 <op>= r7
+         * done:
+         *
+         * Another CPU may insert the element between the lookup and the update, in
+         * which case the update fails and the operation is applied to its element
+         * instead, so that neither of them is lost.
          */
-        if matches!(lval.prefix, Some(Prefix::DeReferencePrefix(_))) {
-            if !var_type.is_pointer() {
-                bail!(
-                    "[Line {}] Cannot reference a non-pointer type.",
-                    self.expr_num,
-                );
-            }
+        let insert_jump = self.emit_null_check();
+        self.emit_map_value_operation(op, size, &value_type)?;
+        let done_jump = self.emit_terminator(Op::Jump { target: PENDING });
+        self.patch_jump(insert_jump);
 
-            self.instructions.push(Instruction::loadx64(reg, reg, 0));
-        }
+        self.emit(Op::mov64(Register::R6, 0));
+        self.emit_arithmetic(op, Register::R6, Err(Register::R7), &value_type)?;
+        let value_offset = self.push_stack(value_type.get_size())?;
+        self.emit(Op::store(size, Register::R10, value_offset, Register::R6));
+        self.emit_map_update(&map, key_offset, value_offset, BPF_NOEXIST);
+
+        let otherwise = self.next_block();
+        let inserted_jump = self.emit_terminator(Op::Branch {
+            op: JumpOperation::IfNotEqual,
+            lhs: Register::R0,
+            rhs: (-EEXIST).into(),
+            then: PENDING,
+            otherwise,
+        });
+        self.emit_map_key_args(&map, key_offset);
+        self.emit(Op::call(Helpers::MapLookupElem));
+        let deleted_jump = self.emit_null_check();
+        self.emit_map_value_operation(op, size, &value_type)?;
+
+        self.patch_jump(done_jump);
+        self.patch_jump(inserted_jump);
+        self.patch_jump(deleted_jump);
 
         Ok(())
     }
 
-    fn emit_set_register_from_rvalue(
+    /// Applies a compound assignment's operator and the operand in R7 to the map
+    /// value R0 points to, atomically if the operation can be.
+    fn emit_map_value_operation(
         &mut self,
-        reg: Register,
-        rval: &RValue,
-        load_type: Option<MemoryOpLoadType>,
+        op: Operator,
+        size: MemoryOpSize,
+        value_type: &QualifiedType,
     ) -> Result<()> {
-        match rval {
-            RValue::Immediate(imm_str) => {
-                if let Some(load_type) = load_type {
-                    let imm = self.parse_immediate(imm_str)?;
-                    self.instructions
-                        .push(Instruction::loadtype(reg, imm, load_type));
-                } else {
-                    let imm = self.parse_immediate(imm_str)?;
-                    self.instructions.push(Instruction::mov64(reg, imm));
-                }
+        let is_signed = matches!(&value_type.base_type, Type::Integer(int) if int.is_signed);
+        let operation = op.get_operation(is_signed);
+        match atomic(operation, Register::R0, 0, Register::R7, size) {
+            Some(_) => self.emit(Op::Atomic {
+                op: operation,
+                size,
+                base: Register::R0,
+                offset: 0,
+                src: Register::R7,
+            }),
+            None => {
+                self.emit(Op::load(size, Register::R6, Register::R0, 0));
+                self.emit_arithmetic(op, Register::R6, Err(Register::R7), value_type)?;
+                self.emit(Op::store(size, Register::R0, 0, Register::R6));
             }
-            RValue::LValue(lval) => {
-                self.emit_set_register_from_lvalue(reg, lval, load_type)?;
+        }
+
+        Ok(())
+    }
+
+    fn emit_delete(&mut self, stmt: &Delete) -> Result<()> {
+        let (map, key) = match self.get_map_access(&stmt.target)? {
+            Some((map, key, [])) => (map, key),
+            _ => {
+                return Err(CompileError::InvalidMap {
+                    span: self.span(&stmt.target.position),
+                    name: stmt.target.name.clone(),
+                    reason: "Only map elements can be deleted",
+                });
             }
-            RValue::FunctionCall(call) => {
-                self.emit_call(call)?;
-                if !matches!(reg, Register::R0) {
-                    self.instructions
-                        .push(Instruction::movx64(reg, Register::R0));
-                }
+        };
+
+        let key_offset = self.emit_map_buffer(Register::R6, key, &map.key_type)?;
+        self.emit_map_key_args(&map, key_offset);
+        self.emit(Op::call(Helpers::MapDeleteElem));
+
+        Ok(())
+    }
+
+    fn emit_deref_member_access(
+        &mut self,
+        reg: Register,
+        qtype: &QualifiedType,
+        member_access: &MemberAccess,
+    ) -> Result<QualifiedType> {
+        let (offset, member_type) = self.get_member_access(qtype, member_access)?;
+        if offset > 0 {
+            self.emit(Op::add64(reg, offset as i32));
+        }
+        Ok(member_type)
+    }
+
+    /// Advances `reg` past a run of member accesses and constant array indices with
+    /// a single relocated `add`, see `get_field_access`. Returns the number of
+    /// dereferences consumed and the resulting type, or `None` if nothing could be
+    /// relocated.
+    fn emit_relocated_access(
+        &mut self,
+        reg: Register,
+        qtype: &QualifiedType,
+        derefs: &[DeReference],
+    ) -> Result<Option<(usize, QualifiedType)>> {
+        let run = match self.get_field_access(qtype, derefs)? {
+            Some(run) => run,
+            None => return Ok(None),
+        };
+
+        let relocation = CoreRelocation {
+            instruction: 0,
+            kind: RelocationKind::FieldByteOffset,
+            type_id: qtype.base_type.get_id().unwrap_or_default(),
+            accesses: run.accesses,
+        };
+        self.emit_relocated(Op::add64(reg, run.offset as i32), relocation);
+        Ok(Some((run.count, run.field_type)))
+    }
+
+    fn emit_deref_array_index(
+        &mut self,
+        reg: Register,
+        qtype: &QualifiedType,
+        array_index: &ArrayIndex,
+        in_memory: bool,
+    ) -> Result<QualifiedType> {
+        if self.get_constant_index(&array_index.element)?.is_none() {
+            return self.emit_deref_runtime_index(reg, qtype, array_index, in_memory);
+        }
+
+        let (offset, element_type) = self.get_array_index(qtype, array_index)?;
+        if offset > 0 {
+            self.emit(Op::add64(reg, offset as i32));
+        }
+        Ok(element_type)
+    }
+
+    /// Advances `reg` to an array element whose index is only known at runtime.
+    /// Plain variable indices are scaled in R9. Anything more complex may need
+    /// every register, so the address is spilled to the stack while the index is
+    /// evaluated. Out of bounds indices skip the access, which reads 0 instead,
+    /// see `emit_out_of_bounds_zero`. Indexing with the counter of a loop whose
+    /// range is longer than the array fails to compile.
+    fn emit_deref_runtime_index(
+        &mut self,
+        reg: Register,
+        qtype: &QualifiedType,
+        array_index: &ArrayIndex,
+        in_memory: bool,
+    ) -> Result<QualifiedType> {
+        let ar = match &qtype.base_type {
+            Type::Array(ar) if !qtype.is_pointer() => ar.clone(),
+            _ => {
+                return Err(CompileError::InvalidType {
+                    span: self.span(&array_index.position),
+                    reason: "Tried to index a non-array type",
+                });
+            }
+        };
+
+        let element_type = self.resolve_type_by_id(ar.element_type)?;
+        let (index_reg, spill) = match array_index.element.as_operand() {
+            Some(Operand::LValue(lval)) if lval.prefix.is_none() && lval.derefs.is_empty() => {
+                let range = self.loop_ranges.get(&lval.name).cloned();
+                match range {
+                    Some(range) if range.end > ar.num_elements && ar.num_elements != 0 => {
+                        return Err(CompileError::IndexOutOfBounds {
+                            span: self.span(&array_index.position),
+                            index: range.end - 1,
+                            len: ar.num_elements,
+                        });
+                    }
+                    _ => (Register::R9, None),
+                }
+            }
+            _ => (reg, Some(self.emit_push_register(reg, None)?)),
+        };
+
+        let index_type =
+            self.emit_set_register_from_rvalue(index_reg, &array_index.element, None)?;
+        if index_type.is_pointer() || !matches!(index_type.base_type, Type::Integer(_) | Type::Void)
+        {
+            return Err(CompileError::InvalidType {
+                span: self.span(&array_index.position),
+                reason: "Array index must be an integer",
+            });
+        }
+
+        /*
+         * if index >= len goto out_of_bounds
+         *
+         * The jump is patched once the element has been read. Negative indices are
+         * out of bounds too, since they're compared unsigned.
+         */
+        if let Ok(len @ 1..) = i32::try_from(ar.num_elements) {
+            let otherwise = self.next_block();
+            let out_of_bounds = self.emit_terminator(Op::Branch {
+                op: JumpOperation::IfGreaterOrEqual,
+                lhs: index_reg,
+                rhs: len.into(),
+                then: PENDING,
+                otherwise,
+            });
+            self.out_of_bounds.push(out_of_bounds);
+        }
+
+        /*
+         * The stride of arrays in memory is relocated as the size of the element type,
+         * which has to be named for loaders to find it on the target.
+         */
+        let is_named = matches!(
+            &element_type.base_type,
+            Type::Struct(_) | Type::Union(_) | Type::Integer(_) | Type::Enum32(_) | Type::Enum64(_)
+        ) && !element_type.base_type.get_name().is_empty();
+        let stride = Op::alu(
+            ArithmeticOperation::Mul,
+            Width::Bits64,
+            index_reg,
+            element_type.get_size() as i32,
+        );
+        if in_memory && self.core_relocations && is_named && !element_type.is_pointer() {
+            let relocation = CoreRelocation {
+                instruction: 0,
+                kind: RelocationKind::TypeSize,
+                type_id: element_type.base_type.get_id().unwrap_or_default(),
+                accesses: vec![],
+            };
+            self.emit_relocated(stride, relocation);
+        } else {
+            self.emit(stride);
+        }
+
+        match spill {
+            None => self.emit(Op::add64(reg, index_reg)),
+            Some(spill) => {
+                let scratch = if reg == Register::R9 {
+                    Register::R8
+                } else {
+                    Register::R9
+                };
+
+                self.emit(Op::load(
+                    MemoryOpSize::DoubleWord,
+                    scratch,
+                    Register::R10,
+                    spill,
+                ));
+                self.emit(Op::add64(reg, scratch));
+            }
+        }
+
+        Ok(element_type)
+    }
+
+    /// Ends the reads through runtime array indices that started with the bounds
+    /// check at `first`: the checks of out of bounds indices skip the read and set
+    /// `reg` to 0 instead, so the rest of the program still runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register the value was read into.
+    /// * `first` - The number of pending bounds checks before the read started.
+    fn emit_out_of_bounds_zero(&mut self, reg: Register, first: usize) {
+        if self.out_of_bounds.len() <= first {
+            return;
+        }
+
+        /*
+         * goto done
+         * out_of_bounds:
+         *   reg = 0
+         * done:
+         */
+        let done_jump = self.emit_terminator(Op::Jump { target: PENDING });
+        for out_of_bounds in self.out_of_bounds.split_off(first) {
+            self.patch_jump(out_of_bounds);
+        }
+        self.emit(Op::mov64(reg, 0));
+        self.patch_jump(done_jump);
+    }
+
+    fn emit_apply_derefs_to_reg(
+        &mut self,
+        reg: Register,
+        var_type: &QualifiedType,
+        derefs: &[DeReference],
+        in_memory: bool,
+    ) -> Result<QualifiedType> {
+        if derefs.is_empty() {
+            return Ok(var_type.clone());
+        }
+
+        if var_type.is_pointer() {
+            self.emit(Op::load(MemoryOpSize::DoubleWord, reg, reg, 0));
+        }
+
+        /*
+         * Once a pointer has been followed, the register points to memory laid out by
+         * the type library, which may differ on the kernel the program runs on.
+         */
+        let in_memory = in_memory || var_type.is_pointer();
+        if in_memory && self.core_relocations {
+            if let Some((count, next_type)) = self.emit_relocated_access(reg, var_type, derefs)? {
+                return self.emit_apply_derefs_to_reg(reg, &next_type, &derefs[count..], true);
+            }
+        }
+
+        let next_type = match &derefs[0] {
+            DeReference::MemberAccess(ma) => self.emit_deref_member_access(reg, var_type, ma)?,
+            DeReference::ArrayIndex(ai) => {
+                self.emit_deref_array_index(reg, var_type, ai, in_memory)?
+            }
+        };
+
+        self.emit_apply_derefs_to_reg(reg, &next_type, &derefs[1..], in_memory)
+    }
+
+    fn emit_set_register_to_lvalue_addr(
+        &mut self,
+        reg: Register,
+        lval: &LValue,
+    ) -> Result<QualifiedType> {
+        if let Some(builtin) = self.get_register_builtin(lval)? {
+            return self.emit_set_register_to_lvalue_addr(reg, &builtin);
+        }
+
+        let info = self.get_variable_by_lvalue(lval)?;
+
+        match info.location {
+            VariableLocation::SpecialImmediate(_) => {
+                return Err(CompileError::ImmutableVariable {
+                    span: self.span(&lval.position),
+                    name: lval.name.clone(),
+                });
+            }
+            VariableLocation::Stack(o) => {
+                self.emit(Op::mov64(reg, Register::R10));
+                self.emit(Op::add64(reg, o));
+            }
+            VariableLocation::Map(_) => {
+                return Err(CompileError::InvalidMap {
+                    span: self.span(&lval.position),
+                    name: lval.name.clone(),
+                    reason: "Map elements can only be read, assigned or deleted",
+                });
+            }
+        }
+
+        self.emit_apply_derefs_to_reg(reg, &info.var_type, &lval.derefs, false)
+    }
+
+    fn emit_set_register_from_lvalue(
+        &mut self,
+        reg: Register,
+        lval: &LValue,
+        load_type: Option<MemoryOpLoadType>,
+    ) -> Result<QualifiedType> {
+        if let Some(builtin) = self.get_register_builtin(lval)? {
+            return self.emit_set_register_from_lvalue(reg, &builtin, load_type);
+        }
+
+        if let Some(access) = self.get_map_access(lval)? {
+            return self.emit_map_read(reg, lval, access);
+        }
+
+        let info = self.get_variable_by_lvalue(lval)?;
+        if let VariableLocation::Map(index) = info.location {
+            let load_type = load_type.unwrap_or(MemoryOpLoadType::Map);
+            let fd = self.maps[index].fd;
+            self.emit(Op::LoadImmediate {
+                dst: reg,
+                imm: fd.into(),
+                load_type,
+            });
+            return Ok(QualifiedType::int::<i64>());
+        }
+
+        if let VariableLocation::SpecialImmediate(v) = info.location {
+            if !lval.derefs.is_empty() {
+                return Err(CompileError::InvalidType {
+                    span: self.span(&lval.position),
+                    reason: "Cannot dereference a special immediate variable",
+                });
+            }
+
+            match load_type {
+                Some(load_type) => self.emit(Op::LoadImmediate {
+                    dst: reg,
+                    imm: v,
+                    load_type,
+                }),
+                None => self.emit(Op::mov64(reg, v)),
+            }
+            return Ok(info.var_type);
+        }
+
+        let first = self.out_of_bounds.len();
+        let mut var_type = self.emit_set_register_to_lvalue_addr(reg, lval)?;
+
+        /*
+         * the register is already holding a pointer to the lvalue so, if a reference
+         * was specified, nothing else needs to be done.
+         */
+        if matches!(lval.prefix, Some(Prefix::ReferencePrefix(_))) {
+            self.emit_out_of_bounds_zero(reg, first);
+            var_type.num_refs += 1;
+            return Ok(var_type);
+        }
+
+        /*
+         * register is pointing to a value of type `var_type`, load it into the register,
+         * if it fits.
+         */
+        match var_type.get_size() {
+            1 => self.emit(Op::load(MemoryOpSize::Byte, reg, reg, 0)),
+            2 => self.emit(Op::load(MemoryOpSize::HalfWord, reg, reg, 0)),
+            4 => self.emit(Op::load(MemoryOpSize::Word, reg, reg, 0)),
+            8 => self.emit(Op::load(MemoryOpSize::DoubleWord, reg, reg, 0)),
+            _ => {
+                return Err(CompileError::InvalidType {
+                    span: self.span(&lval.position),
+                    reason: "Variable too large to be passed in a register",
+                });
+            }
+        }
+
+        /*
+         * the register is now holding `var_type`. if another dereference was requested
+         * then make sure the type being held by the register is a pointer.
+         */
+        if matches!(lval.prefix, Some(Prefix::DeReferencePrefix(_))) {
+            if !var_type.is_pointer() {
+                return Err(CompileError::InvalidType {
+                    span: self.span(&lval.position),
+                    reason: "Cannot dereference a non-pointer type",
+                });
+            }
+
+            self.emit(Op::load(MemoryOpSize::DoubleWord, reg, reg, 0));
+            var_type.num_refs -= 1;
+        }
+
+        self.emit_out_of_bounds_zero(reg, first);
+        Ok(var_type)
+    }
+
+    /// Sets a register to the value of an operand and returns the operand's type.
+    /// Untyped literals are returned as `void`.
+    fn emit_set_register_from_operand(
+        &mut self,
+        reg: Register,
+        operand: &Operand,
+        load_type: Option<MemoryOpLoadType>,
+    ) -> Result<QualifiedType> {
+        match operand {
+            Operand::Immediate(imm_str) => {
+                let imm = self.parse_immediate64(imm_str)?;
+                if let Some(load_type) = load_type {
+                    self.emit(Op::LoadImmediate {
+                        dst: reg,
+                        imm,
+                        load_type,
+                    });
+                } else {
+                    self.emit(Op::mov64(reg, imm));
+                }
+
+                Ok(Default::default())
+            }
+            Operand::StringLiteral(literal) => {
+                let (offset, _) = self.emit_push_string(literal)?;
+                self.emit(Op::mov64(reg, Register::R10));
+                self.emit(Op::add64(reg, offset));
+                Ok(char_pointer_type())
+            }
+            Operand::LValue(lval) => self.emit_set_register_from_lvalue(reg, lval, load_type),
+            Operand::FunctionCall(call) => {
+                let return_type = self.emit_call(call)?;
+                if !matches!(reg, Register::R0) {
+                    self.emit(Op::mov64(reg, Register::R0));
+                }
+
+                Ok(return_type)
+            }
+            Operand::Group(group) => self.emit_set_register_from_rvalue(reg, &group.value, None),
+            Operand::Unary(unary) => {
+                let operand_type =
+                    self.emit_set_register_from_operand(reg, &unary.operand, None)?;
+                let width = if operand_type.get_size() != 4 {
+                    Width::Bits64
+                } else {
+                    Width::Bits32
+                };
+                let op = match &unary.op {
+                    UnaryOperator::Negate(_) => Op::alu(ArithmeticOperation::Neg, width, reg, 0),
+                    UnaryOperator::Invert(_) => Op::alu(ArithmeticOperation::Xor, width, reg, -1),
+                };
+
+                self.emit(op);
+                Ok(operand_type)
+            }
+        }
+    }
+
+    /// Sign-extends a register holding a signed integer narrower than 64 bits.
+    /// Such values are zero-extended when they're loaded or computed with 32-bit
+    /// instructions, so they're sign-extended before being used as 64-bit values,
+    /// e.g. compared with 64-bit jumps or passed to helpers.
+    fn emit_sign_extend(&mut self, reg: Register, value_type: &QualifiedType) {
+        let size = match &value_type.base_type {
+            _ if value_type.is_pointer() => return,
+            Type::Integer(int) if int.is_signed && int.size < 8 => int.size,
+            _ => return,
+        };
+
+        let shift = (64 - size * 8) as i32;
+        self.emit(Op::alu(ArithmeticOperation::Lhs, Width::Bits64, reg, shift));
+        self.emit(Op::alu(ArithmeticOperation::Ash, Width::Bits64, reg, shift));
+    }
+
+    /// Converts a register holding a value of type `from` to a 64-bit value of
+    /// type `to`, the type a comparison is made in. Values that don't fit in a
+    /// narrower `to` are truncated, e.g. `-1` compared with a `__u32` becomes
+    /// `0xffffffff`, and then sign or zero-extended as `to` is signed or not.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register holding the value.
+    /// * `from` - The type of the value in the register.
+    /// * `to` - The type the value is converted to.
+    fn emit_convert(&mut self, reg: Register, from: &QualifiedType, to: &QualifiedType) {
+        let (to_size, to_signed) = match narrow_integer(to) {
+            Some(narrow) => narrow,
+            None => return self.emit_sign_extend(reg, from),
+        };
+
+        /*
+         * Narrower values keep their value in `to`, unless a negative value is
+         * converted to an unsigned type.
+         */
+        let fits = match narrow_integer(from) {
+            Some((size, signed)) if size < to_size => !signed || to_signed,
+            Some((size, signed)) => size == to_size && signed == to_signed,
+            None => false,
+        };
+        if fits {
+            return self.emit_sign_extend(reg, from);
+        }
+
+        let shift = (64 - to_size * 8) as i32;
+        let extend = match to_signed {
+            true => ArithmeticOperation::Ash,
+            false => ArithmeticOperation::Rhs,
+        };
+        self.emit(Op::alu(ArithmeticOperation::Lhs, Width::Bits64, reg, shift));
+        self.emit(Op::alu(extend, Width::Bits64, reg, shift));
+    }
+
+    /// Emits a single arithmetic instruction, `reg = reg <op> src`, choosing between
+    /// 32 and 64-bit operations and signed or unsigned variants based on `value_type`.
+    fn emit_arithmetic(
+        &mut self,
+        op: Operator,
+        reg: Register,
+        src: std::result::Result<i32, Register>,
+        value_type: &QualifiedType,
+    ) -> Result<()> {
+        let (is_64, is_signed) = match &value_type.base_type {
+            _ if value_type.is_pointer() => (true, false),
+            Type::Void => (true, true),
+            Type::Integer(int) if int.size <= 8 => (int.size == 8, int.is_signed),
+            _ => {
+                return Err(CompileError::InvalidType {
+                    span: self.current_span(),
+                    reason: "Arithmetic is only supported on integers and pointers",
+                });
+            }
+        };
+
+        let is_division = matches!(op, Operator::Divide | Operator::Modulo);
+        if is_signed && is_division && !self.signed_division {
+            return Err(CompileError::InvalidType {
+                span: self.current_span(),
+                reason: "Dividing signed integers needs kernel 6.6 or later, \
+                    enable it with `Compiler::set_signed_division`",
+            });
+        }
+
+        self.emit(Op::Arithmetic {
+            op: op.get_operation(is_signed),
+            width: if is_64 { Width::Bits64 } else { Width::Bits32 },
+            signed: is_signed && is_division,
+            dst: reg,
+            lhs: reg,
+            rhs: match src {
+                Ok(imm) => imm.into(),
+                Err(src) => src.into(),
+            },
+        });
+        Ok(())
+    }
+
+    /// Returns whether an lvalue can be loaded into a register without using any
+    /// registers other than the destination, i.e. it's not a map element and all
+    /// array indices are constant.
+    fn is_simple_lvalue(&mut self, lval: &LValue) -> bool {
+        match self.variables.get(&lval.name).map(|info| info.location) {
+            Some(VariableLocation::Stack(_)) | Some(VariableLocation::SpecialImmediate(_)) => {}
+            _ => return false,
+        }
+
+        lval.derefs.iter().all(|deref| match deref {
+            DeReference::MemberAccess(_) => true,
+            DeReference::ArrayIndex(ai) => {
+                matches!(self.get_constant_index(&ai.element), Ok(Some(_)))
+            }
+        })
+    }
+
+    /// Sets a register to the value of an expression tree and returns its type.
+    fn emit_set_register_from_expr(
+        &mut self,
+        reg: Register,
+        expr: &Expr,
+        load_type: Option<MemoryOpLoadType>,
+    ) -> Result<QualifiedType> {
+        let (op, left, right) = match expr {
+            Expr::Operand(operand) => {
+                return self.emit_set_register_from_operand(reg, operand, load_type);
+            }
+            Expr::Binary(op, left, right) => (*op, left, right),
+        };
+
+        /*
+         * Immediates are folded into the instruction, or loaded straight into a
+         * scratch register like other simple operands when they're too wide.
+         * Anything else (e.g. helper calls, which clobber R0-R5) is evaluated first
+         * and spilled to the stack while the left side is evaluated.
+         */
+        let scratch = if reg == Register::R9 {
+            Register::R8
+        } else {
+            Register::R9
+        };
+
+        if let Some(imm) = right.as_imm32() {
+            let left_type = self.emit_set_register_from_expr(reg, left, None)?;
+            let value_type = get_arithmetic_type(op, &left_type, &Default::default());
+            self.check_literal_fits(right, &value_type)?;
+            self.emit_arithmetic(op, reg, Ok(imm), &value_type)?;
+            return Ok(value_type);
+        }
+
+        let is_simple = match right.as_ref() {
+            Expr::Operand(Operand::Immediate(_)) => true,
+            Expr::Operand(Operand::LValue(lval)) => self.is_simple_lvalue(lval),
+            _ => false,
+        };
+
+        let (left_type, right_type) = if is_simple {
+            let left_type = self.emit_set_register_from_expr(reg, left, None)?;
+            let right_type = self.emit_set_register_from_expr(scratch, right, None)?;
+            (left_type, right_type)
+        } else {
+            let right_type = self.emit_set_register_from_expr(reg, right, None)?;
+            let spill = self.emit_push_register(reg, None)?;
+            let left_type = self.emit_set_register_from_expr(reg, left, None)?;
+            self.emit(Op::load(
+                MemoryOpSize::DoubleWord,
+                scratch,
+                Register::R10,
+                spill,
+            ));
+            (left_type, right_type)
+        };
+
+        let value_type = get_arithmetic_type(op, &left_type, &right_type);
+        self.check_literal_fits(left, &value_type)?;
+        self.check_literal_fits(right, &value_type)?;
+        if value_type.get_size() == 8 {
+            self.emit_sign_extend(reg, &left_type);
+            self.emit_sign_extend(scratch, &right_type);
+        }
+        self.emit_arithmetic(op, reg, Err(scratch), &value_type)?;
+        Ok(value_type)
+    }
+
+    /// Sets a register to the value of an rvalue and returns its type. Untyped
+    /// literals are returned as `void`.
+    fn emit_set_register_from_rvalue(
+        &mut self,
+        reg: Register,
+        rval: &RValue,
+        load_type: Option<MemoryOpLoadType>,
+    ) -> Result<QualifiedType> {
+        let expr = Expr::from_rvalue(rval);
+        self.emit_set_register_from_expr(reg, &expr, load_type)
+    }
+
+    /// Evaluates a field information intrinsic, e.g. `field_size(task.comm)`, into
+    /// `reg`. The value is known at compile time, but when compiling with CO-RE
+    /// relocations it's relocated to describe the field on the kernel the program
+    /// runs on. Offsets are relative to the struct the last pointer points to.
+    fn emit_field_info(
+        &mut self,
+        reg: Register,
+        call: &FunctionCall,
+        kind: RelocationKind,
+    ) -> Result<()> {
+        let lval = match call.args.as_slice() {
+            [arg] => match arg.as_operand() {
+                Some(Operand::LValue(lval))
+                    if lval.prefix.is_none()
+                        && matches!(lval.derefs.last(), Some(DeReference::MemberAccess(_))) =>
+                {
+                    lval
+                }
+                _ => {
+                    return Err(CompileError::InvalidType {
+                        span: self.span(&call.position),
+                        reason: "Expected a struct member, e.g. `task.pid`",
+                    });
+                }
+            },
+            _ => {
+                return Err(CompileError::InvalidType {
+                    span: self.span(&call.position),
+                    reason: "Expected a struct member, e.g. `task.pid`",
+                });
+            }
+        };
+
+        /*
+         * Walk the accesses to find the field's type and offset, and the last pointer
+         * that's followed, whose target is the root of the relocation.
+         */
+        let info = self.get_variable_by_lvalue(lval)?;
+        let mut root = None;
+        let mut offset = 0;
+        let mut cur_type = info.var_type;
+        for (i, deref) in lval.derefs.iter().enumerate() {
+            if cur_type.is_pointer() {
+                root = Some((i, cur_type.clone()));
+                offset = 0;
+            }
+
+            let (off, next_type) = match deref {
+                DeReference::MemberAccess(ma) => self.get_member_access(&cur_type, ma)?,
+                DeReference::ArrayIndex(ai) => self.get_array_index(&cur_type, ai)?,
+            };
+            offset += off;
+            cur_type = next_type;
+        }
+
+        let value = match kind {
+            RelocationKind::FieldByteOffset => offset,
+            RelocationKind::FieldByteSize | RelocationKind::TypeSize => cur_type.get_size(),
+            RelocationKind::FieldExists => 1,
+            RelocationKind::FieldSigned => u32::from(
+                !cur_type.is_pointer()
+                    && matches!(&cur_type.base_type, Type::Integer(int) if int.is_signed),
+            ),
+        };
+
+        if let (true, Some((start, root_type))) = (self.core_relocations, root) {
+            let derefs = &lval.derefs[start..];
+            if let Some(run) = self.get_field_access(&root_type, derefs)? {
+                if run.count == derefs.len() {
+                    let relocation = CoreRelocation {
+                        instruction: 0,
+                        kind,
+                        type_id: root_type.base_type.get_id().unwrap_or_default(),
+                        accesses: run.accesses,
+                    };
+                    self.emit_relocated(Op::mov64(reg, value as i32), relocation);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.emit(Op::mov64(reg, value as i32));
+        Ok(())
+    }
+
+    /// Returns whether a type holds a string: a `char` array, or a pointer to a
+    /// `char` or a `char` array.
+    fn is_string_type(&mut self, qtype: &QualifiedType) -> Result<bool> {
+        match &qtype.base_type {
+            _ if qtype.num_refs > 1 => Ok(false),
+            Type::Integer(int) => Ok(qtype.is_pointer() && int.size == 1),
+            Type::Array(ar) => {
+                let element_type = self.resolve_type_by_id(ar.element_type)?;
+                Ok(!element_type.is_pointer() && element_type.get_size() == 1)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Evaluates the argument of a conversion in a `print` format into `reg`, and
+    /// checks that its type matches the conversion. Strings are passed by address,
+    /// so `%s` accepts `char` arrays as well as pointers.
+    fn emit_format_argument(
+        &mut self,
+        reg: Register,
+        arg: &RValue,
+        literal: &StringLiteral,
+        conversion: &Conversion,
+    ) -> Result<()> {
+        let arg_type = match (conversion.kind, arg.as_operand()) {
+            (ConversionKind::String, Some(Operand::LValue(lval)))
+                if lval.prefix.is_none()
+                    && (self.get_register_builtin(lval)?.is_some()
+                        || matches!(
+                            self.get_variable_by_lvalue(lval)?.location,
+                            VariableLocation::Stack(_)
+                        )) =>
+            {
+                let first = self.out_of_bounds.len();
+                let lval_type = self.emit_set_register_to_lvalue_addr(reg, lval)?;
+                if !lval_type.is_pointer() && self.is_string_type(&lval_type)? {
+                    self.emit_out_of_bounds_zero(reg, first);
+                    return Ok(());
+                }
+
+                if lval_type.get_size() == 8 {
+                    self.emit(Op::load(MemoryOpSize::DoubleWord, reg, reg, 0));
+                }
+                self.emit_out_of_bounds_zero(reg, first);
+                lval_type
+            }
+            _ => self.emit_set_register_from_rvalue(reg, arg, None)?,
+        };
+        self.emit_sign_extend(reg, &arg_type);
+
+        let is_integer = !arg_type.is_pointer()
+            && matches!(
+                arg_type.base_type,
+                Type::Void | Type::Integer(_) | Type::Enum32(_) | Type::Enum64(_)
+            );
+        let (matches, reason) = match conversion.kind {
+            ConversionKind::Integer => (
+                is_integer || arg_type.is_pointer(),
+                "Expected an integer for this conversion",
+            ),
+            ConversionKind::Char => (is_integer, "Expected a character for this conversion"),
+            ConversionKind::String => (
+                arg_type.is_pointer() && self.is_string_type(&arg_type)?,
+                "Expected a string, `char` array or `char` pointer for this conversion",
+            ),
+            ConversionKind::Pointer => (
+                arg_type.is_pointer() || (is_integer && matches!(arg_type.get_size(), 0 | 8)),
+                "Expected a pointer for this conversion",
+            ),
+        };
+
+        if !matches {
+            return Err(self.string_error(literal, (conversion.range.clone(), reason)));
+        }
+
+        Ok(())
+    }
+
+    /// Emits a call to `print`, which formats values into the kernel's trace pipe
+    /// like `bpf_trace_printk`. Up to three values are passed in registers, more
+    /// are passed to `bpf_trace_vprintk` in an array on the stack.
+    fn emit_print(&mut self, call: &FunctionCall) -> Result<()> {
+        let literal = match call.args.first().and_then(|arg| arg.as_operand()) {
+            Some(Operand::StringLiteral(literal)) => literal,
+            _ => {
+                return Err(CompileError::InvalidType {
+                    span: self.span(&call.position),
+                    reason: "The first argument of `print` must be a format string",
+                });
+            }
+        };
+
+        let conversions =
+            parse_format(&literal.value).map_err(|error| self.string_error(literal, error))?;
+        let args = &call.args[1..];
+        if args.len() != conversions.len() {
+            return Err(CompileError::InvalidFunction {
+                span: self.span(&call.position),
+                name: call.name.clone(),
+                reason: "The number of arguments doesn't match the format string",
+            });
+        }
+
+        /*
+         * Values are evaluated into an array on the stack first, since evaluating
+         * one may call helpers that clobber the argument registers.
+         */
+        let data = match args.len() {
+            0 => 0,
+            len => self.push_stack(len as u32 * 8)?,
+        };
+        for (i, (arg, conversion)) in args.iter().zip(&conversions).enumerate() {
+            self.emit_format_argument(Register::R6, arg, literal, conversion)?;
+            self.emit(Op::store(
+                MemoryOpSize::DoubleWord,
+                Register::R10,
+                data + i as i16 * 8,
+                Register::R6,
+            ));
+        }
+
+        let (format, size) = self.emit_push_string(literal)?;
+        self.emit(Op::mov64(Register::R1, Register::R10));
+        self.emit(Op::add64(Register::R1, format));
+        self.emit(Op::mov64(Register::R2, size as i32));
+        if args.len() <= 3 {
+            for i in 0..args.len() {
+                let reg = Register::from_num(3 + i as u8).expect("at most three values");
+                self.emit(Op::load(
+                    MemoryOpSize::DoubleWord,
+                    reg,
+                    Register::R10,
+                    data + i as i16 * 8,
+                ));
+            }
+            self.emit(Op::call(Helpers::TracePrintk));
+        } else {
+            self.emit(Op::mov64(Register::R3, Register::R10));
+            self.emit(Op::add64(Register::R3, data));
+            self.emit(Op::mov64(Register::R4, args.len() as i32 * 8));
+            self.emit(Op::call(Helpers::TraceVprintk));
+        }
+
+        Ok(())
+    }
+
+    /// Emits a call to a helper or a function defined by the script, leaving the
+    /// result in R0, and returns the type of the result.
+    fn emit_call(&mut self, call: &FunctionCall) -> Result<QualifiedType> {
+        if let Some(kind) = field_info_kind(&call.name) {
+            self.emit_field_info(Register::R0, call, kind)?;
+            return Ok(unsigned_type(8));
+        }
+
+        if call.name == "print" {
+            self.emit_print(call)?;
+            return Ok(unsigned_type(8));
+        }
+
+        if let Some(index) = self.function_index(&call.name) {
+            return self.emit_local_call(index, call);
+        }
+
+        let helper = match Helpers::from_string(&call.name) {
+            Some(helper) => helper,
+            None => {
+                /*
+                 * Helpers are commonly written with the `bpf_` prefix used in C.
+                 */
+                let functions = self.functions.iter().map(|f| f.name.as_str());
+                let names = Helpers::names()
+                    .map(|name| -> &str { name })
+                    .chain(functions);
+                let suggestion = match call.name.strip_prefix("bpf_") {
+                    Some(name) if Helpers::from_string(name).is_some() => Some(name.to_string()),
+                    _ => closest_match(&call.name, names),
+                };
+
+                return Err(CompileError::UnknownHelper {
+                    span: self.ident_span(&call.position, &call.name),
+                    name: call.name.clone(),
+                    suggestion,
+                });
+            }
+        };
+
+        if let Some(attach) = &self.attach {
+            if !attach.program_type.allows_helper(helper) {
+                return Err(CompileError::InvalidProgram {
+                    span: self.ident_span(&call.position, &call.name),
+                    name: call.name.clone(),
+                    reason: "Helper isn't available to this program type",
+                });
+            }
+        }
+
+        if call.args.len() > 5 {
+            return Err(CompileError::TooManyArguments {
+                span: self.span(&call.position),
+                max: 5,
+            });
+        }
+
+        let types = helper.get_arg_types();
+        self.emit_call_args(&call.args, |compiler, i, reg| {
+            let arg = &call.args[i];
+            let arg_type = compiler.emit_set_register_from_rvalue(reg, arg, Some(types[i]))?;
+            compiler.emit_sign_extend(reg, &arg_type);
+            Ok(())
+        })?;
+        self.emit(Op::call(helper));
+
+        Ok(unsigned_type(8))
+    }
+
+    /// Evaluates the arguments of a call into R1 to R5 with `emit_arg`, which is
+    /// given the index of the argument and its register. Arguments that call
+    /// functions themselves would overwrite the registers of the arguments before
+    /// them, so those are evaluated first and kept on the stack until the others
+    /// have been evaluated.
+    fn emit_call_args<F>(&mut self, args: &[RValue], mut emit_arg: F) -> Result<()>
+    where
+        F: FnMut(&mut Self, usize, Register) -> Result<()>,
+    {
+        let register = |i: usize| Register::from_num(i as u8 + 1).expect("at most five args");
+        let mut spilled = vec![];
+        for (i, arg) in args.iter().enumerate().skip(1) {
+            if self.rvalue_may_call(arg) {
+                emit_arg(self, i, register(i))?;
+                spilled.push((i, self.emit_push_register(register(i), None)?));
+            }
+        }
+
+        for i in 0..args.len() {
+            if !spilled.iter().any(|(spilled, _)| *spilled == i) {
+                emit_arg(self, i, register(i))?;
+            }
+        }
+
+        for (i, offset) in spilled {
+            self.emit(Op::load(
+                MemoryOpSize::DoubleWord,
+                register(i),
+                Register::R10,
+                offset,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether evaluating an rvalue may call a helper or a function, e.g.
+    /// because it reads a map element.
+    fn rvalue_may_call(&mut self, rval: &RValue) -> bool {
+        std::iter::once(&rval.first)
+            .chain(rval.rest.iter().map(|tail| &tail.operand))
+            .any(|operand| self.operand_may_call(operand))
+    }
+
+    fn operand_may_call(&mut self, operand: &Operand) -> bool {
+        match operand {
+            Operand::FunctionCall(call) => field_info_kind(&call.name).is_none(),
+            Operand::Unary(unary) => self.operand_may_call(&unary.operand),
+            Operand::Group(group) => self.rvalue_may_call(&group.value),
+            Operand::LValue(lval) => {
+                matches!(self.get_map_access(lval), Ok(Some(_)))
+                    || lval.derefs.iter().any(|deref| match deref {
+                        DeReference::ArrayIndex(ai) => self.rvalue_may_call(&ai.element),
+                        DeReference::MemberAccess(_) => false,
+                    })
+            }
+            Operand::Immediate(_) | Operand::StringLiteral(_) => false,
+        }
+    }
+
+    /// Returns the index of the function defined by the script with a name.
+    fn function_index(&self, name: &str) -> Option<usize> {
+        self.functions
+            .iter()
+            .position(|function| function.name == name)
+    }
+
+    /// Emits a call to a function defined by the script, and returns its return
+    /// type.
+    fn emit_local_call(&mut self, index: usize, call: &FunctionCall) -> Result<QualifiedType> {
+        let function = self.functions[index].clone();
+        if call.args.len() != function.params.len() {
+            return Err(CompileError::InvalidFunction {
+                span: self.span(&call.position),
+                name: call.name.clone(),
+                reason: "Wrong number of arguments",
+            });
+        }
+
+        self.emit_call_args(&call.args, |compiler, i, reg| {
+            let (arg, (_, param_type)) = (&call.args[i], &function.params[i]);
+            compiler.check_literal_fits(&Expr::from_rvalue(arg), param_type)?;
+            compiler.emit_set_register_from_rvalue(reg, arg, None)?;
+            Ok(())
+        })?;
+
+        self.emit(Op::CallLocal {
+            function: index as u32,
+        });
+        self.calls.push((index, call.position.clone()));
+
+        Ok(function.return_type.unwrap_or_else(|| unsigned_type(8)))
+    }
+
+    fn emit_return(&mut self, ret: &Return) -> Result<()> {
+        match &ret.value {
+            None => {
+                self.emit(Op::mov64(Register::R0, 0));
+                self.emit_terminator(Op::Exit);
+            }
+            Some(value) => {
+                self.check_return_value(value)?;
+                self.emit_set_register_from_rvalue(Register::R0, value, None)?;
+                self.emit_truncate_return();
+                self.emit_terminator(Op::Exit);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an error if the program returns a constant its program type doesn't
+    /// accept, e.g. an XDP program returning something other than an XDP action.
+    fn check_return_value(&mut self, value: &RValue) -> Result<()> {
+        let range = match (&self.attach, self.current_function) {
+            (Some(attach), None) => attach.program_type.return_range(),
+            _ => None,
+        };
+
+        if let (Some((min, max)), Some(Operand::Immediate(imm))) = (range, value.as_operand()) {
+            let value = self.parse_immediate64(imm)?;
+            if value < min || value > max {
+                return Err(CompileError::InvalidProgram {
+                    span: self.current_span(),
+                    name: imm.clone(),
+                    reason: "Return value isn't valid for this program type",
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Truncates the value in R0 to the return type of the function being
+    /// compiled, so callers get a value of the type they expect.
+    fn emit_truncate_return(&mut self) {
+        let return_type = match self.current_function {
+            Some(index) => self.functions[index].return_type.clone(),
+            None => None,
+        };
+
+        let (size, signed) = match return_type.as_ref().map(|t| &t.base_type) {
+            Some(Type::Integer(int)) if int.size < 8 => (int.size, int.is_signed),
+            _ => return,
+        };
+
+        if size == 4 && !signed {
+            self.emit(Op::Move {
+                width: Width::Bits32,
+                dst: Register::R0,
+                src: Register::R0.into(),
+            });
+            return;
+        }
+
+        let shift = (64 - size * 8) as i32;
+        let right = match signed {
+            true => ArithmeticOperation::Ash,
+            false => ArithmeticOperation::Rhs,
+        };
+        self.emit(Op::alu(
+            ArithmeticOperation::Lhs,
+            Width::Bits64,
+            Register::R0,
+            shift,
+        ));
+        self.emit(Op::alu(right, Width::Bits64, Register::R0, shift));
+    }
+
+    /// Returns the access to `struct pt_regs` a kprobe builtin stands for, e.g.
+    /// `ctx.di` for `arg0` on x86_64, or `None` if the lvalue isn't a builtin.
+    /// Builtins can be shadowed by variables, and only exist in the program itself
+    /// since functions don't have its context.
+    fn get_register_builtin(&mut self, lval: &LValue) -> Result<Option<LValue>> {
+        let program_type = match self.current_function {
+            None => self.get_program_type(),
+            Some(_) => None,
+        };
+        if self.variables.contains_key(&lval.name) {
+            return Ok(None);
+        }
+
+        let index = lval
+            .name
+            .strip_prefix("arg")
+            .filter(|index| index.len() == 1)
+            .and_then(|index| index.parse::<usize>().ok())
+            .filter(|index| *index < 6);
+        let register = match (program_type, index) {
+            (Some(ProgramType::Kprobe), Some(index)) => self.arch.argument_register(index),
+            (Some(ProgramType::Kretprobe), _) if lval.name == "retval" => {
+                Some(self.arch.return_register())
+            }
+            _ => None,
+        };
+
+        let (members, element) = match register {
+            Some(register) => register,
+            None => return Ok(None),
+        };
+        let context = match &self.context {
+            Some(context) => context.clone(),
+            None => {
+                return Err(CompileError::UnknownType {
+                    span: self.span(&lval.position),
+                    name: "pt_regs".to_string(),
+                    suggestion: None,
+                });
+            }
+        };
+
+        let position = lval.position.clone();
+        let mut derefs: Vec<DeReference> = members
+            .iter()
+            .map(|name| {
+                DeReference::MemberAccess(MemberAccess {
+                    name: name.to_string(),
+                    position: position.clone(),
+                })
+            })
+            .collect();
+        if let Some(element) = element {
+            derefs.push(DeReference::ArrayIndex(ArrayIndex {
+                element: RValue {
+                    first: Operand::Immediate(element.to_string()),
+                    rest: vec![],
+                },
+                position: position.clone(),
+            }));
+        }
+        derefs.extend(lval.derefs.iter().cloned());
+
+        Ok(Some(LValue {
+            prefix: lval.prefix.clone(),
+            name: context,
+            derefs,
+            position,
+        }))
+    }
+
+    /// Resolves the declared type of a parameter, which only the parameters of
+    /// fentry and fexit programs can leave out.
+    fn resolve_argument_type(&mut self, arg: &TypedArgument) -> Result<QualifiedType> {
+        match &arg.type_name {
+            Some(decl) => self.resolve_type_by_decl(decl),
+            None => Err(CompileError::InvalidType {
+                span: self.span(&arg.position),
+                reason: "Parameters need a type, e.g. `x: __u64`",
+            }),
+        }
+    }
+
+    /// Loads the arguments of the function an fentry or fexit program traces from
+    /// its context, an array holding each argument as a 64-bit value. The context
+    /// can be read directly, so no `probe_read` is needed. fexit programs also get
+    /// the function's return value as `retval`, which follows the arguments.
+    fn emit_load_traced_arguments(&mut self) -> Result<()> {
+        for (name, var_type, index) in self.traced_args.clone() {
+            self.emit(Op::load(
+                MemoryOpSize::DoubleWord,
+                Register::R2,
+                Register::R1,
+                (index * 8) as i16,
+            ));
+            let offset = self.emit_push_register(Register::R2, None)?;
+            self.variables.insert(
+                name,
+                VariableInfo {
+                    var_type,
+                    location: VariableLocation::Stack(offset),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Saves the arguments passed in R1 to R5 to the stack, and creates variables
+    /// for them.
+    fn emit_push_arguments(
+        &mut self,
+        position: &Range<usize>,
+        args: &[TypedArgument],
+    ) -> Result<()> {
+        /*
+         * BPF limits the number of function arguments to 5 (R1 to R5).
+         */
+        if args.len() > 5 {
+            return Err(CompileError::TooManyArguments {
+                span: self.span(position),
+                max: 5,
+            });
+        }
+
+        /*
+         * Push all input arguments to the stack and create variables entries for them.
+         */
+        for (i, arg) in args.iter().enumerate() {
+            let register = Register::from_num((i + 1) as u8).expect("too many args");
+            let arg_type = self.resolve_argument_type(arg)?;
+            let offset = self.emit_push_register(register, None)?;
+            self.args.push((arg.name.clone(), arg_type.clone()));
+            self.variables.insert(
+                arg.name.clone(),
+                VariableInfo {
+                    var_type: arg_type,
+                    location: VariableLocation::Stack(offset),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn emit_prologue(&mut self, position: &Range<usize>, args: &[TypedArgument]) -> Result<()> {
+        /*
+         * Instructions that don't belong to a statement, like the ones saving the
+         * arguments, are attributed to the function's declaration.
+         */
+        self.position = position.clone();
+
+        /*
+         * Programs tracing a kernel function get its arguments from their context,
+         * everything else gets them in R1 to R5.
+         */
+        let is_traced = self
+            .get_program_type()
+            .is_some_and(|program_type| program_type.has_traced_arguments());
+        if is_traced && self.current_function.is_none() {
+            self.emit_load_traced_arguments()?;
+        } else {
+            self.emit_push_arguments(position, args)?;
+        }
+
+        /*
+         * Kprobes read their builtins from the context, which is saved even if the
+         * script doesn't name it. Unused, it's removed by dead store elimination.
+         */
+        let is_kprobe = matches!(
+            self.get_program_type(),
+            Some(ProgramType::Kprobe | ProgramType::Kretprobe)
+        );
+        self.context = None;
+        if is_kprobe && self.current_function.is_none() {
+            match args.first() {
+                Some(arg) => self.context = Some(arg.name.clone()),
+                None => {
+                    if let Some(mut var_type) = self.types.resolve_type_by_name("pt_regs") {
+                        var_type.num_refs += 1;
+                        let offset = self.emit_push_register(Register::R1, None)?;
+                        self.variables.insert(
+                            CONTEXT_VARIABLE.to_string(),
+                            VariableInfo {
+                                var_type,
+                                location: VariableLocation::Stack(offset),
+                            },
+                        );
+                        self.context = Some(CONTEXT_VARIABLE.to_string());
+                    }
+                }
+            }
+        }
+
+        /*
+         * Copy captured strings and structs onto the stack. Arguments are saved first
+         * so an argument with the same name shadows the capture.
+         */
+        for capture in self.captures.clone() {
+            if self.variables.contains_key(&capture.name) {
+                continue;
+            }
+
+            let offset = self.push_stack(capture.bytes.len() as u32)?;
+            self.emit_init_bytes(offset, &capture.bytes);
+            self.variables.insert(
+                capture.name,
+                VariableInfo {
+                    var_type: capture.var_type,
+                    location: VariableLocation::Stack(offset),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn get_jump_operation(&self, op: &Comparator, is_signed: bool) -> JumpOperation {
+        match (op, is_signed) {
+            (Comparator::Equals(_), _) => JumpOperation::IfEqual,
+            (Comparator::NotEquals(_), _) => JumpOperation::IfNotEqual,
+            (Comparator::LessThan(_), false) => JumpOperation::IfLessThan,
+            (Comparator::LessThan(_), true) => JumpOperation::IfSignedLessThan,
+            (Comparator::GreaterThan(_), false) => JumpOperation::IfGreater,
+            (Comparator::GreaterThan(_), true) => JumpOperation::IfSignedGreater,
+            (Comparator::LessOrEqual(_), false) => JumpOperation::IfLessThanOrEqual,
+            (Comparator::LessOrEqual(_), true) => JumpOperation::IfSignedLessThanOrEqual,
+            (Comparator::GreaterOrEqual(_), false) => JumpOperation::IfGreaterOrEqual,
+            (Comparator::GreaterOrEqual(_), true) => JumpOperation::IfSignedGreaterOrEqual,
+        }
+    }
+
+    /// Emits a comparison followed by a jump that is taken when the condition
+    /// does *not* hold. Returns the index of the jump so it can be patched once
+    /// the target is known.
+    fn emit_condition(&mut self, cond: &Condition) -> Result<BlockId> {
+        /*
+         * The left side goes in R6 so that it survives any helper calls made while
+         * evaluating the right side.
+         */
+        let left_type = self.emit_set_register_from_rvalue(Register::R6, &cond.left, None)?;
+        let right = Expr::from_rvalue(&cond.right);
+        let imm = right.as_imm32();
+        let right_type = match imm {
+            Some(_) => Default::default(),
+            None => self.emit_set_register_from_expr(Register::R7, &right, None)?,
+        };
+
+        let value_type = get_arithmetic_type(Operator::Add, &left_type, &right_type);
+        self.check_literal_fits(&right, &value_type)?;
+
+        /*
+         * Jumps compare all 64 bits, so both sides are converted to 64-bit values
+         * of the type the comparison is made in first. Zero-extension doesn't
+         * change whether a value equals a non-negative literal, so those
+         * comparisons are left alone. Literals are reinterpreted like values, e.g.
+         * `200` compared with an `__s8` is `-56`, see `check_literal_fits`.
+         */
+        let imm = imm.map(|imm| match narrow_integer(&value_type) {
+            Some((1, true)) => imm as i8 as i32,
+            Some((2, true)) => imm as i16 as i32,
+            _ => imm,
+        });
+        let is_equality = matches!(cond.op, Comparator::Equals(_) | Comparator::NotEquals(_));
+        if !(is_equality && imm.is_some_and(|imm| imm >= 0)) {
+            self.emit_convert(Register::R6, &left_type, &value_type);
+            if imm.is_none() {
+                self.emit_convert(Register::R7, &right_type, &value_type);
+            }
+        }
+        let is_signed = match &value_type.base_type {
+            _ if value_type.is_pointer() => false,
+            Type::Void => true,
+            Type::Integer(int) => int.is_signed,
+            _ => false,
+        };
+        let op = self.get_jump_operation(&cond.op, is_signed);
+        let op = jump::invert(op).expect("comparisons are always invertible");
+
+        let otherwise = self.next_block();
+        Ok(self.emit_terminator(Op::Branch {
+            op,
+            lhs: Register::R6,
+            rhs: match imm {
+                Some(imm) => imm.into(),
+                None => Register::R7.into(),
+            },
+            then: PENDING,
+            otherwise,
+        }))
+    }
+
+    /// Patches the pending jump ending `block` to land on the next instruction to
+    /// be emitted.
+    fn patch_jump(&mut self, block: BlockId) {
+        let target = self.start_block();
+        let inst = self.ir.blocks[block.0]
+            .insts
+            .last_mut()
+            .expect("patched blocks end with a jump");
+        match &mut inst.op {
+            Op::Jump { target: pending } | Op::Branch { then: pending, .. } => {
+                debug_assert_eq!(*pending, PENDING);
+                *pending = target;
+            }
+            _ => unreachable!("patched blocks end with a jump"),
+        }
+    }
+
+    fn emit_if(&mut self, stmt: &If) -> Result<()> {
+        let else_jump = self.emit_condition(&stmt.condition)?;
+        self.emit_block(&stmt.then.exprs)?;
+
+        match stmt.else_block.as_deref() {
+            None => self.patch_jump(else_jump),
+            Some(else_block) => {
+                /*
+                 * The end of the `then` block skips over the `else` block, unless it
+                 * already returned, in which case the jump would be unreachable.
+                 */
+                let end_jump = if always_returns(&stmt.then.exprs) {
+                    None
+                } else {
+                    Some(self.emit_terminator(Op::Jump { target: PENDING }))
+                };
+
+                self.patch_jump(else_jump);
+                match else_block {
+                    ElseBlock::If(else_if) => self.emit_if(else_if)?,
+                    ElseBlock::Block(block) => self.emit_block(&block.exprs)?,
+                }
+
+                if let Some(end_jump) = end_jump {
+                    self.patch_jump(end_jump);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emits code that increments the 64-bit counter at `offset` on the stack and
+    /// jumps to the end of the loop once it reaches `max`. Returns the block ending
+    /// with the jump so it can be patched.
+    fn emit_loop_counter(&mut self, offset: i16, max: u32) -> Result<BlockId> {
+        let max = match max.try_into() {
+            Ok(max) => max,
+            Err(_) => {
+                return Err(CompileError::TooManyIterations {
+                    span: self.current_span(),
+                    iterations: max,
+                    max: i32::MAX as u32,
+                });
+            }
+        };
+
+        self.emit(Op::load(
+            MemoryOpSize::DoubleWord,
+            Register::R6,
+            Register::R10,
+            offset,
+        ));
+        let exit_jump = self.emit_loop_exit(max);
+        self.emit(Op::add64(Register::R6, 1));
+        self.emit(Op::store(
+            MemoryOpSize::DoubleWord,
+            Register::R10,
+            offset,
+            Register::R6,
+        ));
+
+        Ok(exit_jump)
+    }
+
+    /// Ends the block with a branch out of a loop taken once R6 reaches `max`, to
+    /// be patched with `patch_jump`.
+    fn emit_loop_exit(&mut self, max: i32) -> BlockId {
+        let otherwise = self.next_block();
+        self.emit_terminator(Op::Branch {
+            op: JumpOperation::IfGreaterOrEqual,
+            lhs: Register::R6,
+            rhs: max.into(),
+            then: PENDING,
+            otherwise,
+        })
+    }
+
+    fn emit_for(&mut self, stmt: &For) -> Result<()> {
+        let start = self.parse_immediate::<u32>(&stmt.start)?;
+        let end = self.parse_immediate::<u32>(&stmt.end)?;
+        let end_imm = self.parse_immediate::<i32>(&stmt.end)?;
+        let count = end.saturating_sub(start);
+        if count > self.max_loop_iterations {
+            return Err(CompileError::TooManyIterations {
+                span: self.span(&stmt.position),
+                iterations: count,
+                max: self.max_loop_iterations,
+            });
+        }
+
+        let scope = self.variables.clone();
+        let ranges = self.loop_ranges.clone();
+
+        if self.unroll_loops {
+            /*
+             * Each copy of the body sees the loop variable as a constant, which also
+             * allows it to be used for compile-time array indexing.
+             */
+            for i in start..end {
+                self.variables.insert(
+                    stmt.var.clone(),
+                    VariableInfo {
+                        var_type: unsigned_type(8),
+                        location: VariableLocation::SpecialImmediate(i.into()),
+                    },
+                );
+                self.emit_block(&stmt.body.exprs)?;
+            }
+        } else {
+            /*
+             * i = start
+             * loop:
+             *   if i >= end goto done
+             *   <body>
+             *   i += 1
+             *   goto loop
+             * done:
+             */
+            let offset = self.push_stack(8)?;
+            self.emit(Op::store(
+                MemoryOpSize::DoubleWord,
+                Register::R10,
+                offset,
+                i64::from(start),
+            ));
+            self.variables.insert(
+                stmt.var.clone(),
+                VariableInfo {
+                    var_type: unsigned_type(8),
+                    location: VariableLocation::Stack(offset),
+                },
+            );
+            self.loop_ranges.insert(stmt.var.clone(), start..end);
+
+            let loop_start = self.start_block();
+            self.emit(Op::load(
+                MemoryOpSize::DoubleWord,
+                Register::R6,
+                Register::R10,
+                offset,
+            ));
+            let exit_jump = self.emit_loop_exit(end_imm);
+
+            self.emit_block(&stmt.body.exprs)?;
+
+            self.emit(Op::load(
+                MemoryOpSize::DoubleWord,
+                Register::R6,
+                Register::R10,
+                offset,
+            ));
+            self.emit(Op::add64(Register::R6, 1));
+            self.emit(Op::store(
+                MemoryOpSize::DoubleWord,
+                Register::R10,
+                offset,
+                Register::R6,
+            ));
+            self.emit_terminator(Op::Jump { target: loop_start });
+            self.patch_jump(exit_jump);
+        }
+
+        self.variables = scope;
+        self.loop_ranges = ranges;
+        Ok(())
+    }
+
+    fn emit_while(&mut self, stmt: &While) -> Result<()> {
+        if self.unroll_loops {
+            if self.max_loop_iterations > MAX_UNROLLED_WHILE_ITERATIONS {
+                return Err(CompileError::TooManyIterations {
+                    span: self.span(&stmt.position),
+                    iterations: self.max_loop_iterations,
+                    max: MAX_UNROLLED_WHILE_ITERATIONS,
+                });
+            }
+
+            /*
+             * Every copy of the body is guarded by the condition, all of them jump to
+             * the end once it's false.
+             */
+            let mut exit_jumps = vec![];
+            for _ in 0..self.max_loop_iterations {
+                exit_jumps.push(self.emit_condition(&stmt.condition)?);
+                self.emit_block(&stmt.body.exprs)?;
+            }
+
+            for exit_jump in exit_jumps {
+                self.patch_jump(exit_jump);
+            }
+
+            return Ok(());
+        }
+
+        /*
+         * A hidden counter bounds the number of iterations so the verifier can
+         * prove that the loop terminates.
+         */
+        let offset = self.push_stack(8)?;
+        self.emit(Op::store(
+            MemoryOpSize::DoubleWord,
+            Register::R10,
+            offset,
+            0,
+        ));
+
+        let loop_start = self.start_block();
+        let cond_jump = self.emit_condition(&stmt.condition)?;
+        let counter_jump = self.emit_loop_counter(offset, self.max_loop_iterations)?;
+        self.emit_block(&stmt.body.exprs)?;
+        self.emit_terminator(Op::Jump { target: loop_start });
+        self.patch_jump(cond_jump);
+        self.patch_jump(counter_jump);
+
+        Ok(())
+    }
+
+    /// Emits a list of expressions. Variables declared inside the block go out
+    /// of scope at the end of it.
+    fn emit_block(&mut self, exprs: &[Expression]) -> Result<()> {
+        let scope = self.variables.clone();
+        let position = self.position.clone();
+
+        for expr in exprs {
+            self.position = expr.position().clone();
+
+            match expr {
+                Expression::If(stmt) => {
+                    self.emit_if(stmt)?;
+                }
+                Expression::For(stmt) => {
+                    self.emit_for(stmt)?;
+                }
+                Expression::While(stmt) => {
+                    self.emit_while(stmt)?;
+                }
+                Expression::MapDecl(decl) => {
+                    self.emit_map_decl(decl)?;
+                }
+                Expression::Delete(stmt) => {
+                    self.emit_delete(stmt)?;
+                }
+                Expression::Assignment(assign) => {
+                    self.emit_assign(assign)?;
+                }
+                Expression::FunctionCall(call) => {
+                    self.emit_call(call)?;
+                }
+                Expression::Return(ret) => {
+                    self.emit_return(ret)?;
+                }
+            }
+        }
+
+        /*
+         * Remember the types of the variables going out of scope for the BTF, and
+         * attribute any instructions that follow the block (e.g. a loop's jump back)
+         * to the statement it belongs to.
+         */
+        for info in self.variables.values() {
+            if let VariableLocation::Stack(offset) = info.location {
+                if !self.locals.iter().any(|(o, _)| *o == offset) {
+                    self.locals.push((offset, info.var_type.clone()));
+                }
+            }
+        }
+
+        self.variables = scope;
+        self.position = position;
+        Ok(())
+    }
+
+    /// Emits the body of a function, `end` is where the implicit return at the end
+    /// of it is attributed to.
+    fn emit_body(&mut self, exprs: &[Expression], end: usize) -> Result<()> {
+        self.emit_block(exprs)?;
+
+        /*
+         * Programs implicitly return 0 when no return statement is specified.
+         */
+        if !always_returns(exprs) {
+            self.emit_return(&Return {
+                value: None,
+                position: end..end,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolves the signatures of the functions defined by the script, so they can
+    /// be called before they're compiled.
+    fn declare_functions(&mut self, defs: &[FunctionDef]) -> Result<()> {
+        self.functions.clear();
+        for def in defs {
+            let span = self.ident_span(&def.position, &def.name);
+            if self.function_index(&def.name).is_some() || Helpers::from_string(&def.name).is_some()
+            {
+                return Err(CompileError::InvalidFunction {
+                    span,
+                    name: def.name.clone(),
+                    reason: "Functions must have distinct names from each other and helpers",
+                });
+            }
+
+            if def.args.len() > 5 {
+                return Err(CompileError::TooManyArguments {
+                    span: self.span(&def.position),
+                    max: 5,
+                });
+            }
+
+            let mut params = vec![];
+            for arg in &def.args {
+                let param_type = self.resolve_argument_type(arg)?;
+                if !param_type.is_pointer() && param_type.get_size() > 8 {
+                    return Err(CompileError::InvalidType {
+                        span: self.span(&arg.position),
+                        reason: "Function parameters must fit in a register",
+                    });
+                }
+                params.push((arg.name.clone(), param_type));
+            }
+
+            let return_type = match &def.return_type {
+                Some(decl) => {
+                    let return_type = self.resolve_type_by_decl(decl)?;
+                    if return_type.is_pointer()
+                        || !matches!(return_type.base_type, Type::Integer(_))
+                    {
+                        return Err(CompileError::InvalidType {
+                            span: self.span(&decl.position),
+                            reason: "Functions can only return integers",
+                        });
+                    }
+                    Some(return_type)
+                }
+                None => None,
+            };
+
+            self.functions.push(FunctionInfo {
+                name: def.name.clone(),
+                params,
+                return_type,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Optimizes, lowers and verifies the function that was just emitted, and
+    /// returns it, leaving the compiler ready to emit the next one.
+    fn finish_function(&mut self) -> Result<CompiledFunction> {
+        let report = self.passes.run(&mut self.ir);
+        for stats in report {
+            self.add_pass_stats(stats);
+        }
+        self.lower()?;
+
+        /*
+         * The peephole rewrites and register allocation work on the emitted
+         * instructions, they're reported after the passes over the IR.
+         */
+        let level = self.passes.level();
+        if level >= OptLevel::O1 {
+            let (instructions, new_index) = optimize(&self.instructions);
+            self.record_pass("peephole", instructions, &new_index);
+        }
+        if level >= OptLevel::O2 {
+            let (instructions, new_index) = allocate(&self.instructions, &self.stack_objects);
+            self.record_pass("register-allocation", instructions, &new_index);
+        }
+
+        self.verify_program()?;
+
+        self.stack_objects.clear();
+        Ok(CompiledFunction {
+            ir: std::mem::take(&mut self.ir),
+            instructions: std::mem::take(&mut self.instructions),
+            lines: std::mem::take(&mut self.lines),
+            relocations: std::mem::take(&mut self.relocations),
+            args: std::mem::take(&mut self.args),
+            stack_size: std::mem::take(&mut self.stack),
+            calls: std::mem::take(&mut self.calls),
+        })
+    }
+
+    /// Checks that functions don't call themselves, directly or not, and that the
+    /// stack frames of nested calls fit in the stack. Frames are rounded up to 32
+    /// bytes, like the kernel does.
+    ///
+    /// # Arguments
+    ///
+    /// * `compiled` - The program, followed by the functions defined by the script.
+    /// * `path` - The chain of calls that led to the last function in it.
+    /// * `depth` - The combined size of the frames of the functions in `path`.
+    fn check_calls(
+        &self,
+        compiled: &[CompiledFunction],
+        path: &mut Vec<usize>,
+        depth: u32,
+    ) -> Result<()> {
+        let caller = &compiled[path[path.len() - 1]];
+        let depth = depth + caller.stack_size.max(1).div_ceil(32) * 32;
+        for (callee, position) in &caller.calls {
+            let name = self.functions[*callee].name.clone();
+            let span = self.ident_span(position, &name);
+            if path.contains(&(callee + 1)) {
+                return Err(CompileError::InvalidFunction {
+                    span,
+                    name,
+                    reason: "Functions can't call themselves",
+                });
+            }
+
+            let callee_size = compiled[callee + 1].stack_size.max(1).div_ceil(32) * 32;
+            if path.len() >= MAX_CALL_FRAMES {
+                return Err(CompileError::InvalidFunction {
+                    span,
+                    name,
+                    reason: "Calls can only be nested 8 deep",
+                });
+            }
+            if depth + callee_size > STACK_SIZE {
+                return Err(CompileError::InvalidFunction {
+                    span,
+                    name,
+                    reason: "Nested calls need more than the 512 bytes of stack",
+                });
             }
+
+            path.push(callee + 1);
+            self.check_calls(compiled, path, depth)?;
+            path.pop();
         }
 
         Ok(())
     }
 
-    fn emit_call(&mut self, call: &FunctionCall) -> Result<()> {
-        let helper = match Helpers::from_string(&call.name) {
-            Some(helper) => helper,
-            None => {
-                bail!(
-                    "[Line {}] Unknown helper function \"{}\".",
-                    self.expr_num,
-                    call.name,
-                );
+    /// Lays out the program followed by the functions it calls, and patches the
+    /// calls with the distance to the function they call. Functions that are never
+    /// called are left out, the kernel rejects programs with unreachable code.
+    ///
+    /// # Arguments
+    ///
+    /// * `compiled` - The program, followed by the functions defined by the script.
+    fn link(&mut self, mut compiled: Vec<CompiledFunction>) -> Result<()> {
+        self.check_calls(&compiled, &mut vec![0], 0)?;
+
+        let mut order = vec![0];
+        let mut i = 0;
+        while i < order.len() {
+            for (callee, _) in &compiled[order[i]].calls {
+                if !order.contains(&(callee + 1)) {
+                    order.push(callee + 1);
+                }
             }
-        };
+            i += 1;
+        }
 
-        let types = helper.get_arg_types();
+        let mut starts = vec![0; compiled.len()];
+        self.subprograms.clear();
+        for &function in &order {
+            let start = self.instructions.len();
+            starts[function] = start;
+            if function > 0 {
+                self.subprograms.push((function - 1, start));
+            }
 
-        for (i, arg) in call.args.iter().enumerate() {
-            match i {
-                0 => self.emit_set_register_from_rvalue(Register::R1, arg, Some(types[i]))?,
-                1 => self.emit_set_register_from_rvalue(Register::R2, arg, Some(types[i]))?,
-                2 => self.emit_set_register_from_rvalue(Register::R3, arg, Some(types[i]))?,
-                3 => self.emit_set_register_from_rvalue(Register::R4, arg, Some(types[i]))?,
-                4 => self.emit_set_register_from_rvalue(Register::R5, arg, Some(types[i]))?,
-                _ => {
-                    bail!(
-                        "[Line {}] Function calls can have a maximum of 5 arguments.",
-                        self.expr_num,
-                    );
-                }
-            };
+            let compiled = &mut compiled[function];
+            self.instructions.append(&mut compiled.instructions);
+            self.lines.extend(
+                compiled
+                    .lines
+                    .drain(..)
+                    .map(|(index, source)| (index + start, source)),
+            );
+            self.relocations
+                .extend(compiled.relocations.drain(..).map(|mut relocation| {
+                    relocation.instruction += start;
+                    relocation
+                }));
+        }
+
+        /*
+         * Calls are counted in slots from the instruction after the call, like jumps.
+         */
+        let mut slots = Vec::with_capacity(self.instructions.len() + 1);
+        slots.push(0);
+        for ins in &self.instructions {
+            let last = slots[slots.len() - 1];
+            slots.push(last + jump::slot_count(std::slice::from_ref(ins)) as i32);
+        }
+        for (i, ins) in self.instructions.iter_mut().enumerate() {
+            if let Some(callee) = jump::local_call_offset(ins) {
+                let target = slots[starts[callee as usize + 1]];
+                *ins = jump::call_local(target - slots[i] - 1);
+            }
         }
-        self.instructions.push(Instruction::call(helper as u32));
 
+        let main = compiled.swap_remove(0);
+        self.ir = main.ir;
+        self.args = main.args;
         Ok(())
     }
 
-    fn emit_return(&mut self, ret: &Return) -> Result<()> {
-        match &ret.value {
+    /// Checks the header of a script against the rules of its program type: the
+    /// attach point must have the parts the type needs, and the program can only
+    /// take a pointer to its context as a parameter, unless it takes the arguments
+    /// of the kernel function it traces.
+    fn declare_program(
+        &mut self,
+        header: &ProgramHeader,
+        args: &[TypedArgument],
+    ) -> Result<AttachPoint> {
+        let program_type = match ProgramType::from_name(&header.kind) {
+            Some(program_type) => program_type,
             None => {
-                self.instructions.push(Instruction::mov64(Register::R0, 0));
-                self.instructions.push(Instruction::exit());
-            }
-            Some(value) => {
-                self.emit_set_register_from_rvalue(Register::R0, value, None)?;
-                self.instructions.push(Instruction::exit());
+                return Err(CompileError::UnknownProgramType {
+                    span: self.ident_span(&header.position, &header.kind),
+                    name: header.kind.clone(),
+                    suggestion: closest_match(&header.kind, ProgramType::names()),
+                });
             }
+        };
+
+        let attach = AttachPoint {
+            program_type,
+            target: header.target.clone(),
+        };
+        if attach.target.len() != program_type.target_parts() {
+            return Err(CompileError::InvalidProgram {
+                span: self.span(&header.position),
+                name: attach.to_string(),
+                reason: "Attach point has the wrong number of parts for its program type",
+            });
         }
 
-        Ok(())
-    }
+        self.traced_args.clear();
+        if program_type.has_traced_arguments() {
+            self.declare_traced_arguments(header, program_type, &attach.target[0], args)?;
+            return Ok(attach);
+        }
 
-    fn emit_prologue(&mut self, ast: &ScriptDef) -> Result<()> {
-        /*
-         * BPF limits the number of function arguments to 5 (R1 to R5).
-         */
-        if ast.input.args.len() > 5 {
-            bail!(
-                "[Line {}] Function calls can have a maximum of 5 arguments.",
-                self.expr_num,
-            );
+        if args.len() > 1 {
+            return Err(CompileError::InvalidProgram {
+                span: self.span(&args[1].position),
+                name: args[1].name.clone(),
+                reason: "Programs can only take their context as a parameter",
+            });
         }
 
         /*
-         * Push all input arguments to the stack and create variables entries for them.
+         * Tracepoints each have their own context type, any pointer is accepted.
          */
-        for (i, arg) in ast.input.args.iter().enumerate() {
-            let register = Register::from_num((i + 1) as u8).expect("too many args");
-            let arg_type = self.resolve_type_by_decl(&arg.type_name)?;
-            let offset = self.emit_push_register(register, None)?;
-            self.variables.insert(
-                arg.name.clone(),
-                VariableInfo {
-                    var_type: arg_type,
-                    location: VariableLocation::Stack(offset),
-                },
-            );
+        if let Some(arg) = args.first() {
+            let expected = program_type.context_type();
+            let is_context = match &arg.type_name {
+                Some(decl) => {
+                    decl.is_ref.is_some() && expected.is_none_or(|name| name == decl.name)
+                }
+                None => false,
+            };
+            if !is_context {
+                return Err(CompileError::InvalidProgram {
+                    span: self.span(&arg.position),
+                    name: expected.unwrap_or(&arg.name).to_string(),
+                    reason: "The context parameter must be a pointer to the program's context",
+                });
+            }
         }
 
-        Ok(())
+        Ok(attach)
     }
 
-    fn emit_body(&mut self, ast: &ScriptDef) -> Result<()> {
-        for expr in &ast.exprs {
-            self.expr_num += 1;
+    /// Looks up the prototype of the kernel function an fentry or fexit program
+    /// traces, and records the types of the arguments the script names. Parameters
+    /// without a type get the type of the function's argument.
+    ///
+    /// The type library doesn't keep the return type of prototypes, so fexit
+    /// programs type `retval` by naming it as a parameter, e.g. `retval: ssize_t`.
+    /// It's left out when numbering the arguments and is `__u64` if not named.
+    fn declare_traced_arguments(
+        &mut self,
+        header: &ProgramHeader,
+        program_type: ProgramType,
+        function: &str,
+        args: &[TypedArgument],
+    ) -> Result<()> {
+        let params = match self.types.resolve_type_by_name(function) {
+            Some(QualifiedType {
+                is_function: true,
+                base_type: Type::FunctionProto(proto),
+                ..
+            }) => proto.params,
+            _ => {
+                return Err(CompileError::InvalidProgram {
+                    span: self.ident_span(&header.position, function),
+                    name: function.to_string(),
+                    reason: "No kernel function with this name in the BTF type library",
+                });
+            }
+        };
 
-            match expr {
-                Expression::Assignment(assign) => {
-                    self.emit_assign(assign)?;
-                }
-                Expression::FunctionCall(call) => {
-                    self.emit_call(call)?;
-                }
-                Expression::Return(ret) => {
-                    self.emit_return(ret)?;
-                }
+        /*
+         * Variadic functions end with an unnamed parameter of type void.
+         */
+        let num_params = params.iter().filter(|param| param.type_id != 0).count();
+        let is_fexit = program_type == ProgramType::Fexit;
+        let mut retval = None;
+        let mut index = 0;
+        for arg in args {
+            let is_retval = is_fexit && arg.name == "retval";
+            if !is_retval && index >= num_params {
+                return Err(CompileError::InvalidProgram {
+                    span: self.span(&arg.position),
+                    name: arg.name.clone(),
+                    reason: "The traced function doesn't have this many parameters",
+                });
+            }
+
+            let arg_type = match &arg.type_name {
+                Some(decl) => self.resolve_type_by_decl(decl)?,
+                None if is_retval => unsigned_type(8),
+                None => self.resolve_type_by_id(params[index].type_id)?,
+            };
+            if !arg_type.is_pointer() && arg_type.get_size() > 8 {
+                return Err(CompileError::InvalidType {
+                    span: self.span(&arg.position),
+                    reason: "Function parameters must fit in a register",
+                });
+            }
+
+            if is_retval {
+                retval = Some(arg_type);
+            } else {
+                self.traced_args.push((arg.name.clone(), arg_type, index));
+                index += 1;
             }
         }
 
         /*
-         * Programs implicitly return 0 when no return statement is specified.
+         * The return value follows the arguments in the context.
          */
-        let last = ast.exprs.last();
-        if matches!(last, None) || !matches!(last, Some(Expression::Return(_))) {
-            self.emit_return(&Return { value: None })?;
+        if is_fexit {
+            let retval_type = retval.unwrap_or_else(|| unsigned_type(8));
+            self.traced_args
+                .push(("retval".to_string(), retval_type, num_params));
         }
 
         Ok(())
@@ -894,15 +4628,231 @@ impl<'a> Compiler<'a> {
     /// "#).expect("Failed to compile.");
     /// ```
     pub fn compile(&mut self, script_text: &str) -> Result<()> {
-        let ast = ScriptDef::parse(script_text)?;
-        self.emit_prologue(&ast)?;
-        self.emit_body(&ast)?;
+        self.source = script_text.to_string();
+        let ast = match ScriptDef::parse(script_text) {
+            Ok(ast) => ast,
+            Err(e) => {
+                /*
+                 * When the script ends early, point just past the last token rather
+                 * than at the trailing whitespace.
+                 */
+                let mut position = e.position.min(script_text.len());
+                if script_text[position..].trim().is_empty() {
+                    position = script_text[..position].trim_end().len();
+                }
+
+                return Err(CompileError::ParseError {
+                    span: self.span(&(position..position)),
+                    expected: e.specifics.to_string(),
+                });
+            }
+        };
+        let args = match &ast.input.params {
+            Some(params) => &params.args[..],
+            None => &[],
+        };
+        self.attach = match &ast.input.header {
+            Some(header) => Some(self.declare_program(header, args)?),
+            None => None,
+        };
+        self.declare_functions(&ast.functions)?;
+        self.pass_report.clear();
+
+        /*
+         * Every function is compiled on its own, with its own stack frame. They all
+         * start out with the same variables: the captures.
+         */
+        let globals = self.variables.clone();
+        self.current_function = None;
+        self.emit_prologue(&ast.input.position, args)?;
+        self.emit_body(&ast.exprs, self.source.len())?;
+        let mut compiled = vec![self.finish_function()?];
+
+        for (index, def) in ast.functions.iter().enumerate() {
+            self.variables = globals.clone();
+            self.current_function = Some(index);
+            self.emit_prologue(&def.position, &def.args)?;
+            self.emit_body(&def.body.exprs, def.position.end)?;
+            compiled.push(self.finish_function()?);
+        }
+
+        self.variables = globals;
+        self.current_function = None;
+        self.link(compiled)
+    }
+
+    /// Lowers the IR to BPF instructions, recording which statement each of them
+    /// came from and which instructions the relocations apply to.
+    fn lower(&mut self) -> Result<()> {
+        /*
+         * The block started after the final exit is empty, and can be dropped unless
+         * something jumps past the end of the program.
+         */
+        let last = BlockId(self.ir.blocks.len() - 1);
+        let is_target = self.ir.blocks.iter().any(|block| {
+            block
+                .terminator()
+                .is_some_and(|op| op.successors().contains(&last))
+        });
+        if self.ir.block(last).insts.is_empty() && !is_target {
+            self.ir.blocks.pop();
+        }
 
-        self.instructions = optimize(&self.instructions);
+        let (instructions, origins) = match self.ir.lower_with_origins() {
+            Ok(lowered) => lowered,
+            Err(e) => {
+                let source = self
+                    .ir
+                    .block(e.block)
+                    .insts
+                    .last()
+                    .map(|inst| inst.source.clone());
+                return Err(CompileError::BranchTooLarge {
+                    span: self.span(&source.unwrap_or_default()),
+                    size: e.distance,
+                });
+            }
+        };
+
+        self.instructions = instructions;
+        self.lines.clear();
+        for (index, (block, inst)) in origins.into_iter().enumerate() {
+            let inst = &self.ir.block(block).insts[inst];
+            if self.lines.last().map(|(_, source)| source) != Some(&inst.source) {
+                self.lines.push((index, inst.source.clone()));
+            }
+
+            if let Some(relocation) = inst.relocation {
+                self.relocations[relocation].instruction = index;
+            }
+        }
 
         Ok(())
     }
 
+    /// Replaces the program after a pass over the emitted instructions, and adds
+    /// it to the pass report.
+    fn record_pass(&mut self, name: &str, instructions: Vec<Instruction>, new_index: &[usize]) {
+        let removed = self.instructions.len().saturating_sub(instructions.len());
+        let changed = instructions != self.instructions;
+        self.add_pass_stats(PassStats {
+            name: name.to_string(),
+            runs: 1,
+            changes: usize::from(changed),
+            removed,
+        });
+        self.remap_instructions(instructions, new_index);
+    }
+
+    /// Adds what a pass did to a function to the pass report, which sums it up
+    /// over all the functions of the program.
+    fn add_pass_stats(&mut self, stats: PassStats) {
+        match self.pass_report.iter_mut().find(|s| s.name == stats.name) {
+            Some(total) => {
+                total.runs += stats.runs;
+                total.changes += stats.changes;
+                total.removed += stats.removed;
+            }
+            None => self.pass_report.push(stats),
+        }
+    }
+
+    /// Replaces the program after a pass, moving the line info and relocations to
+    /// the instructions they now refer to.
+    fn remap_instructions(&mut self, instructions: Vec<Instruction>, new_index: &[usize]) {
+        self.instructions = instructions;
+        for (index, _) in &mut self.lines {
+            *index = new_index[*index];
+        }
+        for relocation in &mut self.relocations {
+            relocation.instruction = new_index[relocation.instruction];
+        }
+    }
+
+    /// Runs the program through the verifier checks, so problems the kernel would
+    /// reject it for are reported on the line of the script that caused them.
+    fn verify_program(&mut self) -> Result<()> {
+        let mut args: Vec<RegType> = self
+            .args
+            .iter()
+            .map(|(_, arg_type)| match arg_type.is_pointer() {
+                true => RegType::Unknown,
+                false => RegType::Scalar(None),
+            })
+            .collect();
+
+        /*
+         * Programs with a program type get their context in R1, even if the script
+         * doesn't name it.
+         */
+        if args.is_empty() && self.attach.is_some() && self.current_function.is_none() {
+            args.push(RegType::Unknown);
+        }
+        let maps: HashMap<u32, MapSizes> = self
+            .maps
+            .iter()
+            .map(|map| {
+                let sizes = MapSizes {
+                    key_size: map.key_type.get_size(),
+                    value_size: map.value_type.get_size(),
+                };
+                (map.fd, sizes)
+            })
+            .collect();
+
+        let violation = match verify(&self.instructions, &args, &maps) {
+            Ok(()) => return Ok(()),
+            Err(violation) => violation,
+        };
+
+        /*
+         * The instruction belongs to the last statement that started at or before
+         * it, statements are recorded in the order they were emitted.
+         */
+        let position = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, (index, _))| *index <= violation.instruction)
+            .max_by_key(|(order, (index, _))| (*index, *order))
+            .map(|(_, (_, range))| range.clone())
+            .unwrap_or_default();
+
+        Err(CompileError::VerifierRejected {
+            span: self.span(&position),
+            instruction: violation.instruction,
+            reason: violation.reason,
+        })
+    }
+
+    /// Returns the program type and attach point declared in the script's header
+    /// after `compile` has been called, or `None` if the script has no header.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Compiler, ProgramType};
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.compile(r#"
+    ///     tracepoint:syscalls:sys_enter_read
+    ///         return 0
+    /// "#).expect("Failed to compile.");
+    /// let attach = compiler.get_attach_point().expect("No attach point.");
+    /// assert_eq!(attach.program_type, ProgramType::Tracepoint);
+    /// assert_eq!(attach.section(), "tracepoint/syscalls/sys_enter_read");
+    /// ```
+    pub fn get_attach_point(&self) -> Option<&AttachPoint> {
+        self.attach.as_ref()
+    }
+
+    /// Returns the program type declared in the script's header after `compile`
+    /// has been called, or `None` if the script has no header.
+    pub fn get_program_type(&self) -> Option<ProgramType> {
+        self.attach.as_ref().map(|attach| attach.program_type)
+    }
+
     /// Returns the internally held instructions after `compile` has been called.
     ///
     /// # Example
@@ -925,6 +4875,124 @@ impl<'a> Compiler<'a> {
         &self.instructions
     }
 
+    /// Returns what every optimization pass did after `compile` has been called,
+    /// in the order they ran: the passes over the IR, then the peephole rewrites
+    /// and register allocation.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         a: u32 = 1
+    ///         return a + 2
+    /// "#).expect("Failed to compile.");
+    /// for pass in compiler.get_pass_report() {
+    ///     println!("{}: removed {} instructions", pass.name, pass.removed);
+    /// }
+    /// ```
+    pub fn get_pass_report(&self) -> &[PassStats] {
+        &self.pass_report
+    }
+
+    /// Returns the intermediate representation the script was lowered to after
+    /// `compile` has been called, as optimized before register allocation.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         if a == 1 {
+    ///             return 2
+    ///         }
+    /// "#).expect("Failed to compile.");
+    /// println!("{}", compiler.get_ir());
+    /// assert_eq!(compiler.get_ir().blocks.len(), 3);
+    /// ```
+    pub fn get_ir(&self) -> &Function {
+        &self.ir
+    }
+
+    /// Returns the CO-RE relocations of the program after `compile` has been
+    /// called, which is empty unless `set_core_relocations` was enabled.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Compiler, RelocationKind};
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// btf.add_struct("file", &[("flags", "u32"), ("mode", "u32")])
+    ///     .expect("Failed to add file type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.set_core_relocations(true);
+    /// compiler.compile(r#"
+    ///     fn(f: &file)
+    ///         return field_exists(f.mode)
+    /// "#).expect("Failed to compile.");
+    /// for relocation in compiler.get_core_relocations() {
+    ///     assert_eq!(relocation.kind, RelocationKind::FieldExists);
+    ///     println!("{}: {}", relocation.instruction, relocation.field_path());
+    /// }
+    /// ```
+    pub fn get_core_relocations(&self) -> &[CoreRelocation] {
+        &self.relocations
+    }
+
+    /// Returns the program's instructions relocated to a target type library after
+    /// `compile` has been called with `set_core_relocations` enabled. This is
+    /// shorthand for `relocate` with the type library the compiler was created with.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The type library to relocate the program to, usually the BTF of
+    ///   the kernel the program is going to run on.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Compiler, RelocationError};
+    /// use btf::BtfTypes;
+    ///
+    /// let mut local = BtfTypes::default();
+    /// local.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// local.add_struct("file", &[("flags", "u32"), ("mode", "u32")])
+    ///     .expect("Failed to add file type.");
+    ///
+    /// let mut target = BtfTypes::default();
+    /// target.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// target.add_struct("file", &[("flags", "u32")]).expect("Failed to add file type.");
+    ///
+    /// let mut compiler = Compiler::create(&local);
+    /// compiler.set_core_relocations(true);
+    /// compiler.compile(r#"
+    ///     fn(f: &file)
+    ///         mode: u32 = f.mode
+    ///         return mode
+    /// "#).expect("Failed to compile.");
+    /// match compiler.relocate(&target) {
+    ///     Err(RelocationError::MissingFields(fields)) => assert_eq!(fields[0].field, "file.mode"),
+    ///     _ => panic!("Field shouldn't exist on the target."),
+    /// }
+    /// ```
+    pub fn relocate(
+        &self,
+        target: &BtfTypes,
+    ) -> std::result::Result<Vec<Instruction>, RelocationError> {
+        relocate(&self.instructions, &self.relocations, self.types, target)
+    }
+
     /// Returns the bytecode of a program after `compile` has been called. These
     /// are the raw instructions that make up a BPF program that can be passed
     /// directly to the kernel.
@@ -957,4 +5025,195 @@ impl<'a> Compiler<'a> {
 
         bytecode
     }
+
+    /// Returns the program's BTF after `compile` has been called: the types of its
+    /// arguments, variables and maps, function prototypes for the program and the
+    /// functions it calls, and function and line information mapping instructions
+    /// back to the script.
+    ///
+    /// # Arguments
+    ///
+    /// * `section` - The section the program is placed in, which also names the
+    ///   program's function, e.g. `kprobe/do_sys_open`.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         b: u32 = a + 1
+    ///         return b
+    /// "#).expect("Failed to compile.");
+    /// let btf = compiler.get_btf("kprobe/do_sys_open");
+    /// assert_eq!(btf.func_info().len(), 1);
+    /// assert_eq!(btf.line_info()[0].line(), 3);
+    /// ```
+    pub fn get_btf(&self, section: &str) -> ProgramBtf {
+        let mut writer = BtfWriter::new(self.types);
+
+        let mut locals = self.locals.clone();
+        locals.sort_by_key(|(offset, _)| *offset);
+        for (_, local_type) in &locals {
+            writer.add_type(local_type);
+        }
+        for capture in &self.captures {
+            writer.add_type(&capture.var_type);
+        }
+
+        let func = writer.add_function(&function_name(section), &self.args, None, true);
+        let subprograms: Vec<(usize, u32)> = self
+            .subprograms
+            .iter()
+            .map(|(index, start)| {
+                let function = &self.functions[*index];
+                let return_type = function
+                    .return_type
+                    .clone()
+                    .unwrap_or_else(|| unsigned_type(8));
+                let func = writer.add_function(
+                    &function.name,
+                    &function.params,
+                    Some(&return_type),
+                    false,
+                );
+                (*start, func)
+            })
+            .collect();
+
+        if !self.maps.is_empty() {
+            let maps: Vec<MapBtf> = self
+                .maps
+                .iter()
+                .map(|map| MapBtf {
+                    name: &map.name,
+                    map_type: map.map_type,
+                    key_type: &map.key_type,
+                    value_type: &map.value_type,
+                    max_entries: map.max_entries,
+                })
+                .collect();
+            writer.add_maps(&maps);
+        }
+
+        /*
+         * Line records are kept in instruction indices, line info is in slots. When
+         * several records start at the same instruction (e.g. a statement that
+         * emitted nothing), the last one is the one the instruction belongs to.
+         */
+        let mut slots = Vec::with_capacity(self.instructions.len() + 1);
+        slots.push(0);
+        for ins in &self.instructions {
+            let last = slots[slots.len() - 1];
+            slots.push(last + jump::slot_count(std::slice::from_ref(ins)) as u32);
+        }
+        let program_slots = slots[slots.len() - 1];
+
+        let mut records: Vec<(u32, usize, &Range<usize>)> = self
+            .lines
+            .iter()
+            .enumerate()
+            .map(|(order, (index, range))| (slots[*index], order, range))
+            .filter(|(insn_off, _, _)| *insn_off < program_slots)
+            .collect();
+        records.sort_by_key(|(insn_off, order, _)| (*insn_off, *order));
+        records.dedup_by(|next, prev| {
+            if next.0 == prev.0 {
+                *prev = *next;
+                true
+            } else {
+                false
+            }
+        });
+
+        let lines: Vec<(u32, u32, u32, &str)> = records
+            .iter()
+            .map(|(insn_off, _, range)| {
+                let span = Span::from_range(&self.source, range);
+                let text = self.source.lines().nth(span.line - 1).unwrap_or("");
+                (*insn_off, span.line as u32, span.column as u32, text)
+            })
+            .collect();
+
+        let relocations: Vec<(u32, &CoreRelocation)> = self
+            .relocations
+            .iter()
+            .map(|relocation| (slots[relocation.instruction], relocation))
+            .collect();
+
+        let mut func_info = vec![FuncInfo {
+            insn_off: 0,
+            type_id: func,
+        }];
+        for (start, func) in &subprograms {
+            func_info.push(FuncInfo {
+                insn_off: slots[*start],
+                type_id: *func,
+            });
+        }
+        let text_start = subprograms.first().map(|(start, _)| slots[*start]);
+        writer.finish(section, text_start, func_info, &lines, &relocations)
+    }
+
+    /// Returns the program as an ELF relocatable object file after `compile` has
+    /// been called, which can be loaded by standard BPF loaders such as libbpf
+    /// and `bpftool prog load`. Declared maps are written to the `.maps` section
+    /// and described by the program's BTF (see `get_btf`), and references to them
+    /// are relocated by the loader, so the file descriptors they were captured with
+    /// only need to be distinct placeholders.
+    ///
+    /// # Arguments
+    ///
+    /// * `section` - The section to place the program in, which tells loaders the
+    ///   program type and where to attach it, e.g. `kprobe/do_sys_open` or `xdp`.
+    /// * `license` - The license of the program, e.g. `GPL`.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.capture("counts", 0);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         map counts: array<u32, u32>[1]
+    ///         counts[0] += 1
+    /// "#).expect("Failed to compile.");
+    /// let object = compiler.get_object("kprobe/do_sys_open", "GPL");
+    /// assert_eq!(&object[..4], b"\x7fELF");
+    /// ```
+    pub fn get_object(&self, section: &str, license: &str) -> Vec<u8> {
+        let maps: Vec<ObjectMap> = self
+            .maps
+            .iter()
+            .map(|map| ObjectMap {
+                name: map.name.clone(),
+                fd: map.fd,
+            })
+            .collect();
+
+        let functions: Vec<ObjectFunction> = self
+            .subprograms
+            .iter()
+            .map(|(index, start)| ObjectFunction {
+                name: self.functions[*index].name.clone(),
+                start: *start,
+            })
+            .collect();
+
+        write_object(
+            license,
+            &self.instructions,
+            &functions,
+            &maps,
+            &self.get_btf(section),
+        )
+    }
 }
@@ -0,0 +1,969 @@
+//! Optimizations of the IR that follow values through registers and the stack.
+//! Every pass returns whether it changed the function, so they can be repeated
+//! until none of them finds anything left to do.
+
+use crate::helpers::Helpers;
+use crate::interpreter::{evaluate_alu, evaluate_jump};
+use crate::ir::{lower_op, BlockId, Function, Inst, Op, Value, Width};
+use crate::jump;
+
+use bpf_ins::{ArithmeticOperation, MemoryOpLoadType, MemoryOpSize, Register};
+
+/// The size of the stack frame, in bytes.
+const FRAME_SIZE: usize = 512;
+
+/// What's known about the value of a register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Known {
+    Unknown,
+    Constant(u64),
+    /// An address on the stack, relative to R10.
+    Frame(i64),
+}
+
+/// What's known about the value stored at a stack slot: the register holding it,
+/// or the value it's loaded as.
+type StackFact = (i16, MemoryOpSize, Value);
+
+/// Returns the number of bytes accessed by a memory operation of the given size.
+fn size_bytes(size: MemoryOpSize) -> i16 {
+    match size {
+        MemoryOpSize::Byte => 1,
+        MemoryOpSize::HalfWord => 2,
+        MemoryOpSize::Word => 4,
+        MemoryOpSize::DoubleWord => 8,
+    }
+}
+
+/// Returns whether a value can be used as a 64-bit immediate, which is sign
+/// extended from 32 bits.
+fn fits_imm(value: u64) -> bool {
+    i32::try_from(value as i64).is_ok()
+}
+
+/// Returns what's known about a register or immediate operand.
+fn known_value(value: Value, known: &[Known; 11]) -> Known {
+    match value {
+        Value::Register(reg) => known[reg.as_num() as usize],
+        Value::Immediate(imm) => Known::Constant(imm as u64),
+    }
+}
+
+/// Computes `lhs <op> rhs` the way the kernel does.
+fn evaluate(
+    op: ArithmeticOperation,
+    width: Width,
+    signed: bool,
+    lhs: u64,
+    rhs: u64,
+) -> Option<u64> {
+    let canonical = Op::Arithmetic {
+        op,
+        width,
+        signed,
+        dst: Register::R0,
+        lhs: Register::R0,
+        rhs: Value::Register(Register::R1),
+    };
+    evaluate_alu(&lower_op(&canonical)[0], lhs, rhs)
+}
+
+/// Returns a move setting `dst` to a constant, if it fits in a single instruction.
+fn move_constant(dst: Register, value: u64) -> Option<Op> {
+    if fits_imm(value) {
+        Some(Op::mov64(dst, value as i64))
+    } else if u32::try_from(value).is_ok() {
+        Some(Op::mov32(dst, value as u32 as i32))
+    } else {
+        None
+    }
+}
+
+/// Rewrites an operation given what's known about the registers before it.
+/// Returns `None` when the operation does nothing and can be removed.
+fn fold(op: &Op, known: &[Known; 11]) -> Option<Op> {
+    let constant = |reg: Register| match known[reg.as_num() as usize] {
+        Known::Constant(value) => Some(value),
+        _ => None,
+    };
+    let frame_offset = |base: Register, offset: i16| match known[base.as_num() as usize] {
+        Known::Frame(frame) if base != Register::R10 => i16::try_from(frame + offset as i64).ok(),
+        _ => None,
+    };
+
+    let folded = match *op {
+        Op::Move {
+            width,
+            dst,
+            src: Value::Register(src),
+        } => match (width, constant(src)) {
+            (Width::Bits64, Some(value)) if fits_imm(value) => Op::mov64(dst, value as i64),
+            (Width::Bits32, Some(value)) => Op::mov32(dst, value as u32 as i32),
+            (Width::Bits64, _) if src == dst => return None,
+            _ => *op,
+        },
+        Op::Arithmetic {
+            op: operation,
+            width,
+            signed,
+            dst,
+            lhs,
+            rhs,
+        } => {
+            let rhs_value = match known_value(rhs, known) {
+                Known::Constant(value) => Some(value),
+                _ => None,
+            };
+
+            if let (Some(lhs), Some(rhs)) = (constant(lhs), rhs_value) {
+                if let Some(op) = evaluate(operation, width, signed, lhs, rhs)
+                    .and_then(|value| move_constant(dst, value))
+                {
+                    return Some(op);
+                }
+            }
+
+            /*
+             * Operations that leave a 64-bit value unchanged are plain moves.
+             */
+            let is_identity = matches!(
+                (operation, rhs_value),
+                (ArithmeticOperation::Mul | ArithmeticOperation::Div, Some(1))
+                    | (
+                        ArithmeticOperation::Add
+                            | ArithmeticOperation::Sub
+                            | ArithmeticOperation::Or
+                            | ArithmeticOperation::Xor
+                            | ArithmeticOperation::Lhs
+                            | ArithmeticOperation::Rhs
+                            | ArithmeticOperation::Ash,
+                        Some(0),
+                    )
+            );
+            if width == Width::Bits64 && is_identity {
+                return fold(&Op::mov64(dst, lhs), known);
+            }
+
+            let rhs = match (rhs, rhs_value) {
+                (Value::Register(_), Some(value))
+                    if operation != ArithmeticOperation::Neg
+                        && (width == Width::Bits32 || fits_imm(value)) =>
+                {
+                    match width {
+                        Width::Bits64 => Value::Immediate(value as i64),
+                        Width::Bits32 => Value::Immediate(value as u32 as i32 as i64),
+                    }
+                }
+                _ => rhs,
+            };
+
+            Op::Arithmetic {
+                op: operation,
+                width,
+                signed,
+                dst,
+                lhs,
+                rhs,
+            }
+        }
+        Op::Load {
+            size,
+            dst,
+            base,
+            offset,
+        } => match frame_offset(base, offset) {
+            Some(offset) => Op::load(size, dst, Register::R10, offset),
+            None => *op,
+        },
+        Op::Store {
+            size,
+            base,
+            offset,
+            src,
+        } => {
+            let (base, offset) = match frame_offset(base, offset) {
+                Some(offset) => (Register::R10, offset),
+                None => (base, offset),
+            };
+
+            /*
+             * Smaller stores only write the low bytes of the immediate.
+             */
+            let src = match src {
+                Value::Register(reg) => match constant(reg) {
+                    Some(value) if size != MemoryOpSize::DoubleWord => {
+                        Value::Immediate(value as u32 as i32 as i64)
+                    }
+                    Some(value) if fits_imm(value) => Value::Immediate(value as i64),
+                    _ => src,
+                },
+                Value::Immediate(_) => src,
+            };
+
+            Op::store(size, base, offset, src)
+        }
+        Op::Branch {
+            op,
+            lhs,
+            rhs,
+            then,
+            otherwise,
+        } => {
+            let rhs_value = match known_value(rhs, known) {
+                Known::Constant(value) => Some(value),
+                _ => None,
+            };
+
+            if let (Some(lhs), Some(rhs)) = (constant(lhs), rhs_value) {
+                let ins = jump::if_reg(op, Register::R0, Register::R1, 0);
+                if let Some(taken) = evaluate_jump(&ins, lhs, rhs) {
+                    let target = if taken { then } else { otherwise };
+                    return Some(Op::Jump { target });
+                }
+            }
+
+            let rhs = match rhs_value {
+                Some(value) if fits_imm(value) => Value::Immediate(value as i64),
+                _ => rhs,
+            };
+
+            Op::Branch {
+                op,
+                lhs,
+                rhs,
+                then,
+                otherwise,
+            }
+        }
+        _ => *op,
+    };
+
+    Some(folded)
+}
+
+/// Updates what's known about the registers after an instruction.
+fn transfer(inst: &Inst, known: &mut [Known; 11]) {
+    let value = match (inst.op, inst.relocation) {
+        (_, Some(_)) => Known::Unknown,
+        (Op::Move { width, src, .. }, None) => match (width, known_value(src, known)) {
+            (Width::Bits64, value) => value,
+            (Width::Bits32, Known::Constant(value)) => Known::Constant(value as u32 as u64),
+            (Width::Bits32, _) => Known::Unknown,
+        },
+        (
+            Op::Arithmetic {
+                op,
+                width,
+                signed,
+                lhs,
+                rhs,
+                ..
+            },
+            None,
+        ) => {
+            let lhs = known[lhs.as_num() as usize];
+            match (op, width, lhs, known_value(rhs, known)) {
+                (_, _, Known::Constant(lhs), Known::Constant(rhs)) => {
+                    match evaluate(op, width, signed, lhs, rhs) {
+                        Some(value) => Known::Constant(value),
+                        None => Known::Unknown,
+                    }
+                }
+                (
+                    ArithmeticOperation::Add,
+                    Width::Bits64,
+                    Known::Frame(frame),
+                    Known::Constant(rhs),
+                ) => Known::Frame(frame.wrapping_add(rhs as i64)),
+                (
+                    ArithmeticOperation::Sub,
+                    Width::Bits64,
+                    Known::Frame(frame),
+                    Known::Constant(rhs),
+                ) => Known::Frame(frame.wrapping_sub(rhs as i64)),
+                _ => Known::Unknown,
+            }
+        }
+        (
+            Op::LoadImmediate {
+                imm,
+                load_type: MemoryOpLoadType::Void,
+                ..
+            },
+            None,
+        ) => Known::Constant(imm as u64),
+        _ => Known::Unknown,
+    };
+
+    for reg in inst.op.defs() {
+        known[reg.as_num() as usize] = value;
+    }
+}
+
+/// Propagates constants and stack addresses through the registers of each block,
+/// folding operations on constants, turning register operands that are known to
+/// be constant into immediates, and accessing the stack directly through R10.
+/// Branches on constants become jumps.
+///
+/// # Arguments
+///
+/// * `function` - The function to optimize.
+pub(crate) fn fold_constants(function: &mut Function) -> bool {
+    let mut changed = false;
+    for block in &mut function.blocks {
+        let mut known = [Known::Unknown; 11];
+        known[Register::R10.as_num() as usize] = Known::Frame(0);
+
+        let mut insts = Vec::with_capacity(block.insts.len());
+        for mut inst in block.insts.drain(..) {
+            if inst.relocation.is_none() {
+                match fold(&inst.op, &known) {
+                    Some(op) if op == inst.op => {}
+                    Some(op) => {
+                        inst.op = op;
+                        changed = true;
+                    }
+                    None => {
+                        changed = true;
+                        continue;
+                    }
+                }
+            }
+
+            transfer(&inst, &mut known);
+            insts.push(inst);
+        }
+        block.insts = insts;
+    }
+
+    changed
+}
+/// Returns a set of registers as a bit mask.
+fn register_mask(regs: &[Register]) -> u16 {
+    regs.iter().fold(0, |mask, reg| mask | 1 << reg.as_num())
+}
+
+/// Returns the register of an operand as a bit mask, or nothing for immediates.
+fn value_mask(value: Value) -> u16 {
+    match value {
+        Value::Register(reg) => register_mask(&[reg]),
+        Value::Immediate(_) => 0,
+    }
+}
+
+/// Tracks which registers might hold an address on the stack, so that memory
+/// accesses and helper calls through other pointers are known not to touch it.
+struct FramePointers {
+    /// The registers that might hold one at the start of every block.
+    entry: Vec<u16>,
+    /// Whether an address on the stack might be stored to memory, in which case
+    /// any value loaded from memory might be one.
+    escapes: bool,
+}
+
+impl FramePointers {
+    fn analyze(function: &Function) -> Self {
+        let mut pointers = Self {
+            entry: vec![],
+            escapes: false,
+        };
+
+        loop {
+            pointers.entry = pointers.propagate(function);
+            let escapes = function.blocks.iter().enumerate().any(|(i, block)| {
+                let mut mask = pointers.entry[i];
+                block.insts.iter().any(|inst| {
+                    let stored = match inst.op {
+                        Op::Store { src, .. } => mask & value_mask(src) != 0,
+                        Op::Atomic { src, .. } => mask & register_mask(&[src]) != 0,
+                        _ => false,
+                    };
+                    mask = pointers.step(&inst.op, mask);
+                    stored
+                })
+            });
+
+            if escapes == pointers.escapes {
+                return pointers;
+            }
+            pointers.escapes = escapes;
+        }
+    }
+
+    /// Computes the registers that might hold an address on the stack at the
+    /// start of every block, iterating until loops stop adding any.
+    fn propagate(&self, function: &Function) -> Vec<u16> {
+        let num_blocks = function.blocks.len();
+        let mut entry = vec![register_mask(&[Register::R10]); num_blocks];
+        loop {
+            let mut updated = false;
+            for (i, block) in function.blocks.iter().enumerate() {
+                let mask = block
+                    .insts
+                    .iter()
+                    .fold(entry[i], |mask, inst| self.step(&inst.op, mask));
+                for successor in function.successors(BlockId(i)) {
+                    if successor.0 < num_blocks && entry[successor.0] | mask != entry[successor.0] {
+                        entry[successor.0] |= mask;
+                        updated = true;
+                    }
+                }
+            }
+
+            if !updated {
+                return entry;
+            }
+        }
+    }
+
+    /// Updates the registers that might hold an address on the stack after an
+    /// operation.
+    fn step(&self, op: &Op, mask: u16) -> u16 {
+        let is_pointer = match *op {
+            Op::Move { src, .. } => mask & value_mask(src) != 0,
+            Op::Arithmetic { lhs, rhs, .. } => {
+                mask & (register_mask(&[lhs]) | value_mask(rhs)) != 0
+            }
+            Op::Load { .. } => self.escapes,
+            _ => false,
+        };
+
+        let defs = register_mask(&op.defs());
+        let mask = match is_pointer {
+            true => mask | defs,
+            false => mask & !defs,
+        };
+        mask | register_mask(&[Register::R10])
+    }
+
+    /// Returns what's known about the registers before every instruction of a
+    /// block, along with the ones that might hold an address on the stack.
+    fn states(&self, function: &Function, block: usize) -> Vec<([Known; 11], u16)> {
+        let mut known = [Known::Unknown; 11];
+        known[Register::R10.as_num() as usize] = Known::Frame(0);
+        let mut mask = self.entry[block];
+
+        let mut states = vec![];
+        for inst in &function.blocks[block].insts {
+            states.push((known, mask));
+            transfer(inst, &mut known);
+            mask = self.step(&inst.op, mask);
+        }
+        states
+    }
+}
+
+/// Returns the bytes of the stack frame a pointer might give access to: none when
+/// it can't point to the stack, everything from its address to the top of the
+/// frame when that's known, and the whole frame otherwise.
+fn pointer_range(reg: Register, state: &([Known; 11], u16)) -> std::ops::Range<usize> {
+    let (known, mask) = state;
+    if mask & register_mask(&[reg]) == 0 {
+        return 0..0;
+    }
+
+    match known[reg.as_num() as usize] {
+        Known::Frame(offset) => {
+            let start = (FRAME_SIZE as i64 + offset).clamp(0, FRAME_SIZE as i64);
+            start as usize..FRAME_SIZE
+        }
+        _ => 0..FRAME_SIZE,
+    }
+}
+
+/// Returns the bytes of the stack frame accessed at `offset` from R10, if they're
+/// within the frame.
+fn frame_range(offset: i16, size: MemoryOpSize) -> Option<std::ops::Range<usize>> {
+    let start = usize::try_from(FRAME_SIZE as isize + offset as isize).ok()?;
+    let end = start + size_bytes(size) as usize;
+    (end <= FRAME_SIZE).then_some(start..end)
+}
+
+/// Returns the bytes of the stack frame a helper call always writes, without
+/// reading them, e.g. the destination of `probe_read_kernel`.
+fn call_writes(helper: u32, state: &([Known; 11], u16)) -> Option<std::ops::Range<usize>> {
+    let writes_all = matches!(
+        Helpers::from_id(helper),
+        Some(
+            Helpers::ProbeRead
+                | Helpers::ProbeReadKernel
+                | Helpers::ProbeReadUser
+                | Helpers::GetCurrentComm
+        )
+    );
+
+    let (known, _) = state;
+    match (known[1], known[2]) {
+        (Known::Frame(offset), Known::Constant(size)) if writes_all => {
+            let start = usize::try_from(FRAME_SIZE as i64 + offset).ok()?;
+            let end = start.checked_add(usize::try_from(size).ok()?)?;
+            (end <= FRAME_SIZE).then_some(start..end)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the bytes of the stack frame a call might access, through any
+/// of its arguments.
+fn call_accesses(state: &([Known; 11], u16)) -> Vec<std::ops::Range<usize>> {
+    [
+        Register::R1,
+        Register::R2,
+        Register::R3,
+        Register::R4,
+        Register::R5,
+    ]
+    .into_iter()
+    .map(|reg| pointer_range(reg, state))
+    .collect()
+}
+
+/// Replaces loads from the stack with the constant that was last stored there
+/// within the same block, and removes loads into a register that already holds
+/// the value at that slot, e.g. when a variable is read twice in a row.
+///
+/// # Arguments
+///
+/// * `function` - The function to optimize.
+pub(crate) fn eliminate_redundant_loads(function: &mut Function) -> bool {
+    let pointers = FramePointers::analyze(function);
+    let mut changed = false;
+    for b in 0..function.blocks.len() {
+        let states = pointers.states(function, b);
+        let block = &mut function.blocks[b];
+
+        let mut facts: Vec<StackFact> = vec![];
+        let mut insts = Vec::with_capacity(block.insts.len());
+        for (mut inst, state) in block.insts.drain(..).zip(states) {
+            /*
+             * Forgets what's known about the bytes of the frame in a range.
+             */
+            let forget = |facts: &mut Vec<StackFact>, range: std::ops::Range<usize>| {
+                facts.retain(|(offset, size, _)| {
+                    frame_range(*offset, *size)
+                        .is_some_and(|r| r.end <= range.start || r.start >= range.end)
+                });
+            };
+
+            match inst.op {
+                Op::Load {
+                    size,
+                    dst,
+                    base: Register::R10,
+                    offset,
+                } => {
+                    let fact = facts
+                        .iter()
+                        .find(|(o, s, _)| *o == offset && *s == size)
+                        .map(|(_, _, value)| *value);
+
+                    /*
+                     * Values that are still in a register are left for the register
+                     * allocator, which keeps the whole variable in one.
+                     */
+                    match fact {
+                        Some(Value::Register(reg)) if reg == dst => {
+                            changed = true;
+                            continue;
+                        }
+                        Some(Value::Immediate(value)) => {
+                            if let Some(op) = move_constant(dst, value as u64) {
+                                inst.op = op;
+                                changed = true;
+                            }
+                        }
+                        _ => {}
+                    }
+
+                    facts.retain(|(_, _, value)| *value != Value::Register(dst));
+                    if matches!(inst.op, Op::Load { .. }) {
+                        facts.retain(|(o, s, _)| *o != offset || *s != size);
+                        facts.push((offset, size, dst.into()));
+                    }
+                    insts.push(inst);
+                    continue;
+                }
+                Op::Store {
+                    size,
+                    base: Register::R10,
+                    offset,
+                    src,
+                } => {
+                    match frame_range(offset, size) {
+                        Some(range) => forget(&mut facts, range),
+                        None => facts.clear(),
+                    }
+
+                    /*
+                     * A register stored in fewer than 8 bytes is truncated, so loading
+                     * it back doesn't necessarily give the same value.
+                     */
+                    let mask = match size {
+                        MemoryOpSize::DoubleWord => u64::MAX,
+                        size => (1 << (8 * size_bytes(size))) - 1,
+                    };
+                    match src {
+                        Value::Immediate(imm) => {
+                            let value = (imm as u64 & mask) as i64;
+                            facts.push((offset, size, Value::Immediate(value)));
+                        }
+                        Value::Register(_) if size == MemoryOpSize::DoubleWord => {
+                            facts.push((offset, size, src));
+                        }
+                        Value::Register(_) => {}
+                    }
+                }
+                Op::Store { base, .. } | Op::Atomic { base, .. } => {
+                    forget(&mut facts, pointer_range(base, &state));
+                }
+                Op::Call { .. } | Op::CallLocal { .. } => {
+                    for range in call_accesses(&state) {
+                        forget(&mut facts, range);
+                    }
+                }
+                _ => {}
+            }
+
+            for reg in inst.op.defs() {
+                facts.retain(|(_, _, value)| *value != Value::Register(reg));
+            }
+            insts.push(inst);
+        }
+        block.insts = insts;
+    }
+
+    changed
+}
+
+/// Updates the bytes of the stack frame that might be read later, walking back
+/// over an instruction. Returns whether the instruction is a store none of whose
+/// bytes are read later.
+fn stack_liveness(op: &Op, state: &([Known; 11], u16), live: &mut [bool; FRAME_SIZE]) -> bool {
+    match *op {
+        Op::Store {
+            size,
+            base: Register::R10,
+            offset,
+            ..
+        } => {
+            if let Some(range) = frame_range(offset, size) {
+                if live[range.clone()].iter().all(|live| !live) {
+                    return true;
+                }
+                live[range].fill(false);
+            }
+        }
+        Op::Load {
+            size,
+            base: Register::R10,
+            offset,
+            ..
+        } => match frame_range(offset, size) {
+            Some(range) => live[range].fill(true),
+            None => live.fill(true),
+        },
+        Op::Load { base, .. } | Op::Atomic { base, .. } => {
+            live[pointer_range(base, state)].fill(true);
+        }
+
+        /*
+         * The destination of a helper that fills a buffer is written after the
+         * helper has read everything else.
+         */
+        Op::Call { helper } => {
+            let writes = call_writes(helper, state);
+            if let Some(range) = writes.clone() {
+                live[range].fill(false);
+            }
+
+            for (i, range) in call_accesses(state).into_iter().enumerate() {
+                if i != 0 || writes.is_none() {
+                    live[range].fill(true);
+                }
+            }
+        }
+        Op::CallLocal { .. } => {
+            for range in call_accesses(state) {
+                live[range].fill(true);
+            }
+        }
+        _ => {}
+    }
+
+    false
+}
+
+/// Removes stores to the stack that are overwritten before being read, e.g.
+/// zeroing a variable before assigning all of its fields, or that are never read
+/// at all before the program exits.
+///
+/// # Arguments
+///
+/// * `function` - The function to optimize.
+pub(crate) fn eliminate_dead_stores(function: &mut Function) -> bool {
+    let pointers = FramePointers::analyze(function);
+    let num_blocks = function.blocks.len();
+    let states: Vec<_> = (0..num_blocks)
+        .map(|b| pointers.states(function, b))
+        .collect();
+
+    /*
+     * Compute the bytes of the frame that might be read after every block,
+     * iterating until loops stop adding any.
+     */
+    let mut live_in = vec![[false; FRAME_SIZE]; num_blocks];
+    let live_out = |live_in: &[[bool; FRAME_SIZE]], block: usize| {
+        let mut live = [false; FRAME_SIZE];
+        for successor in function.successors(BlockId(block)) {
+            if let Some(successor) = live_in.get(successor.0) {
+                for (live, successor) in live.iter_mut().zip(successor) {
+                    *live |= successor;
+                }
+            }
+        }
+        live
+    };
+    loop {
+        let mut updated = false;
+        for b in (0..num_blocks).rev() {
+            let mut live = live_out(&live_in, b);
+            for (inst, state) in function.blocks[b].insts.iter().zip(&states[b]).rev() {
+                stack_liveness(&inst.op, state, &mut live);
+            }
+
+            if live != live_in[b] {
+                live_in[b] = live;
+                updated = true;
+            }
+        }
+
+        if !updated {
+            break;
+        }
+    }
+
+    let live_out: Vec<_> = (0..num_blocks).map(|b| live_out(&live_in, b)).collect();
+    let mut changed = false;
+    for ((block, states), mut live) in function.blocks.iter_mut().zip(states).zip(live_out) {
+        let mut insts = Vec::with_capacity(block.insts.len());
+        for (inst, state) in block.insts.drain(..).zip(states).rev() {
+            if stack_liveness(&inst.op, &state, &mut live) {
+                changed = true;
+                continue;
+            }
+            insts.push(inst);
+        }
+        insts.reverse();
+        block.insts = insts;
+    }
+
+    changed
+}
+
+/// Renumbers the blocks jumped to after blocks were removed or merged, given the
+/// new index of every block and of the end of the function.
+fn renumber(function: &mut Function, new_id: &[usize]) {
+    for block in &mut function.blocks {
+        if let Some(inst) = block.insts.last_mut() {
+            match &mut inst.op {
+                Op::Jump { target } => *target = BlockId(new_id[target.0]),
+                Op::Branch {
+                    then, otherwise, ..
+                } => {
+                    *then = BlockId(new_id[then.0]);
+                    *otherwise = BlockId(new_id[otherwise.0]);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Removes blocks that can't be reached, like code following a return, merges
+/// blocks that can only be entered from the one before them into it, and removes
+/// instructions that set registers which are never read afterwards.
+///
+/// # Arguments
+///
+/// * `function` - The function to optimize.
+pub(crate) fn eliminate_dead_code(function: &mut Function) -> bool {
+    let mut changed = false;
+
+    /*
+     * Jumps to a block that does nothing but continue somewhere else go there
+     * directly, and a branch whose targets are the same is a jump.
+     */
+    let num_blocks = function.blocks.len();
+    let forward = |mut id: BlockId| {
+        for _ in 0..num_blocks {
+            match function
+                .blocks
+                .get(id.0)
+                .map(|block| block.insts.as_slice())
+            {
+                Some([]) => id = BlockId(id.0 + 1),
+                Some([inst]) => match inst.op {
+                    Op::Jump { target } => id = target,
+                    _ => break,
+                },
+                _ => break,
+            }
+        }
+        id
+    };
+    let forwarded: Vec<BlockId> = (0..=num_blocks).map(|b| forward(BlockId(b))).collect();
+    for block in &mut function.blocks {
+        let Some(inst) = block.insts.last_mut() else {
+            continue;
+        };
+
+        let op = match inst.op {
+            Op::Jump { target } => Op::Jump {
+                target: forwarded[target.0],
+            },
+            Op::Branch {
+                then, otherwise, ..
+            } if forwarded[then.0] == forwarded[otherwise.0] => Op::Jump {
+                target: forwarded[then.0],
+            },
+            Op::Branch {
+                op,
+                lhs,
+                rhs,
+                then,
+                otherwise,
+            } => Op::Branch {
+                op,
+                lhs,
+                rhs,
+                then: forwarded[then.0],
+                otherwise: forwarded[otherwise.0],
+            },
+            _ => continue,
+        };
+
+        if op != inst.op {
+            inst.op = op;
+            changed = true;
+        }
+    }
+
+    /*
+     * Find the blocks reachable from the entry. Jumps past the last block stay
+     * past it.
+     */
+    let num_blocks = function.blocks.len();
+    let mut reachable = vec![false; num_blocks + 1];
+    let mut pending = vec![BlockId(0)];
+    while let Some(block) = pending.pop() {
+        if block.0 >= num_blocks || reachable[block.0] {
+            continue;
+        }
+        reachable[block.0] = true;
+        pending.extend(function.successors(block));
+    }
+    reachable[num_blocks] = true;
+
+    if reachable.iter().any(|r| !r) {
+        let mut new_id = vec![0; num_blocks + 1];
+        let mut next = 0;
+        for (id, reachable) in new_id.iter_mut().zip(&reachable) {
+            *id = next;
+            next += usize::from(*reachable);
+        }
+
+        let blocks = std::mem::take(&mut function.blocks);
+        function.blocks = blocks
+            .into_iter()
+            .zip(&reachable)
+            .filter_map(|(block, reachable)| reachable.then_some(block))
+            .collect();
+        renumber(function, &new_id);
+        changed = true;
+    }
+
+    /*
+     * A block that's only entered from the one before it, which doesn't branch,
+     * can be appended to it.
+     */
+    let num_blocks = function.blocks.len();
+    let predecessors = function.predecessors();
+    let mergeable: Vec<bool> = (0..num_blocks)
+        .map(|b| {
+            b > 0
+                && predecessors[b] == [BlockId(b - 1)]
+                && function.successors(BlockId(b - 1)) == [BlockId(b)]
+        })
+        .collect();
+
+    if mergeable.contains(&true) {
+        let mut new_id = vec![0; num_blocks + 1];
+        let blocks = std::mem::take(&mut function.blocks);
+        for (b, block) in blocks.into_iter().enumerate() {
+            match function.blocks.last_mut() {
+                Some(last) if mergeable[b] => {
+                    if matches!(last.terminator(), Some(Op::Jump { .. })) {
+                        last.insts.pop();
+                    }
+                    last.insts.extend(block.insts);
+                }
+                _ => function.blocks.push(block),
+            }
+            new_id[b] = function.blocks.len() - 1;
+        }
+        new_id[num_blocks] = function.blocks.len();
+        renumber(function, &new_id);
+        changed = true;
+    }
+
+    /*
+     * Compute the registers live at the start of every block, iterating until
+     * loops stop adding any.
+     */
+    let num_blocks = function.blocks.len();
+    let mut live_in = vec![0u16; num_blocks];
+    let live_out = |live_in: &[u16], block: usize| {
+        function
+            .successors(BlockId(block))
+            .iter()
+            .fold(0, |mask, id| mask | live_in.get(id.0).copied().unwrap_or(0))
+    };
+    loop {
+        let mut updated = false;
+        for b in (0..num_blocks).rev() {
+            let mut live = live_out(&live_in, b);
+            for inst in function.blocks[b].insts.iter().rev() {
+                live = live & !register_mask(&inst.op.defs()) | register_mask(&inst.op.uses());
+            }
+
+            if live != live_in[b] {
+                live_in[b] = live;
+                updated = true;
+            }
+        }
+
+        if !updated {
+            break;
+        }
+    }
+
+    let live_out: Vec<u16> = (0..num_blocks).map(|b| live_out(&live_in, b)).collect();
+    for (block, mut live) in function.blocks.iter_mut().zip(live_out) {
+        let mut insts = Vec::with_capacity(block.insts.len());
+        for inst in block.insts.drain(..).rev() {
+            let defs = register_mask(&inst.op.defs());
+            let is_dead = defs & live == 0 && !inst.op.has_side_effects();
+            if is_dead && inst.relocation.is_none() {
+                changed = true;
+                continue;
+            }
+
+            live = live & !defs | register_mask(&inst.op.uses());
+            insts.push(inst);
+        }
+        insts.reverse();
+        block.insts = insts;
+    }
+
+    changed
+}
@@ -0,0 +1,751 @@
+use crate::elf::StringTable;
+use crate::relocation::CoreRelocation;
+
+use btf::types::{Integer, QualifiedType, Struct, StructMember, Type};
+use btf::BtfTypes;
+
+use std::collections::HashMap;
+
+/// Magic number shared by BTF and BTF.ext headers.
+const BTF_MAGIC: u16 = 0xeb9f;
+
+/// Size of the BTF and BTF.ext headers.
+const BTF_HEADER_SIZE: u32 = 24;
+const BTF_EXT_HEADER_SIZE: u32 = 32;
+
+/// BTF type kinds.
+const KIND_INT: u32 = 1;
+const KIND_PTR: u32 = 2;
+const KIND_ARRAY: u32 = 3;
+const KIND_STRUCT: u32 = 4;
+const KIND_UNION: u32 = 5;
+const KIND_ENUM: u32 = 6;
+const KIND_FWD: u32 = 7;
+const KIND_TYPEDEF: u32 = 8;
+const KIND_VOLATILE: u32 = 9;
+const KIND_CONST: u32 = 10;
+const KIND_RESTRICT: u32 = 11;
+const KIND_FUNC: u32 = 12;
+const KIND_FUNC_PROTO: u32 = 13;
+const KIND_VAR: u32 = 14;
+const KIND_DATASEC: u32 = 15;
+const KIND_FLOAT: u32 = 16;
+const KIND_ENUM64: u32 = 19;
+
+/// Integer encoding flags.
+const INT_SIGNED: u32 = 1 << 0;
+const INT_CHAR: u32 = 1 << 1;
+const INT_BOOL: u32 = 1 << 2;
+
+/// Linkage of functions and variables.
+const LINKAGE_STATIC: u32 = 0;
+const LINKAGE_GLOBAL: u32 = 1;
+
+/// The section functions called by the program are placed in.
+pub const TEXT_SECTION: &str = ".text";
+
+/// File name reported for script lines in line info.
+const SCRIPT_FILE_NAME: &str = "<script>";
+
+/// Size of a BTF-defined map definition: four pointers, for the type, key, value
+/// and max entries.
+pub const MAP_DEF_SIZE: u32 = 32;
+
+/// Maps an instruction to the function it starts, in the format the kernel expects
+/// (`struct bpf_func_info`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FuncInfo {
+    /// Offset of the first instruction of the function, in instructions.
+    pub insn_off: u32,
+    /// The BTF id of the function.
+    pub type_id: u32,
+}
+
+/// Maps an instruction to the script line it was compiled from, in the format the
+/// kernel expects (`struct bpf_line_info`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineInfo {
+    /// Offset of the instruction, in instructions.
+    pub insn_off: u32,
+    /// Offset of the file name in the BTF string table.
+    pub file_name_off: u32,
+    /// Offset of the text of the line in the BTF string table.
+    pub line_off: u32,
+    /// The line number in the upper 22 bits and the column in the lower 10.
+    pub line_col: u32,
+}
+
+/// Describes an instruction that depends on the layout of a type, in the format the
+/// kernel expects (`struct bpf_core_relo`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoreRelo {
+    /// Offset of the instruction, in instructions.
+    pub insn_off: u32,
+    /// The BTF id of the root type.
+    pub type_id: u32,
+    /// Offset of the access string in the BTF string table.
+    pub access_str_off: u32,
+    /// The kind of the relocation, see `RelocationKind`.
+    pub kind: u32,
+}
+
+impl LineInfo {
+    /// Returns the line number, starting at 1.
+    pub fn line(&self) -> u32 {
+        self.line_col >> 10
+    }
+
+    /// Returns the column number, starting at 1.
+    pub fn column(&self) -> u32 {
+        self.line_col & 0x3ff
+    }
+}
+
+/// BTF describing a compiled program: the prototype of its function, the types of
+/// its variables and maps, along with function and line information that maps its
+/// instructions back to the script. Created by `Compiler::get_btf`.
+#[derive(Clone, Debug)]
+pub struct ProgramBtf {
+    section: String,
+    section_name_off: u32,
+    text: Option<(u32, u32)>,
+    btf: Vec<u8>,
+    func_info: Vec<FuncInfo>,
+    line_info: Vec<LineInfo>,
+    core_relos: Vec<CoreRelo>,
+}
+
+impl ProgramBtf {
+    /// Returns the raw BTF data, as loaded with `BPF_BTF_LOAD` or stored in the
+    /// `.BTF` section of an object file.
+    pub fn btf(&self) -> &[u8] {
+        &self.btf
+    }
+
+    /// Returns the function information to pass to `BPF_PROG_LOAD` along with the
+    /// BTF.
+    pub fn func_info(&self) -> &[FuncInfo] {
+        &self.func_info
+    }
+
+    /// Returns the line information to pass to `BPF_PROG_LOAD` along with the BTF.
+    pub fn line_info(&self) -> &[LineInfo] {
+        &self.line_info
+    }
+
+    /// Returns the CO-RE relocations, which loaders apply before loading the program
+    /// (or pass to `BPF_PROG_LOAD` on kernels that apply them).
+    pub fn core_relos(&self) -> &[CoreRelo] {
+        &self.core_relos
+    }
+
+    /// Returns the function and line information, and CO-RE relocations, in the
+    /// format of the `.BTF.ext` section of an object file, which refers to
+    /// instructions by byte offset within the program's section, or within `.text`
+    /// for the functions the program calls.
+    pub fn btf_ext(&self) -> Vec<u8> {
+        /*
+         * Records are grouped by section, each group starts with the section's name
+         * and the number of records.
+         */
+        let sections = |record_size: u32, records: Vec<(u32, Vec<u8>)>| {
+            let mut groups = vec![(self.section_name_off, 0u32, vec![])];
+            if let Some((_, name_off)) = self.text {
+                groups.push((name_off, 0, vec![]));
+            }
+
+            for (insn_off, record) in records {
+                let (group, start) = match self.text {
+                    Some((start, _)) if insn_off >= start => (1, start),
+                    _ => (0, 0),
+                };
+                groups[group].1 += 1;
+                put!(groups[group].2, (insn_off - start) * 8);
+                groups[group].2.extend_from_slice(&record);
+            }
+
+            let mut data = vec![];
+            put!(data, record_size);
+            for (i, (name_off, count, records)) in groups.into_iter().enumerate() {
+                if i > 0 && count == 0 {
+                    continue;
+                }
+                put!(data, name_off);
+                put!(data, count);
+                data.extend_from_slice(&records);
+            }
+            data
+        };
+
+        let func_info = self
+            .func_info
+            .iter()
+            .map(|info| (info.insn_off, info.type_id.to_ne_bytes().to_vec()))
+            .collect();
+        let func_info = sections(8, func_info);
+
+        let line_info = self
+            .line_info
+            .iter()
+            .map(|info| {
+                let mut record = vec![];
+                put!(record, info.file_name_off);
+                put!(record, info.line_off);
+                put!(record, info.line_col);
+                (info.insn_off, record)
+            })
+            .collect();
+        let line_info = sections(16, line_info);
+
+        let mut core_relos = vec![];
+        if !self.core_relos.is_empty() {
+            let records = self
+                .core_relos
+                .iter()
+                .map(|relo| {
+                    let mut record = vec![];
+                    put!(record, relo.type_id);
+                    put!(record, relo.access_str_off);
+                    put!(record, relo.kind);
+                    (relo.insn_off, record)
+                })
+                .collect();
+            core_relos = sections(16, records);
+        }
+
+        let mut ext = vec![];
+        put!(ext, BTF_MAGIC);
+        ext.push(1); /* version */
+        ext.push(0); /* flags */
+        put!(ext, BTF_EXT_HEADER_SIZE);
+        put!(ext, 0u32);
+        put!(ext, func_info.len() as u32);
+        put!(ext, func_info.len() as u32);
+        put!(ext, line_info.len() as u32);
+        put!(ext, (func_info.len() + line_info.len()) as u32);
+        put!(ext, core_relos.len() as u32);
+        ext.extend_from_slice(&func_info);
+        ext.extend_from_slice(&line_info);
+        ext.extend_from_slice(&core_relos);
+        ext
+    }
+
+    /// Returns the name of the section the function and line information refer to.
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    /// Returns the offset, in instructions, of the first function the program
+    /// calls, which object files place in `.text`. Everything before it is the
+    /// program itself.
+    pub fn text_start(&self) -> Option<u32> {
+        self.text.map(|(start, _)| start)
+    }
+}
+
+/// Returns the BTF encoding flags of an integer.
+fn int_encoding(int: &Integer) -> u32 {
+    let mut encoding = 0;
+    if int.is_signed {
+        encoding |= INT_SIGNED;
+    }
+    if int.is_char {
+        encoding |= INT_CHAR;
+    }
+    if int.is_bool {
+        encoding |= INT_BOOL;
+    }
+    encoding
+}
+
+/// A map to describe in the `.maps` data section.
+pub struct MapBtf<'a> {
+    pub name: &'a str,
+    pub map_type: u32,
+    pub key_type: &'a QualifiedType,
+    pub value_type: &'a QualifiedType,
+    pub max_entries: u32,
+}
+
+/// Returns the members of a struct or union in declaration order. The type library
+/// stores members by name, so the order is restored from their offsets, which is
+/// also the order the members are written in by `BtfWriter`.
+///
+/// # Arguments
+///
+/// * `s` - The struct or union.
+pub fn sorted_members(s: &Struct) -> Vec<&StructMember> {
+    let mut members: Vec<_> = s.members.values().collect();
+    members.sort_by_key(|member| (member.offset, member.name.clone()));
+    members
+}
+
+/// Builds program BTF, copying the types it uses from a BTF type library (usually
+/// the kernel's). Structs and unions that are only reached through pointers are
+/// written as forward declarations so that e.g. a `task_struct` argument doesn't
+/// pull in most of the kernel's types.
+pub struct BtfWriter<'a> {
+    source: &'a BtfTypes,
+    strings: StringTable,
+    types: Vec<Vec<u8>>,
+    copied: HashMap<(u32, bool), u32>,
+    integers: HashMap<(String, u32, u32), u32>,
+    arrays: HashMap<(u32, u32), u32>,
+}
+
+impl<'a> BtfWriter<'a> {
+    /// Create a new writer.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The type library types are copied from.
+    pub fn new(source: &'a BtfTypes) -> Self {
+        Self {
+            source,
+            strings: StringTable::new(),
+            types: vec![],
+            copied: HashMap::new(),
+            integers: HashMap::new(),
+            arrays: HashMap::new(),
+        }
+    }
+
+    /// Reserves an id for a type, its data is filled in by `set_type` once the
+    /// types it refers to have been added.
+    fn reserve(&mut self) -> u32 {
+        self.types.push(vec![]);
+        self.types.len() as u32
+    }
+
+    fn set_type(&mut self, id: u32, data: Vec<u8>) {
+        self.types[id as usize - 1] = data;
+    }
+
+    fn push_type(&mut self, data: Vec<u8>) -> u32 {
+        self.types.push(data);
+        self.types.len() as u32
+    }
+
+    /// Encodes the common part of a type: `struct btf_type`.
+    fn header(
+        name_off: u32,
+        kind: u32,
+        vlen: usize,
+        kind_flag: bool,
+        size_or_type: u32,
+    ) -> Vec<u8> {
+        let info = (u32::from(kind_flag) << 31) | (kind << 24) | (vlen as u32 & 0xffff);
+        let mut data = vec![];
+        put!(data, name_off);
+        put!(data, info);
+        put!(data, size_or_type);
+        data
+    }
+
+    /// Adds an integer type, reusing an identical one if it was added before.
+    /// Unnamed integers, which are made up by the compiler, are named after their
+    /// size and signedness.
+    fn add_integer(&mut self, name: &str, size: u32, encoding: u32) -> u32 {
+        let name = match (name, encoding & INT_SIGNED != 0) {
+            ("", true) => format!("s{}", size * 8),
+            ("", false) => format!("u{}", size * 8),
+            (name, _) => name.to_string(),
+        };
+
+        let key = (name, size, encoding);
+        if let Some(id) = self.integers.get(&key) {
+            return *id;
+        }
+
+        let name_off = self.strings.add(&key.0);
+        let mut data = Self::header(name_off, KIND_INT, 0, false, size);
+        put!(data, (encoding << 24) | (size * 8));
+        let id = self.push_type(data);
+        self.integers.insert(key, id);
+        id
+    }
+
+    /// Adds an array of `element` with the given number of elements.
+    fn add_array(&mut self, element: u32, num_elements: u32) -> u32 {
+        if let Some(id) = self.arrays.get(&(element, num_elements)) {
+            return *id;
+        }
+
+        let index_type = self.add_integer("__ARRAY_SIZE_TYPE__", 4, 0);
+        let mut data = Self::header(0, KIND_ARRAY, 0, false, 0);
+        put!(data, element);
+        put!(data, index_type);
+        put!(data, num_elements);
+        let id = self.push_type(data);
+        self.arrays.insert((element, num_elements), id);
+        id
+    }
+
+    fn add_pointer(&mut self, target: u32) -> u32 {
+        self.push_type(Self::header(0, KIND_PTR, 0, false, target))
+    }
+
+    /// Copies a type from the type library and returns its new id.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of the type in the type library.
+    /// * `behind_pointer` - Whether the type is only reached through a pointer, in
+    ///   which case named structs and unions are written as forward declarations.
+    fn copy_type(&mut self, id: u32, behind_pointer: bool) -> u32 {
+        let source = self.source;
+        let source_type = match source.get_type_by_id(id) {
+            Some(source_type) => source_type,
+            None => return 0,
+        };
+
+        let is_fwd = behind_pointer
+            && matches!(source_type, Type::Struct(s) | Type::Union(s) if !s.name.is_empty());
+        if let Some(new_id) = self.copied.get(&(id, is_fwd)) {
+            return *new_id;
+        }
+
+        /*
+         * Type tags don't record the type they apply to and the other kinds can't
+         * be referred to by variables, so they're all treated as `void`.
+         */
+        if matches!(
+            source_type,
+            Type::Void
+                | Type::TypeTag(_)
+                | Type::DeclTag(_)
+                | Type::Variable(_)
+                | Type::DataSection(_)
+        ) {
+            return 0;
+        }
+
+        if let Type::Integer(int) = source_type {
+            let new_id = self.add_integer(&int.name, int.size, int_encoding(int));
+            self.copied.insert((id, false), new_id);
+            return new_id;
+        }
+
+        let new_id = self.reserve();
+        self.copied.insert((id, is_fwd), new_id);
+
+        let data = match source_type {
+            Type::Struct(s) | Type::Union(s) if is_fwd => {
+                let name_off = self.strings.add(&s.name);
+                let is_union = matches!(source_type, Type::Union(_));
+                Self::header(name_off, KIND_FWD, 0, is_union, 0)
+            }
+            Type::Struct(s) | Type::Union(s) => {
+                let kind = match source_type {
+                    Type::Union(_) => KIND_UNION,
+                    _ => KIND_STRUCT,
+                };
+
+                let members = sorted_members(s);
+                let has_bitfields = members.iter().any(|member| member.bitfield_size != 0);
+
+                let name_off = self.strings.add(&s.name);
+                let mut data = Self::header(name_off, kind, members.len(), has_bitfields, s.size);
+                for member in members {
+                    let member_type = self.copy_type(member.type_id, false);
+                    put!(data, self.strings.add(&member.name));
+                    put!(data, member_type);
+                    put!(data, member.bitfield_size << 24 | member.offset);
+                }
+                data
+            }
+            Type::Pointer(ptr) => {
+                let target = self.copy_type(ptr.type_id, true);
+                Self::header(0, KIND_PTR, 0, false, target)
+            }
+            Type::Array(array) => {
+                let element = self.copy_type(array.element_type, false);
+                let index_type = self.add_integer("__ARRAY_SIZE_TYPE__", 4, 0);
+                let mut data = Self::header(0, KIND_ARRAY, 0, false, 0);
+                put!(data, element);
+                put!(data, index_type);
+                put!(data, array.num_elements);
+                data
+            }
+            Type::Enum32(e) | Type::Enum64(e) => {
+                let is_64 = matches!(source_type, Type::Enum64(_));
+                let mut entries: Vec<_> = e.entries.values().collect();
+                entries.sort_by_key(|entry| (entry.value, entry.name.clone()));
+
+                let name_off = self.strings.add(&e.name);
+                let (kind, size) = if is_64 {
+                    (KIND_ENUM64, 8)
+                } else {
+                    (KIND_ENUM, e.size)
+                };
+                let mut data = Self::header(name_off, kind, entries.len(), false, size);
+                for entry in entries {
+                    put!(data, self.strings.add(&entry.name));
+                    put!(data, entry.value as u32);
+                    if is_64 {
+                        put!(data, (entry.value >> 32) as u32);
+                    }
+                }
+                data
+            }
+            Type::Fwd(fwd) => {
+                let name_off = self.strings.add(&fwd.name);
+                Self::header(name_off, KIND_FWD, 0, fwd.is_union, 0)
+            }
+            Type::Typedef(def) => {
+                let target = self.copy_type(def.type_id, behind_pointer);
+                let name_off = self.strings.add(&def.name);
+                Self::header(name_off, KIND_TYPEDEF, 0, false, target)
+            }
+            Type::Volatile(map) | Type::Const(map) | Type::Restrict(map) => {
+                let kind = match source_type {
+                    Type::Volatile(_) => KIND_VOLATILE,
+                    Type::Const(_) => KIND_CONST,
+                    _ => KIND_RESTRICT,
+                };
+                let target = self.copy_type(map.type_id, behind_pointer);
+                Self::header(0, kind, 0, false, target)
+            }
+            Type::Function(func) => {
+                let proto = self.copy_type(func.type_id, true);
+                let name_off = self.strings.add(&func.name);
+                Self::header(name_off, KIND_FUNC, 0, false, proto)
+            }
+            Type::FunctionProto(proto) => {
+                /*
+                 * The type library doesn't keep the return type of prototypes, these
+                 * are only reached through function pointers so `void` is used.
+                 */
+                let mut params = vec![];
+                for param in &proto.params {
+                    params.push((
+                        self.strings.add(&param.name),
+                        self.copy_type(param.type_id, true),
+                    ));
+                }
+
+                let mut data = Self::header(0, KIND_FUNC_PROTO, params.len(), false, 0);
+                for (name_off, param_type) in params {
+                    put!(data, name_off);
+                    put!(data, param_type);
+                }
+                data
+            }
+            Type::Float(float) => {
+                let name_off = self.strings.add(&float.name);
+                Self::header(name_off, KIND_FLOAT, 0, false, float.size)
+            }
+            _ => unreachable!("handled above"),
+        };
+
+        self.set_type(new_id, data);
+        new_id
+    }
+
+    /// Adds a type as used by the compiler and returns its id.
+    ///
+    /// # Arguments
+    ///
+    /// * `qualified_type` - The type to add.
+    pub fn add_type(&mut self, qualified_type: &QualifiedType) -> u32 {
+        let is_pointer = qualified_type.is_pointer();
+        let base_type = &qualified_type.base_type;
+        let mut id = match base_type {
+            /*
+             * Types made up by the compiler (e.g. for literals and captures) don't
+             * exist in the type library.
+             */
+            Type::Integer(int) if int.id == 0 => {
+                self.add_integer(&int.name, int.size, int_encoding(int))
+            }
+            Type::Array(array) if array.id == 0 => {
+                let element = self.copy_type(array.element_type, false);
+                self.add_array(element, array.num_elements)
+            }
+            _ => match base_type.get_id() {
+                Some(id) => self.copy_type(id, is_pointer),
+                None => 0,
+            },
+        };
+
+        for _ in 0..qualified_type.num_refs {
+            id = self.add_pointer(id);
+        }
+
+        id
+    }
+
+    /// Adds a function and its prototype, and returns the id of the function.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the function.
+    /// * `params` - The names and types of the function's parameters.
+    /// * `return_type` - The type the function returns, `int` if it's `None`.
+    /// * `global` - Whether the function is the program, rather than a static
+    ///   function it calls.
+    pub fn add_function(
+        &mut self,
+        name: &str,
+        params: &[(String, QualifiedType)],
+        return_type: Option<&QualifiedType>,
+        global: bool,
+    ) -> u32 {
+        let ret = match return_type {
+            Some(return_type) => self.add_type(return_type),
+            None => self.add_integer("int", 4, INT_SIGNED),
+        };
+        let mut encoded = vec![];
+        for (name, param_type) in params {
+            encoded.push((self.strings.add(name), self.add_type(param_type)));
+        }
+
+        let mut data = Self::header(0, KIND_FUNC_PROTO, encoded.len(), false, ret);
+        for (name_off, param_type) in encoded {
+            put!(data, name_off);
+            put!(data, param_type);
+        }
+        let proto = self.push_type(data);
+
+        let name_off = self.strings.add(name);
+        let linkage = match global {
+            true => LINKAGE_GLOBAL,
+            false => LINKAGE_STATIC,
+        };
+        self.push_type(Self::header(
+            name_off,
+            KIND_FUNC,
+            linkage as usize,
+            false,
+            proto,
+        ))
+    }
+
+    /// Adds BTF-defined map definitions, as found in the `.maps` section of object
+    /// files: a variable per map whose type is a struct of pointers describing the
+    /// map, and a data section holding the variables.
+    ///
+    /// # Arguments
+    ///
+    /// * `maps` - The maps to describe, in the order they're laid out in the section.
+    pub fn add_maps(&mut self, maps: &[MapBtf]) {
+        let int = self.add_integer("int", 4, INT_SIGNED);
+        let mut vars = vec![];
+        for map in maps {
+            let map_type = self.add_array(int, map.map_type);
+            let map_type = self.add_pointer(map_type);
+            let key_type = self.add_type(map.key_type);
+            let key_type = self.add_pointer(key_type);
+            let value_type = self.add_type(map.value_type);
+            let value_type = self.add_pointer(value_type);
+            let max_entries = self.add_array(int, map.max_entries);
+            let max_entries = self.add_pointer(max_entries);
+
+            let mut data = Self::header(0, KIND_STRUCT, 4, false, MAP_DEF_SIZE);
+            let members = [
+                ("type", map_type),
+                ("key", key_type),
+                ("value", value_type),
+                ("max_entries", max_entries),
+            ];
+            for (i, (name, member_type)) in members.iter().enumerate() {
+                put!(data, self.strings.add(name));
+                put!(data, *member_type);
+                put!(data, i as u32 * 64);
+            }
+            let def = self.push_type(data);
+
+            let name_off = self.strings.add(map.name);
+            let mut data = Self::header(name_off, KIND_VAR, 0, false, def);
+            put!(data, LINKAGE_GLOBAL);
+            vars.push(self.push_type(data));
+        }
+
+        let name_off = self.strings.add(".maps");
+        let mut data = Self::header(
+            name_off,
+            KIND_DATASEC,
+            vars.len(),
+            false,
+            vars.len() as u32 * MAP_DEF_SIZE,
+        );
+        for (i, var) in vars.into_iter().enumerate() {
+            put!(data, var);
+            put!(data, i as u32 * MAP_DEF_SIZE);
+            put!(data, MAP_DEF_SIZE);
+        }
+        self.push_type(data);
+    }
+
+    /// Finishes the BTF and returns it along with function and line information,
+    /// and CO-RE relocations.
+    ///
+    /// # Arguments
+    ///
+    /// * `section` - The name of the section holding the program.
+    /// * `text_start` - The offset of the functions the program calls, if it calls
+    ///   any, which object files place in `.text`.
+    /// * `func_info` - The functions of the program.
+    /// * `lines` - The slot offset of each instruction that starts a line, and the
+    ///   line, column and text of that line.
+    /// * `relocations` - The slot offset of each relocated instruction, and its
+    ///   relocation.
+    pub fn finish(
+        mut self,
+        section: &str,
+        text_start: Option<u32>,
+        func_info: Vec<FuncInfo>,
+        lines: &[(u32, u32, u32, &str)],
+        relocations: &[(u32, &CoreRelocation)],
+    ) -> ProgramBtf {
+        let section_name_off = self.strings.add(section);
+        let text = text_start.map(|start| (start, self.strings.add(TEXT_SECTION)));
+        let file_name_off = self.strings.add(SCRIPT_FILE_NAME);
+        let line_info = lines
+            .iter()
+            .map(|&(insn_off, line, column, text)| LineInfo {
+                insn_off,
+                file_name_off,
+                line_off: self.strings.add(text),
+                line_col: line << 10 | column.min(0x3ff),
+            })
+            .collect();
+
+        let core_relos = relocations
+            .iter()
+            .map(|&(insn_off, relocation)| CoreRelo {
+                insn_off,
+                type_id: self.copy_type(relocation.type_id, false),
+                access_str_off: self.strings.add(&relocation.access_string()),
+                kind: relocation.kind as u32,
+            })
+            .collect();
+
+        let types: Vec<u8> = self.types.concat();
+        let strings = self.strings.into_bytes();
+        let mut btf = vec![];
+        put!(btf, BTF_MAGIC);
+        btf.push(1); /* version */
+        btf.push(0); /* flags */
+        put!(btf, BTF_HEADER_SIZE);
+        put!(btf, 0u32);
+        put!(btf, types.len() as u32);
+        put!(btf, types.len() as u32);
+        put!(btf, strings.len() as u32);
+        btf.extend_from_slice(&types);
+        btf.extend_from_slice(&strings);
+
+        ProgramBtf {
+            section: section.to_string(),
+            section_name_off,
+            text,
+            btf,
+            func_info,
+            line_info,
+            core_relos,
+        }
+    }
+}
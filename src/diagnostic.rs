@@ -0,0 +1,186 @@
+use crate::error::CompileError;
+
+use std::fmt;
+
+/// Returns the Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(cur).min(row[j])
+            };
+            prev = cur;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Returns the candidate closest to `name`, if any is close enough to plausibly
+/// be a typo of it.
+///
+/// # Arguments
+///
+/// * `name` - The unknown name.
+/// * `candidates` - The known names to pick from.
+pub(crate) fn closest_match<'a, I>(name: &str, candidates: I) -> Option<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut best: Option<(usize, &str)> = None;
+
+    for candidate in candidates {
+        /*
+         * Cheap length check first, the candidate list can contain every type in
+         * the kernel.
+         */
+        if candidate.is_empty() || candidate.len().abs_diff(name.len()) > max_distance {
+            continue;
+        }
+
+        let distance = edit_distance(name, candidate);
+        if distance <= max_distance && !matches!(best, Some((d, _)) if d <= distance) {
+            best = Some((distance, candidate));
+        }
+    }
+
+    best.map(|(_, candidate)| candidate.to_string())
+}
+
+/// Returns an explanation to print alongside errors that benefit from one.
+fn get_note(error: &CompileError) -> Option<&'static str> {
+    Some(match error {
+        CompileError::MissingTypeId { .. } => {
+            "the BTF data is inconsistent, make sure it matches the running kernel"
+        }
+        CompileError::StackOverflow { .. } => "BPF programs are limited to 512 bytes of stack",
+        CompileError::BitfieldUnsupported { .. } => {
+            "copy the containing struct and mask the bits out instead"
+        }
+        CompileError::TooManyArguments { .. } => "BPF passes arguments in registers R1 to R5",
+        CompileError::RetypedVariable { .. } => {
+            "a type can only be given when a variable is first assigned"
+        }
+        CompileError::NonConstantIndex { .. } => {
+            "only arrays on the stack can be indexed by a variable"
+        }
+        CompileError::TooManyIterations { .. } => {
+            "the limit can be raised with `Compiler::set_max_loop_iterations`"
+        }
+        CompileError::VerifierRejected { .. } => {
+            "the kernel's verifier would refuse to load the program"
+        }
+        _ => return None,
+    })
+}
+
+/// A compile error rendered together with the script it refers to: the offending
+/// line is printed with the exact token underlined, followed by any notes and
+/// suggestions. Created by `CompileError::diagnostic`.
+pub struct Diagnostic<'a> {
+    error: &'a CompileError,
+    source: &'a str,
+}
+
+impl<'a> Diagnostic<'a> {
+    /// Create a new diagnostic.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - The error to render.
+    /// * `source` - The script that was being compiled when the error occurred.
+    pub fn new(error: &'a CompileError, source: &'a str) -> Self {
+        Self { error, source }
+    }
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let span = self.error.span();
+        writeln!(f, "error: {}", self.error.message())?;
+
+        /*
+         * Errors that don't come from the script, e.g. from capturing a value,
+         * have no location and are printed without a snippet.
+         */
+        if span.line != 0 {
+            writeln!(f, " --> line {}, column {}", span.line, span.column)?;
+        }
+
+        let line = span.line.checked_sub(1);
+        if let Some(line) = line.and_then(|n| self.source.lines().nth(n)) {
+            /*
+             * Tabs in front of the token are kept so the underline stays aligned
+             * with the source line, the underline covers at least one character.
+             */
+            let gutter = " ".repeat(span.line.to_string().len());
+            let indent: String = line
+                .chars()
+                .take(span.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let remaining = line
+                .chars()
+                .count()
+                .saturating_sub(span.column.saturating_sub(1));
+            let width = self.source
+                [span.start.min(self.source.len())..span.end.min(self.source.len())]
+                .chars()
+                .count()
+                .min(remaining)
+                .max(1);
+
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{} | {}", span.line, line)?;
+            writeln!(f, "{} | {}{}", gutter, indent, "^".repeat(width))?;
+        }
+
+        if let Some(note) = get_note(self.error) {
+            writeln!(f, "  = note: {}", note)?;
+        }
+
+        if let Some(suggestion) = self.error.suggestion() {
+            writeln!(f, "  = help: did you mean \"{}\"?", suggestion)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl CompileError {
+    /// Returns a renderable diagnostic for this error that shows the offending
+    /// line of the script with the token underlined.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The script that failed to compile.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let script = r#"
+    ///     fn()
+    ///         get_current_pid_tgi()
+    /// "#;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// let mut compiler = Compiler::create(&btf);
+    /// let error = compiler.compile(script).expect_err("Helper shouldn't exist.");
+    /// let rendered = error.diagnostic(script).to_string();
+    /// assert!(rendered.contains("did you mean \"get_current_pid_tgid\"?"));
+    /// ```
+    pub fn diagnostic<'a>(&'a self, source: &'a str) -> Diagnostic<'a> {
+        Diagnostic::new(self, source)
+    }
+}
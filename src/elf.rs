@@ -0,0 +1,473 @@
+use crate::debuginfo::{ProgramBtf, MAP_DEF_SIZE, TEXT_SECTION};
+use crate::jump;
+
+use bpf_ins::{Instruction, MemoryOpLoadType};
+
+use std::collections::HashMap;
+
+/// Machine type for BPF objects.
+const EM_BPF: u16 = 247;
+
+/// Relocatable object file type.
+const ET_REL: u16 = 1;
+
+/// Section types.
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_REL: u32 = 9;
+
+/// Section flags.
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+/// Symbol bindings and types.
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+/// Relocation types for 64-bit immediate loads (`lddw`) of a symbol's address, and
+/// for calls to functions in another section.
+const R_BPF_64_64: u64 = 1;
+const R_BPF_64_32: u64 = 10;
+
+/// Size of the ELF header, section headers, symbols and relocations.
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const REL_SIZE: usize = 16;
+
+/// A map defined by an object file, its definition is described by the BTF.
+pub struct ObjectMap {
+    /// The symbol name of the map.
+    pub name: String,
+    /// The immediate used to reference the map in the program's instructions.
+    pub fd: u32,
+}
+
+/// A function the program calls, which is placed in `.text`.
+pub struct ObjectFunction {
+    /// The symbol name of the function.
+    pub name: String,
+    /// The index of the function's first instruction in the program.
+    pub start: usize,
+}
+
+/// A string table being built, e.g. `.strtab`. Strings that are added more than
+/// once are only stored once.
+pub struct StringTable {
+    data: Vec<u8>,
+    offsets: HashMap<String, u32>,
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        Self {
+            data: vec![0],
+            offsets: HashMap::new(),
+        }
+    }
+
+    /// Appends a string and returns its offset in the table.
+    pub fn add(&mut self, s: &str) -> u32 {
+        if s.is_empty() {
+            return 0;
+        }
+
+        if let Some(offset) = self.offsets.get(s) {
+            return *offset;
+        }
+
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(s.as_bytes());
+        self.data.push(0);
+        self.offsets.insert(s.to_string(), offset);
+        offset
+    }
+
+    /// Returns the contents of the table.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// A section being built, its header is written once the layout of the file is known.
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+/// Returns the name of the program's function symbol given its section name, e.g.
+/// `do_sys_open` for `kprobe/do_sys_open`.
+pub fn function_name(section: &str) -> String {
+    let name = section.rsplit('/').next().unwrap_or(section);
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    match name.chars().next() {
+        Some(c) if !c.is_ascii_digit() => name,
+        _ => format!("prog_{}", name),
+    }
+}
+
+/// Returns the index of the map an instruction loads, if it's a map load.
+fn referenced_map(ins: &Instruction, maps: &[ObjectMap]) -> Option<usize> {
+    maps.iter().position(|map| {
+        *ins == Instruction::loadtype(ins.get_dst_reg(), map.fd.into(), MemoryOpLoadType::Map)
+    })
+}
+
+/// Encodes a section's instructions, with map references turned into plain zero
+/// loads like the ones compilers emit, the loader fills them in from the
+/// relocations. Returns the code and the offset and map of every map reference.
+fn encode_section(instructions: &[Instruction], maps: &[ObjectMap]) -> (Vec<u8>, Vec<(u64, u64)>) {
+    let mut code = vec![];
+    let mut relocations = vec![];
+    for ins in instructions {
+        let (n, x) = match referenced_map(ins, maps) {
+            Some(index) => {
+                relocations.push((code.len() as u64, index as u64));
+                let ins = Instruction::loadtype(ins.get_dst_reg(), 0, MemoryOpLoadType::Void);
+                ins.encode()
+            }
+            None => ins.encode(),
+        };
+        put!(code, n);
+        if let Some(x) = x {
+            put!(code, x);
+        }
+    }
+
+    (code, relocations)
+}
+
+/// Writes a program to an ELF64 relocatable object file, in the layout that
+/// standard BPF loaders (e.g. libbpf and `bpftool prog load`) expect: the
+/// program in its own section, the functions it calls in `.text`, a `license`
+/// section, a `.maps` section with BTF-defined maps, relocations for every
+/// instruction that references a map or calls into `.text` and the program's BTF
+/// in `.BTF` and `.BTF.ext`.
+///
+/// # Arguments
+///
+/// * `license` - The license of the program, e.g. `GPL`.
+/// * `instructions` - The program, followed by the functions it calls.
+/// * `functions` - The functions the program calls, in the order they follow it.
+/// * `maps` - The maps the program references, in the order they're described in
+///   the BTF's `.maps` data section.
+/// * `btf` - The program's BTF, which also names the section holding the program.
+pub fn write_object(
+    license: &str,
+    instructions: &[Instruction],
+    functions: &[ObjectFunction],
+    maps: &[ObjectMap],
+    btf: &ProgramBtf,
+) -> Vec<u8> {
+    let section = btf.section();
+    let mut strings = StringTable::new();
+    let mut sections = vec![];
+
+    /*
+     * Calls from the program into `.text` are relocated against the section's
+     * symbol, with the immediate holding the callee's offset in the section minus
+     * one, like compilers emit them. Calls within `.text` are already relative.
+     */
+    let split = functions.first().map_or(instructions.len(), |f| f.start);
+    let text_slot = jump::slot_count(&instructions[..split]) as i64;
+    let mut program = instructions[..split].to_vec();
+    let mut calls = vec![];
+    let mut slot = 0;
+    for ins in &mut program {
+        if let Some(offset) = jump::local_call_offset(ins) {
+            calls.push(slot as u64 * 8);
+            *ins = jump::call_local((slot + 1 + offset as i64 - text_slot - 1) as i32);
+        }
+        slot += jump::slot_count(std::slice::from_ref(ins)) as i64;
+    }
+
+    let (code, relocations) = encode_section(&program, maps);
+    let code_size = code.len() as u64;
+    sections.push(Section {
+        name: strings.add(section),
+        kind: SHT_PROGBITS,
+        flags: SHF_ALLOC | SHF_EXECINSTR,
+        data: code,
+        link: 0,
+        info: 0,
+        align: 8,
+        entsize: 0,
+    });
+    let code_index = sections.len() as u32;
+
+    let (text, text_relocations) = encode_section(&instructions[split..], maps);
+    let text_index = if functions.is_empty() {
+        0
+    } else {
+        sections.push(Section {
+            name: strings.add(TEXT_SECTION),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            data: text,
+            link: 0,
+            info: 0,
+            align: 8,
+            entsize: 0,
+        });
+        sections.len() as u32
+    };
+
+    let mut license_data = license.as_bytes().to_vec();
+    license_data.push(0);
+    let license_size = license_data.len() as u64;
+    sections.push(Section {
+        name: strings.add("license"),
+        kind: SHT_PROGBITS,
+        flags: SHF_ALLOC | SHF_WRITE,
+        data: license_data,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+    let license_index = sections.len() as u32;
+
+    let maps_index = if maps.is_empty() {
+        0
+    } else {
+        sections.push(Section {
+            name: strings.add(".maps"),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_WRITE,
+            data: vec![0; maps.len() * MAP_DEF_SIZE as usize],
+            link: 0,
+            info: 0,
+            align: 8,
+            entsize: 0,
+        });
+        sections.len() as u32
+    };
+
+    /*
+     * Symbols: the null symbol, the section symbols and the functions the program
+     * calls are local, followed by the program's function, the license and one
+     * symbol per map.
+     */
+    let mut symbols = vec![];
+    let mut add_symbol = |name: u32, bind: u8, kind: u8, shndx: u32, value: u64, size: u64| {
+        put!(symbols, name);
+        symbols.push(bind << 4 | kind);
+        symbols.push(0);
+        put!(symbols, shndx as u16);
+        put!(symbols, value);
+        put!(symbols, size);
+    };
+
+    add_symbol(0, STB_LOCAL, 0, 0, 0, 0);
+    add_symbol(0, STB_LOCAL, STT_SECTION, code_index, 0, 0);
+    let text_symbol = 2;
+    if !functions.is_empty() {
+        add_symbol(0, STB_LOCAL, STT_SECTION, text_index, 0, 0);
+        for (i, function) in functions.iter().enumerate() {
+            let end = functions.get(i + 1).map_or(instructions.len(), |f| f.start);
+            let offset = jump::slot_count(&instructions[split..function.start]) as u64 * 8;
+            let size = jump::slot_count(&instructions[function.start..end]) as u64 * 8;
+            add_symbol(
+                strings.add(&function.name),
+                STB_LOCAL,
+                STT_FUNC,
+                text_index,
+                offset,
+                size,
+            );
+        }
+    }
+
+    let first_global = match functions.len() {
+        0 => 2,
+        n => 3 + n as u32,
+    };
+    add_symbol(
+        strings.add(&function_name(section)),
+        STB_GLOBAL,
+        STT_FUNC,
+        code_index,
+        0,
+        code_size,
+    );
+    add_symbol(
+        strings.add("_license"),
+        STB_GLOBAL,
+        STT_OBJECT,
+        license_index,
+        0,
+        license_size,
+    );
+    let first_map_symbol = u64::from(first_global) + 2;
+    for (i, map) in maps.iter().enumerate() {
+        add_symbol(
+            strings.add(&map.name),
+            STB_GLOBAL,
+            STT_OBJECT,
+            maps_index,
+            u64::from(i as u32 * MAP_DEF_SIZE),
+            u64::from(MAP_DEF_SIZE),
+        );
+    }
+
+    for (name, data) in [(".BTF", btf.btf().to_vec()), (".BTF.ext", btf.btf_ext())] {
+        sections.push(Section {
+            name: strings.add(name),
+            kind: SHT_PROGBITS,
+            flags: 0,
+            data,
+            link: 0,
+            info: 0,
+            align: 4,
+            entsize: 0,
+        });
+    }
+
+    /*
+     * Relocations of the program's section and of `.text`, as the offset of the
+     * instruction, the symbol and the relocation type.
+     */
+    let map_relocation = |(offset, map): (u64, u64)| (offset, first_map_symbol + map, R_BPF_64_64);
+    let mut code_relocations: Vec<(u64, u64, u64)> =
+        relocations.into_iter().map(map_relocation).collect();
+    code_relocations.extend(
+        calls
+            .into_iter()
+            .map(|offset| (offset, text_symbol, R_BPF_64_32)),
+    );
+    code_relocations.sort();
+    let text_relocations: Vec<(u64, u64, u64)> =
+        text_relocations.into_iter().map(map_relocation).collect();
+    let relocated = [
+        (section, code_index, code_relocations),
+        (TEXT_SECTION, text_index, text_relocations),
+    ];
+    let num_relocated = relocated.iter().filter(|(_, _, r)| !r.is_empty()).count();
+
+    /*
+     * Section indices are shifted by one for the null section, which is added
+     * when the headers are written.
+     */
+    let symtab_index = sections.len() as u32 + 1;
+    let strtab_index = symtab_index + 1 + num_relocated as u32;
+    sections.push(Section {
+        name: strings.add(".symtab"),
+        kind: SHT_SYMTAB,
+        flags: 0,
+        data: symbols,
+        link: strtab_index,
+        info: first_global,
+        align: 8,
+        entsize: SYM_SIZE as u64,
+    });
+
+    for (name, index, relocations) in relocated {
+        if relocations.is_empty() {
+            continue;
+        }
+
+        let mut data = vec![];
+        for (offset, symbol, kind) in relocations {
+            put!(data, offset);
+            put!(data, symbol << 32 | kind);
+        }
+
+        sections.push(Section {
+            name: strings.add(&format!(".rel{}", name)),
+            kind: SHT_REL,
+            flags: SHF_INFO_LINK,
+            data,
+            link: symtab_index,
+            info: index,
+            align: 8,
+            entsize: REL_SIZE as u64,
+        });
+    }
+
+    /*
+     * The string table holds both section and symbol names, so it's added last
+     * once every name is in it.
+     */
+    let strtab_name = strings.add(".strtab");
+    sections.push(Section {
+        name: strtab_name,
+        kind: SHT_STRTAB,
+        flags: 0,
+        data: strings.into_bytes(),
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+
+    /*
+     * Layout: ELF header, section contents (each aligned), then section headers.
+     */
+    let mut contents = vec![];
+    let mut offsets = vec![];
+    for section in &sections {
+        let align = section.align.max(1) as usize;
+        let padding = (align - (EHDR_SIZE + contents.len()) % align) % align;
+        contents.resize(contents.len() + padding, 0);
+        offsets.push((EHDR_SIZE + contents.len()) as u64);
+        contents.extend_from_slice(&section.data);
+    }
+    contents.resize(
+        contents.len() + (8 - (EHDR_SIZE + contents.len()) % 8) % 8,
+        0,
+    );
+    let shoff = (EHDR_SIZE + contents.len()) as u64;
+
+    let mut elf = Vec::with_capacity(shoff as usize + (sections.len() + 1) * SHDR_SIZE);
+    elf.extend_from_slice(b"\x7fELF");
+    elf.push(2); /* ELFCLASS64 */
+    elf.push(if cfg!(target_endian = "little") { 1 } else { 2 });
+    elf.push(1); /* EV_CURRENT */
+    elf.resize(16, 0);
+    put!(elf, ET_REL);
+    put!(elf, EM_BPF);
+    put!(elf, 1u32);
+    put!(elf, 0u64); /* entry */
+    put!(elf, 0u64); /* program headers */
+    put!(elf, shoff);
+    put!(elf, 0u32); /* flags */
+    put!(elf, EHDR_SIZE as u16);
+    put!(elf, 0u16);
+    put!(elf, 0u16);
+    put!(elf, SHDR_SIZE as u16);
+    put!(elf, (sections.len() + 1) as u16);
+    put!(elf, sections.len() as u16); /* the string table is the last section */
+    elf.extend_from_slice(&contents);
+
+    elf.resize(elf.len() + SHDR_SIZE, 0);
+    for (section, offset) in sections.iter().zip(offsets) {
+        put!(elf, section.name);
+        put!(elf, section.kind);
+        put!(elf, section.flags);
+        put!(elf, 0u64); /* address */
+        put!(elf, offset);
+        put!(elf, section.data.len() as u64);
+        put!(elf, section.link);
+        put!(elf, section.info);
+        put!(elf, section.align);
+        put!(elf, section.entsize);
+    }
+
+    elf
+}
@@ -0,0 +1,350 @@
+use std::fmt;
+use std::ops::Range;
+
+/// A location in the source text of a script.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset of the first character.
+    pub start: usize,
+    /// Byte offset one past the last character.
+    pub end: usize,
+    /// Line number, starting at 1.
+    pub line: usize,
+    /// Column number of the first character, starting at 1.
+    pub column: usize,
+}
+
+impl Span {
+    /// Creates a span for a byte range of `source`, computing its line and column.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The script text the range refers to.
+    /// * `range` - The byte range within `source`.
+    pub fn from_range(source: &str, range: &Range<usize>) -> Self {
+        let start = range.start.min(source.len());
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+        Self {
+            start: range.start,
+            end: range.end,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+/// The span of errors that don't come from the script.
+const NO_SPAN: Span = Span {
+    start: 0,
+    end: 0,
+    line: 0,
+    column: 0,
+};
+
+/// Errors that can occur while compiling a script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompileError {
+    /// The script doesn't match the grammar.
+    ParseError { span: Span, expected: String },
+    /// A type name isn't present in the BTF type library.
+    UnknownType {
+        span: Span,
+        name: String,
+        suggestion: Option<String>,
+    },
+    /// A variable was used before being declared.
+    UnknownVariable {
+        span: Span,
+        name: String,
+        suggestion: Option<String>,
+    },
+    /// A function call names a helper that doesn't exist.
+    UnknownHelper {
+        span: Span,
+        name: String,
+        suggestion: Option<String>,
+    },
+    /// A member access names a member the struct doesn't have.
+    UnknownMember {
+        span: Span,
+        name: String,
+        suggestion: Option<String>,
+    },
+    /// The script's header names a program type that doesn't exist.
+    UnknownProgramType {
+        span: Span,
+        name: String,
+        suggestion: Option<String>,
+    },
+    /// A type references a type id that isn't in the BTF type library.
+    MissingTypeId { span: Span, type_id: u32 },
+    /// The program needs more than the 512 bytes of stack BPF provides.
+    StackOverflow { span: Span, size: u32 },
+    /// A value was assigned to a type of a different size.
+    SizeMismatch {
+        span: Span,
+        expected: u32,
+        found: u32,
+    },
+    /// A member access refers to a bitfield.
+    BitfieldUnsupported { span: Span, name: String },
+    /// An integer literal is malformed or out of range for its type.
+    InvalidImmediate { span: Span, value: String },
+    /// A string literal or format string is malformed, or a format's conversion
+    /// doesn't match its argument.
+    InvalidString {
+        span: Span,
+        value: String,
+        reason: &'static str,
+    },
+    /// A member access was made on a type that isn't a struct.
+    NotAStruct { span: Span, name: String },
+    /// A value has a type that can't be used in this position.
+    InvalidType { span: Span, reason: &'static str },
+    /// The script uses a feature the compiler doesn't support yet.
+    Unsupported { span: Span, feature: &'static str },
+    /// A function takes or is passed more arguments than BPF allows.
+    TooManyArguments { span: Span, max: usize },
+    /// A variable was given a type after its first assignment.
+    RetypedVariable { span: Span, name: String },
+    /// A variable that can't be assigned to was assigned to.
+    ImmutableVariable { span: Span, name: String },
+    /// A constant array index is past the end of the array.
+    IndexOutOfBounds { span: Span, index: u32, len: u32 },
+    /// An array index must be known at compile time, but isn't.
+    NonConstantIndex { span: Span },
+    /// A loop runs for more iterations than allowed.
+    TooManyIterations {
+        span: Span,
+        iterations: u32,
+        max: u32,
+    },
+    /// A branch needs to jump further than a jump offset can encode.
+    BranchTooLarge { span: Span, size: usize },
+    /// A map is declared or used incorrectly.
+    InvalidMap {
+        span: Span,
+        name: String,
+        reason: &'static str,
+    },
+    /// A function is declared or called incorrectly.
+    InvalidFunction {
+        span: Span,
+        name: String,
+        reason: &'static str,
+    },
+    /// The script doesn't follow the rules of its program type.
+    InvalidProgram {
+        span: Span,
+        name: String,
+        reason: &'static str,
+    },
+    /// A value captured from Rust doesn't match the type it's captured as. These
+    /// errors happen before there's a script, so they have no location.
+    InvalidCapture {
+        name: String,
+        reason: String,
+        suggestion: Option<String>,
+    },
+    /// The program would be rejected by the kernel's verifier when loaded.
+    VerifierRejected {
+        span: Span,
+        instruction: usize,
+        reason: String,
+    },
+}
+
+impl CompileError {
+    /// Returns the location in the script the error refers to. Errors that don't
+    /// come from the script have an empty span on line 0.
+    pub fn span(&self) -> &Span {
+        match self {
+            Self::ParseError { span, .. }
+            | Self::UnknownType { span, .. }
+            | Self::UnknownVariable { span, .. }
+            | Self::UnknownHelper { span, .. }
+            | Self::UnknownMember { span, .. }
+            | Self::UnknownProgramType { span, .. }
+            | Self::MissingTypeId { span, .. }
+            | Self::StackOverflow { span, .. }
+            | Self::SizeMismatch { span, .. }
+            | Self::BitfieldUnsupported { span, .. }
+            | Self::InvalidImmediate { span, .. }
+            | Self::InvalidString { span, .. }
+            | Self::NotAStruct { span, .. }
+            | Self::InvalidType { span, .. }
+            | Self::Unsupported { span, .. }
+            | Self::TooManyArguments { span, .. }
+            | Self::RetypedVariable { span, .. }
+            | Self::ImmutableVariable { span, .. }
+            | Self::IndexOutOfBounds { span, .. }
+            | Self::NonConstantIndex { span }
+            | Self::TooManyIterations { span, .. }
+            | Self::BranchTooLarge { span, .. }
+            | Self::InvalidMap { span, .. }
+            | Self::InvalidFunction { span, .. }
+            | Self::InvalidProgram { span, .. }
+            | Self::VerifierRejected { span, .. } => span,
+            Self::InvalidCapture { .. } => &NO_SPAN,
+        }
+    }
+
+    /// Returns the identifier (variable, type, helper or member name) or literal
+    /// the error is about, if there is one.
+    pub fn identifier(&self) -> Option<&str> {
+        match self {
+            Self::UnknownType { name, .. }
+            | Self::UnknownVariable { name, .. }
+            | Self::UnknownHelper { name, .. }
+            | Self::UnknownMember { name, .. }
+            | Self::UnknownProgramType { name, .. }
+            | Self::BitfieldUnsupported { name, .. }
+            | Self::NotAStruct { name, .. }
+            | Self::RetypedVariable { name, .. }
+            | Self::ImmutableVariable { name, .. }
+            | Self::InvalidMap { name, .. }
+            | Self::InvalidFunction { name, .. }
+            | Self::InvalidProgram { name, .. }
+            | Self::InvalidCapture { name, .. } => Some(name),
+            Self::InvalidImmediate { value, .. } | Self::InvalidString { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the closest known name to the unknown identifier, if any is close
+    /// enough to likely be what was meant.
+    pub fn suggestion(&self) -> Option<&str> {
+        match self {
+            Self::UnknownType { suggestion, .. }
+            | Self::UnknownVariable { suggestion, .. }
+            | Self::UnknownHelper { suggestion, .. }
+            | Self::UnknownMember { suggestion, .. }
+            | Self::UnknownProgramType { suggestion, .. }
+            | Self::InvalidCapture { suggestion, .. } => suggestion.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the error message, without location information.
+    pub fn message(&self) -> String {
+        match self {
+            Self::ParseError { expected, .. } => format!("Syntax error, {}.", expected),
+            Self::UnknownType { name, .. } => format!("No type found with the name \"{}\".", name),
+            Self::UnknownVariable { name, .. } => {
+                format!("No variable with the name \"{}\".", name)
+            }
+            Self::UnknownHelper { name, .. } => format!("Unknown helper function \"{}\".", name),
+            Self::UnknownMember { name, .. } => format!("Member \"{}\" doesn't exist.", name),
+            Self::UnknownProgramType { name, .. } => {
+                format!("Unknown program type \"{}\".", name)
+            }
+            Self::MissingTypeId { type_id, .. } => {
+                format!("Bad BTF database: type id \"{}\" not found.", type_id)
+            }
+            Self::StackOverflow { size, .. } => format!(
+                "Stack size exceeded 512 bytes with this assignment ({} bytes).",
+                size
+            ),
+            Self::SizeMismatch {
+                expected, found, ..
+            } => format!(
+                "Cannot assign a {} byte value to a {} byte type.",
+                found, expected
+            ),
+            Self::BitfieldUnsupported { name, .. } => {
+                format!("Bitfield accesses aren't supported (\"{}\").", name)
+            }
+            Self::InvalidImmediate { value, .. } => format!("Bad immediate value \"{}\".", value),
+            Self::InvalidString { value, reason, .. } => format!("{} (\"{}\").", reason, value),
+            Self::NotAStruct { name, .. } => {
+                format!("Tried to get member \"{}\" on non-struct type.", name)
+            }
+            Self::InvalidType { reason, .. } => format!("{}.", reason),
+            Self::Unsupported { feature, .. } => {
+                format!("{} isn't currently supported.", feature)
+            }
+            Self::TooManyArguments { max, .. } => {
+                format!("Functions can have a maximum of {} arguments.", max)
+            }
+            Self::RetypedVariable { name, .. } => {
+                format!("Can't re-type \"{}\" after first assignment.", name)
+            }
+            Self::ImmutableVariable { name, .. } => {
+                format!("Variable \"{}\" cannot be re-assigned.", name)
+            }
+            Self::IndexOutOfBounds { index, len, .. } => format!(
+                "Tried to access array index {} when array size is {}.",
+                index, len
+            ),
+            Self::NonConstantIndex { .. } => "Array index must be a constant here.".to_string(),
+            Self::TooManyIterations {
+                iterations, max, ..
+            } => format!(
+                "Loop runs for {} iterations, the maximum is {}.",
+                iterations, max
+            ),
+            Self::BranchTooLarge { size, .. } => {
+                format!("Branch is too large to jump over ({} instructions).", size)
+            }
+            Self::InvalidMap { name, reason, .. }
+            | Self::InvalidFunction { name, reason, .. }
+            | Self::InvalidProgram { name, reason, .. } => format!("{} (\"{}\").", reason, name),
+            Self::InvalidCapture { name, reason, .. } => format!("{} (\"{}\").", reason, name),
+            Self::VerifierRejected {
+                instruction,
+                reason,
+                ..
+            } => format!("{} (instruction {}).", reason, instruction),
+        }
+    }
+
+    /// Returns whether the error is caused by the BTF type library rather than
+    /// by the script itself.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::Compiler;
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// let mut compiler = Compiler::create(&btf);
+    /// let error = compiler.compile(r#"
+    ///     fn()
+    ///         return missing
+    /// "#).expect_err("Variable shouldn't exist.");
+    /// assert!(!error.is_btf_error());
+    /// ```
+    pub fn is_btf_error(&self) -> bool {
+        matches!(self, Self::MissingTypeId { .. })
+    }
+
+    /// Returns whether the error is caused by the script, i.e. it can be fixed
+    /// by changing the script.
+    pub fn is_user_error(&self) -> bool {
+        !self.is_btf_error()
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let span = self.span();
+        if span.line == 0 {
+            return write!(f, "{}", self.message());
+        }
+
+        write!(
+            f,
+            "[Line {}, Column {}] {}",
+            span.line,
+            span.column,
+            self.message()
+        )
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Result type used throughout the compiler.
+pub type Result<T> = std::result::Result<T, CompileError>;
@@ -0,0 +1,163 @@
+//! String literals, and the format strings `print` passes to the kernel. Formats
+//! are checked against the conversions `bpf_trace_printk` accepts, so mistakes are
+//! reported when compiling rather than when the program is loaded.
+
+use std::ops::Range;
+
+/// The most values the kernel formats in one call.
+pub(crate) const MAX_FORMAT_ARGS: usize = 12;
+
+/// An error in a string literal: the byte range of the literal's text it refers
+/// to, and why it's invalid.
+pub(crate) type StringError = (Range<usize>, &'static str);
+
+/// The kind of value a conversion prints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConversionKind {
+    /// `%d`, `%i`, `%u`, `%x` and `%X`, optionally with `l` or `ll`.
+    Integer,
+    /// `%c`.
+    Char,
+    /// `%s`, `%pks` and `%pus`, which print the string a pointer points to.
+    String,
+    /// `%p` and its variants, e.g. `%pK` or `%pI4`.
+    Pointer,
+}
+
+/// A conversion in a format string, e.g. `%08lx`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Conversion {
+    pub kind: ConversionKind,
+    /// The byte range of the conversion in the text of the literal.
+    pub range: Range<usize>,
+}
+
+/// Decodes the escape sequences of a string literal, and returns its bytes
+/// followed by a NUL terminator.
+///
+/// # Arguments
+///
+/// * `text` - The text between the literal's quotes.
+pub(crate) fn unescape(text: &str) -> Result<Vec<u8>, StringError> {
+    let mut bytes = Vec::with_capacity(text.len() + 1);
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        let byte = match chars.next() {
+            Some((_, 'n')) => b'\n',
+            Some((_, 't')) => b'\t',
+            Some((_, 'r')) => b'\r',
+            Some((_, '0')) => 0,
+            Some((_, '\\')) => b'\\',
+            Some((_, '"')) => b'"',
+            Some((_, c)) => {
+                return Err((i..i + 1 + c.len_utf8(), "Unknown escape sequence"));
+            }
+            None => return Err((i..i + 1, "Unknown escape sequence")),
+        };
+        bytes.push(byte);
+    }
+
+    bytes.push(0);
+    Ok(bytes)
+}
+
+/// Parses the conversion starting at a `%`, and returns its kind and the offset
+/// just past it.
+///
+/// # Arguments
+///
+/// * `text` - The text of the format string.
+/// * `start` - The offset of the `%`.
+fn parse_conversion(text: &[u8], start: usize) -> Result<(ConversionKind, usize), StringError> {
+    let at = |i: usize| text.get(i).copied().unwrap_or(0);
+    let error = |end: usize, reason| Err((start..end.min(text.len()), reason));
+
+    /*
+     * Flags and a field width, but no precision.
+     */
+    let mut i = start + 1;
+    while matches!(at(i), b'0' | b'+' | b'-' | b' ') {
+        i += 1;
+    }
+    while at(i).is_ascii_digit() {
+        i += 1;
+    }
+
+    let (kind, len) = match (at(i), at(i + 1), at(i + 2)) {
+        (b'p', b'k' | b'u', b's') => (ConversionKind::String, 3),
+        (b'p', b'i' | b'I', b'4' | b'6') => (ConversionKind::Pointer, 3),
+        (b'p', b'K' | b'x' | b's' | b'S' | b'B', _) => (ConversionKind::Pointer, 2),
+        (b'p', next, _) if !next.is_ascii_alphanumeric() => (ConversionKind::Pointer, 1),
+        (b'p', _, _) => {
+            return error(
+                i + 2,
+                "Unsupported pointer conversion, e.g. `%p`, `%pK` or `%pI4`",
+            )
+        }
+        (b's', _, _) => (ConversionKind::String, 1),
+        (b'c', _, _) => (ConversionKind::Char, 1),
+        _ => {
+            let mut len = 0;
+            while len < 2 && at(i + len) == b'l' {
+                len += 1;
+            }
+            if !matches!(at(i + len), b'd' | b'i' | b'u' | b'x' | b'X') {
+                return error(
+                    i + len + 1,
+                    "Unsupported conversion, expected %d, %i, %u, %x, %X, %c, %s or %p",
+                );
+            }
+            (ConversionKind::Integer, len + 1)
+        }
+    };
+
+    Ok((kind, i + len))
+}
+
+/// Parses the conversions of a format string, checking that the kernel accepts
+/// it. The text must be a valid literal, see `unescape`.
+///
+/// # Arguments
+///
+/// * `text` - The text between the literal's quotes.
+pub(crate) fn parse_format(text: &str) -> Result<Vec<Conversion>, StringError> {
+    let bytes = text.as_bytes();
+    let mut conversions = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if bytes.get(i + 1) == Some(&b'0') => {
+                return Err((i..i + 2, "Format strings can't contain NUL characters"));
+            }
+            b'\\' => i += 2,
+            b'%' if bytes.get(i + 1) == Some(&b'%') => i += 2,
+            b'%' => {
+                let (kind, end) = parse_conversion(bytes, i)?;
+                if conversions.len() == MAX_FORMAT_ARGS {
+                    return Err((i..end, "Format strings can have at most 12 conversions"));
+                }
+                conversions.push(Conversion {
+                    kind,
+                    range: i..end,
+                });
+                i = end;
+            }
+            c if c.is_ascii_graphic() || c == b' ' || c == b'\t' => i += 1,
+            _ => {
+                let len = text[i..].chars().next().map_or(1, char::len_utf8);
+                return Err((
+                    i..i + len,
+                    "Format strings can only contain printable ASCII characters",
+                ));
+            }
+        }
+    }
+
+    Ok(conversions)
+}
@@ -1,5 +1,6 @@
 use bpf_ins::MemoryOpLoadType;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Helpers {
     MapLookupElem = 1,
     MapUpdateElem = 2,
@@ -117,8 +118,130 @@ pub enum Helpers {
     CheckMtu = 163,
     ForEachMapElem = 164,
     Snprintf = 165,
+    TraceVprintk = 177,
 }
 
+/// The C names of all helper functions, without the `bpf_` prefix.
+const HELPER_NAMES: &[(&str, Helpers)] = &[
+    ("map_lookup_elem", Helpers::MapLookupElem),
+    ("map_update_elem", Helpers::MapUpdateElem),
+    ("map_delete_elem", Helpers::MapDeleteElem),
+    ("probe_read", Helpers::ProbeRead),
+    ("trace_printk", Helpers::TracePrintk),
+    ("skb_store_bytes", Helpers::SkbStoreBytes),
+    ("l3_csum_replace", Helpers::L3CsumReplace),
+    ("l4_csum_replace", Helpers::L4CsumReplace),
+    ("tail_call", Helpers::TailCall),
+    ("clone_redirect", Helpers::CloneRedirect),
+    ("get_current_pid_tgid", Helpers::GetCurrentPidTgid),
+    ("get_current_uid_gid", Helpers::GetCurrentUidGid),
+    ("get_current_comm", Helpers::GetCurrentComm),
+    ("skb_vlan_push", Helpers::SkbVlanPush),
+    ("skb_vlan_pop", Helpers::SkbVlanPop),
+    ("skb_get_tunnel_key", Helpers::SkbGetTunnelKey),
+    ("skb_set_tunnel_key", Helpers::SkbSetTunnelKey),
+    ("redirect", Helpers::Redirect),
+    ("perf_event_output", Helpers::PerfEventOutput),
+    ("skb_load_bytes", Helpers::SkbLoadBytes),
+    ("get_stackid", Helpers::GetStackid),
+    ("skb_get_tunnel_opt", Helpers::SkbGetTunnelOpt),
+    ("skb_set_tunnel_opt", Helpers::SkbSetTunnelOpt),
+    ("skb_change_proto", Helpers::SkbChangeProto),
+    ("skb_change_type", Helpers::SkbChangeType),
+    ("skb_under_cgroup", Helpers::SkbUnderCgroup),
+    ("probe_write_user", Helpers::ProbeWriteUser),
+    ("current_task_under_cgroup", Helpers::CurrentTaskUnderCgroup),
+    ("skb_change_tail", Helpers::SkbChangeTail),
+    ("skb_pull_data", Helpers::SkbPullData),
+    ("get_numa_node_id", Helpers::GetNumaNodeId),
+    ("skb_change_head", Helpers::SkbChangeHead),
+    ("xdp_adjust_head", Helpers::XdpAdjustHead),
+    ("probe_read_str", Helpers::ProbeReadStr),
+    ("set_hash", Helpers::SetHash),
+    ("setsockopt", Helpers::Setsockopt),
+    ("skb_adjust_room", Helpers::SkbAdjustRoom),
+    ("redirect_map", Helpers::RedirectMap),
+    ("sk_redirect_map", Helpers::SkRedirectMap),
+    ("sock_map_update", Helpers::SockMapUpdate),
+    ("xdp_adjust_meta", Helpers::XdpAdjustMeta),
+    ("perf_event_read_value", Helpers::PerfEventReadValue),
+    ("perf_prog_read_value", Helpers::PerfProgReadValue),
+    ("getsockopt", Helpers::Getsockopt),
+    ("override_return", Helpers::OverrideReturn),
+    ("sock_ops_cb_flags_set", Helpers::SockOpsCbFlagsSet),
+    ("msg_redirect_map", Helpers::MsgRedirectMap),
+    ("msg_apply_bytes", Helpers::MsgApplyBytes),
+    ("msg_cork_bytes", Helpers::MsgCorkBytes),
+    ("msg_pull_data", Helpers::MsgPullData),
+    ("bind", Helpers::Bind),
+    ("xdp_adjust_tail", Helpers::XdpAdjustTail),
+    ("skb_get_xfrm_state", Helpers::SkbGetXfrmState),
+    ("get_stack", Helpers::GetStack),
+    ("skb_load_bytes_relative", Helpers::SkbLoadBytesRelative),
+    ("fib_lookup", Helpers::FibLookup),
+    ("sock_hash_update", Helpers::SockHashUpdate),
+    ("msg_redirect_hash", Helpers::MsgRedirectHash),
+    ("sk_redirect_hash", Helpers::SkRedirectHash),
+    ("lwt_push_encap", Helpers::LwtPushEncap),
+    ("lwt_seg6_store_bytes", Helpers::LwtSeg6StoreBytes),
+    ("lwt_seg6_adjust_srh", Helpers::LwtSeg6AdjustSrh),
+    ("lwt_seg6_action", Helpers::LwtSeg6Action),
+    ("rc_repeat", Helpers::RcRepeat),
+    ("rc_keydown", Helpers::RcKeydown),
+    ("sk_select_reuseport", Helpers::SkSelectReuseport),
+    ("sk_release", Helpers::SkRelease),
+    ("map_push_elem", Helpers::MapPushElem),
+    ("map_pop_elem", Helpers::MapPopElem),
+    ("map_peek_elem", Helpers::MapPeekElem),
+    ("msg_push_data", Helpers::MsgPushData),
+    ("msg_pop_data", Helpers::MsgPopData),
+    ("rc_pointer_rel", Helpers::RcPointerRel),
+    ("spin_lock", Helpers::SpinLock),
+    ("spin_unlock", Helpers::SpinUnlock),
+    ("skb_ecn_set_ce", Helpers::SkbEcnSetCe),
+    ("tcp_check_syncookie", Helpers::TcpCheckSyncookie),
+    ("sysctl_get_name", Helpers::SysctlGetName),
+    ("sysctl_get_current_value", Helpers::SysctlGetCurrentValue),
+    ("sysctl_get_new_value", Helpers::SysctlGetNewValue),
+    ("sysctl_set_new_value", Helpers::SysctlSetNewValue),
+    ("strtol", Helpers::Strtol),
+    ("strtoul", Helpers::Strtoul),
+    ("sk_storage_delete", Helpers::SkStorageDelete),
+    ("send_signal", Helpers::SendSignal),
+    ("skb_output", Helpers::SkbOutput),
+    ("probe_read_user", Helpers::ProbeReadUser),
+    ("probe_read_kernel", Helpers::ProbeReadKernel),
+    ("probe_read_user_str", Helpers::ProbeReadUserStr),
+    ("probe_read_kernel_str", Helpers::ProbeReadKernelStr),
+    ("tcp_send_ack", Helpers::TcpSendAck),
+    ("send_signal_thread", Helpers::SendSignalThread),
+    ("read_branch_records", Helpers::ReadBranchRecords),
+    ("get_ns_current_pid_tgid", Helpers::GetNsCurrentPidTgid),
+    ("xdp_output", Helpers::XdpOutput),
+    ("sk_assign", Helpers::SkAssign),
+    ("seq_printf", Helpers::SeqPrintf),
+    ("seq_write", Helpers::SeqWrite),
+    ("ringbuf_output", Helpers::RingbufOutput),
+    ("csum_level", Helpers::CsumLevel),
+    ("get_task_stack", Helpers::GetTaskStack),
+    ("load_hdr_opt", Helpers::LoadHdrOpt),
+    ("store_hdr_opt", Helpers::StoreHdrOpt),
+    ("reserve_hdr_opt", Helpers::ReserveHdrOpt),
+    ("d_path", Helpers::DPath),
+    ("copy_from_user", Helpers::CopyFromUser),
+    ("snprintf_btf", Helpers::SnprintfBtf),
+    ("seq_printf_btf", Helpers::SeqPrintfBtf),
+    ("redirect_neigh", Helpers::RedirectNeigh),
+    ("redirect_peer", Helpers::RedirectPeer),
+    ("task_storage_delete", Helpers::TaskStorageDelete),
+    ("bprm_opts_set", Helpers::BprmOptsSet),
+    ("ima_inode_hash", Helpers::ImaInodeHash),
+    ("check_mtu", Helpers::CheckMtu),
+    ("for_each_map_elem", Helpers::ForEachMapElem),
+    ("snprintf", Helpers::Snprintf),
+    ("trace_vprintk", Helpers::TraceVprintk),
+];
+
 impl Helpers {
     /// Returns the argument types for a given helper function.
     pub fn get_arg_types(&self) -> &[MemoryOpLoadType] {
@@ -188,238 +311,57 @@ impl Helpers {
     /// matches!(Helpers::from_string("map_update_elem"), Some(Helpers::MapUpdateElem));
     /// ```
     pub fn from_string(name: &str) -> Option<Self> {
-        Some(if name.eq("map_update_elem") {
-            Helpers::MapUpdateElem
-        } else if name.eq("map_delete_elem") {
-            Helpers::MapDeleteElem
-        } else if name.eq("probe_read") {
-            Helpers::ProbeRead
-        } else if name.eq("trace_printk") {
-            Helpers::TracePrintk
-        } else if name.eq("skb_store_bytes") {
-            Helpers::SkbStoreBytes
-        } else if name.eq("l3_csum_replace") {
-            Helpers::L3CsumReplace
-        } else if name.eq("l4_csum_replace") {
-            Helpers::L4CsumReplace
-        } else if name.eq("tail_call") {
-            Helpers::TailCall
-        } else if name.eq("clone_redirect") {
-            Helpers::CloneRedirect
-        } else if name.eq("get_current_pid_tgid") {
-            Helpers::GetCurrentPidTgid
-        } else if name.eq("get_current_uid_gid") {
-            Helpers::GetCurrentUidGid
-        } else if name.eq("get_current_comm") {
-            Helpers::GetCurrentComm
-        } else if name.eq("skb_vlan_push") {
-            Helpers::SkbVlanPush
-        } else if name.eq("skb_vlan_pop") {
-            Helpers::SkbVlanPop
-        } else if name.eq("skb_get_tunnel_key") {
-            Helpers::SkbGetTunnelKey
-        } else if name.eq("skb_set_tunnel_key") {
-            Helpers::SkbSetTunnelKey
-        } else if name.eq("redirect") {
-            Helpers::Redirect
-        } else if name.eq("perf_event_output") {
-            Helpers::PerfEventOutput
-        } else if name.eq("skb_load_bytes") {
-            Helpers::SkbLoadBytes
-        } else if name.eq("get_stackid") {
-            Helpers::GetStackid
-        } else if name.eq("skb_get_tunnel_opt") {
-            Helpers::SkbGetTunnelOpt
-        } else if name.eq("skb_set_tunnel_opt") {
-            Helpers::SkbSetTunnelOpt
-        } else if name.eq("skb_change_proto") {
-            Helpers::SkbChangeProto
-        } else if name.eq("skb_change_type") {
-            Helpers::SkbChangeType
-        } else if name.eq("skb_under_cgroup") {
-            Helpers::SkbUnderCgroup
-        } else if name.eq("probe_write_user") {
-            Helpers::ProbeWriteUser
-        } else if name.eq("current_task_under_cgroup") {
-            Helpers::CurrentTaskUnderCgroup
-        } else if name.eq("skb_change_tail") {
-            Helpers::SkbChangeTail
-        } else if name.eq("skb_pull_data") {
-            Helpers::SkbPullData
-        } else if name.eq("get_numa_node_id") {
-            Helpers::GetNumaNodeId
-        } else if name.eq("skb_change_head") {
-            Helpers::SkbChangeHead
-        } else if name.eq("xdp_adjust_head") {
-            Helpers::XdpAdjustHead
-        } else if name.eq("probe_read_str") {
-            Helpers::ProbeReadStr
-        } else if name.eq("set_hash") {
-            Helpers::SetHash
-        } else if name.eq("setsockopt") {
-            Helpers::Setsockopt
-        } else if name.eq("skb_adjust_room") {
-            Helpers::SkbAdjustRoom
-        } else if name.eq("redirect_map") {
-            Helpers::RedirectMap
-        } else if name.eq("sk_redirect_map") {
-            Helpers::SkRedirectMap
-        } else if name.eq("sock_map_update") {
-            Helpers::SockMapUpdate
-        } else if name.eq("xdp_adjust_meta") {
-            Helpers::XdpAdjustMeta
-        } else if name.eq("perf_event_read_value") {
-            Helpers::PerfEventReadValue
-        } else if name.eq("perf_prog_read_value") {
-            Helpers::PerfProgReadValue
-        } else if name.eq("getsockopt") {
-            Helpers::Getsockopt
-        } else if name.eq("override_return") {
-            Helpers::OverrideReturn
-        } else if name.eq("sock_ops_cb_flags_set") {
-            Helpers::SockOpsCbFlagsSet
-        } else if name.eq("msg_redirect_map") {
-            Helpers::MsgRedirectMap
-        } else if name.eq("msg_apply_bytes") {
-            Helpers::MsgApplyBytes
-        } else if name.eq("msg_cork_bytes") {
-            Helpers::MsgCorkBytes
-        } else if name.eq("msg_pull_data") {
-            Helpers::MsgPullData
-        } else if name.eq("bind") {
-            Helpers::Bind
-        } else if name.eq("xdp_adjust_tail") {
-            Helpers::XdpAdjustTail
-        } else if name.eq("skb_get_xfrm_state") {
-            Helpers::SkbGetXfrmState
-        } else if name.eq("get_stack") {
-            Helpers::GetStack
-        } else if name.eq("skb_load_bytes_relative") {
-            Helpers::SkbLoadBytesRelative
-        } else if name.eq("fib_lookup") {
-            Helpers::FibLookup
-        } else if name.eq("sock_hash_update") {
-            Helpers::SockHashUpdate
-        } else if name.eq("msg_redirect_hash") {
-            Helpers::MsgRedirectHash
-        } else if name.eq("sk_redirect_hash") {
-            Helpers::SkRedirectHash
-        } else if name.eq("lwt_push_encap") {
-            Helpers::LwtPushEncap
-        } else if name.eq("lwt_seg6_store_bytes") {
-            Helpers::LwtSeg6StoreBytes
-        } else if name.eq("lwt_seg6_adjust_srh") {
-            Helpers::LwtSeg6AdjustSrh
-        } else if name.eq("lwt_seg6_action") {
-            Helpers::LwtSeg6Action
-        } else if name.eq("rc_repeat") {
-            Helpers::RcRepeat
-        } else if name.eq("rc_keydown") {
-            Helpers::RcKeydown
-        } else if name.eq("sk_select_reuseport") {
-            Helpers::SkSelectReuseport
-        } else if name.eq("sk_release") {
-            Helpers::SkRelease
-        } else if name.eq("map_push_elem") {
-            Helpers::MapPushElem
-        } else if name.eq("map_pop_elem") {
-            Helpers::MapPopElem
-        } else if name.eq("map_peek_elem") {
-            Helpers::MapPeekElem
-        } else if name.eq("msg_push_data") {
-            Helpers::MsgPushData
-        } else if name.eq("msg_pop_data") {
-            Helpers::MsgPopData
-        } else if name.eq("rc_pointer_rel") {
-            Helpers::RcPointerRel
-        } else if name.eq("spin_lock") {
-            Helpers::SpinLock
-        } else if name.eq("spin_unlock") {
-            Helpers::SpinUnlock
-        } else if name.eq("skb_ecn_set_ce") {
-            Helpers::SkbEcnSetCe
-        } else if name.eq("tcp_check_syncookie") {
-            Helpers::TcpCheckSyncookie
-        } else if name.eq("sysctl_get_name") {
-            Helpers::SysctlGetName
-        } else if name.eq("sysctl_get_current_value") {
-            Helpers::SysctlGetCurrentValue
-        } else if name.eq("sysctl_get_new_value") {
-            Helpers::SysctlGetNewValue
-        } else if name.eq("sysctl_set_new_value") {
-            Helpers::SysctlSetNewValue
-        } else if name.eq("strtol") {
-            Helpers::Strtol
-        } else if name.eq("strtoul") {
-            Helpers::Strtoul
-        } else if name.eq("sk_storage_delete") {
-            Helpers::SkStorageDelete
-        } else if name.eq("send_signal") {
-            Helpers::SendSignal
-        } else if name.eq("skb_output") {
-            Helpers::SkbOutput
-        } else if name.eq("probe_read_user") {
-            Helpers::ProbeReadUser
-        } else if name.eq("probe_read_kernel") {
-            Helpers::ProbeReadKernel
-        } else if name.eq("probe_read_user_str") {
-            Helpers::ProbeReadUserStr
-        } else if name.eq("probe_read_kernel_str") {
-            Helpers::ProbeReadKernelStr
-        } else if name.eq("tcp_send_ack") {
-            Helpers::TcpSendAck
-        } else if name.eq("send_signal_thread") {
-            Helpers::SendSignalThread
-        } else if name.eq("read_branch_records") {
-            Helpers::ReadBranchRecords
-        } else if name.eq("get_ns_current_pid_tgid") {
-            Helpers::GetNsCurrentPidTgid
-        } else if name.eq("xdp_output") {
-            Helpers::XdpOutput
-        } else if name.eq("sk_assign") {
-            Helpers::SkAssign
-        } else if name.eq("seq_printf") {
-            Helpers::SeqPrintf
-        } else if name.eq("seq_write") {
-            Helpers::SeqWrite
-        } else if name.eq("ringbuf_output") {
-            Helpers::RingbufOutput
-        } else if name.eq("csum_level") {
-            Helpers::CsumLevel
-        } else if name.eq("get_task_stack") {
-            Helpers::GetTaskStack
-        } else if name.eq("load_hdr_opt") {
-            Helpers::LoadHdrOpt
-        } else if name.eq("store_hdr_opt") {
-            Helpers::StoreHdrOpt
-        } else if name.eq("reserve_hdr_opt") {
-            Helpers::ReserveHdrOpt
-        } else if name.eq("d_path") {
-            Helpers::DPath
-        } else if name.eq("copy_from_user") {
-            Helpers::CopyFromUser
-        } else if name.eq("snprintf_btf") {
-            Helpers::SnprintfBtf
-        } else if name.eq("seq_printf_btf") {
-            Helpers::SeqPrintfBtf
-        } else if name.eq("redirect_neigh") {
-            Helpers::RedirectNeigh
-        } else if name.eq("redirect_peer") {
-            Helpers::RedirectPeer
-        } else if name.eq("task_storage_delete") {
-            Helpers::TaskStorageDelete
-        } else if name.eq("bprm_opts_set") {
-            Helpers::BprmOptsSet
-        } else if name.eq("ima_inode_hash") {
-            Helpers::ImaInodeHash
-        } else if name.eq("check_mtu") {
-            Helpers::CheckMtu
-        } else if name.eq("for_each_map_elem") {
-            Helpers::ForEachMapElem
-        } else if name.eq("snprintf") {
-            Helpers::Snprintf
-        } else {
-            return None;
-        })
+        HELPER_NAMES
+            .iter()
+            .find(|(helper_name, _)| *helper_name == name)
+            .map(|(_, helper)| *helper)
+    }
+
+    /// Returns a Helper from its id, i.e. the immediate of a call instruction.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of the helper function.
+    ///
+    /// # Examples
+    /// ```
+    /// use bpf_script::Helpers;
+    ///
+    /// assert_eq!(Helpers::from_id(14), Some(Helpers::GetCurrentPidTgid));
+    /// ```
+    pub fn from_id(id: u32) -> Option<Self> {
+        HELPER_NAMES
+            .iter()
+            .find(|(_, helper)| *helper as u32 == id)
+            .map(|(_, helper)| *helper)
+    }
+
+    /// Returns the name of the helper function, as accepted by `from_string`.
+    ///
+    /// # Examples
+    /// ```
+    /// use bpf_script::Helpers;
+    ///
+    /// assert_eq!(Helpers::GetCurrentPidTgid.name(), "get_current_pid_tgid");
+    /// ```
+    pub fn name(&self) -> &'static str {
+        HELPER_NAMES
+            .iter()
+            .find(|(_, helper)| helper == self)
+            .map(|(name, _)| *name)
+            .expect("every helper has a name")
+    }
+
+    /// Returns an iterator over the names of all known helper functions, as
+    /// accepted by `from_string`.
+    ///
+    /// # Examples
+    /// ```
+    /// use bpf_script::Helpers;
+    ///
+    /// assert!(Helpers::names().any(|name| name == "get_current_pid_tgid"));
+    /// ```
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        HELPER_NAMES.iter().map(|(name, _)| *name)
     }
 }
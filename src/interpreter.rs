@@ -0,0 +1,969 @@
+use crate::helpers::Helpers;
+
+use bpf_ins::Instruction;
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Size of the stack, R10 points to its end.
+pub const STACK_SIZE: usize = 512;
+
+/// The maximum number of nested calls to functions of the program, counting the
+/// program itself. Each of them gets a stack of its own.
+const MAX_CALL_FRAMES: usize = 8;
+
+/// Addresses of the memory regions the interpreter creates. They're far apart so
+/// out-of-bounds accesses don't land in another region.
+const CONTEXT_ADDRESS: u64 = 0x1000_0000;
+const STACK_ADDRESS: u64 = 0x2000_0000;
+const MAP_VALUE_ADDRESS: u64 = 0x3000_0000;
+const MAP_VALUE_ALIGN: u64 = 0x1000;
+
+/// Default number of instructions a program may execute before it's stopped.
+const DEFAULT_MAX_INSTRUCTIONS: usize = 1_000_000;
+
+/// Instruction classes, the lower 3 bits of the opcode.
+const CLASS_LD: u8 = 0x00;
+const CLASS_LDX: u8 = 0x01;
+const CLASS_ST: u8 = 0x02;
+const CLASS_STX: u8 = 0x03;
+const CLASS_ALU: u8 = 0x04;
+const CLASS_JMP: u8 = 0x05;
+const CLASS_JMP32: u8 = 0x06;
+const CLASS_ALU64: u8 = 0x07;
+
+/// Memory modes, the upper 3 bits of the opcode of memory instructions.
+const MODE_MEM: u8 = 0x60;
+const MODE_ATOMIC: u8 = 0xc0;
+
+/// Flag of atomic operations that return the old value in the source register.
+const ATOMIC_FETCH: i32 = 0x01;
+
+/// Source operand bit of ALU and jump instructions.
+const SOURCE_REGISTER: u8 = 0x08;
+
+/// Pseudo source register of 64-bit immediate loads that load a map.
+const PSEUDO_MAP_FD: u8 = 1;
+
+/// Pseudo source register of calls to functions of the program.
+const PSEUDO_CALL: u8 = 1;
+
+/// Map types that are arrays, whose elements always exist.
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+
+/// Flags of `map_update_elem`.
+const BPF_NOEXIST: u64 = 1;
+const BPF_EXIST: u64 = 2;
+
+/// Error numbers returned by helpers, negated.
+const ENOENT: i64 = 2;
+const E2BIG: i64 = 7;
+const EFAULT: i64 = 14;
+const EEXIST: i64 = 17;
+const EINVAL: i64 = 22;
+
+/// A mock implementation of a helper function. It's called with the interpreter's
+/// environment and the values of R1 to R5, and returns the value of R0.
+pub type HelperFn = Box<dyn FnMut(&mut Environment, [u64; 5]) -> u64>;
+
+/// Errors that can occur while interpreting a program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExecutionError {
+    /// An instruction isn't supported by the interpreter.
+    InvalidInstruction { pc: usize },
+    /// An instruction accessed memory outside of any region.
+    InvalidMemoryAccess {
+        pc: usize,
+        address: u64,
+        size: usize,
+    },
+    /// A jump landed outside of the program or in the middle of a wide instruction.
+    InvalidJump { pc: usize },
+    /// A helper was called that has no implementation.
+    MissingHelper { pc: usize, id: u32 },
+    /// A map was used that wasn't created with `create_map`.
+    UnknownMap { pc: usize, fd: u32 },
+    /// The program ran past its last instruction without exiting.
+    MissingExit,
+    /// The program executed too many instructions, see `set_max_instructions`.
+    TooManyInstructions { max: usize },
+    /// A call to a function of the program was nested too deeply.
+    CallStackOverflow { pc: usize },
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidInstruction { pc } => write!(f, "[{}] Unsupported instruction.", pc),
+            Self::InvalidMemoryAccess { pc, address, size } => write!(
+                f,
+                "[{}] Invalid memory access of {} bytes at {:#x}.",
+                pc, size, address
+            ),
+            Self::InvalidJump { pc } => write!(f, "[{}] Jump out of bounds.", pc),
+            Self::MissingHelper { pc, id } => match Helpers::from_id(*id) {
+                Some(helper) => write!(f, "[{}] No implementation of {:?}.", pc, helper),
+                None => write!(f, "[{}] Unknown helper {}.", pc, id),
+            },
+            Self::UnknownMap { pc, fd } => write!(f, "[{}] Unknown map {}.", pc, fd),
+            Self::MissingExit => write!(f, "Program ended without an exit."),
+            Self::TooManyInstructions { max } => {
+                write!(f, "Program executed more than {} instructions.", max)
+            }
+            Self::CallStackOverflow { pc } => write!(
+                f,
+                "[{}] Calls nested more than {} deep.",
+                pc, MAX_CALL_FRAMES
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+/// A contiguous block of memory the program can access.
+struct Region {
+    address: u64,
+    data: Vec<u8>,
+}
+
+/// An in-memory map. Values are stored in memory regions so that programs can
+/// modify them through the pointers returned by lookups.
+struct Map {
+    map_type: u32,
+    key_size: usize,
+    value_size: usize,
+    max_entries: u32,
+    entries: HashMap<Vec<u8>, u64>,
+}
+
+impl Map {
+    fn is_array(&self) -> bool {
+        matches!(
+            self.map_type,
+            BPF_MAP_TYPE_ARRAY | BPF_MAP_TYPE_PERCPU_ARRAY
+        )
+    }
+
+    /// Returns the index of an array map's element, if it's in bounds.
+    fn array_index(&self, key: &[u8]) -> Option<u32> {
+        let index = u32::from_ne_bytes(key.get(..4)?.try_into().ok()?);
+        (index < self.max_entries).then_some(index)
+    }
+}
+
+/// The memory and maps a program runs with, which helpers can access.
+pub struct Environment {
+    regions: Vec<Region>,
+    maps: HashMap<u32, Map>,
+    next_value_address: u64,
+}
+
+impl Environment {
+    fn new() -> Self {
+        Self {
+            regions: vec![],
+            maps: HashMap::new(),
+            next_value_address: MAP_VALUE_ADDRESS,
+        }
+    }
+
+    fn region(&self, address: u64, size: usize) -> Option<(usize, usize)> {
+        self.regions.iter().enumerate().find_map(|(i, region)| {
+            let offset = address.checked_sub(region.address)? as usize;
+            let end = offset.checked_add(size)?;
+            (end <= region.data.len()).then_some((i, offset))
+        })
+    }
+
+    fn add_region(&mut self, address: u64, data: Vec<u8>) {
+        self.regions.retain(|region| region.address != address);
+        self.regions.push(Region { address, data });
+    }
+
+    /// Reads memory, returns `None` if any of it isn't mapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address to read from.
+    /// * `size` - The number of bytes to read.
+    pub fn read(&self, address: u64, size: usize) -> Option<&[u8]> {
+        let (i, offset) = self.region(address, size)?;
+        Some(&self.regions[i].data[offset..offset + size])
+    }
+
+    /// Writes memory, returns `None` if any of it isn't mapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address to write to.
+    /// * `bytes` - The bytes to write.
+    pub fn write(&mut self, address: u64, bytes: &[u8]) -> Option<()> {
+        let (i, offset) = self.region(address, bytes.len())?;
+        self.regions[i].data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Some(())
+    }
+
+    fn map(&self, fd: u32) -> Option<&Map> {
+        self.maps.get(&fd)
+    }
+
+    fn allocate_value(&mut self, value: &[u8]) -> u64 {
+        let address = self.next_value_address;
+        let size = (value.len() as u64).max(1);
+        self.next_value_address += size.div_ceil(MAP_VALUE_ALIGN) * MAP_VALUE_ALIGN;
+        self.add_region(address, value.to_vec());
+        address
+    }
+
+    /// Looks up an element of a map and returns the address of its value, like
+    /// `map_lookup_elem`. Returns `None` if the map or element doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor the map was created with.
+    /// * `key` - The key of the element.
+    pub fn map_lookup(&mut self, fd: u32, key: &[u8]) -> Option<u64> {
+        let map = self.map(fd)?;
+        if let Some(address) = map.entries.get(key) {
+            return Some(*address);
+        }
+
+        /*
+         * Elements of arrays always exist, they're created on first use.
+         */
+        map.array_index(key).filter(|_| map.is_array())?;
+        let value = vec![0; map.value_size];
+        let address = self.allocate_value(&value);
+        self.maps
+            .get_mut(&fd)?
+            .entries
+            .insert(key.to_vec(), address);
+        Some(address)
+    }
+
+    /// Reads the value of an element of a map, returns `None` if the map or element
+    /// doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor the map was created with.
+    /// * `key` - The key of the element.
+    pub fn map_value(&self, fd: u32, key: &[u8]) -> Option<&[u8]> {
+        let map = self.map(fd)?;
+        let address = *map.entries.get(key)?;
+        self.read(address, map.value_size)
+    }
+
+    /// Creates or updates an element of a map, like `map_update_elem`. Returns 0 on
+    /// success or a negative error number.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor the map was created with.
+    /// * `key` - The key of the element.
+    /// * `value` - The value of the element.
+    /// * `flags` - `BPF_ANY` (0), `BPF_NOEXIST` (1) or `BPF_EXIST` (2).
+    pub fn map_update(&mut self, fd: u32, key: &[u8], value: &[u8], flags: u64) -> i64 {
+        let map = match self.map(fd) {
+            Some(map) => map,
+            None => return -EINVAL,
+        };
+
+        if key.len() != map.key_size || value.len() != map.value_size || flags > BPF_EXIST {
+            return -EINVAL;
+        }
+
+        let existing = map.entries.get(key).copied();
+        if map.is_array() {
+            if map.array_index(key).is_none() {
+                return -E2BIG;
+            }
+            if flags == BPF_NOEXIST {
+                return -EEXIST;
+            }
+        } else {
+            match (existing, flags) {
+                (Some(_), BPF_NOEXIST) => return -EEXIST,
+                (None, BPF_EXIST) => return -ENOENT,
+                (None, _) if map.entries.len() >= map.max_entries as usize => return -E2BIG,
+                _ => {}
+            }
+        }
+
+        match existing {
+            Some(address) => {
+                self.write(address, value);
+            }
+            None => {
+                let address = self.allocate_value(value);
+                if let Some(map) = self.maps.get_mut(&fd) {
+                    map.entries.insert(key.to_vec(), address);
+                }
+            }
+        }
+        0
+    }
+
+    /// Deletes an element of a map, like `map_delete_elem`. Returns 0 on success or
+    /// a negative error number.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor the map was created with.
+    /// * `key` - The key of the element.
+    pub fn map_delete(&mut self, fd: u32, key: &[u8]) -> i64 {
+        let map = match self.maps.get_mut(&fd) {
+            Some(map) => map,
+            None => return -EINVAL,
+        };
+
+        if map.is_array() {
+            return -EINVAL;
+        }
+
+        match map.entries.remove(key) {
+            Some(address) => {
+                self.regions.retain(|region| region.address != address);
+                0
+            }
+            None => -ENOENT,
+        }
+    }
+
+    /// Copies memory for the `probe_read` family of helpers. On failure the
+    /// destination is zeroed, like the kernel does.
+    fn probe_read(&mut self, dst: u64, size: u64, src: u64) -> u64 {
+        let size = size as usize;
+        match self.read(src, size).map(|data| data.to_vec()) {
+            Some(data) => match self.write(dst, &data) {
+                Some(()) => 0,
+                None => -EFAULT as u64,
+            },
+            None => {
+                self.write(dst, &vec![0; size]);
+                -EFAULT as u64
+            }
+        }
+    }
+
+    /// Copies a NUL-terminated string for the `probe_read_str` family of helpers.
+    /// Returns the length of the string including the NUL.
+    fn probe_read_str(&mut self, dst: u64, size: u64, src: u64) -> u64 {
+        let size = size as usize;
+        if size == 0 {
+            return 0;
+        }
+
+        let mut string = vec![];
+        while string.len() < size - 1 {
+            match self.read(src + string.len() as u64, 1) {
+                Some([0]) => break,
+                Some(&[c]) => string.push(c),
+                _ if string.is_empty() => {
+                    self.write(dst, &vec![0; size]);
+                    return -EFAULT as u64;
+                }
+                _ => break,
+            }
+        }
+        string.push(0);
+
+        match self.write(dst, &string) {
+            Some(()) => string.len() as u64,
+            None => -EFAULT as u64,
+        }
+    }
+}
+
+/// Reads a key or value of a map from the environment's memory for a helper.
+fn map_buffer(env: &Environment, fd: u64, address: u64, value: bool) -> Option<Vec<u8>> {
+    let map = env.map(fd as u32)?;
+    let size = if value { map.value_size } else { map.key_size };
+    env.read(address, size).map(|data| data.to_vec())
+}
+
+/// The default mock implementations: map helpers over the interpreter's maps and
+/// the `probe_read` family over its memory.
+fn default_helpers() -> HashMap<u32, HelperFn> {
+    let mut helpers: HashMap<u32, HelperFn> = HashMap::new();
+    helpers.insert(
+        Helpers::MapLookupElem as u32,
+        Box::new(|env, args| {
+            map_buffer(env, args[0], args[1], false)
+                .and_then(|key| env.map_lookup(args[0] as u32, &key))
+                .unwrap_or(0)
+        }),
+    );
+    helpers.insert(
+        Helpers::MapUpdateElem as u32,
+        Box::new(|env, args| {
+            let key = map_buffer(env, args[0], args[1], false);
+            let value = map_buffer(env, args[0], args[2], true);
+            match (key, value) {
+                (Some(key), Some(value)) => {
+                    env.map_update(args[0] as u32, &key, &value, args[3]) as u64
+                }
+                _ => -EFAULT as u64,
+            }
+        }),
+    );
+    helpers.insert(
+        Helpers::MapDeleteElem as u32,
+        Box::new(|env, args| match map_buffer(env, args[0], args[1], false) {
+            Some(key) => env.map_delete(args[0] as u32, &key) as u64,
+            None => -EFAULT as u64,
+        }),
+    );
+    for helper in [
+        Helpers::ProbeRead,
+        Helpers::ProbeReadKernel,
+        Helpers::ProbeReadUser,
+    ] {
+        helpers.insert(
+            helper as u32,
+            Box::new(|env, args| env.probe_read(args[0], args[1], args[2])),
+        );
+    }
+    for helper in [
+        Helpers::ProbeReadStr,
+        Helpers::ProbeReadKernelStr,
+        Helpers::ProbeReadUserStr,
+    ] {
+        helpers.insert(
+            helper as u32,
+            Box::new(|env, args| env.probe_read_str(args[0], args[1], args[2])),
+        );
+    }
+    helpers
+}
+
+/// Executes BPF programs in userspace, so compiled scripts can be tested without
+/// loading them into a kernel. Programs run with a 512-byte stack at R10, and
+/// helper functions are replaced by mocks: maps and the `probe_read` family are
+/// implemented over in-memory maps and memory added with `add_memory`, other
+/// helpers can be provided with `set_helper`.
+pub struct Interpreter {
+    env: Environment,
+    helpers: HashMap<u32, HelperFn>,
+    max_instructions: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    /// Create a new interpreter with the default helper mocks.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Compiler, Interpreter};
+    /// use btf::BtfTypes;
+    ///
+    /// let btf = BtfTypes::default();
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         a = 20
+    ///         return a + 30
+    /// "#).expect("Failed to compile.");
+    ///
+    /// let mut interpreter = Interpreter::new();
+    /// let result = interpreter.run(compiler.get_instructions(), &[]);
+    /// assert_eq!(result, Ok(50));
+    /// ```
+    pub fn new() -> Self {
+        Self {
+            env: Environment::new(),
+            helpers: default_helpers(),
+            max_instructions: DEFAULT_MAX_INSTRUCTIONS,
+        }
+    }
+
+    /// Provides the implementation of a helper function, replacing the default
+    /// mock if there is one.
+    ///
+    /// # Arguments
+    ///
+    /// * `helper` - The helper function to implement.
+    /// * `function` - Called with the environment and the values of R1 to R5, and
+    ///   returns the value of R0.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Compiler, Helpers, Interpreter};
+    /// use btf::BtfTypes;
+    ///
+    /// let btf = BtfTypes::default();
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return get_current_pid_tgid()
+    /// "#).expect("Failed to compile.");
+    ///
+    /// let mut interpreter = Interpreter::new();
+    /// interpreter.set_helper(Helpers::GetCurrentPidTgid, |_, _| 1234 << 32 | 1234);
+    /// let result = interpreter.run(compiler.get_instructions(), &[]);
+    /// assert_eq!(result, Ok(1234 << 32 | 1234));
+    /// ```
+    pub fn set_helper<F>(&mut self, helper: Helpers, function: F)
+    where
+        F: FnMut(&mut Environment, [u64; 5]) -> u64 + 'static,
+    {
+        self.helpers.insert(helper as u32, Box::new(function));
+    }
+
+    /// Sets the maximum number of instructions a program may execute, after which
+    /// it's stopped with an error. This catches programs that never terminate.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - The maximum number of instructions.
+    pub fn set_max_instructions(&mut self, max: usize) {
+        self.max_instructions = max;
+    }
+
+    /// Adds memory that programs can read and write, e.g. kernel structs read with
+    /// `probe_read_kernel`. Replaces any memory previously added at the same address.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address of the memory.
+    /// * `bytes` - The initial contents of the memory.
+    pub fn add_memory(&mut self, address: u64, bytes: &[u8]) {
+        self.env.add_region(address, bytes.to_vec());
+    }
+
+    /// Creates an in-memory map that programs can access with the map helpers.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor the map was captured with.
+    /// * `map_type` - The BPF map type, arrays (2 and 6) have all their elements
+    ///   from the start, every other type behaves like a hash map.
+    /// * `key_size` - The size of the map's keys.
+    /// * `value_size` - The size of the map's values.
+    /// * `max_entries` - The maximum number of elements.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Compiler, Interpreter};
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.capture("counts", 3);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         map counts: array<u32, u32>[1]
+    ///         counts[0] += 1
+    /// "#).expect("Failed to compile.");
+    ///
+    /// let mut interpreter = Interpreter::new();
+    /// interpreter.create_map(3, 2, 4, 4, 1);
+    /// for _ in 0..3 {
+    ///     interpreter.run(compiler.get_instructions(), &[]).expect("Failed to run.");
+    /// }
+    /// let value = interpreter.map_value(3, &0u32.to_ne_bytes());
+    /// assert_eq!(value, Some(&3u32.to_ne_bytes()[..]));
+    /// ```
+    pub fn create_map(
+        &mut self,
+        fd: u32,
+        map_type: u32,
+        key_size: usize,
+        value_size: usize,
+        max_entries: u32,
+    ) {
+        self.env.maps.insert(
+            fd,
+            Map {
+                map_type,
+                key_size,
+                value_size,
+                max_entries,
+                entries: HashMap::new(),
+            },
+        );
+    }
+
+    /// Returns the value of an element of a map, if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor the map was created with.
+    /// * `key` - The key of the element.
+    pub fn map_value(&self, fd: u32, key: &[u8]) -> Option<&[u8]> {
+        self.env.map_value(fd, key)
+    }
+
+    /// Returns the environment programs run with, e.g. to inspect memory.
+    pub fn environment(&mut self) -> &mut Environment {
+        &mut self.env
+    }
+
+    /// Runs a program with a context, which R1 points to, and returns the value it
+    /// exits with. Maps and memory added to the interpreter persist across runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `instructions` - The program.
+    /// * `context` - The contents of the context, e.g. a `struct pt_regs`.
+    pub fn run(
+        &mut self,
+        instructions: &[Instruction],
+        context: &[u8],
+    ) -> Result<u64, ExecutionError> {
+        self.env.add_region(CONTEXT_ADDRESS, context.to_vec());
+        self.execute(instructions, [CONTEXT_ADDRESS, 0, 0, 0, 0])
+    }
+
+    /// Runs a program with its arguments in R1 to R5, like scripts declared with
+    /// arguments are called, and returns the value it exits with.
+    ///
+    /// # Arguments
+    ///
+    /// * `instructions` - The program.
+    /// * `args` - The values of R1 to R5, missing ones are zero.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Compiler, Interpreter};
+    /// use btf::BtfTypes;
+    ///
+    /// let mut btf = BtfTypes::default();
+    /// btf.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+    /// let mut compiler = Compiler::create(&btf);
+    /// compiler.compile(r#"
+    ///     fn(a: u32, b: u32)
+    ///         return a * b
+    /// "#).expect("Failed to compile.");
+    ///
+    /// let mut interpreter = Interpreter::new();
+    /// let result = interpreter.run_with_args(compiler.get_instructions(), &[6, 7]);
+    /// assert_eq!(result, Ok(42));
+    /// ```
+    pub fn run_with_args(
+        &mut self,
+        instructions: &[Instruction],
+        args: &[u64],
+    ) -> Result<u64, ExecutionError> {
+        let mut regs = [0; 5];
+        for (reg, arg) in regs.iter_mut().zip(args) {
+            *reg = *arg;
+        }
+        self.execute(instructions, regs)
+    }
+
+    fn execute(
+        &mut self,
+        instructions: &[Instruction],
+        args: [u64; 5],
+    ) -> Result<u64, ExecutionError> {
+        /*
+         * Jumps are counted in slots, wide instructions take up two of them.
+         */
+        let mut slots = vec![];
+        for (i, ins) in instructions.iter().enumerate() {
+            slots.push(Some(i));
+            if ins.is_wide() {
+                slots.push(None);
+            }
+        }
+
+        /*
+         * Every call frame gets its own stack below the caller's, the callee-saved
+         * registers (R6 to R10) are restored when the function returns.
+         */
+        let stack_size = STACK_SIZE * MAX_CALL_FRAMES;
+        self.env.add_region(STACK_ADDRESS, vec![0; stack_size]);
+        let mut regs = [0u64; 11];
+        regs[1..6].copy_from_slice(&args);
+        regs[10] = STACK_ADDRESS + stack_size as u64;
+        let mut frames: Vec<(usize, [u64; 5])> = vec![];
+
+        let mut pc = 0;
+        for _ in 0..self.max_instructions {
+            let ins = match slots.get(pc) {
+                Some(Some(i)) => &instructions[*i],
+                Some(None) => return Err(ExecutionError::InvalidJump { pc }),
+                None => return Err(ExecutionError::MissingExit),
+            };
+
+            let (raw, wide) = ins.encode();
+            let opcode = raw as u8;
+            let dst = ((raw >> 8) & 0xf) as usize;
+            let src = ((raw >> 12) & 0xf) as usize;
+            let offset = (raw >> 16) as u16 as i16;
+            let imm = (raw >> 32) as u32 as i32;
+            if dst > 10 || src > 10 {
+                return Err(ExecutionError::InvalidInstruction { pc });
+            }
+
+            let invalid = || ExecutionError::InvalidInstruction { pc };
+            let address = regs[src].wrapping_add(offset as i64 as u64);
+            let size = match opcode & 0x18 {
+                0x00 => 4,
+                0x08 => 2,
+                0x10 => 1,
+                _ => 8,
+            };
+
+            let mut next = pc + 1;
+            match opcode & 0x07 {
+                CLASS_LD if opcode == 0x18 => {
+                    let value = (imm as u32 as u64) | (wide.unwrap_or(0) & 0xffff_ffff_0000_0000);
+                    if src as u8 != 0 && src as u8 != PSEUDO_MAP_FD {
+                        return Err(invalid());
+                    }
+                    if src as u8 == PSEUDO_MAP_FD && self.env.map(value as u32).is_none() {
+                        return Err(ExecutionError::UnknownMap {
+                            pc,
+                            fd: value as u32,
+                        });
+                    }
+                    regs[dst] = value;
+                    next = pc + 2;
+                }
+                CLASS_LDX if opcode & 0xe0 == MODE_MEM => {
+                    regs[dst] = self.load(pc, address, size)?;
+                }
+                CLASS_ST if opcode & 0xe0 == MODE_MEM => {
+                    let address = regs[dst].wrapping_add(offset as i64 as u64);
+                    self.store(pc, address, size, imm as i64 as u64)?;
+                }
+                CLASS_STX if opcode & 0xe0 == MODE_MEM => {
+                    let address = regs[dst].wrapping_add(offset as i64 as u64);
+                    self.store(pc, address, size, regs[src])?;
+                }
+                CLASS_STX if opcode & 0xe0 == MODE_ATOMIC && (size == 4 || size == 8) => {
+                    let address = regs[dst].wrapping_add(offset as i64 as u64);
+                    let old = self.load(pc, address, size)?;
+                    let new = match imm & !ATOMIC_FETCH {
+                        0x00 => old.wrapping_add(regs[src]),
+                        0x40 => old | regs[src],
+                        0x50 => old & regs[src],
+                        0xa0 => old ^ regs[src],
+                        _ => return Err(invalid()),
+                    };
+                    self.store(pc, address, size, new)?;
+                    if imm & ATOMIC_FETCH != 0 {
+                        regs[src] = old;
+                    }
+                }
+                CLASS_ALU | CLASS_ALU64 => {
+                    regs[dst] = evaluate_alu(ins, regs[dst], regs[src]).ok_or_else(invalid)?;
+                }
+                class @ (CLASS_JMP | CLASS_JMP32) => match opcode & 0xf0 {
+                    0x90 if class == CLASS_JMP => match frames.pop() {
+                        Some((ret, saved)) => {
+                            regs[6..].copy_from_slice(&saved);
+                            next = ret;
+                        }
+                        None => return Ok(regs[0]),
+                    },
+                    0x80 if class == CLASS_JMP && src as u8 == PSEUDO_CALL => {
+                        if frames.len() + 1 >= MAX_CALL_FRAMES {
+                            return Err(ExecutionError::CallStackOverflow { pc });
+                        }
+
+                        next = (pc as isize + 1 + imm as isize) as usize;
+                        if next >= slots.len() || slots[next].is_none() {
+                            return Err(ExecutionError::InvalidJump { pc });
+                        }
+
+                        let mut saved = [0; 5];
+                        saved.copy_from_slice(&regs[6..]);
+                        frames.push((pc + 1, saved));
+                        regs[10] -= STACK_SIZE as u64;
+                    }
+                    0x80 if class == CLASS_JMP && src == 0 => {
+                        let id = imm as u32;
+                        let helper = self
+                            .helpers
+                            .get_mut(&id)
+                            .ok_or(ExecutionError::MissingHelper { pc, id })?;
+                        let args = [regs[1], regs[2], regs[3], regs[4], regs[5]];
+                        regs[0] = helper(&mut self.env, args);
+                    }
+                    _ => {
+                        let taken = evaluate_jump(ins, regs[dst], regs[src]).ok_or_else(invalid)?;
+                        if taken {
+                            next = (pc as isize + 1 + offset as isize) as usize;
+                            if next >= slots.len() || slots[next].is_none() {
+                                return Err(ExecutionError::InvalidJump { pc });
+                            }
+                        }
+                    }
+                },
+                _ => return Err(invalid()),
+            }
+
+            pc = next;
+        }
+
+        Err(ExecutionError::TooManyInstructions {
+            max: self.max_instructions,
+        })
+    }
+
+    fn load(&self, pc: usize, address: u64, size: usize) -> Result<u64, ExecutionError> {
+        let data = self
+            .env
+            .read(address, size)
+            .ok_or(ExecutionError::InvalidMemoryAccess { pc, address, size })?;
+        let mut bytes = [0; 8];
+        bytes[..size].copy_from_slice(data);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn store(
+        &mut self,
+        pc: usize,
+        address: u64,
+        size: usize,
+        value: u64,
+    ) -> Result<(), ExecutionError> {
+        self.env
+            .write(address, &value.to_le_bytes()[..size])
+            .ok_or(ExecutionError::InvalidMemoryAccess { pc, address, size })
+    }
+}
+
+/// Evaluates an ALU instruction the way the interpreter executes it, given the
+/// values of its destination and source registers. This is also used to fold
+/// constants at compile time.
+pub(crate) fn evaluate_alu(ins: &Instruction, dst: u64, src: u64) -> Option<u64> {
+    let (raw, _) = ins.encode();
+    let opcode = raw as u8;
+    let offset = (raw >> 16) as u16 as i16;
+    let imm = (raw >> 32) as u32 as i32;
+    let src = if opcode & SOURCE_REGISTER != 0 {
+        src
+    } else {
+        imm as i64 as u64
+    };
+
+    match opcode & 0x07 {
+        CLASS_ALU64 => alu64(opcode & 0xf0, dst, src, offset, imm),
+        CLASS_ALU => alu32(opcode & 0xf0, dst as u32, src as u32, offset, imm).map(u64::from),
+        _ => None,
+    }
+}
+
+/// Evaluates whether a conditional jump is taken, given the values of the
+/// registers it compares, see `evaluate_alu`.
+pub(crate) fn evaluate_jump(ins: &Instruction, dst: u64, src: u64) -> Option<bool> {
+    let (raw, _) = ins.encode();
+    let opcode = raw as u8;
+    let imm = (raw >> 32) as u32 as i32;
+    let src = if opcode & SOURCE_REGISTER != 0 {
+        src
+    } else {
+        imm as i64 as u64
+    };
+
+    match opcode & 0x07 {
+        CLASS_JMP => compare(opcode & 0xf0, dst, src),
+        CLASS_JMP32 => compare32(opcode & 0xf0, dst as u32, src as u32),
+        _ => None,
+    }
+}
+
+/// Performs a 64-bit ALU operation. Division by zero results in zero and modulo
+/// by zero leaves the destination unchanged, like in the kernel. Signed division
+/// and modulo have an offset of 1.
+fn alu64(op: u8, dst: u64, src: u64, offset: i16, imm: i32) -> Option<u64> {
+    let signed = offset == 1;
+    Some(match op {
+        0x00 => dst.wrapping_add(src),
+        0x10 => dst.wrapping_sub(src),
+        0x20 => dst.wrapping_mul(src),
+        0x30 if src == 0 => 0,
+        0x30 if signed => (dst as i64).wrapping_div(src as i64) as u64,
+        0x30 => dst / src,
+        0x40 => dst | src,
+        0x50 => dst & src,
+        0x60 => dst.wrapping_shl(src as u32 & 63),
+        0x70 => dst.wrapping_shr(src as u32 & 63),
+        0x80 => (dst as i64).wrapping_neg() as u64,
+        0x90 if src == 0 => dst,
+        0x90 if signed => (dst as i64).wrapping_rem(src as i64) as u64,
+        0x90 => dst % src,
+        0xa0 => dst ^ src,
+        0xb0 => src,
+        0xc0 => (dst as i64).wrapping_shr(src as u32 & 63) as u64,
+        0xd0 => match imm {
+            16 => (dst as u16).swap_bytes() as u64,
+            32 => (dst as u32).swap_bytes() as u64,
+            64 => dst.swap_bytes(),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+/// Performs a 32-bit ALU operation, see `alu64`.
+fn alu32(op: u8, dst: u32, src: u32, offset: i16, imm: i32) -> Option<u32> {
+    let signed = offset == 1;
+    Some(match op {
+        0x00 => dst.wrapping_add(src),
+        0x10 => dst.wrapping_sub(src),
+        0x20 => dst.wrapping_mul(src),
+        0x30 if src == 0 => 0,
+        0x30 if signed => (dst as i32).wrapping_div(src as i32) as u32,
+        0x30 => dst / src,
+        0x40 => dst | src,
+        0x50 => dst & src,
+        0x60 => dst.wrapping_shl(src & 31),
+        0x70 => dst.wrapping_shr(src & 31),
+        0x80 => (dst as i32).wrapping_neg() as u32,
+        0x90 if src == 0 => dst,
+        0x90 if signed => (dst as i32).wrapping_rem(src as i32) as u32,
+        0x90 => dst % src,
+        0xa0 => dst ^ src,
+        0xb0 => src,
+        0xc0 => (dst as i32).wrapping_shr(src & 31) as u32,
+        0xd0 => match imm {
+            16 => (dst as u16).swap_bytes() as u32,
+            32 => dst.swap_bytes(),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+/// Evaluates the condition of a 64-bit jump.
+fn compare(op: u8, dst: u64, src: u64) -> Option<bool> {
+    Some(match op {
+        0x00 => true,
+        0x10 => dst == src,
+        0x20 => dst > src,
+        0x30 => dst >= src,
+        0x40 => dst & src != 0,
+        0x50 => dst != src,
+        0x60 => (dst as i64) > (src as i64),
+        0x70 => (dst as i64) >= (src as i64),
+        0xa0 => dst < src,
+        0xb0 => dst <= src,
+        0xc0 => (dst as i64) < (src as i64),
+        0xd0 => (dst as i64) <= (src as i64),
+        _ => return None,
+    })
+}
+
+/// Evaluates the condition of a 32-bit jump.
+fn compare32(op: u8, dst: u32, src: u32) -> Option<bool> {
+    match op {
+        0x60 => Some((dst as i32) > (src as i32)),
+        0x70 => Some((dst as i32) >= (src as i32)),
+        0xc0 => Some((dst as i32) < (src as i32)),
+        0xd0 => Some((dst as i32) <= (src as i32)),
+        _ => compare(op, dst.into(), src.into()),
+    }
+}
@@ -0,0 +1,876 @@
+//! The compiler's intermediate representation. Scripts are lowered from their
+//! syntax tree into a function made of basic blocks of three-address instructions
+//! on BPF registers, which is then laid out and lowered into BPF instructions.
+//!
+//! Blocks are stored in the order they're laid out in, and end in a terminator
+//! (a jump, a branch or an exit). Jumps to the block that follows are free, they
+//! disappear when the function is lowered.
+
+use crate::atomic::atomic;
+use crate::helpers::Helpers;
+use crate::jump;
+
+use bpf_ins::{
+    ArithmeticOperation, Instruction, JumpOperation, MemoryOpLoadType, MemoryOpSize, Register,
+};
+
+use std::fmt;
+use std::ops::Range;
+
+/// Identifies a basic block by its index in `Function::blocks`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+/// An operand of an instruction, either a register or an immediate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    Register(Register),
+    Immediate(i64),
+}
+
+impl From<Register> for Value {
+    fn from(reg: Register) -> Self {
+        Self::Register(reg)
+    }
+}
+
+impl From<i8> for Value {
+    fn from(imm: i8) -> Self {
+        Self::Immediate(imm.into())
+    }
+}
+
+impl From<i16> for Value {
+    fn from(imm: i16) -> Self {
+        Self::Immediate(imm.into())
+    }
+}
+
+impl From<i32> for Value {
+    fn from(imm: i32) -> Self {
+        Self::Immediate(imm.into())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(imm: i64) -> Self {
+        Self::Immediate(imm)
+    }
+}
+
+/// The width of a move or arithmetic operation. 32-bit operations zero the upper
+/// half of their destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    Bits32,
+    Bits64,
+}
+
+/// The operation performed by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// `dst = src`. 64-bit immediates that don't fit in 32 bits are loaded with the
+    /// two-slot `lddw`.
+    Move {
+        width: Width,
+        dst: Register,
+        src: Value,
+    },
+    /// `dst = lhs <op> rhs`, where immediates must fit in 32 bits. `signed` selects
+    /// signed division and modulo, `Neg` ignores `rhs`. `rhs` can only be `dst`
+    /// when `lhs` is too, or the operation is commutative.
+    Arithmetic {
+        op: ArithmeticOperation,
+        width: Width,
+        signed: bool,
+        dst: Register,
+        lhs: Register,
+        rhs: Value,
+    },
+    /// `dst = *(size *)(base + offset)`
+    Load {
+        size: MemoryOpSize,
+        dst: Register,
+        base: Register,
+        offset: i16,
+    },
+    /// `*(size *)(base + offset) = src`, where immediates must fit in 32 bits.
+    Store {
+        size: MemoryOpSize,
+        base: Register,
+        offset: i16,
+        src: Value,
+    },
+    /// `lock *(size *)(base + offset) <op>= src`, only add, or, and and xor of
+    /// words and double words exist.
+    Atomic {
+        op: ArithmeticOperation,
+        size: MemoryOpSize,
+        base: Register,
+        offset: i16,
+        src: Register,
+    },
+    /// `dst = imm` with `lddw`, where the load type says what the immediate refers
+    /// to, e.g. the file descriptor of a map.
+    LoadImmediate {
+        dst: Register,
+        imm: i64,
+        load_type: MemoryOpLoadType,
+    },
+    /// Calls a helper function by id, with arguments in R1-R5. The result is
+    /// returned in R0 and R1-R5 are clobbered.
+    Call { helper: u32 },
+    /// Calls another function of the program by index, with arguments in R1-R5.
+    /// The result is returned in R0 and R1-R5 are clobbered, the callee has its own
+    /// stack frame. It's lowered with the index in place of the offset, which is
+    /// fixed up once the functions are laid out.
+    CallLocal { function: u32 },
+    /// Continues at another block.
+    Jump { target: BlockId },
+    /// `if lhs <op> rhs goto then else goto otherwise`, where immediates must fit
+    /// in 32 bits.
+    Branch {
+        op: JumpOperation,
+        lhs: Register,
+        rhs: Value,
+        then: BlockId,
+        otherwise: BlockId,
+    },
+    /// Returns from the program with the value in R0.
+    Exit,
+}
+
+impl Op {
+    /// Creates a 64-bit move: `dst = src`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - The register to set.
+    /// * `src` - The register or immediate to set it to.
+    pub fn mov64(dst: Register, src: impl Into<Value>) -> Self {
+        Self::Move {
+            width: Width::Bits64,
+            dst,
+            src: src.into(),
+        }
+    }
+
+    /// Creates a 32-bit move, which zero-extends the value: `dst = (u32)src`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - The register to set.
+    /// * `src` - The register or immediate to set it to.
+    pub fn mov32(dst: Register, src: impl Into<Value>) -> Self {
+        Self::Move {
+            width: Width::Bits32,
+            dst,
+            src: src.into(),
+        }
+    }
+
+    /// Creates an arithmetic operation that updates a register in place:
+    /// `dst <op>= src`.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The operation to perform.
+    /// * `width` - Whether it's a 32 or 64-bit operation.
+    /// * `dst` - The register to update.
+    /// * `src` - The register or immediate operand.
+    pub fn alu(
+        op: ArithmeticOperation,
+        width: Width,
+        dst: Register,
+        src: impl Into<Value>,
+    ) -> Self {
+        Self::Arithmetic {
+            op,
+            width,
+            signed: false,
+            dst,
+            lhs: dst,
+            rhs: src.into(),
+        }
+    }
+
+    /// Creates a 64-bit addition: `dst += src`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - The register to update.
+    /// * `src` - The register or immediate to add.
+    pub fn add64(dst: Register, src: impl Into<Value>) -> Self {
+        Self::alu(ArithmeticOperation::Add, Width::Bits64, dst, src)
+    }
+
+    /// Creates a load from memory: `dst = *(size *)(base + offset)`.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the value to load.
+    /// * `dst` - The register to load the value into.
+    /// * `base` - The register holding the address.
+    /// * `offset` - The offset from the address.
+    pub fn load(size: MemoryOpSize, dst: Register, base: Register, offset: i16) -> Self {
+        Self::Load {
+            size,
+            dst,
+            base,
+            offset,
+        }
+    }
+
+    /// Creates a store to memory: `*(size *)(base + offset) = src`.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the value to store.
+    /// * `base` - The register holding the address.
+    /// * `offset` - The offset from the address.
+    /// * `src` - The register or immediate to store.
+    pub fn store(size: MemoryOpSize, base: Register, offset: i16, src: impl Into<Value>) -> Self {
+        Self::Store {
+            size,
+            base,
+            offset,
+            src: src.into(),
+        }
+    }
+
+    /// Creates a call to a helper function.
+    ///
+    /// # Arguments
+    ///
+    /// * `helper` - The helper to call.
+    pub fn call(helper: Helpers) -> Self {
+        Self::Call {
+            helper: helper as u32,
+        }
+    }
+
+    /// Returns whether the operation ends a block.
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Self::Jump { .. } | Self::Branch { .. } | Self::Exit)
+    }
+
+    /// Returns the blocks control can continue at after a terminator.
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Self::Jump { target } => vec![*target],
+            Self::Branch {
+                then, otherwise, ..
+            } => vec![*then, *otherwise],
+            _ => vec![],
+        }
+    }
+
+    /// Returns the registers the operation reads.
+    pub fn uses(&self) -> Vec<Register> {
+        let value = |value: &Value| match value {
+            Value::Register(reg) => Some(*reg),
+            Value::Immediate(_) => None,
+        };
+
+        match self {
+            Self::Move { src, .. } => value(src).into_iter().collect(),
+            Self::Arithmetic {
+                op: ArithmeticOperation::Neg,
+                lhs,
+                ..
+            } => vec![*lhs],
+            Self::Arithmetic { lhs, rhs, .. } => {
+                [Some(*lhs), value(rhs)].into_iter().flatten().collect()
+            }
+            Self::Load { base, .. } => vec![*base],
+            Self::Store { base, src, .. } => {
+                [Some(*base), value(src)].into_iter().flatten().collect()
+            }
+            Self::Atomic { base, src, .. } => vec![*base, *src],
+            Self::LoadImmediate { .. } | Self::Jump { .. } => vec![],
+            Self::Call { .. } | Self::CallLocal { .. } => ARGUMENT_REGISTERS.to_vec(),
+            Self::Branch { lhs, rhs, .. } => {
+                [Some(*lhs), value(rhs)].into_iter().flatten().collect()
+            }
+            Self::Exit => vec![Register::R0],
+        }
+    }
+
+    /// Returns the registers the operation writes, including the ones clobbered by
+    /// calls.
+    pub fn defs(&self) -> Vec<Register> {
+        match self {
+            Self::Move { dst, .. }
+            | Self::Arithmetic { dst, .. }
+            | Self::Load { dst, .. }
+            | Self::LoadImmediate { dst, .. } => vec![*dst],
+            Self::Call { .. } | Self::CallLocal { .. } => {
+                [&[Register::R0], &ARGUMENT_REGISTERS[..]].concat()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Returns whether the operation does more than set its destination register,
+    /// i.e. whether it has to be kept even when the register is never read.
+    pub fn has_side_effects(&self) -> bool {
+        matches!(
+            self,
+            Self::Store { .. }
+                | Self::Atomic { .. }
+                | Self::Call { .. }
+                | Self::CallLocal { .. }
+                | Self::Jump { .. }
+                | Self::Branch { .. }
+                | Self::Exit
+        )
+    }
+}
+
+/// The registers helper arguments are passed in.
+const ARGUMENT_REGISTERS: [Register; 5] = [
+    Register::R1,
+    Register::R2,
+    Register::R3,
+    Register::R4,
+    Register::R5,
+];
+
+/// An instruction of the IR: an operation along with where it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inst {
+    /// The operation performed.
+    pub op: Op,
+    /// The byte range of the script the instruction was generated for, e.g. the
+    /// statement it implements.
+    pub source: Range<usize>,
+    /// The index of the CO-RE relocation that applies to the instruction, if any.
+    pub relocation: Option<usize>,
+}
+
+impl Inst {
+    /// Creates an instruction that doesn't belong to any part of a script.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The operation performed by the instruction.
+    pub fn new(op: Op) -> Self {
+        Self {
+            op,
+            source: 0..0,
+            relocation: None,
+        }
+    }
+}
+
+/// A basic block: a list of instructions that are executed in order, ending in a
+/// terminator. A block without a terminator continues at the next block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+}
+
+impl Block {
+    /// Returns the operation that ends the block, if it has one.
+    pub fn terminator(&self) -> Option<&Op> {
+        self.insts
+            .last()
+            .map(|inst| &inst.op)
+            .filter(|op| op.is_terminator())
+    }
+}
+
+/// A branch whose target is too far away for the 16-bit offset of a BPF jump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BranchTooLarge {
+    /// The block the branch ends.
+    pub block: BlockId,
+    /// The distance to the target, in instruction slots.
+    pub distance: usize,
+}
+
+/// A function of the IR. The first block is the entry point.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Function {
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    /// Returns a block of the function.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The block to return.
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0]
+    }
+
+    /// Returns the blocks control can continue at after a block: the targets of
+    /// its terminator, or the next block if it doesn't have one.
+    pub fn successors(&self, id: BlockId) -> Vec<BlockId> {
+        match self.block(id).terminator() {
+            Some(op) => op.successors(),
+            None => vec![BlockId(id.0 + 1)],
+        }
+    }
+
+    /// Returns the predecessors of every block, indexed by block.
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![vec![]; self.blocks.len()];
+        for i in 0..self.blocks.len() {
+            for successor in self.successors(BlockId(i)) {
+                if let Some(list) = predecessors.get_mut(successor.0) {
+                    if !list.contains(&BlockId(i)) {
+                        list.push(BlockId(i));
+                    }
+                }
+            }
+        }
+
+        predecessors
+    }
+
+    /// Lowers the function into BPF instructions, laying out the blocks in order.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_ins::{Instruction, JumpOperation, Register};
+    /// use bpf_script::ir::{BlockId, Block, Function, Inst, Op};
+    ///
+    /// let mut function = Function::default();
+    /// function.blocks.push(Block {
+    ///     insts: vec![
+    ///         Inst::new(Op::mov64(Register::R0, 0)),
+    ///         Inst::new(Op::Branch {
+    ///             op: JumpOperation::IfEqual,
+    ///             lhs: Register::R1,
+    ///             rhs: 0.into(),
+    ///             then: BlockId(2),
+    ///             otherwise: BlockId(1),
+    ///         }),
+    ///     ],
+    /// });
+    /// function.blocks.push(Block {
+    ///     insts: vec![Inst::new(Op::mov64(Register::R0, 1))],
+    /// });
+    /// function.blocks.push(Block {
+    ///     insts: vec![Inst::new(Op::Exit)],
+    /// });
+    /// let instructions = function.lower().expect("Failed to lower.");
+    /// assert_eq!(instructions.len(), 4);
+    /// assert_eq!(instructions[1].get_offset(), 1);
+    /// assert_eq!(instructions[3], Instruction::exit());
+    /// ```
+    pub fn lower(&self) -> Result<Vec<Instruction>, BranchTooLarge> {
+        Ok(self.lower_with_origins()?.0)
+    }
+
+    /// Lowers the function like `lower`, also returning the block and index of the
+    /// instruction each BPF instruction was generated from.
+    pub(crate) fn lower_with_origins(&self) -> Result<Lowered, BranchTooLarge> {
+        let mut instructions = vec![];
+        let mut origins = vec![];
+        let mut starts = vec![0; self.blocks.len() + 1];
+        let mut jumps = vec![];
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            starts[i] = instructions.len();
+            let next = BlockId(i + 1);
+            for (j, inst) in block.insts.iter().enumerate() {
+                let origin = (BlockId(i), j);
+                let mut push = |ins: Instruction, target: Option<BlockId>| {
+                    if let Some(target) = target {
+                        jumps.push((instructions.len(), target, BlockId(i)));
+                    }
+                    instructions.push(ins);
+                    origins.push(origin);
+                };
+
+                match inst.op {
+                    Op::Jump { target } if target != next => push(jump::always(0), Some(target)),
+                    Op::Jump { .. } => {}
+                    Op::Branch {
+                        op,
+                        lhs,
+                        rhs,
+                        then,
+                        otherwise,
+                    } => {
+                        /*
+                         * The branch is taken to `then`, unless that's the next block
+                         * and the condition can be inverted to fall through to it.
+                         */
+                        let (op, target, fallthrough) = match jump::invert(op) {
+                            Some(inverted) if then == next && otherwise != next => {
+                                (inverted, otherwise, then)
+                            }
+                            _ => (op, then, otherwise),
+                        };
+
+                        let ins = match rhs {
+                            Value::Register(rhs) => jump::if_reg(op, lhs, rhs, 0),
+                            Value::Immediate(imm) => jump::if_imm(op, lhs, imm as i32, 0),
+                        };
+                        push(ins, Some(target));
+                        if fallthrough != next {
+                            push(jump::always(0), Some(fallthrough));
+                        }
+                    }
+                    op => {
+                        for ins in lower_op(&op) {
+                            push(ins, None);
+                        }
+                    }
+                }
+            }
+        }
+
+        /*
+         * A jump to the block after the last one lands just past the end of the
+         * program.
+         */
+        starts[self.blocks.len()] = instructions.len();
+
+        /*
+         * Jump offsets are counted in slots rather than instructions, since wide
+         * instructions take up two slots.
+         */
+        let mut slots = vec![0; instructions.len() + 1];
+        for (i, ins) in instructions.iter().enumerate() {
+            slots[i + 1] = slots[i] + jump::slot_count(std::slice::from_ref(ins));
+        }
+
+        for (index, target, block) in jumps {
+            let offset = slots[starts[target.0]] as isize - slots[index] as isize - 1;
+            let offset = match i16::try_from(offset) {
+                Ok(offset) => offset,
+                Err(_) => {
+                    return Err(BranchTooLarge {
+                        block,
+                        distance: offset.unsigned_abs(),
+                    });
+                }
+            };
+
+            instructions[index] = jump::with_offset(&instructions[index], offset);
+        }
+
+        Ok((instructions, origins))
+    }
+}
+
+/// Lowered instructions along with the block and index of the instruction each
+/// one came from.
+type Lowered = (Vec<Instruction>, Vec<(BlockId, usize)>);
+
+/// Returns whether `a <op> b` is the same as `b <op> a`.
+fn is_commutative(op: ArithmeticOperation) -> bool {
+    matches!(
+        op,
+        ArithmeticOperation::Add
+            | ArithmeticOperation::Mul
+            | ArithmeticOperation::And
+            | ArithmeticOperation::Or
+            | ArithmeticOperation::Xor
+    )
+}
+
+/// Lowers a move: `dst = src`.
+fn lower_move(width: Width, dst: Register, src: Value) -> Instruction {
+    match (width, src) {
+        (Width::Bits64, Value::Register(src)) => Instruction::movx64(dst, src),
+        (Width::Bits32, Value::Register(src)) => Instruction::movx32(dst, src),
+        (Width::Bits64, Value::Immediate(imm)) => match i32::try_from(imm) {
+            Ok(imm) => Instruction::mov64(dst, imm),
+            Err(_) => Instruction::loadtype(dst, imm, MemoryOpLoadType::Void),
+        },
+        (Width::Bits32, Value::Immediate(imm)) => Instruction::mov32(dst, imm as i32),
+    }
+}
+
+/// Lowers an operation other than a jump into the BPF instructions implementing it.
+pub(crate) fn lower_op(op: &Op) -> Vec<Instruction> {
+    match *op {
+        Op::Move { width, dst, src } => vec![lower_move(width, dst, src)],
+        Op::Arithmetic {
+            op,
+            width,
+            signed,
+            dst,
+            lhs,
+            mut rhs,
+        } => {
+            /*
+             * BPF arithmetic updates its destination in place, so the left operand is
+             * moved there first, unless that would overwrite the right operand.
+             */
+            let mut lowered = vec![];
+            if lhs != dst {
+                if rhs == Value::Register(dst) {
+                    assert!(is_commutative(op), "{} overwrites its operand", op_name(op));
+                    rhs = Value::Register(lhs);
+                } else {
+                    lowered.push(lower_move(width, dst, Value::Register(lhs)));
+                }
+            }
+
+            let ins = match (rhs, width) {
+                (Value::Immediate(imm), Width::Bits64) => Instruction::alu64(dst, imm as i32, op),
+                (Value::Immediate(imm), Width::Bits32) => Instruction::alu32(dst, imm as i32, op),
+                (Value::Register(src), Width::Bits64) => Instruction::alux64(dst, src, op),
+                (Value::Register(src), Width::Bits32) => Instruction::alux32(dst, src, op),
+            };
+
+            /*
+             * Signed division and modulo share their opcodes with the unsigned variants
+             * and are distinguished by an offset of 1 (cpu v4).
+             */
+            let is_division = matches!(op, ArithmeticOperation::Div | ArithmeticOperation::Mod);
+            match signed && is_division {
+                true => lowered.push(jump::with_offset(&ins, 1)),
+                false => lowered.push(ins),
+            }
+
+            lowered
+        }
+        Op::Load {
+            size,
+            dst,
+            base,
+            offset,
+        } => vec![Instruction::loadx(dst, base, offset, size)],
+        Op::Store {
+            size,
+            base,
+            offset,
+            src,
+        } => match src {
+            Value::Register(src) => vec![Instruction::storex(base, offset, src, size)],
+            Value::Immediate(imm) => vec![Instruction::store(base, offset, imm, size)],
+        },
+        Op::Atomic {
+            op,
+            size,
+            base,
+            offset,
+            src,
+        } => vec![atomic(op, base, offset, src, size).expect("atomic operation exists")],
+        Op::LoadImmediate {
+            dst,
+            imm,
+            load_type,
+        } => vec![Instruction::loadtype(dst, imm, load_type)],
+        Op::Call { helper } => vec![Instruction::call(helper)],
+        Op::CallLocal { function } => vec![jump::call_local(function as i32)],
+        Op::Exit => vec![Instruction::exit()],
+        Op::Jump { .. } | Op::Branch { .. } => unreachable!("jumps are lowered with their block"),
+    }
+}
+
+/// Returns the symbol of an arithmetic operation, as used in the dump.
+fn op_name(op: ArithmeticOperation) -> &'static str {
+    match op {
+        ArithmeticOperation::Add => "+",
+        ArithmeticOperation::Sub => "-",
+        ArithmeticOperation::Mul => "*",
+        ArithmeticOperation::Div => "/",
+        ArithmeticOperation::Or => "|",
+        ArithmeticOperation::And => "&",
+        ArithmeticOperation::Lhs => "<<",
+        ArithmeticOperation::Rhs => ">>",
+        ArithmeticOperation::Neg => "-",
+        ArithmeticOperation::Mod => "%",
+        ArithmeticOperation::Xor => "^",
+        ArithmeticOperation::Mov => "=",
+        ArithmeticOperation::Ash => "s>>",
+        ArithmeticOperation::End => "bswap",
+    }
+}
+
+/// Returns the symbol of a comparison, as used in the dump.
+fn comparison_name(op: JumpOperation) -> &'static str {
+    match op {
+        JumpOperation::IfEqual => "==",
+        JumpOperation::IfNotEqual => "!=",
+        JumpOperation::IfGreater => ">",
+        JumpOperation::IfGreaterOrEqual => ">=",
+        JumpOperation::IfLessThan => "<",
+        JumpOperation::IfLessThanOrEqual => "<=",
+        JumpOperation::IfSignedGreater => "s>",
+        JumpOperation::IfSignedGreaterOrEqual => "s>=",
+        JumpOperation::IfSignedLessThan => "s<",
+        JumpOperation::IfSignedLessThanOrEqual => "s<=",
+        JumpOperation::IfAnd => "&",
+        JumpOperation::Absolute | JumpOperation::Call | JumpOperation::Exit => "?",
+    }
+}
+
+/// Returns the C type of a memory access, as used in the dump.
+fn size_name(size: MemoryOpSize) -> &'static str {
+    match size {
+        MemoryOpSize::Byte => "u8",
+        MemoryOpSize::HalfWord => "u16",
+        MemoryOpSize::Word => "u32",
+        MemoryOpSize::DoubleWord => "u64",
+    }
+}
+
+/// Formats a register, using the `w` prefix of 32-bit operations.
+fn reg(reg: Register, width: Width) -> String {
+    match width {
+        Width::Bits32 => format!("w{}", reg.as_num()),
+        Width::Bits64 => format!("r{}", reg.as_num()),
+    }
+}
+
+/// Formats an operand.
+fn value(value: Value, width: Width) -> String {
+    match value {
+        Value::Register(r) => reg(r, width),
+        Value::Immediate(imm) => imm.to_string(),
+    }
+}
+
+/// Formats a memory operand: `*(size *)(base + offset)`.
+fn memory(size: MemoryOpSize, base: Register, offset: i16) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!(
+        "*({} *)({} {} {})",
+        size_name(size),
+        reg(base, Width::Bits64),
+        sign,
+        offset.unsigned_abs()
+    )
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Op::Move { width, dst, src } => {
+                write!(f, "{} = {}", reg(dst, width), value(src, width))
+            }
+            Op::Arithmetic {
+                op: ArithmeticOperation::Neg,
+                width,
+                dst,
+                lhs,
+                ..
+            } => write!(f, "{} = -{}", reg(dst, width), reg(lhs, width)),
+            Op::Arithmetic {
+                op,
+                width,
+                signed,
+                dst,
+                lhs,
+                rhs,
+            } => {
+                let sign = match signed
+                    && matches!(op, ArithmeticOperation::Div | ArithmeticOperation::Mod)
+                {
+                    true => "s",
+                    false => "",
+                };
+                write!(
+                    f,
+                    "{} = {} {}{} {}",
+                    reg(dst, width),
+                    reg(lhs, width),
+                    sign,
+                    op_name(op),
+                    value(rhs, width)
+                )
+            }
+            Op::Load {
+                size,
+                dst,
+                base,
+                offset,
+            } => write!(
+                f,
+                "{} = {}",
+                reg(dst, Width::Bits64),
+                memory(size, base, offset)
+            ),
+            Op::Store {
+                size,
+                base,
+                offset,
+                src,
+            } => write!(
+                f,
+                "{} = {}",
+                memory(size, base, offset),
+                value(src, Width::Bits64)
+            ),
+            Op::Atomic {
+                op,
+                size,
+                base,
+                offset,
+                src,
+            } => write!(
+                f,
+                "lock {} {}= {}",
+                memory(size, base, offset),
+                op_name(op),
+                reg(src, Width::Bits64)
+            ),
+            Op::LoadImmediate {
+                dst,
+                imm,
+                load_type,
+            } => match load_type {
+                MemoryOpLoadType::Void => write!(f, "{} = {} ll", reg(dst, Width::Bits64), imm),
+                MemoryOpLoadType::Map => write!(f, "{} = map {}", reg(dst, Width::Bits64), imm),
+                _ => write!(f, "{} = {:?} {}", reg(dst, Width::Bits64), load_type, imm),
+            },
+            Op::Call { helper } => match Helpers::from_id(helper) {
+                Some(helper) => write!(f, "call {}", helper.name()),
+                None => write!(f, "call #{}", helper),
+            },
+            Op::CallLocal { function } => write!(f, "call func{}", function),
+            Op::Jump { target } => write!(f, "goto {}", target),
+            Op::Branch {
+                op,
+                lhs,
+                rhs,
+                then,
+                otherwise,
+            } => write!(
+                f,
+                "if {} {} {} goto {} else {}",
+                reg(lhs, Width::Bits64),
+                comparison_name(op),
+                value(rhs, Width::Bits64),
+                then,
+                otherwise
+            ),
+            Op::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.op)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "{}:", BlockId(i))?;
+            for inst in &block.insts {
+                writeln!(f, "    {}", inst)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use bpf_ins::{Instruction, JumpOperation, Opcode, Register};
+
+/// Opcode class for 64-bit jumps.
+const CLASS_JUMP: u8 = 0x05;
+
+/// Source operand bit; set when the comparison is against a register.
+const SOURCE_REGISTER: u8 = 0x08;
+
+/// Source register of a call to a function of the program rather than a helper
+/// (`BPF_PSEUDO_CALL`).
+const PSEUDO_CALL: Register = Register::R1;
+
+/// Returns the operation bits of a jump opcode.
+fn operation_code(op: JumpOperation) -> u8 {
+    match op {
+        JumpOperation::Absolute => 0x00,
+        JumpOperation::IfEqual => 0x10,
+        JumpOperation::IfGreater => 0x20,
+        JumpOperation::IfGreaterOrEqual => 0x30,
+        JumpOperation::IfAnd => 0x40,
+        JumpOperation::IfNotEqual => 0x50,
+        JumpOperation::IfSignedGreater => 0x60,
+        JumpOperation::IfSignedGreaterOrEqual => 0x70,
+        JumpOperation::Call => 0x80,
+        JumpOperation::Exit => 0x90,
+        JumpOperation::IfLessThan => 0xa0,
+        JumpOperation::IfLessThanOrEqual => 0xb0,
+        JumpOperation::IfSignedLessThan => 0xc0,
+        JumpOperation::IfSignedLessThanOrEqual => 0xd0,
+    }
+}
+
+/// `bpf-ins` doesn't provide constructors for conditional jumps, so they are
+/// built from their raw encoding and decoded back into an `Instruction`.
+fn encode(opcode: u8, dst: Register, src: Register, offset: i16, imm: i32) -> Instruction {
+    let raw = opcode as u64
+        | (dst.as_num() as u64) << 8
+        | (src.as_num() as u64) << 12
+        | (offset as u16 as u64) << 16
+        | (imm as u32 as u64) << 32;
+
+    Instruction::decode(&[raw]).expect("jump encoding is always valid")
+}
+
+/// Returns the logical inverse of a conditional jump operation, i.e. the
+/// operation that is taken exactly when `op` is not.
+///
+/// # Arguments
+///
+/// * `op` - The conditional jump operation to invert.
+pub fn invert(op: JumpOperation) -> Option<JumpOperation> {
+    Some(match op {
+        JumpOperation::IfEqual => JumpOperation::IfNotEqual,
+        JumpOperation::IfNotEqual => JumpOperation::IfEqual,
+        JumpOperation::IfGreater => JumpOperation::IfLessThanOrEqual,
+        JumpOperation::IfGreaterOrEqual => JumpOperation::IfLessThan,
+        JumpOperation::IfLessThan => JumpOperation::IfGreaterOrEqual,
+        JumpOperation::IfLessThanOrEqual => JumpOperation::IfGreater,
+        JumpOperation::IfSignedGreater => JumpOperation::IfSignedLessThanOrEqual,
+        JumpOperation::IfSignedGreaterOrEqual => JumpOperation::IfSignedLessThan,
+        JumpOperation::IfSignedLessThan => JumpOperation::IfSignedGreaterOrEqual,
+        JumpOperation::IfSignedLessThanOrEqual => JumpOperation::IfSignedGreater,
+        _ => return None,
+    })
+}
+
+/// Creates an unconditional jump: `PC += offset`.
+///
+/// # Arguments
+///
+/// * `offset` - The number of instructions to skip, relative to the next instruction.
+pub fn always(offset: i16) -> Instruction {
+    encode(
+        CLASS_JUMP | operation_code(JumpOperation::Absolute),
+        Register::R0,
+        Register::R0,
+        offset,
+        0,
+    )
+}
+
+/// Creates a conditional jump comparing a register against an immediate:
+/// `if reg <op> imm { PC += offset }`.
+///
+/// # Arguments
+///
+/// * `op` - The comparison to perform.
+/// * `reg` - The register on the left side of the comparison.
+/// * `imm` - The immediate on the right side of the comparison.
+/// * `offset` - The number of instructions to skip when the condition holds.
+pub fn if_imm(op: JumpOperation, reg: Register, imm: i32, offset: i16) -> Instruction {
+    encode(
+        CLASS_JUMP | operation_code(op),
+        reg,
+        Register::R0,
+        offset,
+        imm,
+    )
+}
+
+/// Creates a conditional jump comparing two registers:
+/// `if dst <op> src { PC += offset }`.
+///
+/// # Arguments
+///
+/// * `op` - The comparison to perform.
+/// * `dst` - The register on the left side of the comparison.
+/// * `src` - The register on the right side of the comparison.
+/// * `offset` - The number of instructions to skip when the condition holds.
+pub fn if_reg(op: JumpOperation, dst: Register, src: Register, offset: i16) -> Instruction {
+    encode(
+        CLASS_JUMP | SOURCE_REGISTER | operation_code(op),
+        dst,
+        src,
+        offset,
+        0,
+    )
+}
+
+/// Creates a call to a function of the program (a pseudo-call): `call PC + offset`.
+///
+/// # Arguments
+///
+/// * `offset` - The number of slots from the next instruction to the first
+///   instruction of the function.
+pub fn call_local(offset: i32) -> Instruction {
+    encode(
+        CLASS_JUMP | operation_code(JumpOperation::Call),
+        Register::R0,
+        PSEUDO_CALL,
+        0,
+        offset,
+    )
+}
+
+/// Returns the offset of a call to a function of the program, if the instruction
+/// is one.
+///
+/// # Arguments
+///
+/// * `ins` - The instruction to check.
+pub fn local_call_offset(ins: &Instruction) -> Option<i32> {
+    (call_local(ins.get_imm() as i32) == *ins).then_some(ins.get_imm() as i32)
+}
+
+/// Returns a copy of a jump instruction with its offset replaced. Used to patch
+/// forward jumps once the target is known.
+///
+/// # Arguments
+///
+/// * `ins` - The jump instruction to patch.
+/// * `offset` - The new jump offset.
+pub fn with_offset(ins: &Instruction, offset: i16) -> Instruction {
+    let (raw, _) = ins.encode();
+    let raw = (raw & !(0xffff << 16)) | (offset as u16 as u64) << 16;
+    Instruction::decode(&[raw]).expect("re-encoded jump is always valid")
+}
+
+/// Returns whether an instruction is a jump with a relative offset, i.e. any
+/// jump other than `call` and `exit`.
+///
+/// # Arguments
+///
+/// * `ins` - The instruction to check.
+pub fn is_branch(ins: &Instruction) -> bool {
+    match ins.get_opcode() {
+        Opcode::Jump(jump) => !matches!(
+            jump.get_operation(),
+            JumpOperation::Call | JumpOperation::Exit
+        ),
+        _ => false,
+    }
+}
+
+/// Returns the number of 8-byte slots a list of instructions occupies. Jump offsets
+/// are counted in slots, and wide instructions (e.g. 64-bit immediate loads) take
+/// up two of them.
+///
+/// # Arguments
+///
+/// * `instructions` - The instructions to count.
+pub fn slot_count(instructions: &[Instruction]) -> usize {
+    instructions
+        .iter()
+        .map(|ins| if ins.is_wide() { 2 } else { 1 })
+        .sum()
+}
+
+/// Fixes up the offsets of the jumps of a program after instructions were
+/// replaced or removed, so they jump to the same instructions as before.
+///
+/// # Arguments
+///
+/// * `old` - The program before the change.
+/// * `new` - The program after the change, whose jumps are patched.
+/// * `new_index` - The index in `new` of every instruction of `old`, plus one past
+///   the end. Removed instructions map to the instruction that followed them.
+pub fn retarget(old: &[Instruction], new: &mut [Instruction], new_index: &[usize]) {
+    let mut old_slots = vec![0; old.len() + 1];
+    let mut slot_index = vec![0; slot_count(old) + 1];
+    for (i, ins) in old.iter().enumerate() {
+        old_slots[i + 1] = old_slots[i] + slot_count(std::slice::from_ref(ins));
+        slot_index[old_slots[i + 1]] = i + 1;
+    }
+
+    let mut new_slots = vec![0; new.len() + 1];
+    for (i, ins) in new.iter().enumerate() {
+        new_slots[i + 1] = new_slots[i] + slot_count(std::slice::from_ref(ins));
+    }
+
+    for (i, ins) in old.iter().enumerate() {
+        if !is_branch(ins) {
+            continue;
+        }
+
+        let target_slot = (old_slots[i] as isize + 1 + ins.get_offset() as isize) as usize;
+        let target = new_index[slot_index[target_slot]];
+        let offset = new_slots[target] as isize - new_slots[new_index[i]] as isize - 1;
+        new[new_index[i]] = with_offset(ins, offset as i16);
+    }
+}
@@ -1,15 +1,60 @@
+/// Appends an integer to a byte buffer in the target's (i.e. the host's) byte order.
+macro_rules! put {
+    ($buf:expr, $value:expr) => {
+        $buf.extend_from_slice(&$value.to_ne_bytes())
+    };
+}
+
+mod arch;
+mod atomic;
 mod compiler;
+mod dataflow;
+mod debuginfo;
+mod diagnostic;
+mod elf;
+mod error;
+mod format;
 mod helpers;
+mod interpreter;
+pub mod ir;
+mod jump;
 mod optimizer;
+pub mod pass;
+mod program;
+mod regalloc;
+mod relocation;
+mod tracefs;
+mod verifier;
 
+pub use arch::Arch;
 pub use compiler::Compiler;
+pub use debuginfo::{CoreRelo, FuncInfo, LineInfo, ProgramBtf};
+pub use diagnostic::Diagnostic;
+pub use error::{CompileError, Span};
 pub use helpers::Helpers;
+pub use interpreter::{Environment, ExecutionError, HelperFn, Interpreter, STACK_SIZE};
+pub use program::{AttachPoint, ProgramType};
+pub use relocation::{
+    relocate, CoreRelocation, FieldAccess, MissingField, RelocationError, RelocationKind,
+};
+pub use tracefs::{FormatError, TraceField, TraceFormat};
 
 #[cfg(test)]
 mod tests {
-    use crate::{Compiler, Helpers};
-    use bpf_ins::{Instruction, Register};
+    use crate::ir::{Block, BlockId, Function, Inst, Op, Value, Width};
+    use crate::optimizer::optimize;
+    use crate::pass::{OptLevel, Pass};
+    use crate::verifier::{verify, MapSizes};
+    use crate::{
+        atomic, jump, relocate, Arch, CompileError, Compiler, ExecutionError, FormatError, Helpers,
+        Interpreter, ProgramType, RelocationError, RelocationKind, Span, TraceFormat,
+    };
+    use bpf_ins::{
+        ArithmeticOperation, Instruction, JumpOperation, MemoryOpLoadType, MemoryOpSize, Register,
+    };
     use btf::BtfTypes;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
 
     fn compile_and_compare(prog: &str, expected: &[Instruction]) {
         let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
@@ -23,6 +68,12 @@ mod tests {
         }
     }
 
+    fn compile_error(prog: &str) -> CompileError {
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap_err()
+    }
+
     #[test]
     fn empty_program() {
         let prog = r#"
@@ -60,9 +111,8 @@ mod tests {
         "#;
 
         let expected = [
-            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
-            Instruction::loadx32(Register::R0, Register::R10, -8),  // r0 = *(r10 - 8)
-            Instruction::exit(),                                    // exit
+            Instruction::movx32(Register::R0, Register::R1), // r0 = r1
+            Instruction::exit(),                             // exit
         ];
 
         compile_and_compare(prog, &expected);
@@ -78,12 +128,8 @@ mod tests {
         "#;
 
         let expected = [
-            Instruction::store64(Register::R10, -16, 0), // *(r10 - 16) = 0
-            Instruction::store64(Register::R10, -8, 0),  // *(r10 - 8) = 0
-            Instruction::store64(Register::R10, -16, 100), // *(r10 - 16) = 100
-            Instruction::store64(Register::R10, -8, 200), // *(r10 - 8) = 200
-            Instruction::mov64(Register::R0, 0),         // r0 = 0
-            Instruction::exit(),                         // exit
+            Instruction::mov64(Register::R0, 0), // r0 = 0
+            Instruction::exit(),                 // exit
         ];
 
         compile_and_compare(prog, &expected);
@@ -100,24 +146,20 @@ mod tests {
         "#;
 
         let expected = [
-            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
-            Instruction::store64(Register::R10, -24, 0),            // *(r10 - 24) = 0
-            Instruction::store64(Register::R10, -16, 0),            // *(r10 - 16) = 0
-            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
-            Instruction::movx64(Register::R1, Register::R10),       // r1 = r10
-            Instruction::add64(Register::R1, -24),                  // r1 -= 24
-            Instruction::mov64(Register::R2, 8),                    // r2 = 8
-            Instruction::movx64(Register::R3, Register::R6),        // r3 = r6
-            Instruction::call(Helpers::ProbeReadKernel as u32),     // call #113
-            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
-            Instruction::add64(Register::R6, 8),                    // r6 += 8
-            Instruction::movx64(Register::R1, Register::R10),       // r3 = r6
-            Instruction::add64(Register::R1, -16),                  // r1 -= 16
-            Instruction::mov64(Register::R2, 8),                    // r2 = 8
-            Instruction::movx64(Register::R3, Register::R6),        // r3 = r6
-            Instruction::call(Helpers::ProbeReadKernel as u32),     // call #113
-            Instruction::mov64(Register::R0, 50),                   // r0 = 50
-            Instruction::exit(),                                    // exit
+            Instruction::movx64(Register::R6, Register::R1), // r6 = r1
+            Instruction::movx64(Register::R1, Register::R10), // r1 = r10
+            Instruction::add64(Register::R1, -24),           // r1 -= 24
+            Instruction::mov64(Register::R2, 8),             // r2 = 8
+            Instruction::movx64(Register::R3, Register::R6), // r3 = r6
+            Instruction::call(Helpers::ProbeReadKernel as u32), // call #113
+            Instruction::add64(Register::R6, 8),             // r6 += 8
+            Instruction::movx64(Register::R1, Register::R10), // r1 = r10
+            Instruction::add64(Register::R1, -16),           // r1 -= 16
+            Instruction::mov64(Register::R2, 8),             // r2 = 8
+            Instruction::movx64(Register::R3, Register::R6), // r3 = r6
+            Instruction::call(Helpers::ProbeReadKernel as u32), // call #113
+            Instruction::mov64(Register::R0, 50),            // r0 = 50
+            Instruction::exit(),                             // exit
         ];
 
         compile_and_compare(prog, &expected);
@@ -132,7 +174,6 @@ mod tests {
 
         let expected = [
             Instruction::call(Helpers::GetCurrentUidGid as u32), // call #15
-            Instruction::storex64(Register::R10, -8, Register::R0), // *(r10 - 8) = r0
             Instruction::mov64(Register::R0, 0),                 // r0 = 0
             Instruction::exit(),                                 // exit
         ];
@@ -149,9 +190,8 @@ mod tests {
         "#;
 
         let expected = [
-            Instruction::store64(Register::R10, -8, 100), // *(r10 - 8) = 100
             Instruction::call(Helpers::GetCurrentUidGid as u32), // call #15
-            Instruction::exit(),                          // exit
+            Instruction::exit(),                                 // exit
         ];
 
         compile_and_compare(prog, &expected);
@@ -173,4 +213,2864 @@ mod tests {
 
         compile_and_compare(prog, &expected);
     }
+
+    #[test]
+    fn if_return() {
+        let prog = r#"
+            fn(a: int)
+                if a == 5 {
+                    return 1
+                }
+                return 0
+        "#;
+
+        let expected = [
+            Instruction::movx32(Register::R6, Register::R1), // r6 = r1
+            jump::if_imm(JumpOperation::IfNotEqual, Register::R6, 5, 2), // if r6 != 5 goto +2
+            Instruction::mov64(Register::R0, 1),             // r0 = 1
+            Instruction::exit(),                             // exit
+            Instruction::mov64(Register::R0, 0),             // r0 = 0
+            Instruction::exit(),                             // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn if_else() {
+        let prog = r#"
+            fn(a: int, b: int)
+                if a < b {
+                    c: __u64 = 1
+                } else if a >= 10 {
+                    c: __u64 = 2
+                } else {
+                    return 3
+                }
+                return a
+        "#;
+
+        /*
+         * Signed ints are zero-extended when loaded, so they're sign-extended before
+         * being compared with 64-bit jumps.
+         */
+        let expected = [
+            Instruction::movx32(Register::R6, Register::R1), // r6 = r1
+            Instruction::movx32(Register::R7, Register::R2), // r7 = r2
+            Instruction::alu64(Register::R6, 32, ArithmeticOperation::Lhs), // r6 <<= 32
+            Instruction::alu64(Register::R6, 32, ArithmeticOperation::Ash), // r6 s>>= 32
+            Instruction::alu64(Register::R7, 32, ArithmeticOperation::Lhs), // r7 <<= 32
+            Instruction::alu64(Register::R7, 32, ArithmeticOperation::Ash), // r7 s>>= 32
+            jump::if_reg(
+                JumpOperation::IfSignedLessThan,
+                Register::R6,
+                Register::R7,
+                6,
+            ), // if r6 s< r7 goto +6
+            Instruction::movx32(Register::R6, Register::R1), // r6 = r1
+            Instruction::alu64(Register::R6, 32, ArithmeticOperation::Lhs), // r6 <<= 32
+            Instruction::alu64(Register::R6, 32, ArithmeticOperation::Ash), // r6 s>>= 32
+            jump::if_imm(JumpOperation::IfSignedGreaterOrEqual, Register::R6, 10, 2), // if r6 s>= 10 goto +2
+            Instruction::mov64(Register::R0, 3),                                      // r0 = 3
+            Instruction::exit(),                                                      // exit
+            Instruction::movx32(Register::R0, Register::R1),                          // r0 = r1
+            Instruction::exit(),                                                      // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.run_with_args(&expected, &[-1i64 as u64, 5]),
+            Ok(0xffffffff)
+        );
+        assert_eq!(
+            interpreter.run_with_args(&expected, &[7, -5i64 as u64]),
+            Ok(3)
+        );
+        assert_eq!(
+            interpreter.run_with_args(&expected, &[12, -5i64 as u64]),
+            Ok(12)
+        );
+    }
+
+    #[test]
+    fn signed_narrow_values() {
+        /*
+         * 32-bit results are zero-extended too, and sign-extended again when they're
+         * compared or mixed with 64-bit values.
+         */
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        for (prog, args, expected) in [
+            (
+                "fn(a: int)\n b: int = a - 10\n if b < 0 {\n return 1\n }\n return 2",
+                [3, 0],
+                1,
+            ),
+            (
+                "fn(a: int)\n if a == -1 {\n return 1\n }\n return 2",
+                [-1i64 as u64, 0],
+                1,
+            ),
+            (
+                "fn(a: int, b: __s64)\n if a < b {\n return 1\n }\n return 2",
+                [-1i64 as u64, 5],
+                1,
+            ),
+            (
+                "fn(a: __s16)\n x: __s64 = 0\n return x + a",
+                [0xfffe, 0],
+                -2i64 as u64,
+            ),
+            /*
+             * Literals compared with narrow unsigned values are truncated to their
+             * width, as if they were converted to the unsigned type.
+             */
+            (
+                "fn(a: __u32)\n if a == -1 {\n return 1\n }\n return 2",
+                [0xffff_ffff, 0],
+                1,
+            ),
+            (
+                "fn(a: __u32)\n if a == 0xffffffff {\n return 1\n }\n return 2",
+                [0xffff_ffff, 0],
+                1,
+            ),
+            (
+                "fn(a: __u32)\n if a < -1 {\n return 1\n }\n return 2",
+                [5, 0],
+                1,
+            ),
+            (
+                "fn(a: __u8)\n if a == -2 {\n return 1\n }\n return 2",
+                [0xfe, 0],
+                1,
+            ),
+        ] {
+            for level in [OptLevel::O0, OptLevel::O2] {
+                let mut compiler = Compiler::create(&btf);
+                compiler.set_opt_level(level);
+                compiler.compile(prog).unwrap();
+
+                let mut interpreter = Interpreter::new();
+                let result = interpreter.run_with_args(compiler.get_instructions(), &args);
+                assert_eq!(result, Ok(expected), "{}", prog);
+            }
+        }
+    }
+
+    #[test]
+    fn for_loop() {
+        let prog = r#"
+            fn()
+                for i in 0..4 {
+                    get_current_pid_tgid()
+                }
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R6, 0), // r6 = 0
+            jump::if_imm(JumpOperation::IfGreaterOrEqual, Register::R6, 4, 3), // if r6 >= 4 goto +3
+            Instruction::call(Helpers::GetCurrentPidTgid as u32), // call #14
+            Instruction::add64(Register::R6, 1), // r6 += 1
+            jump::always(-4),                    // goto -4
+            Instruction::mov64(Register::R0, 0), // r0 = 0
+            Instruction::exit(),                 // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn for_loop_unrolled() {
+        let prog = r#"
+            fn()
+                fsid: __kernel_fsid_t = 0
+                for i in 0..2 {
+                    fsid.val[i] = 7
+                }
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_unroll_loops(true);
+        compiler.compile(prog).unwrap();
+
+        let expected = [
+            Instruction::mov64(Register::R0, 0), // r0 = 0
+            Instruction::exit(),                 // exit
+        ];
+
+        assert_eq!(compiler.get_instructions(), &expected);
+    }
+
+    #[test]
+    fn for_loop_runtime_index() {
+        let prog = r#"
+            fn()
+                fsid: __kernel_fsid_t = 0
+                for i in 0..2 {
+                    v: int = fsid.val[i]
+                }
+        "#;
+
+        let expected = [
+            Instruction::store64(Register::R10, -8, 0), // *(r10 - 8) = 0
+            Instruction::mov64(Register::R7, 0),        // r7 = 0
+            Instruction::movx64(Register::R6, Register::R7), // r6 = r7
+            jump::if_imm(JumpOperation::IfGreaterOrEqual, Register::R6, 2, 17), // if r6 >= 2 goto +17
+            Instruction::movx64(Register::R6, Register::R10),                   // r6 = r10
+            Instruction::add64(Register::R6, -8),                               // r6 -= 8
+            Instruction::movx64(Register::R9, Register::R7),                    // r9 = r7
+            jump::if_imm(JumpOperation::IfGreaterOrEqual, Register::R9, 2, 3), // if r9 >= 2 goto +3
+            Instruction::alu64(Register::R9, 4, ArithmeticOperation::Mul),     // r9 *= 4
+            Instruction::addx64(Register::R6, Register::R9),                   // r6 += r9
+            jump::always(1),                                                   // goto +1
+            Instruction::mov64(Register::R6, 0),                               // r6 = 0
+            Instruction::movx64(Register::R1, Register::R10),                  // r1 = r10
+            Instruction::add64(Register::R1, -20),                             // r1 -= 20
+            Instruction::mov64(Register::R2, 4),                               // r2 = 4
+            Instruction::movx64(Register::R3, Register::R6),                   // r3 = r6
+            Instruction::call(Helpers::ProbeReadKernel as u32),                // call #113
+            Instruction::movx64(Register::R6, Register::R7),                   // r6 = r7
+            Instruction::add64(Register::R6, 1),                               // r6 += 1
+            Instruction::movx64(Register::R7, Register::R6),                   // r7 = r6
+            jump::always(-19),                                                 // goto -19
+            Instruction::mov64(Register::R0, 0),                               // r0 = 0
+            Instruction::exit(),                                               // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn array_bounds() {
+        /*
+         * Indices known at compile time are checked when compiling, including those
+         * of unrolled loops, as are the ranges of loops used as indices at runtime.
+         */
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        for (prog, unroll) in [
+            (
+                "fn()\n fsid: __kernel_fsid_t = 0\n v: int = fsid.val[2]",
+                false,
+            ),
+            (
+                "fn()\n fsid: __kernel_fsid_t = 0\n for i in 0..3 {\n v: int = fsid.val[i]\n }",
+                false,
+            ),
+            (
+                "fn()\n fsid: __kernel_fsid_t = 0\n for i in 0..3 {\n v: int = fsid.val[i]\n }",
+                true,
+            ),
+        ] {
+            let mut compiler = Compiler::create(&btf);
+            compiler.set_unroll_loops(unroll);
+            let error = compiler.compile(prog).unwrap_err();
+            assert!(
+                matches!(
+                    error,
+                    CompileError::IndexOutOfBounds {
+                        index: 2,
+                        len: 2,
+                        ..
+                    }
+                ),
+                "{}",
+                prog
+            );
+        }
+
+        /*
+         * Other indices are checked at runtime. Reading out of bounds, negative
+         * indices included, gives 0 and the rest of the program still runs.
+         */
+        let prog = r#"
+            fn(a: int)
+                fsid: __kernel_fsid_t = 0
+                fsid.val[0] = 5
+                fsid.val[1] = 7
+                v: int = fsid.val[a]
+                w: int = fsid.val[a] + 1
+                return v + w + 100
+        "#;
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+
+        let mut interpreter = Interpreter::new();
+        for (arg, expected) in [(0, 111), (1, 115), (2, 101), (-1i64 as u64, 101)] {
+            let result = interpreter.run_with_args(compiler.get_instructions(), &[arg]);
+            assert_eq!(result, Ok(expected), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn while_loop() {
+        let prog = r#"
+            fn(a: int)
+                while a != 0 {
+                    a = 0
+                }
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_max_loop_iterations(16);
+        compiler.compile(prog).unwrap();
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::mov64(Register::R7, 0),                    // r7 = 0
+            Instruction::loadx32(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
+            jump::if_imm(JumpOperation::IfEqual, Register::R6, 0, 6), // if r6 == 0 goto +6
+            Instruction::movx64(Register::R6, Register::R7),        // r6 = r7
+            jump::if_imm(JumpOperation::IfGreaterOrEqual, Register::R6, 16, 4), // if r6 >= 16 goto +4
+            Instruction::add64(Register::R6, 1),                                // r6 += 1
+            Instruction::movx64(Register::R7, Register::R6),                    // r7 = r6
+            Instruction::store32(Register::R10, -8, 0),                         // *(r10 - 8) = 0
+            jump::always(-8),                                                   // goto -8
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+        ];
+
+        assert_eq!(compiler.get_instructions(), &expected);
+    }
+
+    #[test]
+    fn while_loop_unrolled() {
+        let prog = r#"
+            fn(a: int)
+                b: int = 0
+                while b < a {
+                    b += 3
+                }
+                return b
+        "#;
+
+        /*
+         * Unrolled `while` loops need a small loop limit, since there's a copy of
+         * the body for every iteration they may run for.
+         */
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_unroll_loops(true);
+        let error = compiler.compile(prog).unwrap_err();
+        assert!(matches!(
+            error,
+            CompileError::TooManyIterations {
+                iterations: 1024,
+                max: 32,
+                ..
+            }
+        ));
+
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_unroll_loops(true);
+        compiler.set_max_loop_iterations(4);
+        compiler.compile(prog).unwrap();
+
+        let mut interpreter = Interpreter::new();
+        for (arg, expected) in [(0, 0), (5, 6), (100, 12)] {
+            let result = interpreter.run_with_args(compiler.get_instructions(), &[arg]);
+            assert_eq!(result, Ok(expected), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn split_pid_tgid() {
+        let prog = r#"
+            fn()
+                pid_tgid: __u64 = get_current_pid_tgid()
+                tgid: __u32 = pid_tgid >> 32
+                return tgid
+        "#;
+
+        let expected = [
+            Instruction::call(Helpers::GetCurrentPidTgid as u32), // call #14
+            Instruction::movx64(Register::R6, Register::R0),      // r6 = r0
+            Instruction::alu64(Register::R6, 32, ArithmeticOperation::Rhs), // r6 >>= 32
+            Instruction::movx32(Register::R6, Register::R6),      // r6 = r6
+            Instruction::movx64(Register::R0, Register::R6),      // r0 = r6
+            Instruction::exit(),                                  // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn operator_precedence() {
+        let prog = r#"
+            fn(a: int, b: int)
+                return a + b * 2 - ~a
+        "#;
+
+        let expected = [
+            Instruction::movx32(Register::R0, Register::R1), // r0 = r1
+            Instruction::alu32(Register::R0, -1, ArithmeticOperation::Xor), // r0 ^= -1
+            Instruction::movx64(Register::R6, Register::R0), // r6 = r0
+            Instruction::movx32(Register::R0, Register::R2), // r0 = r2
+            Instruction::alu32(Register::R0, 2, ArithmeticOperation::Mul), // r0 *= 2
+            Instruction::movx64(Register::R9, Register::R0), // r9 = r0
+            Instruction::movx32(Register::R0, Register::R1), // r0 = r1
+            Instruction::alux32(Register::R0, Register::R9, ArithmeticOperation::Add), // r0 += r9
+            Instruction::movx64(Register::R9, Register::R6), // r9 = r6
+            Instruction::alux32(Register::R0, Register::R9, ArithmeticOperation::Sub), // r0 -= r9
+            Instruction::exit(),                             // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn bitwise_condition() {
+        let prog = r#"
+            fn(flags: int)
+                if flags & 0x4 != 0 {
+                    return -1
+                }
+        "#;
+
+        let expected = [
+            Instruction::movx32(Register::R6, Register::R1), // r6 = r1
+            Instruction::alu32(Register::R6, 4, ArithmeticOperation::And), // r6 &= 4
+            jump::if_imm(JumpOperation::IfEqual, Register::R6, 0, 2), // if r6 == 0 goto +2
+            Instruction::mov64(Register::R0, -1),            // r0 = -1
+            Instruction::exit(),                             // exit
+            Instruction::mov64(Register::R0, 0),             // r0 = 0
+            Instruction::exit(),                             // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn signed_arithmetic() {
+        let prog = r#"
+            fn(a: int)
+                return a / 2 >> 1
+        "#;
+
+        let sdiv32 = jump::with_offset(
+            &Instruction::alu32(Register::R0, 2, ArithmeticOperation::Div),
+            1,
+        );
+        let expected = [
+            Instruction::movx32(Register::R0, Register::R1), // r0 = r1
+            sdiv32,                                          // w0 s/= 2
+            Instruction::alu32(Register::R0, 1, ArithmeticOperation::Ash), // w0 s>>= 1
+            Instruction::exit(),                             // exit
+        ];
+
+        /*
+         * Signed division is only emitted when it's enabled, since older kernels
+         * reject it.
+         */
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::InvalidType { .. }));
+        assert_eq!(
+            &prog[error.span().start..error.span().end],
+            "return a / 2 >> 1"
+        );
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_signed_division(true);
+        compiler.compile(prog).unwrap();
+        assert_eq!(compiler.get_instructions(), &expected);
+    }
+
+    #[test]
+    fn return_group() {
+        /*
+         * `return` is a keyword, so a parenthesized value isn't a call to a helper
+         * named `return`.
+         */
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        for (prog, expected) in [
+            ("fn(a: int)\n return (a + 2)", 3),
+            ("fn(a: int)\n return (a + 2) * 7", 21),
+            (
+                "fn(a: int)\n return_value: int = a\n return return_value",
+                1,
+            ),
+        ] {
+            let mut compiler = Compiler::create(&btf);
+            compiler.compile(prog).unwrap();
+
+            let mut interpreter = Interpreter::new();
+            let result = interpreter.run_with_args(compiler.get_instructions(), &[1]);
+            assert_eq!(result, Ok(expected), "{}", prog);
+        }
+    }
+
+    #[test]
+    fn unknown_variable_error() {
+        let prog = r#"
+            fn()
+                a: int = b
+        "#;
+
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::UnknownVariable { .. }));
+        assert_eq!(error.identifier(), Some("b"));
+        assert_eq!(error.span().line, 3);
+        assert_eq!(error.span().column, 26);
+        assert_eq!(&prog[error.span().start..error.span().end], "b");
+        assert!(error.is_user_error());
+    }
+
+    #[test]
+    fn unknown_type_and_helper_errors() {
+        let prog = r#"
+            fn(a: &not_a_type)
+        "#;
+
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::UnknownType { .. }));
+        assert_eq!(error.identifier(), Some("not_a_type"));
+        assert_eq!(error.span().line, 2);
+        assert_eq!(error.span().column, 20);
+
+        let prog = r#"
+            fn()
+                not_a_helper(1)
+        "#;
+
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::UnknownHelper { .. }));
+        assert_eq!(error.identifier(), Some("not_a_helper"));
+        assert_eq!(error.span().line, 3);
+        assert_eq!(error.span().column, 17);
+    }
+
+    #[test]
+    fn member_and_parse_errors() {
+        let prog = r#"
+            fn(vec: &iovec)
+                len: __kernel_size_t = vec.iov_length
+        "#;
+
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::UnknownMember { .. }));
+        assert_eq!(error.identifier(), Some("iov_length"));
+        assert_eq!(error.span().line, 3);
+
+        let prog = r#"
+            fn()
+                return 1 +
+        "#;
+
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::ParseError { .. }));
+        assert_eq!(error.span().line, 3);
+    }
+
+    #[test]
+    fn diagnostic_rendering() {
+        let prog = "fn(vec: &iovec)\n    len: __kernel_size_t = vec.iov_lne\n";
+
+        let error = compile_error(prog);
+        assert_eq!(error.suggestion(), Some("iov_len"));
+        assert_eq!(
+            error.diagnostic(prog).to_string(),
+            concat!(
+                "error: Member \"iov_lne\" doesn't exist.\n",
+                " --> line 2, column 32\n",
+                "  |\n",
+                "2 |     len: __kernel_size_t = vec.iov_lne\n",
+                "  |                                ^^^^^^^\n",
+                "  = help: did you mean \"iov_len\"?\n",
+            )
+        );
+
+        /*
+         * Errors without a location are rendered without a snippet.
+         */
+        let error = CompileError::InvalidImmediate {
+            span: Span::default(),
+            value: "300".to_string(),
+        };
+        assert_eq!(
+            error.diagnostic(prog).to_string(),
+            "error: Bad immediate value \"300\".\n"
+        );
+    }
+
+    #[test]
+    fn did_you_mean_suggestions() {
+        let prog = r#"
+            fn(a: &task_strct)
+        "#;
+        assert_eq!(compile_error(prog).suggestion(), Some("task_struct"));
+
+        let prog = r#"
+            fn()
+                bpf_get_current_pid_tgid()
+        "#;
+        assert_eq!(
+            compile_error(prog).suggestion(),
+            Some("get_current_pid_tgid")
+        );
+
+        let prog = r#"
+            fn()
+                counter: __u64 = 0
+                return countr
+        "#;
+        assert_eq!(compile_error(prog).suggestion(), Some("counter"));
+
+        let prog = r#"
+            fn()
+                return xyz
+        "#;
+        assert_eq!(compile_error(prog).suggestion(), None);
+    }
+
+    fn compile_with_map(prog: &str) -> Vec<Instruction> {
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("counts", 3);
+        compiler.compile(prog).unwrap();
+        compiler.get_instructions().to_vec()
+    }
+
+    #[test]
+    fn map_compound_assign() {
+        let prog = r#"
+            fn()
+                map counts: hash<__u32, __u64>
+                counts[1] += 1
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R7, 1),        // r7 = 1
+            Instruction::store32(Register::R10, -4, 1), // *(r10 - 4) = 1
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map 3
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -4),       // r2 -= 4
+            Instruction::call(Helpers::MapLookupElem as u32), // call map_lookup_elem
+            jump::if_imm(JumpOperation::IfEqual, Register::R0, 0, 2), // if r0 == 0 goto +2
+            atomic::atomic(
+                ArithmeticOperation::Add,
+                Register::R0,
+                0,
+                Register::R7,
+                MemoryOpSize::DoubleWord,
+            )
+            .unwrap(), // lock *(u64 *)r0 += r7
+            jump::always(19),                           // goto +19
+            Instruction::mov64(Register::R6, 0),        // r6 = 0
+            Instruction::addx64(Register::R6, Register::R7), // r6 += r7
+            Instruction::storex64(Register::R10, -16, Register::R6), // *(r10 - 16) = r6
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map 3
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -4),       // r2 -= 4
+            Instruction::movx64(Register::R3, Register::R10), // r3 = r10
+            Instruction::add64(Register::R3, -16),      // r3 -= 16
+            Instruction::mov64(Register::R4, 1),        // r4 = BPF_NOEXIST
+            Instruction::call(Helpers::MapUpdateElem as u32), // call map_update_elem
+            jump::if_imm(JumpOperation::IfNotEqual, Register::R0, -17, 7), // if r0 != -EEXIST goto +7
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map 3
+            Instruction::movx64(Register::R2, Register::R10),              // r2 = r10
+            Instruction::add64(Register::R2, -4),                          // r2 -= 4
+            Instruction::call(Helpers::MapLookupElem as u32),              // call map_lookup_elem
+            jump::if_imm(JumpOperation::IfEqual, Register::R0, 0, 1),      // if r0 == 0 goto +1
+            atomic::atomic(
+                ArithmeticOperation::Add,
+                Register::R0,
+                0,
+                Register::R7,
+                MemoryOpSize::DoubleWord,
+            )
+            .unwrap(), // lock *(u64 *)r0 += r7
+            Instruction::mov64(Register::R0, 0),                           // r0 = 0
+            Instruction::exit(),                                           // exit
+        ];
+
+        assert_eq!(compile_with_map(prog), expected);
+    }
+
+    #[test]
+    fn map_lookup_and_delete() {
+        let prog = r#"
+            fn()
+                map counts: hash<__u32, __u64>
+                k: __u32 = 7
+                delete counts[k]
+                return counts[k]
+        "#;
+
+        let expected = [
+            Instruction::mov32(Register::R6, 7),        // r6 = 7
+            Instruction::store32(Register::R10, -8, 7), // *(r10 - 8) = 7
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map 3
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -8),       // r2 -= 8
+            Instruction::call(Helpers::MapDeleteElem as u32), // call #3
+            Instruction::movx64(Register::R0, Register::R6), // r0 = r6
+            Instruction::storex32(Register::R10, -12, Register::R0), // *(r10 - 12) = r0
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map 3
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -12),      // r2 -= 12
+            Instruction::call(Helpers::MapLookupElem as u32), // call #1
+            jump::if_imm(JumpOperation::IfEqual, Register::R0, 0, 1), // if r0 == 0 goto +1
+            Instruction::loadx64(Register::R0, Register::R0, 0), // r0 = *(r0 + 0)
+            Instruction::exit(),                        // exit
+        ];
+
+        assert_eq!(compile_with_map(prog), expected);
+    }
+
+    #[test]
+    fn map_declaration_errors() {
+        let prog = r#"
+            fn()
+                map missing: hash<__u32, __u64>
+        "#;
+        assert!(matches!(
+            compile_error(prog),
+            CompileError::InvalidMap { name, .. } if name == "missing"
+        ));
+
+        let prog = r#"
+            fn()
+                map counts: hashmap<__u32, __u64>
+        "#;
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("counts", 3);
+        let error = compiler.compile(prog).unwrap_err();
+        assert!(matches!(error, CompileError::InvalidMap { name, .. } if name == "hashmap"));
+
+        let prog = r#"
+            fn()
+                map counts: hash<__u32, __u64>
+                map other: hash<__u32, __u64>
+        "#;
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("counts", 3);
+        compiler.capture("other", 3);
+        let error = compiler.compile(prog).unwrap_err();
+        assert!(matches!(error, CompileError::InvalidMap { name, .. } if name == "other"));
+    }
+
+    #[test]
+    fn capture_wide_integer() {
+        let prog = r#"
+            fn()
+                return big
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("big", 0x1_0000_0001);
+        compiler.compile(prog).unwrap();
+
+        let expected = [
+            Instruction::loadtype(Register::R0, 0x1_0000_0001, MemoryOpLoadType::Void), // r0 = 0x100000001
+            Instruction::exit(),                                                        // exit
+        ];
+
+        assert_eq!(compiler.get_instructions(), &expected);
+    }
+
+    #[test]
+    fn capture_struct_and_bytes() {
+        let prog = r#"
+            fn()
+                if comm[2] == 115 {
+                    return fsid.val[1]
+                }
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        let fsid = [1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        compiler
+            .capture_struct("fsid", "__kernel_fsid_t", &fsid)
+            .unwrap();
+        compiler.capture_bytes("comm", b"bash\0").unwrap();
+        compiler.compile(prog).unwrap();
+
+        let expected = [
+            Instruction::store32(Register::R10, -4, -1), // *(r10 - 4) = -1
+            Instruction::store32(Register::R10, -16, i32::from_ne_bytes(*b"bash")), // *(r10 - 16) = "bash"
+            Instruction::loadx8(Register::R6, Register::R10, -14), // r6 = *(r10 - 14)
+            jump::if_imm(JumpOperation::IfNotEqual, Register::R6, 115, 2), // if r6 != 115 goto +2
+            Instruction::loadx32(Register::R0, Register::R10, -4), // r0 = *(r10 - 4)
+            Instruction::exit(),                                   // exit
+            Instruction::mov64(Register::R0, 0),                   // r0 = 0
+            Instruction::exit(),                                   // exit
+        ];
+
+        assert_eq!(compiler.get_instructions(), &expected);
+    }
+
+    #[test]
+    fn capture_errors() {
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+
+        let error = compiler.capture_int("small", "__u8", 256).unwrap_err();
+        assert!(matches!(error, CompileError::InvalidCapture { .. }));
+        assert_eq!(error.identifier(), Some("256"));
+        assert!(matches!(
+            compiler.capture_int("ptr", "task_struct", 0),
+            Err(CompileError::InvalidCapture { .. })
+        ));
+        assert!(matches!(
+            compiler.capture_struct("fsid", "__kernel_fsid_t", &[0; 4]),
+            Err(CompileError::InvalidCapture { .. })
+        ));
+        assert!(matches!(
+            compiler.capture_struct("fsid", "__u64", &[0; 8]),
+            Err(CompileError::InvalidCapture { .. })
+        ));
+        let error = compiler.capture_int("small", "__u88", 0).unwrap_err();
+        assert_eq!(error.suggestion(), Some("__u8"));
+
+        /*
+         * Captures don't come from the script, so errors have no location.
+         */
+        let error = compiler.capture_int("x", "__u8", 300).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Captured value doesn't fit in \"__u8\" (\"300\")."
+        );
+        assert_eq!(
+            error.diagnostic("fn()\n return x").to_string(),
+            "error: Captured value doesn't fit in \"__u8\" (\"300\").\n"
+        );
+    }
+
+    #[test]
+    fn wide_literals() {
+        let prog = r#"
+            fn()
+                a: __u64 = 0x100000000
+                b: __u64 = a + 0xffffffff
+                return 0xdeadbeefcafe
+        "#;
+
+        let expected = [
+            Instruction::loadtype(Register::R0, 0xdeadbeefcafe, MemoryOpLoadType::Void), // r0 = 0xdeadbeefcafe
+            Instruction::exit(),                                                         // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn literal_out_of_range() {
+        let prog = r#"
+            fn()
+                x: __u32 = 1
+                return x + 0x100000000
+        "#;
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::InvalidImmediate { .. }));
+        assert_eq!(error.identifier(), Some("0x100000000"));
+
+        let prog = r#"
+            fn()
+                x: __u32 = 0x100000000
+        "#;
+        assert!(matches!(
+            compile_error(prog),
+            CompileError::InvalidImmediate { .. }
+        ));
+
+        let prog = r#"
+            fn()
+                return 0x10000000000000000
+        "#;
+        assert!(matches!(
+            compile_error(prog),
+            CompileError::InvalidImmediate { .. }
+        ));
+    }
+
+    #[test]
+    fn object_file() {
+        let prog = r#"
+            fn()
+                map counts: hash<__u32, __u64>[64]
+                counts[1] = 0
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("counts", 7);
+        compiler.compile(prog).unwrap();
+        let object = compiler.get_object("kprobe/do_sys_open", "GPL");
+
+        let u16_at = |o: usize| u16::from_ne_bytes(object[o..o + 2].try_into().unwrap());
+        let u32_at = |o: usize| u32::from_ne_bytes(object[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_ne_bytes(object[o..o + 8].try_into().unwrap());
+        assert_eq!(&object[..4], b"\x7fELF");
+        assert_eq!(u16_at(16), 1); // ET_REL
+        assert_eq!(u16_at(18), 247); // EM_BPF
+
+        /*
+         * Collect the sections by name from the section headers.
+         */
+        let shoff = u64_at(40) as usize;
+        let shnum = u16_at(60) as usize;
+        let strtab = shoff + u16_at(62) as usize * 64;
+        let names = u64_at(strtab + 24) as usize;
+        let sections: Vec<(String, usize, usize)> = (0..shnum)
+            .map(|i| {
+                let header = shoff + i * 64;
+                let name = names + u32_at(header) as usize;
+                let len = object[name..].iter().position(|&c| c == 0).unwrap();
+                let name = String::from_utf8(object[name..name + len].to_vec()).unwrap();
+                (
+                    name,
+                    u64_at(header + 24) as usize,
+                    u64_at(header + 32) as usize,
+                )
+            })
+            .collect();
+        let section = |name: &str| {
+            let (_, offset, size) = sections.iter().find(|(n, _, _)| n == name).unwrap();
+            &object[*offset..*offset + *size]
+        };
+
+        assert_eq!(section("license"), b"GPL\0");
+        assert_eq!(section(".maps"), [0; 32]);
+        assert_eq!(&section(".BTF")[..2], 0xeb9fu16.to_ne_bytes());
+        assert_eq!(&section(".BTF.ext")[..2], 0xeb9fu16.to_ne_bytes());
+
+        /*
+         * The map load is relocated against the map's symbol and its fd is zeroed.
+         */
+        let code = section("kprobe/do_sys_open");
+        let rel = section(".relkprobe/do_sys_open");
+        assert_eq!(rel.len(), 16);
+        let offset = u64::from_ne_bytes(rel[..8].try_into().unwrap()) as usize;
+        let info = u64::from_ne_bytes(rel[8..].try_into().unwrap());
+        assert_eq!(info, 4 << 32 | 1);
+        let (lddw, _) = Instruction::loadtype(Register::R1, 0, MemoryOpLoadType::Void).encode();
+        assert_eq!(
+            u64::from_ne_bytes(code[offset..offset + 8].try_into().unwrap()),
+            lddw
+        );
+    }
+
+    #[test]
+    fn program_btf() {
+        let prog = r#"
+            fn(a: int)
+                b: int = a + 1
+                if b == 2 {
+                    return 1
+                }
+                return b
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+        let program = compiler.get_btf("kprobe/do_sys_open");
+
+        assert_eq!(&program.btf()[..2], 0xeb9fu16.to_ne_bytes());
+        assert_eq!(program.func_info().len(), 1);
+        assert_eq!(program.func_info()[0].insn_off, 0);
+
+        /*
+         * Every statement gets a line record, in instruction order. The argument
+         * stays in its register, so no prologue is left to attribute to the
+         * function's declaration.
+         */
+        let lines: Vec<(u32, u32)> = program
+            .line_info()
+            .iter()
+            .map(|info| (info.insn_off, info.line()))
+            .collect();
+        assert_eq!(lines[0], (0, 3));
+        for line in [3, 4, 5, 7] {
+            assert!(lines.iter().any(|(_, l)| *l == line));
+        }
+        assert!(lines.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(program.line_info().iter().all(|info| info.column() > 0));
+    }
+
+    #[test]
+    fn core_relocations() {
+        let prog = r#"
+            fn(head: &list_head, uts: &new_utsname)
+                first: u64 = head.next
+                c: u8 = uts.nodename[3]
+                prev: u64 = head.prev.prev
+                return 0
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_core_relocations(true);
+        compiler.compile(prog).unwrap();
+
+        /*
+         * Every access through a pointer is relocated, including ones at offset 0,
+         * and runs of accesses within a struct share a single relocation.
+         */
+        let instructions = compiler.get_instructions();
+        let relocations = compiler.get_core_relocations();
+        let accesses: Vec<(String, String, Instruction)> = relocations
+            .iter()
+            .map(|r| {
+                assert_eq!(r.kind, RelocationKind::FieldByteOffset);
+                (
+                    r.access_string(),
+                    r.field_path(),
+                    instructions[r.instruction],
+                )
+            })
+            .collect();
+        assert_eq!(
+            accesses,
+            [
+                (
+                    "0:0".into(),
+                    "next".into(),
+                    Instruction::add64(Register::R6, 0)
+                ),
+                (
+                    "0:1:3".into(),
+                    "nodename[3]".into(),
+                    Instruction::add64(Register::R6, 68)
+                ),
+                (
+                    "0:1".into(),
+                    "prev".into(),
+                    Instruction::loadx64(Register::R6, Register::R6, 8)
+                ),
+                (
+                    "0:1".into(),
+                    "prev".into(),
+                    Instruction::add64(Register::R6, 8)
+                ),
+            ]
+        );
+
+        let program = compiler.get_btf("kprobe/do_sys_open");
+        assert_eq!(program.core_relos().len(), 4);
+        assert!(program
+            .core_relos()
+            .iter()
+            .zip(relocations)
+            .all(|(relo, r)| relo.insn_off == r.instruction as u32));
+
+        /*
+         * Without CO-RE the same program has no relocations and skips zero offsets.
+         */
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+        assert!(compiler.get_core_relocations().is_empty());
+        assert!(compiler
+            .get_btf("kprobe/do_sys_open")
+            .core_relos()
+            .is_empty());
+    }
+
+    #[test]
+    fn field_info() {
+        let prog = r#"
+            fn(uts: &new_utsname, head: &list_head, ts: &timespec64)
+                size: u64 = field_size(uts.release)
+                offset: u64 = field_offset(uts.release)
+                signed: u64 = field_signed(ts.tv_nsec)
+                exists: u64 = field_exists(head.prev.next)
+                return 0
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_core_relocations(true);
+        compiler.compile(prog).unwrap();
+
+        let instructions = compiler.get_instructions();
+        let values: Vec<(RelocationKind, String, Instruction)> = compiler
+            .get_core_relocations()
+            .iter()
+            .map(|r| (r.kind, r.access_string(), instructions[r.instruction]))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                (
+                    RelocationKind::FieldByteSize,
+                    "0:2".into(),
+                    Instruction::mov64(Register::R0, 65)
+                ),
+                (
+                    RelocationKind::FieldByteOffset,
+                    "0:2".into(),
+                    Instruction::mov64(Register::R0, 130)
+                ),
+                (
+                    RelocationKind::FieldSigned,
+                    "0:1".into(),
+                    Instruction::mov64(Register::R0, 1)
+                ),
+                (
+                    RelocationKind::FieldExists,
+                    "0:0".into(),
+                    Instruction::mov64(Register::R0, 1)
+                ),
+            ]
+        );
+
+        assert!(matches!(
+            compile_error("fn(uts: &new_utsname)\n  x: u64 = field_size(uts.release[0])"),
+            CompileError::InvalidType { .. }
+        ));
+        assert!(matches!(
+            compile_error("fn(head: &list_head)\n  x: u64 = field_size(head)"),
+            CompileError::InvalidType { .. }
+        ));
+        assert!(matches!(
+            compile_error("fn(head: &list_head)\n  x: u64 = field_exists(head.nope)"),
+            CompileError::UnknownMember { .. }
+        ));
+    }
+
+    /// Creates a type library with a table of entries, the layout of which depends
+    /// on whether `padded` is set.
+    fn table_types(padded: bool) -> BtfTypes {
+        let mut btf = BtfTypes::default();
+        btf.add_integer("u8", 1, false).unwrap();
+        btf.add_integer("u32", 4, false).unwrap();
+        btf.add_integer("u64", 8, false).unwrap();
+        btf.add_array("name_t", "u32", "u8", 8).unwrap();
+        match padded {
+            false => btf.add_struct("entry", &[("id", "u32"), ("name", "name_t")]),
+            true => btf.add_struct(
+                "entry",
+                &[("pad", "u64"), ("id", "u32"), ("name", "name_t")],
+            ),
+        }
+        .unwrap();
+        btf.add_array("entries_t", "u32", "entry", 4).unwrap();
+        match padded {
+            false => btf.add_struct("table", &[("count", "u32"), ("entries", "entries_t")]),
+            true => btf.add_struct(
+                "table",
+                &[("flags", "u64"), ("count", "u32"), ("entries", "entries_t")],
+            ),
+        }
+        .unwrap();
+        btf
+    }
+
+    #[test]
+    fn relocate_to_target() {
+        let prog = r#"
+            fn(t: &table, i: u32)
+                count: u32 = t.count
+                c: u8 = t.entries[1].name[2]
+                id: u32 = t.entries[i].id
+                exists: u64 = field_exists(t.count)
+                return count
+        "#;
+
+        let local = table_types(false);
+        let target = table_types(true);
+        let mut compiler = Compiler::create(&local);
+        compiler.set_core_relocations(true);
+        compiler.compile(prog).unwrap();
+        assert!(compiler
+            .get_core_relocations()
+            .iter()
+            .any(|r| r.kind == RelocationKind::TypeSize));
+
+        /*
+         * Relocating to the target is the same as compiling against it.
+         */
+        let mut expected = Compiler::create(&target);
+        expected.set_core_relocations(true);
+        expected.compile(prog).unwrap();
+        assert_ne!(compiler.get_instructions(), expected.get_instructions());
+        assert_eq!(
+            compiler.relocate(&target).unwrap(),
+            expected.get_instructions()
+        );
+        assert_eq!(
+            relocate(
+                compiler.get_instructions(),
+                compiler.get_core_relocations(),
+                &local,
+                &local
+            )
+            .unwrap(),
+            compiler.get_instructions()
+        );
+    }
+
+    #[test]
+    fn relocate_missing_fields() {
+        let prog = r#"
+            fn(t: &table, i: u32)
+                exists: u64 = field_exists(t.count)
+                count: u32 = t.count
+                id: u32 = t.entries[i].id
+                return count
+        "#;
+
+        let local = table_types(false);
+        let mut target = BtfTypes::default();
+        target.add_integer("u32", 4, false).unwrap();
+        target.add_struct("entry", &[("name", "u32")]).unwrap();
+        target.add_array("entries_t", "u32", "entry", 4).unwrap();
+        target
+            .add_struct("table", &[("entries", "entries_t")])
+            .unwrap();
+
+        let mut compiler = Compiler::create(&local);
+        compiler.set_core_relocations(true);
+        compiler.compile(prog).unwrap();
+
+        let missing = match compiler.relocate(&target) {
+            Err(RelocationError::MissingFields(missing)) => missing,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        let fields: Vec<&str> = missing.iter().map(|m| m.field.as_str()).collect();
+        assert_eq!(fields, ["table.count", "entry.id"]);
+        assert!(RelocationError::MissingFields(missing)
+            .to_string()
+            .contains("table.count doesn't exist"));
+
+        /*
+         * Existence checks of missing fields are relocated to 0 rather than failing.
+         */
+        let prog = r#"
+            fn(t: &table)
+                return field_exists(t.count)
+        "#;
+        let mut compiler = Compiler::create(&local);
+        compiler.set_core_relocations(true);
+        compiler.compile(prog).unwrap();
+        let instructions = compiler.relocate(&target).unwrap();
+        let relocation = &compiler.get_core_relocations()[0];
+        assert_eq!(
+            instructions[relocation.instruction],
+            Instruction::mov64(Register::R0, 0)
+        );
+    }
+
+    #[test]
+    fn interpret_arithmetic() {
+        let prog = r#"
+            fn(a: __u64, b: __s64)
+                c: __u64 = 0
+                i: __u64 = 0
+                while i < a {
+                    c += i
+                    i += 1
+                }
+                if b < 0 {
+                    return c * 2
+                }
+                return c
+        "#;
+
+        let instructions = compile_with_map(prog);
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.run_with_args(&instructions, &[10, 1]), Ok(45));
+        assert_eq!(
+            interpreter.run_with_args(&instructions, &[10, -1i64 as u64]),
+            Ok(90)
+        );
+
+        interpreter.set_max_instructions(100);
+        assert_eq!(
+            interpreter.run_with_args(&instructions, &[1000, 1]),
+            Err(ExecutionError::TooManyInstructions { max: 100 })
+        );
+    }
+
+    #[test]
+    fn interpret_maps() {
+        let prog = r#"
+            fn(key: __u32)
+                map counts: hash<__u32, __u64>[2]
+                counts[key] += 5
+        "#;
+
+        let instructions = compile_with_map(prog);
+        let mut interpreter = Interpreter::new();
+        interpreter.create_map(3, 1, 4, 8, 2);
+        for key in [1u64, 1, 2, 3] {
+            interpreter.run_with_args(&instructions, &[key]).unwrap();
+        }
+
+        let value = |key: u32| {
+            interpreter
+                .map_value(3, &key.to_ne_bytes())
+                .map(|v| v.to_vec())
+        };
+        assert_eq!(value(1), Some(10u64.to_ne_bytes().to_vec()));
+        assert_eq!(value(2), Some(5u64.to_ne_bytes().to_vec()));
+        assert_eq!(value(3), None); // the map is full
+
+        /*
+         * When another program inserts the key between the lookup and the update,
+         * the update fails with -EEXIST and the add is applied to its element.
+         */
+        let mut interpreter = Interpreter::new();
+        interpreter.create_map(3, 1, 4, 8, 2);
+        interpreter.set_helper(Helpers::MapUpdateElem, |env, _| {
+            env.map_update(3, &1u32.to_ne_bytes(), &7u64.to_ne_bytes(), 0);
+            -17i64 as u64
+        });
+        interpreter.run_with_args(&instructions, &[1]).unwrap();
+        assert_eq!(
+            interpreter.map_value(3, &1u32.to_ne_bytes()),
+            Some(&12u64.to_ne_bytes()[..])
+        );
+
+        /*
+         * Maps have to be created before programs that use them run.
+         */
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(
+            interpreter.run_with_args(&instructions, &[1]),
+            Err(ExecutionError::UnknownMap { fd: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn interpret_mocked_helpers() {
+        let prog = r#"
+            fn(file: &file)
+                pid: __u64 = get_current_pid_tgid()
+                ino: __u64 = file.f_inode.i_ino
+                return ino + pid
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let file = btf.resolve_type_by_name("file").unwrap();
+        let inode = btf.resolve_type_by_name("inode").unwrap();
+        let f_inode = match &file.base_type {
+            btf::types::Type::Struct(s) => s.members["f_inode"].offset / 8,
+            _ => panic!("file isn't a struct"),
+        };
+        let i_ino = match &inode.base_type {
+            btf::types::Type::Struct(s) => s.members["i_ino"].offset / 8,
+            _ => panic!("inode isn't a struct"),
+        };
+
+        let instructions = compile_with_map(prog);
+        let mut interpreter = Interpreter::new();
+
+        /*
+         * Helpers without a mock are reported rather than returning garbage.
+         */
+        assert!(matches!(
+            interpreter.run_with_args(&instructions, &[0x1000]),
+            Err(ExecutionError::MissingHelper { id: 14, .. })
+        ));
+
+        interpreter.set_helper(Helpers::GetCurrentPidTgid, |_, _| 7);
+        let mut file_memory = vec![0; f_inode as usize + 8];
+        file_memory[f_inode as usize..].copy_from_slice(&0x2000u64.to_ne_bytes());
+        let mut inode_memory = vec![0; i_ino as usize + 8];
+        inode_memory[i_ino as usize..].copy_from_slice(&35u64.to_ne_bytes());
+        interpreter.add_memory(0x1000, &file_memory);
+        interpreter.add_memory(0x2000, &inode_memory);
+        assert_eq!(interpreter.run_with_args(&instructions, &[0x1000]), Ok(42));
+
+        /*
+         * Reads of unmapped kernel memory fail and zero the destination.
+         */
+        file_memory[f_inode as usize..].copy_from_slice(&0x9000u64.to_ne_bytes());
+        interpreter.add_memory(0x3000, &file_memory);
+        assert_eq!(interpreter.run_with_args(&instructions, &[0x3000]), Ok(7));
+    }
+
+    #[test]
+    fn verifier_rejects_helper_arguments() {
+        let prog = r#"
+            fn()
+                map counts: hash<__u32, __u64>
+                k: __u32 = 1
+                r: __u64 = map_delete_elem(counts, k)
+                return r
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("counts", 3);
+        let error = compiler.compile(prog).unwrap_err();
+        assert!(matches!(
+            &error,
+            CompileError::VerifierRejected { reason, .. }
+                if reason == "Argument 2 of MapDeleteElem must point to initialized memory"
+        ));
+        assert_eq!(error.span().line, 5);
+
+        let prog = r#"
+            fn()
+                map counts: hash<__u32, __u64>
+                k: __u32 = 1
+                r: __u64 = map_delete_elem(counts, &k)
+                return r
+        "#;
+        compile_with_map(prog);
+    }
+
+    #[test]
+    fn verifier_rules() {
+        let reject = |instructions: &[Instruction]| {
+            let maps = [(
+                3,
+                MapSizes {
+                    key_size: 4,
+                    value_size: 8,
+                },
+            )]
+            .into();
+            verify(instructions, &[], &maps).unwrap_err()
+        };
+
+        let violation = reject(&[
+            Instruction::movx64(Register::R0, Register::R2), // r0 = r2
+            Instruction::exit(),                             // exit
+        ]);
+        assert_eq!(violation.instruction, 0);
+        assert_eq!(violation.reason, "R2 is read before it's written");
+
+        let violation = reject(&[
+            Instruction::loadx64(Register::R0, Register::R10, -520), // r0 = *(r10 - 520)
+            Instruction::exit(),                                     // exit
+        ]);
+        assert!(violation.reason.contains("outside the 512 byte stack"));
+
+        let violation = reject(&[
+            Instruction::store32(Register::R10, -8, 0), // *(r10 - 8) = 0
+            Instruction::loadx64(Register::R0, Register::R10, -8), // r0 = *(r10 - 8)
+            Instruction::exit(),                        // exit
+        ]);
+        assert_eq!(
+            violation.reason,
+            "Stack at offset -4 is read before it's written"
+        );
+
+        let violation = reject(&[
+            Instruction::mov64(Register::R1, 64),                // r1 = 64
+            Instruction::loadx64(Register::R0, Register::R1, 0), // r0 = *(r1 + 0)
+            Instruction::exit(),                                 // exit
+        ]);
+        assert!(violation.reason.contains("R1 is a scalar"));
+
+        let violation = reject(&[
+            Instruction::movx64(Register::R1, Register::R10), // r1 = r10
+            Instruction::alu64(Register::R1, 2, ArithmeticOperation::Mul), // r1 *= 2
+            Instruction::mov64(Register::R0, 0),              // r0 = 0
+            Instruction::exit(),                              // exit
+        ]);
+        assert_eq!(violation.instruction, 1);
+        assert_eq!(
+            violation.reason,
+            "Pointer arithmetic with multiplication is prohibited"
+        );
+
+        /*
+         * The lookup's result may only be used on the branch where it isn't null.
+         */
+        let lookup = [
+            Instruction::store32(Register::R10, -4, 0), // *(r10 - 4) = 0
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map 3
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -4),       // r2 -= 4
+            Instruction::call(Helpers::MapLookupElem as u32), // call map_lookup_elem
+        ];
+        let mut program = lookup.to_vec();
+        program.extend([
+            Instruction::loadx64(Register::R0, Register::R0, 0), // r0 = *(r0 + 0)
+            Instruction::exit(),                                 // exit
+        ]);
+        assert!(reject(&program).reason.contains("R0 may be null"));
+
+        let mut program = lookup.to_vec();
+        program.extend([
+            jump::if_imm(JumpOperation::IfEqual, Register::R0, 0, 1), // if r0 == 0 goto +1
+            Instruction::loadx64(Register::R0, Register::R0, 4),      // r0 = *(r0 + 4)
+            Instruction::exit(),                                      // exit
+        ]);
+        assert!(reject(&program)
+            .reason
+            .contains("outside the 8 byte map value"));
+
+        /*
+         * Stack accesses at runtime offsets need a bound on the offset, and every
+         * byte they may access has to be in the stack and initialized.
+         */
+        let indexed = |bound, initialized: &[i16]| {
+            let mut program: Vec<_> = initialized
+                .iter()
+                .map(|offset| Instruction::store64(Register::R10, *offset, 0))
+                .collect();
+            program.push(Instruction::call(Helpers::GetCurrentPidTgid as u32));
+            if let Some(bound) = bound {
+                program.extend([
+                    jump::if_imm(JumpOperation::IfLessThan, Register::R0, bound, 2), // if r0 < bound goto +2
+                    Instruction::mov64(Register::R0, 0),                             // r0 = 0
+                    Instruction::exit(),                                             // exit
+                ]);
+            }
+            program.extend([
+                Instruction::alu64(Register::R0, 3, ArithmeticOperation::Lhs), // r0 <<= 3
+                Instruction::movx64(Register::R1, Register::R10),              // r1 = r10
+                Instruction::add64(Register::R1, -16),                         // r1 -= 16
+                Instruction::addx64(Register::R1, Register::R0),               // r1 += r0
+                Instruction::loadx64(Register::R0, Register::R1, 0),           // r0 = *(r1 + 0)
+                Instruction::exit(),                                           // exit
+            ]);
+            verify(&program, &[], &Default::default())
+        };
+        assert_eq!(indexed(Some(2), &[-8, -16]), Ok(()));
+        assert_eq!(
+            indexed(None, &[-8, -16]).unwrap_err().reason,
+            "Stack access at an unknown offset"
+        );
+        assert_eq!(
+            indexed(Some(2), &[-8]).unwrap_err().reason,
+            "Stack at offset -16 is read before it's written"
+        );
+        assert!(indexed(Some(3), &[-8, -16])
+            .unwrap_err()
+            .reason
+            .contains("outside the 512 byte stack"));
+
+        /*
+         * Every path has to end in an exit.
+         */
+        let violation = reject(&[
+            Instruction::mov64(Register::R0, 0), // r0 = 0
+            jump::if_imm(JumpOperation::IfEqual, Register::R10, 0, 1), // if r10 == 0 goto +1
+            Instruction::exit(),                 // exit
+            jump::always(-1),                    // goto -1
+        ]);
+        assert_eq!(violation.instruction, 3);
+        assert_eq!(violation.reason, "No path from here reaches an exit");
+
+        let violation = reject(&[Instruction::mov64(Register::R0, 0)]);
+        assert_eq!(
+            violation.reason,
+            "Execution can run past the end of the program"
+        );
+    }
+
+    #[test]
+    fn register_allocation() {
+        let prog = r#"
+            fn(a: int)
+                b: __u64 = get_current_pid_tgid()
+                c: __u64 = get_current_uid_gid()
+                return a + b + c
+        "#;
+
+        /*
+         * Variables stay in registers, and the ones that are live across helper
+         * calls are kept in the callee saved R6-R9.
+         */
+        let expected = [
+            Instruction::movx64(Register::R6, Register::R1), // r6 = r1
+            Instruction::call(Helpers::GetCurrentPidTgid as u32), // call #14
+            Instruction::movx64(Register::R9, Register::R0), // r9 = r0
+            Instruction::call(Helpers::GetCurrentUidGid as u32), // call #15
+            Instruction::movx64(Register::R7, Register::R0), // r7 = r0
+            Instruction::movx32(Register::R0, Register::R6), // r0 = r6
+            Instruction::alu64(Register::R0, 32, ArithmeticOperation::Lhs), // r0 <<= 32
+            Instruction::alu64(Register::R0, 32, ArithmeticOperation::Ash), // r0 s>>= 32
+            Instruction::addx64(Register::R0, Register::R9), // r0 += r9
+            Instruction::movx64(Register::R9, Register::R7), // r9 = r7
+            Instruction::addx64(Register::R0, Register::R9), // r0 += r9
+            Instruction::exit(),                             // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_helper(Helpers::GetCurrentPidTgid, |_, _| 10);
+        interpreter.set_helper(Helpers::GetCurrentUidGid, |_, _| 100);
+        assert_eq!(interpreter.run_with_args(&expected, &[1]), Ok(111));
+    }
+
+    #[test]
+    fn ir_dump() {
+        let prog = r#"
+            fn(a: int)
+                b: __u64 = 0
+                while b < a {
+                    b += 2
+                }
+                return b
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+
+        /*
+         * The condition and the loop counter each end a block, and both leave the
+         * loop by branching to the block holding the return.
+         */
+        let expected = "\
+bb0:
+    *(u64 *)(r10 - 8) = r1
+    *(u64 *)(r10 - 16) = 0
+    *(u64 *)(r10 - 24) = 0
+    goto bb1
+bb1:
+    r6 = *(u64 *)(r10 - 16)
+    r7 = *(u32 *)(r10 - 8)
+    r7 = r7 << 32
+    r7 = r7 s>> 32
+    if r6 >= r7 goto bb4 else bb2
+bb2:
+    r6 = *(u64 *)(r10 - 24)
+    if r6 >= 1024 goto bb4 else bb3
+bb3:
+    r6 = r6 + 1
+    *(u64 *)(r10 - 24) = r6
+    r6 = *(u64 *)(r10 - 16)
+    r6 = r6 + 2
+    *(u64 *)(r10 - 16) = r6
+    goto bb1
+bb4:
+    r0 = *(u64 *)(r10 - 16)
+    exit
+";
+        assert_eq!(compiler.get_ir().to_string(), expected);
+        assert_eq!(
+            compiler.get_ir().predecessors()[4],
+            vec![BlockId(1), BlockId(2)]
+        );
+    }
+
+    #[test]
+    fn ir_lowering() {
+        /*
+         * bb0 branches to the block right after it when the condition holds, so the
+         * condition is inverted to fall through to it instead. The jump from bb1 to
+         * bb2 is dropped, and the jump back from bb2 skips the two-slot lddw.
+         */
+        let mut function = Function::default();
+        function.blocks.push(Block {
+            insts: vec![Inst::new(Op::Branch {
+                op: JumpOperation::IfEqual,
+                lhs: Register::R1,
+                rhs: 0.into(),
+                then: BlockId(1),
+                otherwise: BlockId(3),
+            })],
+        });
+        function.blocks.push(Block {
+            insts: vec![
+                Inst::new(Op::mov64(Register::R0, 0x1_0000_0000i64)),
+                Inst::new(Op::Jump { target: BlockId(2) }),
+            ],
+        });
+        function.blocks.push(Block {
+            insts: vec![
+                Inst::new(Op::alu(
+                    ArithmeticOperation::Sub,
+                    Width::Bits64,
+                    Register::R1,
+                    1,
+                )),
+                Inst::new(Op::Jump { target: BlockId(0) }),
+            ],
+        });
+        function.blocks.push(Block {
+            insts: vec![Inst::new(Op::Exit)],
+        });
+
+        let expected = [
+            jump::if_imm(JumpOperation::IfNotEqual, Register::R1, 0, 4), // if r1 != 0 goto +4
+            Instruction::loadtype(Register::R0, 0x1_0000_0000, MemoryOpLoadType::Void), // r0 = 0x100000000 ll
+            Instruction::alu64(Register::R1, 1, ArithmeticOperation::Sub),              // r1 -= 1
+            jump::always(-5),                                                           // goto -5
+            Instruction::exit(),                                                        // exit
+        ];
+
+        assert_eq!(function.lower().unwrap(), expected);
+    }
+
+    #[test]
+    fn constant_folding() {
+        let prog = r#"
+            fn(x: __u64)
+                a: __u64 = 6
+                b: __u64 = a * 7
+                if b == 42 {
+                    return x + 0xffffffff
+                }
+                return b
+        "#;
+
+        /*
+         * The condition is always true, so only the first return is left and the
+         * stores to `a` and `b` are never read.
+         */
+        let expected = [
+            Instruction::movx64(Register::R0, Register::R1), // r0 = r1
+            Instruction::loadtype(Register::R9, 0xffffffff, MemoryOpLoadType::Void), // r9 = 0xffffffff ll
+            Instruction::addx64(Register::R0, Register::R9),                         // r0 += r9
+            Instruction::exit(),                                                     // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.run_with_args(&expected, &[1]),
+            Ok(0x1_0000_0000)
+        );
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let prog = r#"
+            fn()
+                return 1
+                get_current_pid_tgid()
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R0, 1), // r0 = 1
+            Instruction::exit(),                 // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn dead_stores() {
+        let prog = r#"
+            fn()
+                map counts: hash<__u32, iovec>
+                vec: iovec = 0
+                vec.iov_base = 100
+                vec.iov_len = 200
+                counts[1] = vec
+        "#;
+
+        /*
+         * Zeroing `vec` is overwritten by assigning all of its fields.
+         */
+        let expected = [
+            Instruction::store64(Register::R10, -16, 100), // *(r10 - 16) = 100
+            Instruction::store64(Register::R10, -8, 200),  // *(r10 - 8) = 200
+            Instruction::store32(Register::R10, -20, 1),   // *(r10 - 20) = 1
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map 3
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -20),         // r2 -= 20
+            Instruction::movx64(Register::R3, Register::R10), // r3 = r10
+            Instruction::add64(Register::R3, -16),         // r3 -= 16
+            Instruction::mov64(Register::R4, 0),           // r4 = 0
+            Instruction::call(Helpers::MapUpdateElem as u32), // call map_update_elem
+            Instruction::mov64(Register::R0, 0),           // r0 = 0
+            Instruction::exit(),                           // exit
+        ];
+
+        assert_eq!(compile_with_map(prog), expected);
+    }
+
+    #[test]
+    fn opt_levels() {
+        let prog = r#"
+            fn(a: __u64)
+                b: __u64 = 6
+                c: __u64 = b * 7
+                if a > c {
+                    return a - c
+                }
+                return c
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let compile = |level| {
+            let mut compiler = Compiler::create(&btf);
+            compiler.set_opt_level(level);
+            compiler.compile(prog).unwrap();
+            (
+                compiler.get_instructions().to_vec(),
+                compiler.get_pass_report().to_vec(),
+            )
+        };
+
+        let (o0, report) = compile(OptLevel::O0);
+        assert!(report.is_empty());
+
+        let (o1, report) = compile(OptLevel::O1);
+        let names: Vec<&str> = report.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["constant-folding", "dead-code", "peephole"]);
+        assert!(report[0].fired());
+
+        let (o2, report) = compile(OptLevel::O2);
+        let names: Vec<&str> = report.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "constant-folding",
+                "redundant-loads",
+                "dead-stores",
+                "dead-code",
+                "peephole",
+                "register-allocation"
+            ]
+        );
+        assert!(report.iter().map(|p| p.removed).sum::<usize>() > 0);
+
+        assert!(o0.len() > o1.len() && o1.len() > o2.len());
+        let mut interpreter = Interpreter::new();
+        for instructions in [&o0, &o1, &o2] {
+            assert_eq!(interpreter.run_with_args(instructions, &[50]), Ok(8));
+            assert_eq!(interpreter.run_with_args(instructions, &[10]), Ok(42));
+        }
+    }
+
+    #[test]
+    fn custom_pass() {
+        /*
+         * Turns every addition of registers into a subtraction.
+         */
+        struct Subtract {
+            runs: Rc<Cell<usize>>,
+        }
+
+        impl Pass for Subtract {
+            fn name(&self) -> &str {
+                "subtract"
+            }
+
+            fn run(&mut self, function: &mut Function) -> bool {
+                self.runs.set(self.runs.get() + 1);
+                let mut changed = false;
+                for inst in function.blocks.iter_mut().flat_map(|b| &mut b.insts) {
+                    if let Op::Arithmetic {
+                        op: op @ ArithmeticOperation::Add,
+                        rhs: Value::Register(_),
+                        ..
+                    } = &mut inst.op
+                    {
+                        *op = ArithmeticOperation::Sub;
+                        changed = true;
+                    }
+                }
+                changed
+            }
+        }
+
+        let prog = r#"
+            fn(a: __u64, b: __u64)
+                return a + b
+        "#;
+
+        /*
+         * Custom passes run even when optimizations are disabled, and are repeated
+         * until they stop changing the program.
+         */
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let runs = Rc::new(Cell::new(0));
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_opt_level(OptLevel::O0);
+        compiler.add_pass(Subtract { runs: runs.clone() });
+        compiler.compile(prog).unwrap();
+
+        assert_eq!(runs.get(), 2);
+        let report = compiler.get_pass_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].runs, report[0].changes), (2, 1));
+
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.run_with_args(compiler.get_instructions(), &[5, 3]),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn peephole_blocks() {
+        /*
+         * A jump to the load splits it from the add, they can't be combined.
+         */
+        let program = [
+            jump::if_imm(JumpOperation::IfEqual, Register::R1, 0, 1), // if r1 == 0 goto +1
+            Instruction::add64(Register::R2, 8),                      // r2 += 8
+            Instruction::loadx64(Register::R2, Register::R2, 0),      // r2 = *(r2 + 0)
+            Instruction::exit(),                                      // exit
+        ];
+        assert_eq!(optimize(&program).0, program);
+
+        /*
+         * Sequences that use different registers don't match.
+         */
+        let program = [
+            Instruction::movx64(Register::R2, Register::R1), // r2 = r1
+            Instruction::add64(Register::R2, 8),             // r2 += 8
+            Instruction::loadx64(Register::R3, Register::R2, 0), // r3 = *(r2 + 0)
+            Instruction::add64(Register::R4, 8),             // r4 += 8
+            Instruction::loadx64(Register::R4, Register::R5, 0), // r4 = *(r5 + 0)
+            Instruction::exit(),                             // exit
+        ];
+        assert_eq!(optimize(&program).0, program);
+
+        /*
+         * Jumps over a rewritten sequence and a two-slot lddw are shortened by the
+         * instructions that were removed.
+         */
+        let program = [
+            jump::if_imm(JumpOperation::IfEqual, Register::R1, 0, 5), // if r1 == 0 goto +5
+            Instruction::movx64(Register::R2, Register::R1),          // r2 = r1
+            Instruction::add64(Register::R2, 16),                     // r2 += 16
+            Instruction::loadx32(Register::R2, Register::R2, 0),      // r2 = *(r2 + 0)
+            Instruction::loadtype(Register::R3, 1 << 32, MemoryOpLoadType::Void), // r3 = 1 << 32 ll
+            Instruction::movx64(Register::R0, Register::R2),          // r0 = r2
+            Instruction::exit(),                                      // exit
+        ];
+        let expected = [
+            jump::if_imm(JumpOperation::IfEqual, Register::R1, 0, 3), // if r1 == 0 goto +3
+            Instruction::loadx32(Register::R2, Register::R1, 16),     // r2 = *(r1 + 16)
+            Instruction::loadtype(Register::R3, 1 << 32, MemoryOpLoadType::Void), // r3 = 1 << 32 ll
+            Instruction::movx64(Register::R0, Register::R2),          // r0 = r2
+            Instruction::exit(),                                      // exit
+        ];
+        let (optimized, new_index) = optimize(&program);
+        assert_eq!(optimized, expected);
+        assert_eq!(new_index, [0, 1, 1, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn local_functions() {
+        let prog = r#"
+            fn add(a: __u64, b: __u64) -> __u64 {
+                return a + b
+            }
+            fn(x: __u64)
+                return add(x, 1)
+        "#;
+
+        compile_and_compare(
+            prog,
+            &[
+                Instruction::mov64(Register::R2, 1),             // r2 = 1
+                jump::call_local(1),                             // call pc+1
+                Instruction::exit(),                             // exit
+                Instruction::movx64(Register::R0, Register::R1), // r0 = r1
+                Instruction::movx64(Register::R9, Register::R2), // r9 = r2
+                Instruction::addx64(Register::R0, Register::R9), // r0 += r9
+                Instruction::exit(),                             // exit
+            ],
+        );
+
+        /*
+         * Functions can call each other, get pointers to the caller's stack and
+         * share maps, values are truncated to the return type.
+         */
+        let prog = r#"
+            fn low(x: __u64) -> __u8 {
+                return x
+            }
+            fn sign(x: __u64) -> __s8 {
+                return x
+            }
+            fn get(p: &__u64) -> __u64 {
+                return *p + low(*p)
+            }
+            fn count(key: __u32) {
+                map counts: array<__u32, __u64>[4]
+                counts[key] += 1
+            }
+            fn(a: __u64, b: __u64)
+                map counts: array<__u32, __u64>[4]
+                count(1)
+                count(1)
+                if b == 1 {
+                    return sign(a)
+                }
+                v: __u64 = a
+                return get(&v) + counts[1]
+        "#;
+
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+            let mut compiler = Compiler::create(&btf);
+            compiler.set_opt_level(level);
+            compiler.capture("counts", 3);
+            compiler.compile(prog).unwrap();
+            let instructions = compiler.get_instructions();
+
+            let mut interpreter = Interpreter::new();
+            interpreter.create_map(3, 2, 4, 8, 4);
+            let result = interpreter.run_with_args(instructions, &[0x1ff, 1]);
+            assert_eq!(result, Ok(u64::MAX));
+            let result = interpreter.run_with_args(instructions, &[0x1ff, 0]);
+            assert_eq!(result, Ok(0x1ff + 0xff + 4));
+        }
+    }
+
+    #[test]
+    fn nested_calls() {
+        /*
+         * Arguments that call functions are evaluated before the other arguments
+         * are put in their registers.
+         */
+        let prog = r#"
+            fn add(a: __u64, b: __u64, c: __u64) -> __u64 {
+                return a * 100 + b * 10 + c
+            }
+            fn(x: __u64)
+                map counts: array<__u32, __u64>[4]
+                v: __u64 = 0
+                probe_read_kernel(&v, 8, get_current_uid_gid())
+                counts[1] = 3
+                return add(x, get_current_pid_tgid(), counts[1]) + v
+        "#;
+
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+            let mut compiler = Compiler::create(&btf);
+            compiler.set_opt_level(level);
+            compiler.capture("counts", 3);
+            compiler.compile(prog).unwrap();
+            let instructions = compiler.get_instructions();
+
+            let mut interpreter = Interpreter::new();
+            interpreter.create_map(3, 2, 4, 8, 4);
+            interpreter.set_helper(Helpers::GetCurrentPidTgid, |_, _| 2);
+            interpreter.set_helper(Helpers::GetCurrentUidGid, |_, _| 0x1000);
+            interpreter.add_memory(0x1000, &1000u64.to_ne_bytes());
+            let result = interpreter.run_with_args(instructions, &[1]);
+            assert_eq!(result, Ok(1123));
+        }
+    }
+
+    #[test]
+    fn local_function_errors() {
+        let prog = r#"
+            fn ping(x: __u64) -> __u64 {
+                return pong(x)
+            }
+            fn pong(x: __u64) -> __u64 {
+                return ping(x)
+            }
+            fn()
+                return ping(1)
+        "#;
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::InvalidFunction { .. }));
+        assert_eq!((error.span().line, error.identifier()), (6, Some("ping")));
+
+        let prog = r#"
+            fn f(a: __u64) {
+            }
+            fn()
+                return f(1, 2)
+        "#;
+        assert!(matches!(
+            compile_error(prog),
+            CompileError::InvalidFunction { .. }
+        ));
+
+        let prog = r#"
+            fn f() {
+            }
+            fn f() {
+            }
+            fn()
+                return 0
+        "#;
+        assert!(matches!(
+            compile_error(prog),
+            CompileError::InvalidFunction { .. }
+        ));
+
+        let prog = r#"
+            fn f() -> &task_struct {
+            }
+            fn()
+                return 0
+        "#;
+        assert!(matches!(
+            compile_error(prog),
+            CompileError::InvalidType { .. }
+        ));
+
+        let prog = r#"
+            fn lookup(pid: __u32) -> __u32 {
+                return pid
+            }
+            fn()
+                return lookpu(1)
+        "#;
+        assert_eq!(compile_error(prog).suggestion(), Some("lookup"));
+
+        /*
+         * Each frame is rounded up to 32 bytes, 2 * 168 + 168 bytes need 544.
+         */
+        let prog = r#"
+            fn big() -> __u64 {
+                a: pt_regs = 0
+                b: pt_regs = 0
+                return a.ax + b.bx
+            }
+            fn()
+                c: pt_regs = 0
+                return big() + c.cx
+        "#;
+        let error = compile_error(prog);
+        assert!(matches!(error, CompileError::InvalidFunction { .. }));
+        assert_eq!(error.span().line, 9);
+    }
+
+    #[test]
+    fn local_function_object() {
+        let prog = r#"
+            fn unused() {
+            }
+            fn inc(x: __u32) -> __u32 {
+                return x + 1
+            }
+            fn()
+                map counts: hash<__u32, __u64>[64]
+                counts[inc(1)] = 0
+                return inc(2)
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("counts", 7);
+        compiler.compile(prog).unwrap();
+
+        /*
+         * Only the functions the program calls are emitted, each with its own
+         * function info.
+         */
+        let instructions = compiler.get_instructions();
+        let calls: Vec<usize> = (0..instructions.len())
+            .filter(|i| jump::local_call_offset(&instructions[*i]).is_some())
+            .collect();
+        assert_eq!(calls.len(), 2);
+        let program = compiler.get_btf("kprobe/do_sys_open");
+        assert_eq!(program.func_info().len(), 2);
+        assert_eq!(program.text_start(), Some(program.func_info()[1].insn_off));
+
+        /*
+         * The function is placed in `.text`, and calls to it are relocated against
+         * the section's symbol, which follows the program's.
+         */
+        let object = compiler.get_object("kprobe/do_sys_open", "GPL");
+        let u16_at = |o: usize| u16::from_ne_bytes(object[o..o + 2].try_into().unwrap());
+        let u32_at = |o: usize| u32::from_ne_bytes(object[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_ne_bytes(object[o..o + 8].try_into().unwrap());
+        let shoff = u64_at(40) as usize;
+        let shnum = u16_at(60) as usize;
+        let strtab = shoff + u16_at(62) as usize * 64;
+        let names = u64_at(strtab + 24) as usize;
+        let section = |wanted: &str| {
+            (0..shnum).find_map(|i| {
+                let header = shoff + i * 64;
+                let name = names + u32_at(header) as usize;
+                let len = object[name..].iter().position(|&c| c == 0).unwrap();
+                let offset = u64_at(header + 24) as usize;
+                let size = u64_at(header + 32) as usize;
+                (&object[name..name + len] == wanted.as_bytes())
+                    .then(|| &object[offset..offset + size])
+            })
+        };
+
+        let text = section(".text").unwrap();
+        assert_eq!(text.len() % 8, 0);
+        let rel: Vec<u64> = section(".relkprobe/do_sys_open")
+            .unwrap()
+            .chunks(8)
+            .map(|c| u64::from_ne_bytes(c.try_into().unwrap()))
+            .collect();
+        let call_relocations = rel.chunks(2).filter(|r| r[1] == 2 << 32 | 10).count();
+        assert_eq!(call_relocations, 2);
+        assert!(rel.chunks(2).any(|r| r[1] == 6 << 32 | 1));
+        assert!(section(".rel.text").is_none());
+    }
+
+    #[test]
+    fn program_header() {
+        let prog = r#"
+            kprobe:do_sys_openat2 fn(regs: &pt_regs)
+                return regs.di
+        "#;
+
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+        assert_eq!(compiler.get_program_type(), Some(ProgramType::Kprobe));
+        let attach = compiler.get_attach_point().unwrap();
+        assert_eq!(attach.target, ["do_sys_openat2"]);
+        assert_eq!(attach.section(), "kprobe/do_sys_openat2");
+        assert_eq!(attach.to_string(), "kprobe:do_sys_openat2");
+
+        /*
+         * Tracepoints don't need to take their context.
+         */
+        let prog = r#"
+            tracepoint:syscalls:sys_enter_read
+                return get_current_pid_tgid()
+        "#;
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+        let attach = compiler.get_attach_point().unwrap();
+        assert_eq!(attach.program_type.bpf_prog_type(), 5);
+        assert_eq!(attach.section(), "tracepoint/syscalls/sys_enter_read");
+
+        compile_and_compare(
+            r#"
+            xdp fn(ctx: &xdp_md)
+                return 2
+            "#,
+            &[
+                Instruction::mov64(Register::R0, 2), // r0 = 2
+                Instruction::exit(),                 // exit
+            ],
+        );
+
+        /*
+         * Scripts without a header have no program type.
+         */
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile("fn()\n return 0").unwrap();
+        assert!(compiler.get_attach_point().is_none());
+    }
+
+    #[test]
+    fn program_header_errors() {
+        let error = compile_error("kprob:do_sys_openat2 fn(regs: &pt_regs)\n return 1");
+        assert!(matches!(error, CompileError::UnknownProgramType { .. }));
+        assert_eq!(error.suggestion(), Some("kprobe"));
+
+        let error = compile_error("kprobe fn(regs: &pt_regs)\n return 0");
+        assert!(matches!(error, CompileError::InvalidProgram { .. }));
+
+        let error = compile_error("tracepoint:sys_enter_read\n return 0");
+        assert!(matches!(error, CompileError::InvalidProgram { .. }));
+
+        let error = compile_error("kprobe:do_sys_openat2 fn(ctx: &xdp_md)\n return 0");
+        assert_eq!(error.identifier(), Some("pt_regs"));
+
+        let error = compile_error("xdp fn(ctx: xdp_md)\n return 0");
+        assert_eq!(error.identifier(), Some("xdp_md"));
+
+        let error = compile_error("xdp fn(ctx: &xdp_md, len: __u32)\n return 0");
+        assert_eq!(error.identifier(), Some("len"));
+
+        let error = compile_error("xdp fn(ctx: &xdp_md)\n return 5");
+        assert!(matches!(error, CompileError::InvalidProgram { .. }));
+        assert_eq!(error.identifier(), Some("5"));
+
+        /*
+         * Helpers are checked in functions too, since they run as part of the
+         * program.
+         */
+        let prog = r#"
+            fn pid() -> __u64 {
+                return get_current_pid_tgid()
+            }
+            xdp fn(ctx: &xdp_md)
+                return pid()
+        "#;
+        let error = compile_error(prog);
+        assert_eq!(error.identifier(), Some("get_current_pid_tgid"));
+        assert_eq!(error.span().line, 3);
+    }
+
+    #[test]
+    fn kprobe_builtins() {
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let mut regs = [0u8; 168];
+        for (i, reg) in regs.chunks_mut(8).enumerate() {
+            reg.copy_from_slice(&(i as u64).to_ne_bytes());
+        }
+
+        /*
+         * On x86_64, arguments are in di, si, dx, cx, r8 and r9, and the return value
+         * in ax, which are at these indices of `struct pt_regs`.
+         */
+        let cases = [
+            ("kprobe:vfs_read", "arg0", 14),
+            ("kprobe:vfs_read", "arg1", 13),
+            ("kprobe:vfs_read", "arg3", 11),
+            ("kprobe:vfs_read fn(regs: &pt_regs)", "arg5", 8),
+            ("kretprobe:vfs_read", "retval", 10),
+        ];
+        for (header, builtin, expected) in cases {
+            let prog = format!("{}\n return {}", header, builtin);
+            let mut compiler = Compiler::create(&btf);
+            compiler.set_target_arch(Arch::X86_64);
+            compiler.compile(&prog).unwrap();
+
+            let mut interpreter = Interpreter::new();
+            let result = interpreter.run(compiler.get_instructions(), &regs);
+            assert_eq!(result, Ok(expected), "{}", builtin);
+        }
+
+        /*
+         * Variables shadow builtins, and builtins only exist where the registers
+         * they stand for hold them.
+         */
+        let prog = "kprobe:vfs_read\n arg0: __u64 = 7\n return arg0";
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.run(compiler.get_instructions(), &regs), Ok(7));
+
+        for prog in [
+            "kprobe:vfs_read\n return arg6",
+            "kprobe:vfs_read\n return retval",
+            "kretprobe:vfs_read\n return arg0",
+            "fn()\n return arg0",
+        ] {
+            assert!(matches!(
+                compile_error(prog),
+                CompileError::UnknownVariable { .. }
+            ));
+        }
+    }
+
+    /// Writes BTF for aarch64's `struct pt_regs`, whose registers are in an
+    /// anonymous union, and loads it. `BtfTypes` can't add unions itself.
+    ///
+    /// ```c
+    /// struct pt_regs {
+    ///     union {
+    ///         struct user_pt_regs user_regs;
+    ///         struct { u64 regs[31]; u64 sp; u64 pc; u64 pstate; };
+    ///     };
+    ///     u64 orig_x0;
+    /// };
+    /// ```
+    const BTF_INT: u32 = 1;
+    const BTF_ARRAY: u32 = 3;
+    const BTF_STRUCT: u32 = 4;
+    const BTF_UNION: u32 = 5;
+
+    /// Encodes a BTF type library and loads it. Each type is its name, kind, size
+    /// and members; arrays have a single member holding their element type, index
+    /// type and length.
+    fn btf_from_types(
+        file_name: &str,
+        strings: &[u8],
+        types: &[(u32, u32, u32, Vec<[u32; 3]>)],
+    ) -> BtfTypes {
+        let mut type_data = vec![];
+        for (name, kind, size, members) in types {
+            let (vlen, extra) = match *kind {
+                BTF_INT => (0, vec![size * 8]),
+                BTF_ARRAY => (0, members.concat()),
+                _ => (members.len() as u32, members.concat()),
+            };
+            let info = (kind << 24) | vlen;
+            for word in [*name, info, *size].into_iter().chain(extra) {
+                type_data.extend_from_slice(&word.to_ne_bytes());
+            }
+        }
+
+        let mut data = vec![];
+        data.extend_from_slice(&0xeb9fu16.to_ne_bytes());
+        data.extend_from_slice(&[1, 0]);
+        for word in [24, 0, type_data.len(), type_data.len(), strings.len()] {
+            data.extend_from_slice(&(word as u32).to_ne_bytes());
+        }
+        data.extend_from_slice(&type_data);
+        data.extend_from_slice(strings);
+
+        let path = std::env::temp_dir().join(format!("{}.{}.btf", file_name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let btf = BtfTypes::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        btf
+    }
+
+    fn aarch64_pt_regs_btf() -> BtfTypes {
+        let mut strings = vec![0u8];
+        let mut name = |name: &str| {
+            let offset = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            offset
+        };
+        let u64_name = name("u64");
+        let [regs, sp, pc, pstate] = ["regs", "sp", "pc", "pstate"].map(&mut name);
+        let user_pt_regs = name("user_pt_regs");
+        let user_regs = name("user_regs");
+        let orig_x0 = name("orig_x0");
+        let pt_regs = name("pt_regs");
+
+        /*
+         * `pt_regs` keeps its registers in an anonymous union of `user_pt_regs`
+         * and an anonymous struct with the same members.
+         */
+        let registers = [
+            [regs, 2, 0],
+            [sp, 1, 1984],
+            [pc, 1, 2048],
+            [pstate, 1, 2112],
+        ];
+        let types = [
+            (u64_name, BTF_INT, 8, vec![]),
+            (0, BTF_ARRAY, 0, vec![[1, 1, 31]]),
+            (user_pt_regs, BTF_STRUCT, 272, registers.to_vec()),
+            (0, BTF_STRUCT, 272, registers.to_vec()),
+            (0, BTF_UNION, 272, vec![[user_regs, 3, 0], [0, 4, 0]]),
+            (
+                pt_regs,
+                BTF_STRUCT,
+                280,
+                vec![[0, 5, 0], [orig_x0, 1, 2176]],
+            ),
+        ];
+        btf_from_types("aarch64_pt_regs", &strings, &types)
+    }
+
+    #[test]
+    fn anonymous_members() {
+        /*
+         * struct two_unions {
+         *     union { int a; long b; };
+         *     union { int c; long d; };
+         * };
+         */
+        let mut strings = vec![0u8];
+        let mut name = |name: &str| {
+            let offset = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            offset
+        };
+        let [int, long, a, b, c, d] = ["int", "long", "a", "b", "c", "d"].map(&mut name);
+        let two_unions = name("two_unions");
+        let types = [
+            (int, BTF_INT, 4, vec![]),
+            (long, BTF_INT, 8, vec![]),
+            (0, BTF_UNION, 8, vec![[a, 1, 0], [b, 2, 0]]),
+            (0, BTF_UNION, 8, vec![[c, 1, 0], [d, 2, 0]]),
+            (two_unions, BTF_STRUCT, 16, vec![[0, 3, 0], [0, 4, 64]]),
+        ];
+        let btf = btf_from_types("two_unions", &strings, &types);
+
+        /*
+         * The type library only keeps the last anonymous member, the other one is
+         * reported as unsupported rather than missing.
+         */
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile("fn(s: &two_unions)\n return s.c").unwrap();
+        let mut memory = [0u8; 16];
+        memory[..4].copy_from_slice(&7u32.to_ne_bytes());
+        memory[8..12].copy_from_slice(&9u32.to_ne_bytes());
+        let mut interpreter = Interpreter::new();
+        interpreter.add_memory(0x1000, &memory);
+        let result = interpreter.run_with_args(compiler.get_instructions(), &[0x1000]);
+        assert_eq!(result, Ok(9));
+
+        for member in ["a", "e"] {
+            let mut compiler = Compiler::create(&btf);
+            let prog = format!("fn(s: &two_unions)\n return s.{}", member);
+            let error = compiler.compile(&prog).unwrap_err();
+            assert!(
+                matches!(error, CompileError::Unsupported { .. }),
+                "{}",
+                member
+            );
+            assert_eq!(error.span().column, 11);
+        }
+
+        /*
+         * Members that don't exist are suggested from the anonymous members too.
+         */
+        let btf = aarch64_pt_regs_btf();
+        let mut compiler = Compiler::create(&btf);
+        let error = compiler
+            .compile("fn(regs: &pt_regs)\n return regs.pstat")
+            .unwrap_err();
+        assert!(matches!(error, CompileError::UnknownMember { .. }));
+        assert_eq!(error.suggestion(), Some("pstate"));
+    }
+
+    #[test]
+    fn kprobe_builtins_aarch64() {
+        let btf = aarch64_pt_regs_btf();
+
+        let mut regs = [0u8; 280];
+        for (i, reg) in regs.chunks_mut(8).enumerate() {
+            reg.copy_from_slice(&(100 + i as u64).to_ne_bytes());
+        }
+
+        /*
+         * On aarch64, arguments are in x0 to x7, and the return value in x0.
+         */
+        for (header, builtin, expected) in [
+            ("kprobe:vfs_read", "arg0 + arg5", 205),
+            ("kretprobe:vfs_read", "retval", 100),
+        ] {
+            let prog = format!("{}\n return {}", header, builtin);
+            let mut compiler = Compiler::create(&btf);
+            compiler.set_target_arch(Arch::from_name("arm64").unwrap());
+            compiler.compile(&prog).unwrap();
+
+            let mut interpreter = Interpreter::new();
+            let result = interpreter.run(compiler.get_instructions(), &regs);
+            assert_eq!(result, Ok(expected), "{}", builtin);
+        }
+
+        /*
+         * Relocations go through the anonymous union, but it isn't written in the
+         * field's path.
+         */
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_target_arch(Arch::from_name("arm64").unwrap());
+        compiler.set_core_relocations(true);
+        compiler.compile("kprobe:vfs_read\n return arg5").unwrap();
+        let relocations = compiler.get_core_relocations();
+        assert_eq!(relocations.len(), 1);
+        assert_eq!(relocations[0].field_path(), "user_regs.regs[5]");
+
+        /*
+         * The x86_64 registers don't exist in the aarch64 layout.
+         */
+        let mut compiler = Compiler::create(&btf);
+        compiler.set_target_arch(Arch::X86_64);
+        let error = compiler
+            .compile("kprobe:vfs_read\n return arg0")
+            .unwrap_err();
+        assert_eq!(error.identifier(), Some("di"));
+    }
+
+    #[test]
+    fn fentry_arguments() {
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let context: Vec<u8> = [0u64, 0, 4096, 0, 17]
+            .iter()
+            .flat_map(|arg| arg.to_ne_bytes())
+            .collect();
+
+        /*
+         * Arguments are read straight from the context, and typed by the traced
+         * function's prototype: `count` is a `size_t` and `file` points to a
+         * `struct file`.
+         */
+        let prog = r#"
+            fentry:vfs_read fn(file, buf, count, pos)
+                map counts: array<__u32, __u64>[4]
+                counts[0] = count
+                counts[1] = field_size(file.f_pos)
+                return 0
+        "#;
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("counts", 3);
+        compiler.compile(prog).unwrap();
+        let attach = compiler.get_attach_point().unwrap();
+        assert_eq!(attach.program_type.bpf_prog_type(), 26);
+        assert_eq!(attach.section(), "fentry/vfs_read");
+
+        let instructions = compiler.get_instructions();
+        assert!(instructions.contains(&Instruction::loadx64(Register::R2, Register::R1, 16)));
+        assert!(!instructions
+            .iter()
+            .any(|ins| *ins == Instruction::call(Helpers::ProbeReadKernel as u32)));
+
+        let mut interpreter = Interpreter::new();
+        interpreter.create_map(3, 2, 4, 8, 4);
+        assert_eq!(interpreter.run(instructions, &context), Ok(0));
+        let value = interpreter.map_value(3, &0u32.to_ne_bytes());
+        assert_eq!(value, Some(&4096u64.to_ne_bytes()[..]));
+        let value = interpreter.map_value(3, &1u32.to_ne_bytes());
+        assert_eq!(value, Some(&8u64.to_ne_bytes()[..]));
+
+        /*
+         * fexit programs get the return value after the arguments.
+         */
+        let prog = r#"
+            fexit:vfs_read fn(file, buf, count, pos)
+                map counts: array<__u32, __u64>[4]
+                counts[2] = retval
+                return 0
+        "#;
+        let mut compiler = Compiler::create(&btf);
+        compiler.capture("counts", 3);
+        compiler.compile(prog).unwrap();
+        assert_eq!(
+            interpreter.run(compiler.get_instructions(), &context),
+            Ok(0)
+        );
+        let value = interpreter.map_value(3, &2u32.to_ne_bytes());
+        assert_eq!(value, Some(&17u64.to_ne_bytes()[..]));
+
+        /*
+         * Naming `retval` gives it a type, so errors compare as negative. It isn't
+         * counted as one of the function's arguments.
+         */
+        let context: Vec<u8> = [0u64, 0, 4096, 0, -14i64 as u64]
+            .iter()
+            .flat_map(|arg| arg.to_ne_bytes())
+            .collect();
+        for (params, written) in [("file, retval: ssize_t", true), ("file", false)] {
+            let prog = format!(
+                "fexit:vfs_read fn({})\n map counts: array<__u32, __u64>[4]\n if retval < 0 {{\n counts[3] = 1\n }}\n return 0",
+                params
+            );
+            let mut compiler = Compiler::create(&btf);
+            compiler.capture("counts", 3);
+            compiler.compile(&prog).unwrap();
+            let mut interpreter = Interpreter::new();
+            interpreter.create_map(3, 2, 4, 8, 4);
+            assert_eq!(
+                interpreter.run(compiler.get_instructions(), &context),
+                Ok(0)
+            );
+            let value = interpreter.map_value(3, &3u32.to_ne_bytes());
+            assert_eq!(value.is_some(), written);
+        }
+    }
+
+    #[test]
+    fn fentry_errors() {
+        let error = compile_error("fentry:vfs_reed fn(file)\n return 0");
+        assert!(matches!(error, CompileError::InvalidProgram { .. }));
+        assert_eq!(error.identifier(), Some("vfs_reed"));
+
+        let error = compile_error("fentry:vfs_read fn(a, b, c, d, e)\n return 0");
+        assert_eq!(error.identifier(), Some("e"));
+
+        let error = compile_error("fexit:vfs_read fn(a, b, c, d, retval, e)\n return 0");
+        assert_eq!(error.identifier(), Some("e"));
+
+        let error = compile_error("fentry:vfs_read fn(file)\n return 1");
+        assert!(matches!(error, CompileError::InvalidProgram { .. }));
+
+        let error = compile_error("fentry:vfs_read fn(file, buf)\n return buf.f_pos");
+        assert!(matches!(error, CompileError::NotAStruct { .. }));
+
+        /*
+         * Only the parameters of fentry and fexit programs can be left untyped.
+         */
+        let error = compile_error("fn(a)\n return a");
+        assert!(matches!(error, CompileError::InvalidType { .. }));
+        assert_eq!(error.span().column, 4);
+    }
+
+    #[test]
+    fn tracefs_format() {
+        let fixture = |name| format!("{}/fixtures/{}.format", env!("CARGO_MANIFEST_DIR"), name);
+
+        let format = TraceFormat::from_file(fixture("sys_enter_openat")).unwrap();
+        assert_eq!(format.name, "sys_enter_openat");
+        assert_eq!(format.id, 633);
+        assert_eq!(format.fields.len(), 9);
+        let filename = &format.fields[6];
+        assert_eq!(filename.name, "filename");
+        assert_eq!(filename.type_name, "const char *");
+        assert_eq!((filename.offset, filename.size), (24, 8));
+        assert!(filename.is_pointer());
+        assert!(format.fields[3].is_signed);
+
+        let format = TraceFormat::from_file(fixture("sched_switch")).unwrap();
+        let prev_comm = &format.fields[4];
+        assert_eq!(prev_comm.name, "prev_comm");
+        assert_eq!(prev_comm.type_name, "char");
+        assert_eq!(prev_comm.array_len, Some(16));
+
+        let format = TraceFormat::from_file(fixture("sched_process_exec")).unwrap();
+        assert!(format.fields[4].is_data_loc());
+        assert_eq!(format.fields[4].type_name, "__data_loc char[]");
+
+        /*
+         * Errors point at the offending line.
+         */
+        assert_eq!(
+            TraceFormat::parse("name: a\nID: 1\nformat:\n\tfield:int x;\toffset:0;\n"),
+            Err(FormatError::Syntax {
+                line: 4,
+                reason: "Expected the size of the field"
+            })
+        );
+        assert!(matches!(
+            TraceFormat::parse("name: a\nID: x\n"),
+            Err(FormatError::Syntax { line: 2, .. })
+        ));
+        assert!(matches!(
+            TraceFormat::parse("name: a\n"),
+            Err(FormatError::Syntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            TraceFormat::from_file(fixture("sys_enter_missing")),
+            Err(FormatError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn tracefs_context() {
+        let fixture = |name| format!("{}/fixtures/{}.format", env!("CARGO_MANIFEST_DIR"), name);
+        let mut btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        for name in ["sys_enter_openat", "sched_switch"] {
+            let format = TraceFormat::from_file(fixture(name)).unwrap();
+            format.add_to_btf(&mut btf).unwrap();
+        }
+
+        let mut context = [0u8; 64];
+        context[24..32].copy_from_slice(&0x1000u64.to_ne_bytes());
+        context[32..40].copy_from_slice(&0o100u64.to_ne_bytes());
+
+        /*
+         * Fields are found at the offsets the format file gives them, past the
+         * padding after `__syscall_nr`.
+         */
+        for (field, expected) in [("flags", 0o100), ("filename", 0x1000), ("dfd", 0)] {
+            let prog = format!(
+                "tracepoint:syscalls:sys_enter_openat fn(ctx: &sys_enter_openat)\n return ctx.{}",
+                field
+            );
+            let mut compiler = Compiler::create(&btf);
+            compiler.compile(&prog).unwrap();
+
+            let mut interpreter = Interpreter::new();
+            let result = interpreter.run(compiler.get_instructions(), &context);
+            assert_eq!(result, Ok(expected), "{}", field);
+        }
+
+        let mut context = [0u8; 64];
+        context[41] = b'x';
+        context[56..60].copy_from_slice(&4242i32.to_ne_bytes());
+        let prog = r#"
+            tracepoint:sched:sched_switch fn(ctx: &sched_switch)
+                if ctx.next_comm[1] == 120 {
+                    return ctx.next_pid
+                }
+                return field_size(ctx.prev_comm)
+        "#;
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.run(compiler.get_instructions(), &context);
+        assert_eq!(result, Ok(4242));
+
+        /*
+         * Overlapping fields can't be laid out as a struct.
+         */
+        let format = TraceFormat::parse(
+            "name: bad\nID: 1\nformat:\n\
+             \tfield:int a;\toffset:0;\tsize:4;\tsigned:1;\n\
+             \tfield:int b;\toffset:2;\tsize:4;\tsigned:1;\n",
+        )
+        .unwrap();
+        assert!(format.add_to_btf(&mut btf).is_none());
+    }
+
+    #[test]
+    fn print_builtin() {
+        let btf = BtfTypes::from_file("/sys/kernel/btf/vmlinux").unwrap();
+        let prog = r#"
+            kprobe:vfs_read fn(regs: &pt_regs)
+                count: __u32 = 3
+                print("count=%u buf=%lx\n", count, regs.si)
+                print("%s: %c%d %d %d\n", "vfs_read", 35, 1, 2, regs.dx)
+                return 0
+        "#;
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+
+        /*
+         * Up to three values fit in the registers of `trace_printk`, more are passed
+         * to `trace_vprintk` in an array.
+         */
+        let instructions = compiler.get_instructions();
+        assert!(instructions.contains(&Instruction::call(Helpers::TracePrintk as u32)));
+        assert!(instructions.contains(&Instruction::call(Helpers::TraceVprintk as u32)));
+
+        let lines = Rc::new(RefCell::new(vec![]));
+        let mut interpreter = Interpreter::new();
+        let printk = lines.clone();
+        interpreter.set_helper(Helpers::TracePrintk, move |env, args| {
+            let format = env.read(args[0], args[1] as usize).unwrap().to_vec();
+            printk.borrow_mut().push((format, args[2..].to_vec()));
+            0
+        });
+        let vprintk = lines.clone();
+        interpreter.set_helper(Helpers::TraceVprintk, move |env, args| {
+            let format = env.read(args[0], args[1] as usize).unwrap().to_vec();
+            let mut values: Vec<u64> = env
+                .read(args[2], args[3] as usize)
+                .unwrap()
+                .chunks(8)
+                .map(|value| u64::from_ne_bytes(value.try_into().unwrap()))
+                .collect();
+            assert_eq!(env.read(values[0], 9), Some(&b"vfs_read\0"[..]));
+            values[0] = 0;
+            vprintk.borrow_mut().push((format, values));
+            0
+        });
+
+        let mut regs = [0u8; 168];
+        regs[96..104].copy_from_slice(&42u64.to_ne_bytes());
+        regs[104..112].copy_from_slice(&0x1000u64.to_ne_bytes());
+        assert_eq!(interpreter.run(instructions, &regs), Ok(0));
+        assert_eq!(
+            *lines.borrow(),
+            [
+                (b"count=%u buf=%lx\n\0".to_vec(), vec![3, 0x1000, 0]),
+                (b"%s: %c%d %d %d\n\0".to_vec(), vec![0, 35, 1, 2, 42]),
+            ]
+        );
+
+        /*
+         * String literals can be passed to helpers too, and `char` arrays print
+         * with `%s`.
+         */
+        let prog = r#"
+            fn(task: &task_struct)
+                trace_printk("hi\n", 4)
+                print("%s %s\n", task.comm, &task.comm)
+        "#;
+        let mut compiler = Compiler::create(&btf);
+        compiler.compile(prog).unwrap();
+    }
+
+    #[test]
+    fn print_errors() {
+        let error = compile_error("fn()\n print(\"%d %q\\n\", 1, 2)");
+        assert!(matches!(error, CompileError::InvalidString { .. }));
+        assert_eq!(error.identifier(), Some("%q"));
+        assert_eq!(error.span().column, 12);
+
+        let error = compile_error("fn()\n print(\"a\\qb\")");
+        assert_eq!(error.identifier(), Some("\\q"));
+        assert_eq!(error.span().column, 10);
+
+        let error = compile_error("fn()\n print(\"%d %d\\n\", 1)");
+        assert!(matches!(error, CompileError::InvalidFunction { .. }));
+
+        let error = compile_error("fn(regs: &pt_regs)\n print(\"%s\\n\", regs.di)");
+        assert!(matches!(error, CompileError::InvalidString { .. }));
+        assert_eq!(error.identifier(), Some("%s"));
+
+        let error = compile_error("fn(regs: &pt_regs)\n print(\"%c\\n\", regs)");
+        assert_eq!(error.identifier(), Some("%c"));
+
+        let error = compile_error("fn()\n print(\"%pZ\", 0)");
+        assert_eq!(error.identifier(), Some("%pZ"));
+
+        let error = compile_error("fn()\n print(\"a\\0\")");
+        assert_eq!(error.identifier(), Some("\\0"));
+
+        let format = "%d ".repeat(13);
+        let args = ", 1".repeat(13);
+        let error = compile_error(&format!("fn()\n print(\"{}\"{})", format, args));
+        assert_eq!(error.span().column, 45);
+
+        let error = compile_error("fn(x: __u64)\n print(x)");
+        assert!(matches!(error, CompileError::InvalidType { .. }));
+
+        let error = compile_error("fn()\n s = \"abc\"");
+        assert!(matches!(error, CompileError::InvalidType { .. }));
+    }
 }
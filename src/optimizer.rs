@@ -1,10 +1,63 @@
-use bpf_ins::{Instruction, Opcode};
+use crate::jump;
 
-struct Optimizer {
+use bpf_ins::{Instruction, JumpOperation, Opcode, Register};
+
+/// A rewrite of a short sequence of instructions into a cheaper one.
+struct Peephole {
+    /// The number of instructions the rewrite replaces.
     pub num_instructions: usize,
+    /// Returns the replacement for the first `num_instructions` instructions of the
+    /// slice, or `None` if they don't match.
     pub function: fn(&[Instruction]) -> Option<Vec<Instruction>>,
 }
 
+/// Returns the destination, base register and offset of a load, if the
+/// instruction is one.
+///
+/// # Arguments
+///
+/// * `ins` - The instruction to check.
+fn as_load(ins: &Instruction) -> Option<(Register, Register, i16)> {
+    let size = match ins.get_opcode() {
+        Opcode::Memory(memory) => *memory.get_size(),
+        _ => return None,
+    };
+
+    let load = Instruction::loadx(ins.get_dst_reg(), ins.get_src_reg(), ins.get_offset(), size);
+    (load == *ins).then_some((ins.get_dst_reg(), ins.get_src_reg(), ins.get_offset()))
+}
+
+/// Returns a copy of a load with its base register and offset replaced.
+///
+/// # Arguments
+///
+/// * `ins` - The load to copy.
+/// * `base` - The new base register.
+/// * `offset` - The new offset.
+fn with_base(ins: &Instruction, base: Register, offset: i16) -> Option<Instruction> {
+    match ins.get_opcode() {
+        Opcode::Memory(memory) => Some(Instruction::loadx(
+            ins.get_dst_reg(),
+            base,
+            offset,
+            *memory.get_size(),
+        )),
+        _ => None,
+    }
+}
+
+/// Returns the register and the amount an instruction adds a 32-bit immediate to
+/// a register by, if it does.
+///
+/// # Arguments
+///
+/// * `ins` - The instruction to check.
+fn as_add(ins: &Instruction) -> Option<(Register, i16)> {
+    let imm: i32 = ins.get_imm().try_into().ok()?;
+    let offset = imm.try_into().ok()?;
+    (Instruction::add64(ins.get_dst_reg(), imm) == *ins).then_some((ins.get_dst_reg(), offset))
+}
+
 /// Makes the following optimization:
 ///
 ///   r2 = r1   | r2 = *(r1 + N)
@@ -12,26 +65,18 @@ struct Optimizer {
 ///   r2 = *r2  |
 ///
 fn optimize_mov_add_load(ins: &[Instruction]) -> Option<Vec<Instruction>> {
-    let load_size = if let Opcode::Memory(memory) = ins[2].get_opcode() {
-        *memory.get_size()
-    } else {
+    let (dst, src) = (ins[0].get_dst_reg(), ins[0].get_src_reg());
+    if Instruction::movx64(dst, src) != ins[0] {
         return None;
-    };
-
-    let check0 = Instruction::movx64(ins[0].get_dst_reg(), ins[0].get_src_reg());
-    let check1 = Instruction::add64(ins[1].get_dst_reg(), ins[1].get_imm().try_into().ok()?);
-    let check2 = Instruction::loadx(ins[2].get_dst_reg(), ins[2].get_src_reg(), 0, load_size);
+    }
 
-    if check0 != ins[0] || check1 != ins[1] || check2 != ins[2] {
+    let (add_dst, offset) = as_add(&ins[1])?;
+    let (load_dst, load_base, load_offset) = as_load(&ins[2])?;
+    if add_dst != dst || load_dst != dst || load_base != dst || load_offset != 0 {
         return None;
     }
 
-    Some(vec![Instruction::loadx(
-        ins[0].get_dst_reg(),
-        ins[0].get_src_reg(),
-        ins[1].get_imm().try_into().ok()?,
-        load_size,
-    )])
+    Some(vec![with_base(&ins[2], src, offset)?])
 }
 
 ///
@@ -41,67 +86,125 @@ fn optimize_mov_add_load(ins: &[Instruction]) -> Option<Vec<Instruction>> {
 ///   r2 = *r2  |
 ///
 fn optimize_add_load(ins: &[Instruction]) -> Option<Vec<Instruction>> {
-    let load_size = if let Opcode::Memory(memory) = ins[1].get_opcode() {
-        *memory.get_size()
-    } else {
-        return None;
-    };
-
-    let check0 = Instruction::add64(ins[0].get_dst_reg(), ins[0].get_imm().try_into().ok()?);
-    let check1 = Instruction::loadx(ins[1].get_dst_reg(), ins[1].get_src_reg(), 0, load_size);
-
-    if check0 != ins[0] || check1 != ins[1] {
+    let (dst, offset) = as_add(&ins[0])?;
+    let (load_dst, load_base, load_offset) = as_load(&ins[1])?;
+    if load_dst != dst || load_base != dst || load_offset != 0 {
         return None;
     }
 
-    Some(vec![Instruction::loadx(
-        ins[0].get_dst_reg(),
-        ins[0].get_dst_reg(),
-        ins[0].get_imm().try_into().ok()?,
-        load_size,
-    )])
+    Some(vec![with_base(&ins[1], dst, offset)?])
 }
 
-/// List of optimizers used by the `optimize` function.
-const OPTIMIZERS: [Optimizer; 2] = [
-    Optimizer {
+/// List of rewrites used by the `optimize` function, tried in order.
+const PEEPHOLES: [Peephole; 2] = [
+    Peephole {
         num_instructions: 3,
         function: optimize_mov_add_load,
     },
-    Optimizer {
+    Peephole {
         num_instructions: 2,
         function: optimize_add_load,
     },
 ];
 
-/// Applies various optimizations to the given list of instructions.
+/// Returns whether an instruction is an `exit`.
+///
+/// # Arguments
+///
+/// * `ins` - The instruction to check.
+fn is_exit(ins: &Instruction) -> bool {
+    match ins.get_opcode() {
+        Opcode::Jump(jump) => matches!(jump.get_operation(), JumpOperation::Exit),
+        _ => false,
+    }
+}
+
+/// Returns, for every instruction of a program, whether it starts a basic block:
+/// the first instruction, the targets of jumps and the instructions that follow
+/// jumps and exits.
+///
+/// # Arguments
+///
+/// * `instructions` - The program.
+fn block_starts(instructions: &[Instruction]) -> Vec<bool> {
+    /*
+     * Jump offsets are counted in slots, which wide instructions take two of.
+     */
+    let mut slot_index = vec![None; jump::slot_count(instructions) + 1];
+    let mut slot = 0;
+    for (i, ins) in instructions.iter().enumerate() {
+        slot_index[slot] = Some(i);
+        slot += jump::slot_count(std::slice::from_ref(ins));
+    }
+    slot_index[slot] = Some(instructions.len());
+
+    let mut starts = vec![false; instructions.len() + 1];
+    starts[0] = true;
+    let mut slot = 0;
+    for (i, ins) in instructions.iter().enumerate() {
+        slot += jump::slot_count(std::slice::from_ref(ins));
+        if jump::is_branch(ins) || is_exit(ins) {
+            starts[i + 1] = true;
+        }
+
+        if jump::is_branch(ins) {
+            let target = slot as isize + ins.get_offset() as isize;
+            let target = usize::try_from(target).ok();
+            if let Some(Some(target)) = target.and_then(|target| slot_index.get(target)) {
+                starts[*target] = true;
+            }
+        }
+    }
+
+    starts
+}
+
+/// Applies various optimizations to the given list of instructions. Rewrites only
+/// apply to instructions within a basic block, so nothing jumps into the middle of
+/// a rewritten sequence, and jumps are fixed up to account for the instructions
+/// that were removed. Returns the optimized instructions along with, for every
+/// original instruction (and one past the end), the index of the instruction it
+/// ended up in.
 ///
 /// # Arguments
 ///
 /// * `instructions` - The program, as a list of instructions, to optimize.
-pub fn optimize(instructions: &[Instruction]) -> Vec<Instruction> {
-    let mut num_eliminated = 0;
+pub fn optimize(instructions: &[Instruction]) -> (Vec<Instruction>, Vec<usize>) {
+    let starts = block_starts(instructions);
     let mut optimized = vec![];
-    'outer: for i in 0..instructions.len() {
-        let start = usize::min(instructions.len(), i + num_eliminated);
-        let remaining = &instructions[start..];
-        if remaining.is_empty() {
-            break;
-        }
 
-        for optimizer in OPTIMIZERS
+    /*
+     * Maps the index of every original instruction to the index of the instruction
+     * it ended up in, so jump offsets can be fixed up afterwards.
+     */
+    let mut new_index = vec![0; instructions.len() + 1];
+    let mut i = 0;
+    'outer: while i < instructions.len() {
+        let block_len = starts[i + 1..]
             .iter()
-            .filter(|o| o.num_instructions <= remaining.len())
+            .position(|start| *start)
+            .map_or(instructions.len() - i, |len| len + 1);
+        let remaining = &instructions[i..i + block_len];
+
+        for peephole in PEEPHOLES
+            .iter()
+            .filter(|p| p.num_instructions <= remaining.len())
         {
-            if let Some(mut instructions) = (optimizer.function)(remaining) {
-                optimized.append(&mut instructions);
-                num_eliminated += optimizer.num_instructions - 1;
+            if let Some(mut replacement) = (peephole.function)(remaining) {
+                new_index[i..i + peephole.num_instructions].fill(optimized.len());
+                optimized.append(&mut replacement);
+                i += peephole.num_instructions;
                 continue 'outer;
             }
         }
 
+        new_index[i] = optimized.len();
         optimized.push(remaining[0]);
+        i += 1;
     }
+    new_index[instructions.len()] = optimized.len();
+
+    jump::retarget(instructions, &mut optimized, &new_index);
 
-    optimized
+    (optimized, new_index)
 }
@@ -0,0 +1,240 @@
+//! Optimization passes over the IR, and the pass manager that runs them. The
+//! passes a compiler runs are picked by its optimization level, and custom passes
+//! can be added to run after the built-in ones.
+//!
+//! # Example
+//! ```
+//! use bpf_script::ir::Function;
+//! use bpf_script::pass::{OptLevel, Pass, PassManager};
+//!
+//! /// Counts the functions it's run over.
+//! struct Counter(usize);
+//!
+//! impl Pass for Counter {
+//!     fn name(&self) -> &str {
+//!         "counter"
+//!     }
+//!
+//!     fn run(&mut self, _function: &mut Function) -> bool {
+//!         self.0 += 1;
+//!         false
+//!     }
+//! }
+//!
+//! let mut manager = PassManager::new(OptLevel::O0);
+//! manager.add_pass(Counter(0));
+//! let report = manager.run(&mut Function::default());
+//! assert_eq!(report[0].name, "counter");
+//! assert_eq!(report[0].runs, 1);
+//! ```
+
+use crate::dataflow::{
+    eliminate_dead_code, eliminate_dead_stores, eliminate_redundant_loads, fold_constants,
+};
+use crate::ir::Function;
+
+/// The maximum number of times the passes are repeated, in case a pass keeps on
+/// reporting changes.
+const MAX_ROUNDS: usize = 32;
+
+/// An optimization pass over the IR.
+pub trait Pass {
+    /// Returns the name of the pass, as it appears in reports.
+    fn name(&self) -> &str;
+
+    /// Runs the pass over a function. Returns whether the function was changed,
+    /// passes are repeated until none of them changes it.
+    ///
+    /// # Arguments
+    ///
+    /// * `function` - The function to optimize.
+    fn run(&mut self, function: &mut Function) -> bool;
+}
+
+/// How much a program is optimized, like a C compiler's `-O` flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// No optimizations: the program is emitted exactly as it was generated,
+    /// with every variable on the stack.
+    O0,
+    /// Constant folding, dead code elimination and the peephole rewrites of the
+    /// emitted instructions.
+    O1,
+    /// Everything in `O1`, plus redundant load and dead store elimination and
+    /// keeping variables in registers.
+    #[default]
+    O2,
+}
+
+/// Replaces operations on constants with their result and removes branches that
+/// always go the same way.
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &str {
+        "constant-folding"
+    }
+
+    fn run(&mut self, function: &mut Function) -> bool {
+        fold_constants(function)
+    }
+}
+
+/// Removes loads of stack slots whose value is already known.
+pub struct RedundantLoads;
+
+impl Pass for RedundantLoads {
+    fn name(&self) -> &str {
+        "redundant-loads"
+    }
+
+    fn run(&mut self, function: &mut Function) -> bool {
+        eliminate_redundant_loads(function)
+    }
+}
+
+/// Removes stores to the stack that are never read.
+pub struct DeadStores;
+
+impl Pass for DeadStores {
+    fn name(&self) -> &str {
+        "dead-stores"
+    }
+
+    fn run(&mut self, function: &mut Function) -> bool {
+        eliminate_dead_stores(function)
+    }
+}
+
+/// Removes unreachable blocks and instructions whose results are never used.
+pub struct DeadCode;
+
+impl Pass for DeadCode {
+    fn name(&self) -> &str {
+        "dead-code"
+    }
+
+    fn run(&mut self, function: &mut Function) -> bool {
+        eliminate_dead_code(function)
+    }
+}
+
+/// What a pass did while optimizing a program.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PassStats {
+    /// The name of the pass.
+    pub name: String,
+    /// The number of times the pass ran.
+    pub runs: usize,
+    /// The number of times the pass changed the program.
+    pub changes: usize,
+    /// The number of instructions the pass removed.
+    pub removed: usize,
+}
+
+impl PassStats {
+    /// Returns whether the pass changed the program.
+    pub fn fired(&self) -> bool {
+        self.changes > 0
+    }
+}
+
+/// Runs the passes of an optimization level, followed by any custom passes.
+pub struct PassManager {
+    level: OptLevel,
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    /// Creates a pass manager that runs the built-in passes of a level.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The optimization level.
+    pub fn new(level: OptLevel) -> Self {
+        Self {
+            level,
+            passes: vec![],
+        }
+    }
+
+    /// Returns the optimization level.
+    pub fn level(&self) -> OptLevel {
+        self.level
+    }
+
+    /// Changes the optimization level. Custom passes are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The optimization level.
+    pub fn set_level(&mut self, level: OptLevel) {
+        self.level = level;
+    }
+
+    /// Adds a pass to run after the built-in passes, and after any custom passes
+    /// added before it. Custom passes run at every level, including `O0`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pass` - The pass to add.
+    pub fn add_pass(&mut self, pass: impl Pass + 'static) {
+        self.passes.push(Box::new(pass));
+    }
+
+    /// Runs the passes over a function, repeating them until none of them changes
+    /// it. Returns what every pass did, in the order they ran.
+    ///
+    /// # Arguments
+    ///
+    /// * `function` - The function to optimize.
+    pub fn run(&mut self, function: &mut Function) -> Vec<PassStats> {
+        let mut builtin: Vec<Box<dyn Pass>> = match self.level {
+            OptLevel::O0 => vec![],
+            OptLevel::O1 => vec![Box::new(ConstantFolding), Box::new(DeadCode)],
+            OptLevel::O2 => vec![
+                Box::new(ConstantFolding),
+                Box::new(RedundantLoads),
+                Box::new(DeadStores),
+                Box::new(DeadCode),
+            ],
+        };
+
+        let mut passes: Vec<&mut Box<dyn Pass>> =
+            builtin.iter_mut().chain(self.passes.iter_mut()).collect();
+        let mut stats: Vec<PassStats> = passes
+            .iter()
+            .map(|pass| PassStats {
+                name: pass.name().to_string(),
+                ..Default::default()
+            })
+            .collect();
+
+        let num_insts =
+            |function: &Function| -> usize { function.blocks.iter().map(|b| b.insts.len()).sum() };
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for (pass, stats) in passes.iter_mut().zip(&mut stats) {
+                let before = num_insts(function);
+                stats.runs += 1;
+                if pass.run(function) {
+                    stats.changes += 1;
+                    stats.removed += before.saturating_sub(num_insts(function));
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        stats
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new(OptLevel::default())
+    }
+}
@@ -0,0 +1,276 @@
+use crate::helpers::Helpers;
+
+use std::fmt;
+
+/// The kind of program a script is compiled into, declared in the script's header,
+/// e.g. `kprobe:do_sys_openat2 fn(regs: &pt_regs)`. It decides what the program's
+/// context is, which helpers it can call and which values it can return.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgramType {
+    /// Runs when a kernel function is entered, with its registers as context.
+    Kprobe,
+    /// Runs when a kernel function returns, with its registers as context.
+    Kretprobe,
+    /// Runs when a kernel function is entered, with its arguments as context,
+    /// typed by the function's BTF prototype.
+    Fentry,
+    /// Runs when a kernel function returns, with its arguments and return value
+    /// as context.
+    Fexit,
+    /// Runs when a static tracepoint is hit, with the tracepoint's record as
+    /// context.
+    Tracepoint,
+    /// Runs when a static tracepoint is hit, with the tracepoint's raw arguments
+    /// as context.
+    RawTracepoint,
+    /// Runs when a perf event overflows.
+    PerfEvent,
+    /// Runs for every packet received by a network device, before an `sk_buff`
+    /// is allocated for it.
+    Xdp,
+    /// Filters the packets received by a socket.
+    SocketFilter,
+    /// Classifies packets in the traffic control layer.
+    SchedCls,
+}
+
+/// The header names of all program types.
+const PROGRAM_TYPE_NAMES: &[(&str, ProgramType)] = &[
+    ("kprobe", ProgramType::Kprobe),
+    ("kretprobe", ProgramType::Kretprobe),
+    ("fentry", ProgramType::Fentry),
+    ("fexit", ProgramType::Fexit),
+    ("tracepoint", ProgramType::Tracepoint),
+    ("raw_tracepoint", ProgramType::RawTracepoint),
+    ("perf_event", ProgramType::PerfEvent),
+    ("xdp", ProgramType::Xdp),
+    ("socket", ProgramType::SocketFilter),
+    ("tc", ProgramType::SchedCls),
+];
+
+impl ProgramType {
+    /// Returns a program type from its name, as written in the script header.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the program type.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::ProgramType;
+    ///
+    /// assert_eq!(ProgramType::from_name("xdp"), Some(ProgramType::Xdp));
+    /// ```
+    pub fn from_name(name: &str) -> Option<Self> {
+        PROGRAM_TYPE_NAMES
+            .iter()
+            .find(|(type_name, _)| *type_name == name)
+            .map(|(_, program_type)| *program_type)
+    }
+
+    /// Returns the name of the program type, as accepted by `from_name`.
+    pub fn name(&self) -> &'static str {
+        PROGRAM_TYPE_NAMES
+            .iter()
+            .find(|(_, program_type)| program_type == self)
+            .map(|(name, _)| *name)
+            .expect("every program type has a name")
+    }
+
+    /// Returns an iterator over the names of all program types.
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        PROGRAM_TYPE_NAMES.iter().map(|(name, _)| *name)
+    }
+
+    /// Returns the kernel's `enum bpf_prog_type` value for the program type, as
+    /// passed to `BPF_PROG_LOAD`.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::ProgramType;
+    ///
+    /// assert_eq!(ProgramType::Kprobe.bpf_prog_type(), 2);
+    /// ```
+    pub fn bpf_prog_type(&self) -> u32 {
+        match self {
+            Self::SocketFilter => 1,
+            Self::Kprobe | Self::Kretprobe => 2,
+            Self::SchedCls => 3,
+            Self::Tracepoint => 5,
+            Self::Xdp => 6,
+            Self::PerfEvent => 7,
+            Self::RawTracepoint => 17,
+            Self::Fentry | Self::Fexit => 26,
+        }
+    }
+
+    /// Returns the name of the struct the program's context points to, or `None`
+    /// if it depends on the attach point, like the record of a tracepoint or the
+    /// arguments of an fentry program.
+    pub fn context_type(&self) -> Option<&'static str> {
+        match self {
+            Self::Kprobe | Self::Kretprobe => Some("pt_regs"),
+            Self::Tracepoint | Self::Fentry | Self::Fexit => None,
+            Self::RawTracepoint => Some("bpf_raw_tracepoint_args"),
+            Self::PerfEvent => Some("bpf_perf_event_data"),
+            Self::Xdp => Some("xdp_md"),
+            Self::SocketFilter | Self::SchedCls => Some("__sk_buff"),
+        }
+    }
+
+    /// Returns the number of `:` separated parts the attach target of the program
+    /// type has, e.g. 2 for the category and name of a tracepoint.
+    pub fn target_parts(&self) -> usize {
+        match self {
+            Self::Kprobe | Self::Kretprobe | Self::Fentry | Self::Fexit | Self::RawTracepoint => 1,
+            Self::Tracepoint => 2,
+            Self::PerfEvent | Self::Xdp | Self::SocketFilter | Self::SchedCls => 0,
+        }
+    }
+
+    /// Returns the range of values the program can return, or `None` if any value
+    /// is accepted.
+    pub fn return_range(&self) -> Option<(i64, i64)> {
+        match self {
+            /*
+             * XDP_ABORTED to XDP_REDIRECT and TC_ACT_UNSPEC to TC_ACT_TRAP, the
+             * kernel rejects fentry and fexit programs returning anything but 0.
+             */
+            Self::Xdp => Some((0, 4)),
+            Self::SchedCls => Some((-1, 8)),
+            Self::Fentry | Self::Fexit => Some((0, 0)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the program's context holds the arguments of a kernel
+    /// function, one 64-bit value per argument.
+    pub fn has_traced_arguments(&self) -> bool {
+        matches!(self, Self::Fentry | Self::Fexit)
+    }
+
+    /// Returns whether the program type traces the kernel rather than processing
+    /// packets.
+    pub fn is_tracing(&self) -> bool {
+        matches!(
+            self,
+            Self::Kprobe
+                | Self::Kretprobe
+                | Self::Fentry
+                | Self::Fexit
+                | Self::Tracepoint
+                | Self::RawTracepoint
+                | Self::PerfEvent
+        )
+    }
+
+    /// Returns whether programs of the type can call a helper.
+    ///
+    /// # Arguments
+    ///
+    /// * `helper` - The helper to check.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{Helpers, ProgramType};
+    ///
+    /// assert!(ProgramType::Kprobe.allows_helper(Helpers::ProbeRead));
+    /// assert!(!ProgramType::Xdp.allows_helper(Helpers::ProbeRead));
+    /// ```
+    pub fn allows_helper(&self, helper: Helpers) -> bool {
+        use Helpers::*;
+
+        match helper {
+            MapLookupElem | MapUpdateElem | MapDeleteElem | MapPushElem | MapPopElem
+            | MapPeekElem | TracePrintk | TailCall | GetNumaNodeId | SpinLock | SpinUnlock
+            | RingbufOutput | ForEachMapElem | Snprintf | Strtol | Strtoul | PerfEventOutput
+            | TraceVprintk => true,
+            ProbeRead
+            | ProbeReadStr
+            | ProbeReadUser
+            | ProbeReadKernel
+            | ProbeReadUserStr
+            | ProbeReadKernelStr
+            | GetCurrentPidTgid
+            | GetCurrentUidGid
+            | GetCurrentComm
+            | GetStackid
+            | GetStack
+            | ProbeWriteUser
+            | CurrentTaskUnderCgroup
+            | SendSignal
+            | SendSignalThread
+            | GetNsCurrentPidTgid
+            | GetTaskStack
+            | PerfEventReadValue => self.is_tracing(),
+            OverrideReturn => *self == Self::Kprobe,
+            DPath => matches!(self, Self::Fentry | Self::Fexit),
+            PerfProgReadValue | ReadBranchRecords => *self == Self::PerfEvent,
+            XdpAdjustHead | XdpAdjustMeta | XdpAdjustTail | XdpOutput | RedirectMap => {
+                *self == Self::Xdp
+            }
+            Redirect | FibLookup | CheckMtu => matches!(self, Self::Xdp | Self::SchedCls),
+            SkbLoadBytes | SkbLoadBytesRelative => {
+                matches!(self, Self::SocketFilter | Self::SchedCls)
+            }
+            SkbStoreBytes | L3CsumReplace | L4CsumReplace | CloneRedirect | SkbVlanPush
+            | SkbVlanPop | SkbGetTunnelKey | SkbSetTunnelKey | SkbGetTunnelOpt
+            | SkbSetTunnelOpt | SkbChangeProto | SkbChangeType | SkbUnderCgroup | SkbChangeTail
+            | SkbPullData | SkbChangeHead | SetHash | SkbAdjustRoom | SkbGetXfrmState
+            | SkbEcnSetCe | CsumLevel | RedirectNeigh | RedirectPeer => *self == Self::SchedCls,
+
+            /*
+             * The rest belong to program types scripts can't declare.
+             */
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ProgramType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Where a program attaches, as declared in the script's header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttachPoint {
+    /// The kind of program.
+    pub program_type: ProgramType,
+    /// The parts of the attach target, e.g. the category and name of a tracepoint.
+    pub target: Vec<String>,
+}
+
+impl AttachPoint {
+    /// Returns the ELF section name loaders like libbpf expect the program in,
+    /// e.g. `tracepoint/syscalls/sys_enter_read`.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::{AttachPoint, ProgramType};
+    ///
+    /// let attach = AttachPoint {
+    ///     program_type: ProgramType::Kprobe,
+    ///     target: vec!["do_sys_openat2".to_string()],
+    /// };
+    /// assert_eq!(attach.section(), "kprobe/do_sys_openat2");
+    /// ```
+    pub fn section(&self) -> String {
+        std::iter::once(self.program_type.name())
+            .chain(self.target.iter().map(|part| part.as_str()))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+impl fmt::Display for AttachPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program_type)?;
+        for part in &self.target {
+            write!(f, ":{}", part)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,473 @@
+use crate::jump;
+use crate::verifier::{decode, Decoded};
+
+use bpf_ins::{Instruction, Register};
+
+/// Instruction classes, the lower 3 bits of the opcode.
+const CLASS_LD: u8 = 0x00;
+const CLASS_LDX: u8 = 0x01;
+const CLASS_ST: u8 = 0x02;
+const CLASS_STX: u8 = 0x03;
+const CLASS_ALU: u8 = 0x04;
+const CLASS_JMP: u8 = 0x05;
+const CLASS_JMP32: u8 = 0x06;
+const CLASS_ALU64: u8 = 0x07;
+
+/// Memory modes of load and store instructions.
+const MODE_MEM: u8 = 0x60;
+const MODE_ATOMIC: u8 = 0xc0;
+
+/// Flag of atomic operations that return the old value in the source register.
+const ATOMIC_FETCH: i64 = 0x01;
+
+/// Source operand bit of ALU and jump instructions.
+const SOURCE_REGISTER: u8 = 0x08;
+
+/// The ALU operations the pass looks for.
+const ALU_ADD: u8 = 0x00;
+const ALU_MOV: u8 = 0xb0;
+
+/// The frame pointer, which is never allocated.
+const FRAME_POINTER: usize = 10;
+
+/// Registers clobbered by helper calls.
+const CALLER_SAVED: u16 = 0b11_1111;
+
+/// The order registers are tried in: callee-saved registers first since they
+/// survive helper calls, then the argument registers for values that don't live
+/// across one.
+const ALLOCATION_ORDER: [usize; 9] = [6, 7, 8, 9, 1, 2, 3, 4, 5];
+
+/// A stack slot that can live in a register: it's only ever accessed whole, by
+/// loads and stores relative to R10, and its address is never taken.
+struct Variable {
+    size: i64,
+    /// Registers the variable is copied from or to, which are preferred so the
+    /// copies can be removed.
+    hints: Vec<usize>,
+    /// Registers the variable can't be assigned to.
+    conflicts: u16,
+    /// First instruction the variable is live at.
+    start: usize,
+    register: Option<usize>,
+}
+
+/// How an instruction accesses a variable.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// `*(r10 + offset) = reg`
+    Store(usize),
+    /// `*(r10 + offset) = imm`
+    StoreImmediate(i32),
+    /// `reg = *(r10 + offset)`
+    Load(usize),
+    /// `reg = *(u32 *)(r10 + offset)` of an 8-byte variable.
+    LoadLow(usize),
+}
+
+/// The variable, and how it's accessed, for every instruction that touches one.
+type Accesses = Vec<Option<(usize, Access)>>;
+
+/// The registers and variables live at a point of the program.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct Live {
+    regs: u16,
+    vars: u128,
+}
+
+/// Returns the registers an instruction reads and writes.
+fn register_effects(ins: &Decoded) -> (u16, u16) {
+    let dst = 1 << ins.dst;
+    let src = 1 << ins.src;
+    let class = ins.opcode & 0x07;
+    let op = ins.opcode & 0xf0;
+    let source = if ins.opcode & SOURCE_REGISTER != 0 {
+        src
+    } else {
+        0
+    };
+
+    match class {
+        CLASS_LD => (0, dst),
+        CLASS_LDX => (src, dst),
+        CLASS_ST => (dst, 0),
+        CLASS_STX if ins.opcode & 0xe0 == MODE_ATOMIC && ins.imm & ATOMIC_FETCH != 0 => {
+            (dst | src, src)
+        }
+        CLASS_STX => (dst | src, 0),
+        CLASS_ALU | CLASS_ALU64 if op == ALU_MOV => (source, dst),
+        CLASS_ALU | CLASS_ALU64 => (dst | source, dst),
+        CLASS_JMP if op == 0x80 => (CALLER_SAVED & !1, CALLER_SAVED),
+        CLASS_JMP if op == 0x90 => (1, 0),
+        CLASS_JMP if op == 0x00 => (0, 0),
+        _ => (dst | source, 0),
+    }
+}
+
+/// Returns whether an access to an object of `size` bytes can be done on a
+/// register instead. Objects are only kept in registers if they're 4 or 8 bytes,
+/// and are written whole. 8-byte objects can also have their lower 4 bytes read,
+/// e.g. when a 32-bit argument is saved as 64 bits.
+fn is_whole_access(ins: &Decoded, size: u32) -> bool {
+    let size = i64::from(size);
+    let low_half = cfg!(target_endian = "little") && size == 8 && ins.size == 4;
+    let whole = match ins.opcode & 0x07 {
+        CLASS_LDX => ins.size == size || low_half,
+        _ => ins.size == size,
+    };
+    whole && matches!(size, 4 | 8)
+}
+
+/// Returns the instructions that can execute after each instruction.
+fn successors(instructions: &[Instruction], decoded: &[Decoded]) -> Vec<Vec<usize>> {
+    let mut slots = vec![0];
+    let mut slot_index = vec![];
+    for (i, ins) in instructions.iter().enumerate() {
+        slot_index.push(i);
+        if ins.is_wide() {
+            slot_index.push(i);
+        }
+        slots.push(slot_index.len());
+    }
+    slot_index.push(instructions.len());
+
+    decoded
+        .iter()
+        .enumerate()
+        .map(|(i, ins)| {
+            let next = i + 1;
+            let target = || slot_index[(slots[next] as i64 + ins.offset) as usize];
+            match (ins.opcode & 0x07, ins.opcode & 0xf0) {
+                (CLASS_JMP, 0x90) => vec![],
+                (CLASS_JMP, 0x80) => vec![next],
+                (CLASS_JMP, 0x00) => vec![target()],
+                (CLASS_JMP | CLASS_JMP32, _) => vec![next, target()],
+                _ => vec![next],
+            }
+            .into_iter()
+            .filter(|next| *next < instructions.len())
+            .collect()
+        })
+        .collect()
+}
+
+/// Finds the stack slots that can be kept in registers, and how each instruction
+/// accesses them. Returns `None` if the stack is used in a way the pass doesn't
+/// understand, in which case nothing is allocated.
+fn find_variables(
+    decoded: &[Decoded],
+    objects: &[(i16, u32)],
+) -> Option<(Vec<Variable>, Accesses)> {
+    let containing = |offset: i64| {
+        objects.iter().position(|(start, size)| {
+            let start = i64::from(*start);
+            offset >= start && offset < start + i64::from(*size)
+        })
+    };
+
+    /*
+     * Objects whose address is taken, or that are accessed partially or atomically,
+     * have to stay on the stack.
+     */
+    let mut excluded = vec![false; objects.len()];
+    let mut accessed = vec![false; objects.len()];
+    for (i, ins) in decoded.iter().enumerate() {
+        let class = ins.opcode & 0x07;
+        let mode = ins.opcode & 0xe0;
+        let base = match class {
+            CLASS_LDX if mode == MODE_MEM => Some(ins.src),
+            CLASS_ST | CLASS_STX => Some(ins.dst),
+            _ => None,
+        };
+
+        if base == Some(FRAME_POINTER) {
+            let object = containing(ins.offset)?;
+            let (start, size) = objects[object];
+            accessed[object] = true;
+            if mode != MODE_MEM || ins.offset != i64::from(start) || !is_whole_access(ins, size) {
+                excluded[object] = true;
+            }
+        }
+
+        let (reads, _) = register_effects(ins);
+        let reads_frame = reads & (1 << FRAME_POINTER) != 0;
+        let is_copy = matches!(class, CLASS_ALU64) && ins.opcode & 0xf0 == ALU_MOV;
+        if is_copy && ins.src == FRAME_POINTER && ins.opcode & SOURCE_REGISTER != 0 {
+            /*
+             * Addresses of stack objects are taken by copying R10 and adding the
+             * object's offset to it.
+             */
+            let add = decoded.get(i + 1)?;
+            if add.opcode != CLASS_ALU64 | ALU_ADD || add.dst != ins.dst {
+                return None;
+            }
+            excluded[containing(add.imm)?] = true;
+        } else if reads_frame {
+            let stores_frame = class == CLASS_STX && ins.src == FRAME_POINTER;
+            if stores_frame || (base != Some(FRAME_POINTER) && class != CLASS_JMP) {
+                return None;
+            }
+        }
+    }
+
+    let mut variables = vec![];
+    let mut index = vec![None; objects.len()];
+    for (object, (_, size)) in objects.iter().enumerate() {
+        if accessed[object] && !excluded[object] && variables.len() < 128 {
+            index[object] = Some(variables.len());
+            variables.push(Variable {
+                size: i64::from(*size),
+                hints: vec![],
+                conflicts: 0,
+                start: usize::MAX,
+                register: None,
+            });
+        }
+    }
+
+    let accesses = decoded
+        .iter()
+        .map(|ins| {
+            let class = ins.opcode & 0x07;
+            let base = match class {
+                CLASS_LDX => ins.src,
+                CLASS_ST | CLASS_STX => ins.dst,
+                _ => return None,
+            };
+            if base != FRAME_POINTER || ins.opcode & 0xe0 != MODE_MEM {
+                return None;
+            }
+
+            let object = containing(ins.offset)?;
+            let var = index[object]?;
+            let access = match class {
+                CLASS_LDX if ins.size < i64::from(objects[object].1) => Access::LoadLow(ins.dst),
+                CLASS_LDX => Access::Load(ins.dst),
+                CLASS_STX => Access::Store(ins.src),
+                _ => Access::StoreImmediate(ins.imm as i32),
+            };
+            Some((var, access))
+        })
+        .collect();
+
+    Some((variables, accesses))
+}
+
+/// Keeps stack variables in registers where possible. Every stack slot that's
+/// only loaded and stored whole, and whose address is never taken, is a candidate.
+/// Candidates are assigned registers by a linear scan in the order they become
+/// live: a register is available to a variable if the program doesn't otherwise
+/// use it while the variable is live. Callee-saved registers (R6 to R9) are tried
+/// first, the caller-saved ones (R1 to R5) are only available to variables that
+/// aren't live across a helper call. Loads and stores of allocated variables
+/// become moves, which are removed when the variable was given the same register.
+///
+/// Returns the new program and the index of every instruction of the old program
+/// in the new one, plus one past the end.
+///
+/// # Arguments
+///
+/// * `instructions` - The program.
+/// * `objects` - The offset and size of every object the program keeps on the
+///   stack. Objects must not overlap.
+pub fn allocate(
+    instructions: &[Instruction],
+    objects: &[(i16, u32)],
+) -> (Vec<Instruction>, Vec<usize>) {
+    let unchanged = || (instructions.to_vec(), (0..=instructions.len()).collect());
+    let decoded: Vec<Decoded> = instructions.iter().map(decode).collect();
+    let (mut variables, accesses) = match find_variables(&decoded, objects) {
+        Some((variables, accesses)) if !variables.is_empty() => (variables, accesses),
+        _ => return unchanged(),
+    };
+
+    /*
+     * Liveness of the registers and variables, computed backwards until it stops
+     * changing.
+     */
+    let successors = successors(instructions, &decoded);
+    let effects: Vec<(Live, Live)> = decoded
+        .iter()
+        .zip(&accesses)
+        .map(|(ins, access)| {
+            let (mut reads, mut writes) = register_effects(ins);
+            let (mut uses, mut defs) = (0, 0);
+            if let Some((var, access)) = access {
+                match access {
+                    Access::Load(_) | Access::LoadLow(_) => uses |= 1 << var,
+                    _ => defs |= 1 << var,
+                }
+            }
+            reads &= !(1 << FRAME_POINTER);
+            writes &= !(1 << FRAME_POINTER);
+            (
+                Live {
+                    regs: reads,
+                    vars: uses,
+                },
+                Live {
+                    regs: writes,
+                    vars: defs,
+                },
+            )
+        })
+        .collect();
+
+    let mut live_in = vec![Live::default(); instructions.len()];
+    let mut live_out = vec![Live::default(); instructions.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..instructions.len()).rev() {
+            let mut out = Live::default();
+            for next in &successors[i] {
+                out.regs |= live_in[*next].regs;
+                out.vars |= live_in[*next].vars;
+            }
+
+            let (uses, defs) = effects[i];
+            let live = Live {
+                regs: uses.regs | (out.regs & !defs.regs),
+                vars: uses.vars | (out.vars & !defs.vars),
+            };
+            if live != live_in[i] || out != live_out[i] {
+                live_in[i] = live;
+                live_out[i] = out;
+                changed = true;
+            }
+        }
+    }
+
+    /*
+     * A variable conflicts with a register that's written while the variable is
+     * live, or that's live when the variable is written, except when the write is
+     * a copy between the two. Variables conflict with each other the same way.
+     */
+    let mut interferes = vec![0u128; variables.len()];
+    for (i, (_, defs)) in effects.iter().enumerate() {
+        let access = accesses[i];
+        for (v, variable) in variables.iter_mut().enumerate() {
+            let bit = 1u128 << v;
+            if live_in[i].vars & bit != 0 || live_out[i].vars & bit != 0 {
+                variable.start = variable.start.min(i);
+            }
+
+            if live_out[i].vars & bit != 0 {
+                let mut clobbered = defs.regs;
+                if let Some((var, Access::Load(reg))) = access {
+                    if var == v {
+                        clobbered &= !(1 << reg);
+                    }
+                }
+                variable.conflicts |= clobbered;
+                interferes[v] |= defs.vars & !bit;
+            }
+
+            if defs.vars & bit != 0 {
+                let mut live = live_out[i].regs;
+                if let Some((_, Access::Store(reg))) = access {
+                    live &= !(1 << reg);
+                }
+                variable.conflicts |= live;
+                interferes[v] |= live_out[i].vars & !bit;
+            }
+
+            match access {
+                Some((var, Access::Store(reg)))
+                | Some((var, Access::Load(reg)))
+                | Some((var, Access::LoadLow(reg)))
+                    if var == v =>
+                {
+                    variable.hints.push(reg);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /*
+     * Variables live when the program starts are read before they're written,
+     * which only happens in programs the verifier rejects.
+     */
+    for (v, variable) in variables.iter_mut().enumerate() {
+        if live_in[0].vars & (1 << v) != 0 {
+            variable.conflicts = u16::MAX;
+        }
+    }
+
+    let mut order: Vec<usize> = (0..variables.len()).collect();
+    order.sort_by_key(|v| variables[*v].start);
+    for v in order {
+        let taken: u16 = (0..variables.len())
+            .filter(|u| interferes[v] & (1 << u) != 0 || interferes[*u] & (1 << v) != 0)
+            .filter_map(|u| variables[u].register)
+            .fold(0, |taken, reg| taken | 1 << reg);
+
+        let variable = &variables[v];
+        let available = |reg: &usize| (variable.conflicts | taken) & (1 << reg) == 0;
+        let register = variable
+            .hints
+            .iter()
+            .filter(|reg| ALLOCATION_ORDER.contains(reg))
+            .chain(ALLOCATION_ORDER.iter())
+            .find(|reg| available(reg))
+            .copied();
+        variables[v].register = register;
+    }
+
+    /*
+     * Loads and stores of allocated variables become moves, and moves of a
+     * register to itself are dropped.
+     */
+    let mut allocated = vec![];
+    let mut new_index = vec![0; instructions.len() + 1];
+    for (i, ins) in instructions.iter().enumerate() {
+        new_index[i] = allocated.len();
+        let (variable, access) = match accesses[i] {
+            Some((v, access)) => match variables[v].register {
+                Some(register) => (&variables[v], (register, access)),
+                None => {
+                    allocated.push(*ins);
+                    continue;
+                }
+            },
+            None => {
+                allocated.push(*ins);
+                continue;
+            }
+        };
+
+        let reg = |num: usize| Register::from_num(num as u8).expect("valid register");
+        /*
+         * 4-byte variables are kept zero-extended, like loads from the stack would
+         * leave them, so only their loads can be dropped.
+         */
+        let wide = variable.size == 8;
+        match access {
+            (register, Access::Store(src)) if register == src && wide => {}
+            (register, Access::Load(dst)) if register == dst => {}
+            (register, Access::Store(src)) if wide => {
+                allocated.push(Instruction::movx64(reg(register), reg(src)))
+            }
+            (register, Access::Store(src)) => {
+                allocated.push(Instruction::movx32(reg(register), reg(src)))
+            }
+            (register, Access::StoreImmediate(imm)) if wide => {
+                allocated.push(Instruction::mov64(reg(register), imm))
+            }
+            (register, Access::StoreImmediate(imm)) => {
+                allocated.push(Instruction::mov32(reg(register), imm))
+            }
+            (register, Access::Load(dst)) => {
+                allocated.push(Instruction::movx64(reg(dst), reg(register)))
+            }
+            (register, Access::LoadLow(dst)) => {
+                allocated.push(Instruction::movx32(reg(dst), reg(register)))
+            }
+        }
+    }
+    new_index[instructions.len()] = allocated.len();
+
+    jump::retarget(instructions, &mut allocated, &new_index);
+    (allocated, new_index)
+}
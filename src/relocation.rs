@@ -0,0 +1,363 @@
+use bpf_ins::Instruction;
+use btf::types::{QualifiedType, Type};
+use btf::BtfTypes;
+
+use std::fmt;
+
+/// Instruction classes, the lower 3 bits of the opcode.
+const CLASS_MASK: u8 = 0x07;
+const CLASS_LDX: u8 = 0x01;
+const CLASS_ST: u8 = 0x02;
+const CLASS_STX: u8 = 0x03;
+const CLASS_ALU: u8 = 0x04;
+const CLASS_ALU64: u8 = 0x07;
+
+/// Source operand bit of ALU instructions; set when the operand is a register.
+const SOURCE_REGISTER: u8 = 0x08;
+
+/// What a CO-RE relocation patches into its instruction, with the values of
+/// `enum bpf_core_relo_kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// The byte offset of the field from the start of the root type, patched into
+    /// the immediate of an `add` or the offset of a load.
+    FieldByteOffset = 0,
+    /// The size of the field in bytes.
+    FieldByteSize = 1,
+    /// 1 if the field exists, 0 otherwise.
+    FieldExists = 2,
+    /// 1 if the field is a signed integer, 0 otherwise.
+    FieldSigned = 3,
+    /// The size of the root type in bytes, e.g. the stride of an array of it.
+    TypeSize = 9,
+}
+
+/// One step of a field access, starting from the root type of a relocation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldAccess {
+    /// A struct member, by name and by index in the struct's declaration order.
+    Member { name: String, index: u32 },
+    /// An array element, by index.
+    Element(u32),
+}
+
+/// A CO-RE (compile once, run everywhere) relocation: an instruction that depends
+/// on the layout of a kernel type, along with the field access it was compiled
+/// from so that it can be patched for a kernel whose layout differs. Created when
+/// compiling with `Compiler::set_core_relocations`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoreRelocation {
+    /// The index of the instruction to patch, in `Compiler::get_instructions`.
+    pub instruction: usize,
+    /// What gets patched into the instruction.
+    pub kind: RelocationKind,
+    /// The id of the root type in the type library the program was compiled with.
+    pub type_id: u32,
+    /// The accesses from the root type to the field.
+    pub accesses: Vec<FieldAccess>,
+}
+
+impl CoreRelocation {
+    /// Returns the access string as stored in `.BTF.ext`, e.g. `0:3:1` for the
+    /// second element of the fourth member of the root type. The leading `0`
+    /// indexes the root type itself, as if it were an array.
+    pub fn access_string(&self) -> String {
+        let mut access = String::from("0");
+        for step in &self.accesses {
+            let index = match step {
+                FieldAccess::Member { index, .. } => *index,
+                FieldAccess::Element(index) => *index,
+            };
+            access.push_str(&format!(":{}", index));
+        }
+        access
+    }
+
+    /// Returns the accessed field as it's written in scripts, e.g. `mm.pgd` or
+    /// `comm[2]`. Anonymous members aren't written, so they're left out.
+    pub fn field_path(&self) -> String {
+        let mut path = String::new();
+        for step in &self.accesses {
+            match step {
+                FieldAccess::Member { name, .. } if name.is_empty() => {}
+                FieldAccess::Member { name, .. } if path.is_empty() => path.push_str(name),
+                FieldAccess::Member { name, .. } => path.push_str(&format!(".{}", name)),
+                FieldAccess::Element(index) => path.push_str(&format!("[{}]", index)),
+            }
+        }
+        path
+    }
+}
+
+/// A field that a relocation refers to which can't be relocated to the target,
+/// usually because it doesn't exist there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingField {
+    /// The index of the instruction the relocation applies to.
+    pub instruction: usize,
+    /// The field, e.g. `task_struct.mm`, or the type for relocations of types.
+    pub field: String,
+    /// Why the field couldn't be relocated.
+    pub reason: &'static str,
+}
+
+/// Errors that can occur while relocating a program to a target type library.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelocationError {
+    /// Fields the program accesses don't exist on the target, or their types
+    /// aren't compatible with the ones the program was compiled against.
+    MissingFields(Vec<MissingField>),
+    /// A relocation doesn't match the program or the type library the program was
+    /// compiled with.
+    InvalidRelocation {
+        instruction: usize,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for RelocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingFields(fields) => {
+                write!(f, "Fields can't be relocated to the target:")?;
+                for field in fields {
+                    write!(
+                        f,
+                        "\n  {} {} (instruction {})",
+                        field.field, field.reason, field.instruction
+                    )?;
+                }
+                Ok(())
+            }
+            Self::InvalidRelocation {
+                instruction,
+                reason,
+            } => write!(
+                f,
+                "Invalid relocation for instruction {}: {}.",
+                instruction, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RelocationError {}
+
+/// Where a field is, as resolved in a type library.
+struct Field {
+    offset: u32,
+    field_type: QualifiedType,
+}
+
+/// Follows a relocation's accesses from its root type.
+///
+/// # Arguments
+///
+/// * `types` - The type library the root type is from.
+/// * `root` - The root type of the relocation.
+/// * `accesses` - The accesses from the root type to the field.
+fn resolve_field(
+    types: &BtfTypes,
+    root: &QualifiedType,
+    accesses: &[FieldAccess],
+) -> Result<Field, &'static str> {
+    let mut offset = 0;
+    let mut cur_type = root.clone();
+    for access in accesses {
+        if cur_type.is_pointer() {
+            return Err("is behind a pointer");
+        }
+
+        match (access, &cur_type.base_type) {
+            (FieldAccess::Member { name, .. }, Type::Struct(st) | Type::Union(st)) => {
+                let member = st.members.get(name).ok_or("doesn't exist")?;
+                if member.offset % 8 != 0 {
+                    return Err("is a bitfield");
+                }
+
+                offset += member.offset / 8;
+                cur_type = types
+                    .resolve_type_by_id(member.type_id)
+                    .ok_or("has a type that doesn't exist")?;
+            }
+            (FieldAccess::Element(index), Type::Array(array)) => {
+                /*
+                 * Arrays without elements are flexible array members, which can be
+                 * indexed past their end.
+                 */
+                if *index >= array.num_elements && array.num_elements != 0 {
+                    return Err("is out of bounds");
+                }
+
+                let element_type = types
+                    .resolve_type_by_id(array.element_type)
+                    .ok_or("has a type that doesn't exist")?;
+                offset += element_type.get_size() * index;
+                cur_type = element_type;
+            }
+            _ => return Err("doesn't exist"),
+        }
+    }
+
+    Ok(Field {
+        offset,
+        field_type: cur_type,
+    })
+}
+
+/// Returns whether a field of type `target` can be accessed as if it were of type
+/// `local`. Sizes may differ, but the kind of type may not, except that integers
+/// and enums are interchangeable.
+fn is_compatible(local: &QualifiedType, target: &QualifiedType) -> bool {
+    if local.is_pointer() || target.is_pointer() {
+        return local.is_pointer() && target.is_pointer();
+    }
+
+    let is_integer = |t: &Type| matches!(t, Type::Integer(_) | Type::Enum32(_) | Type::Enum64(_));
+    match (&local.base_type, &target.base_type) {
+        (l, t) if is_integer(l) && is_integer(t) => true,
+        (l, t) => std::mem::discriminant(l) == std::mem::discriminant(t),
+    }
+}
+
+/// Returns a copy of an instruction with the value of a relocation patched in: the
+/// immediate of an ALU instruction, or the offset of a memory access for field
+/// offsets. Returns `None` if the instruction can't hold the value.
+fn patch_instruction(ins: &Instruction, kind: RelocationKind, value: u32) -> Option<Instruction> {
+    if ins.is_wide() {
+        return None;
+    }
+
+    let (raw, _) = ins.encode();
+    let raw = match raw as u8 & CLASS_MASK {
+        CLASS_LDX | CLASS_ST | CLASS_STX if kind == RelocationKind::FieldByteOffset => {
+            let offset = i16::try_from(value).ok()?;
+            (raw & !(0xffff << 16)) | (offset as u16 as u64) << 16
+        }
+        CLASS_ALU | CLASS_ALU64 if raw as u8 & SOURCE_REGISTER == 0 => {
+            let imm = i32::try_from(value).ok()?;
+            (raw & 0xffff_ffff) | (imm as u32 as u64) << 32
+        }
+        _ => return None,
+    };
+
+    Instruction::decode(&[raw]).ok()
+}
+
+/// Relocates a compiled program to a target type library, usually the BTF of the
+/// kernel the program is going to run on, by patching the instructions recorded by
+/// its CO-RE relocations with the offsets, sizes and other properties of the fields
+/// on the target. Field existence checks of fields missing on the target evaluate
+/// to 0, any other relocation of a missing field fails with a list of all of them.
+///
+/// # Arguments
+///
+/// * `instructions` - The program's instructions.
+/// * `relocations` - The program's CO-RE relocations.
+/// * `local` - The type library the program was compiled with.
+/// * `target` - The type library to relocate the program to.
+///
+/// # Example
+/// ```
+/// use bpf_script::{relocate, Compiler};
+/// use btf::BtfTypes;
+///
+/// let mut local = BtfTypes::default();
+/// local.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+/// local.add_struct("file", &[("flags", "u32"), ("mode", "u32")])
+///     .expect("Failed to add file type.");
+///
+/// let mut target = BtfTypes::default();
+/// target.add_integer("u32", 4, false).expect("Failed to add u32 type.");
+/// target.add_struct("file", &[("mode", "u32"), ("flags", "u32")])
+///     .expect("Failed to add file type.");
+///
+/// let mut compiler = Compiler::create(&local);
+/// compiler.set_core_relocations(true);
+/// compiler.compile(r#"
+///     fn(f: &file)
+///         mode: u32 = f.mode
+///         return mode
+/// "#).expect("Failed to compile.");
+/// let instructions = relocate(
+///     compiler.get_instructions(),
+///     compiler.get_core_relocations(),
+///     &local,
+///     &target,
+/// ).expect("Failed to relocate.");
+/// ```
+pub fn relocate(
+    instructions: &[Instruction],
+    relocations: &[CoreRelocation],
+    local: &BtfTypes,
+    target: &BtfTypes,
+) -> Result<Vec<Instruction>, RelocationError> {
+    let mut relocated = instructions.to_vec();
+    let mut missing = vec![];
+    for relocation in relocations {
+        let invalid = |reason| RelocationError::InvalidRelocation {
+            instruction: relocation.instruction,
+            reason,
+        };
+
+        let local_root = local
+            .resolve_type_by_id(relocation.type_id)
+            .ok_or_else(|| invalid("the root type doesn't exist"))?;
+        let root_name = local_root.base_type.get_name();
+        let local_field = resolve_field(local, &local_root, &relocation.accesses)
+            .map_err(|_| invalid("the field doesn't exist in the local types"))?;
+
+        let field = match relocation.accesses.is_empty() {
+            true => root_name.to_string(),
+            false => format!("{}.{}", root_name, relocation.field_path()),
+        };
+
+        /*
+         * Find the field on the target, starting from the type with the same name
+         * and kind as the root type.
+         */
+        let target_field = match target.resolve_type_by_name(root_name) {
+            Some(root) if is_compatible(&local_root, &root) => {
+                resolve_field(target, &root, &relocation.accesses).and_then(|target_field| {
+                    match is_compatible(&local_field.field_type, &target_field.field_type) {
+                        true => Ok(target_field),
+                        false => Err("has an incompatible type"),
+                    }
+                })
+            }
+            _ => Err("doesn't exist"),
+        };
+
+        let value = match (relocation.kind, target_field) {
+            (RelocationKind::FieldExists, Ok(_)) => 1,
+            (RelocationKind::FieldExists, Err(_)) => 0,
+            (_, Err(reason)) => {
+                missing.push(MissingField {
+                    instruction: relocation.instruction,
+                    field,
+                    reason,
+                });
+                continue;
+            }
+            (RelocationKind::FieldByteOffset, Ok(target_field)) => target_field.offset,
+            (RelocationKind::FieldByteSize, Ok(target_field)) => target_field.field_type.get_size(),
+            (RelocationKind::FieldSigned, Ok(target_field)) => u32::from(
+                !target_field.field_type.is_pointer()
+                    && matches!(target_field.field_type.base_type, Type::Integer(int) if int.is_signed),
+            ),
+            (RelocationKind::TypeSize, Ok(target_field)) => target_field.field_type.get_size(),
+        };
+
+        let ins = relocated
+            .get(relocation.instruction)
+            .ok_or_else(|| invalid("the instruction doesn't exist"))?;
+        relocated[relocation.instruction] = patch_instruction(ins, relocation.kind, value)
+            .ok_or_else(|| invalid("the instruction can't be patched"))?;
+    }
+
+    if !missing.is_empty() {
+        return Err(RelocationError::MissingFields(missing));
+    }
+
+    Ok(relocated)
+}
@@ -0,0 +1,325 @@
+//! Parser for the `format` files tracefs describes tracepoint records with, e.g.
+//! `/sys/kernel/tracing/events/syscalls/sys_enter_openat/format`. The record of a
+//! tracepoint is the context of programs attached to it, and not every record
+//! has a matching type in the kernel's BTF.
+
+use btf::types::Type;
+use btf::BtfTypes;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Where tracefs is usually mounted.
+const TRACEFS_EVENTS: &str = "/sys/kernel/tracing/events";
+
+/// Errors that can occur while reading a format file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FormatError {
+    /// The file couldn't be read.
+    Io(String),
+    /// A line doesn't have the syntax of a format file.
+    Syntax { line: usize, reason: &'static str },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "Failed to read format: {}.", error),
+            Self::Syntax { line, reason } => write!(f, "[Line {}] {}.", line, reason),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// A field of a tracepoint record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceField {
+    /// The name of the field.
+    pub name: String,
+    /// The C type of the field, without the name and array length, e.g.
+    /// `const char *`.
+    pub type_name: String,
+    /// The offset of the field from the start of the record, in bytes.
+    pub offset: u32,
+    /// The size of the field, in bytes.
+    pub size: u32,
+    /// Whether the field is a signed integer.
+    pub is_signed: bool,
+    /// The number of elements, if the field is a fixed size array.
+    pub array_len: Option<u32>,
+}
+
+impl TraceField {
+    /// Returns whether the field is a pointer. Pointers are described as unsigned
+    /// integers, since the record doesn't say what they point to in a way types
+    /// can be looked up by.
+    pub fn is_pointer(&self) -> bool {
+        self.type_name.contains('*')
+    }
+
+    /// Returns whether the field locates variable length data stored after the
+    /// record, e.g. a string. Such fields hold the data's offset from the start of
+    /// the record in their lower 16 bits, and its length in the upper 16 bits.
+    pub fn is_data_loc(&self) -> bool {
+        self.type_name.starts_with("__data_loc")
+    }
+}
+
+/// The layout of a tracepoint's record, as described by its tracefs format file.
+///
+/// # Example
+/// ```
+/// use bpf_script::{Compiler, TraceFormat};
+/// use btf::BtfTypes;
+///
+/// let format = TraceFormat::parse(
+///     "name: sys_enter_close\n\
+///      ID: 700\n\
+///      format:\n\
+///      \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;\n\
+///      \tfield:int __syscall_nr;\toffset:8;\tsize:4;\tsigned:1;\n\
+///      \tfield:unsigned int fd;\toffset:16;\tsize:8;\tsigned:0;\n",
+/// )
+/// .expect("Failed to parse format.");
+///
+/// let mut btf = BtfTypes::default();
+/// format.add_to_btf(&mut btf).expect("Failed to add record type.");
+/// let mut compiler = Compiler::create(&btf);
+/// compiler.compile(r#"
+///     tracepoint:syscalls:sys_enter_close fn(ctx: &sys_enter_close)
+///         return ctx.fd
+/// "#).expect("Failed to compile.");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceFormat {
+    /// The name of the tracepoint, e.g. `sys_enter_openat`.
+    pub name: String,
+    /// The id of the tracepoint, as used by perf events.
+    pub id: u32,
+    /// The fields of the record, in the order they're laid out.
+    pub fields: Vec<TraceField>,
+}
+
+/// Parses the value of a `key:value` pair in a format file.
+///
+/// # Arguments
+///
+/// * `part` - The text of the pair.
+/// * `key` - The expected key.
+fn parse_value<'b>(part: &'b str, key: &str) -> Option<&'b str> {
+    part.trim()
+        .strip_prefix(key)
+        .and_then(|part| part.strip_prefix(':'))
+        .map(|value| value.trim())
+}
+
+/// Parses the declaration of a field, e.g. `char comm[16]`, into its name, type
+/// and array length.
+///
+/// # Arguments
+///
+/// * `decl` - The declaration, as written in the format file.
+fn parse_declaration(decl: &str) -> Option<(String, String, Option<u32>)> {
+    let (decl, array_len) = match decl.strip_suffix(']') {
+        Some(decl) => {
+            let (decl, len) = decl.rsplit_once('[')?;
+            (decl.trim_end(), Some(len.trim().parse().ok()?))
+        }
+        None => (decl, None),
+    };
+
+    let start = decl.rfind(|c: char| c.is_whitespace() || c == '*')? + 1;
+    let name = &decl[start..];
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    Some((
+        name.to_string(),
+        decl[..start].trim().to_string(),
+        array_len,
+    ))
+}
+
+/// Parses a line describing a field, e.g.
+/// `field:int flags; offset:32; size:8; signed:0;`.
+///
+/// # Arguments
+///
+/// * `line` - The line, without leading whitespace.
+fn parse_field(line: &str) -> Result<TraceField, &'static str> {
+    let parts: Vec<&str> = line.split(';').map(|part| part.trim()).collect();
+    if parts.len() < 3 {
+        return Err("Expected a field, its offset and its size");
+    }
+
+    let decl = parse_value(parts[0], "field").ok_or("Expected a field declaration")?;
+    let (name, type_name, array_len) =
+        parse_declaration(decl).ok_or("Expected a type and a name")?;
+
+    let offset = parse_value(parts[1], "offset")
+        .and_then(|value| value.parse().ok())
+        .ok_or("Expected the offset of the field")?;
+    let size = parse_value(parts[2], "size")
+        .and_then(|value| value.parse().ok())
+        .ok_or("Expected the size of the field")?;
+
+    /*
+     * Old kernels don't say whether fields are signed.
+     */
+    let is_signed = parts
+        .get(3)
+        .and_then(|part| parse_value(part, "signed"))
+        .is_some_and(|value| value == "1");
+
+    if matches!(array_len, Some(len) if len == 0 || size % len != 0) {
+        return Err("The size of the array isn't a multiple of its length");
+    }
+
+    Ok(TraceField {
+        name,
+        type_name,
+        offset,
+        size,
+        is_signed,
+        array_len,
+    })
+}
+
+impl TraceFormat {
+    /// Returns the path of the format file of a tracepoint, in tracefs' usual
+    /// mount point.
+    ///
+    /// # Arguments
+    ///
+    /// * `category` - The category of the tracepoint, e.g. `syscalls`.
+    /// * `name` - The name of the tracepoint, e.g. `sys_enter_openat`.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::TraceFormat;
+    ///
+    /// let path = TraceFormat::event_path("sched", "sched_switch");
+    /// assert_eq!(path.to_str(), Some("/sys/kernel/tracing/events/sched/sched_switch/format"));
+    /// ```
+    pub fn event_path(category: &str, name: &str) -> PathBuf {
+        [TRACEFS_EVENTS, category, name, "format"].iter().collect()
+    }
+
+    /// Parses the format file at a path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the format file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, FormatError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|error| FormatError::Io(error.to_string()))?;
+        Self::parse(&text)
+    }
+
+    /// Parses the text of a format file.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The contents of the format file.
+    pub fn parse(text: &str) -> Result<Self, FormatError> {
+        let mut name = None;
+        let mut id = None;
+        let mut fields: Vec<TraceField> = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let syntax = |reason| FormatError::Syntax {
+                line: i + 1,
+                reason,
+            };
+            let line = line.trim();
+
+            /*
+             * What follows the fields is how the kernel prints the record.
+             */
+            if line.starts_with("print fmt:") {
+                break;
+            }
+
+            if let Some(value) = parse_value(line, "name") {
+                name = Some(value.to_string());
+            } else if let Some(value) = parse_value(line, "ID") {
+                id = Some(value.parse().map_err(|_| syntax("Bad tracepoint id"))?);
+            } else if line.starts_with("field:") {
+                let field = parse_field(line).map_err(syntax)?;
+                if fields.iter().any(|other| other.name == field.name) {
+                    return Err(syntax("Field is described more than once"));
+                }
+                fields.push(field);
+            } else if !line.is_empty() && line != "format:" {
+                return Err(syntax("Expected a name, id or field"));
+            }
+        }
+
+        let missing = |reason| FormatError::Syntax {
+            line: text.lines().count(),
+            reason,
+        };
+        let name = name.ok_or_else(|| missing("The tracepoint has no name"))?;
+        let id = id.ok_or_else(|| missing("The tracepoint has no id"))?;
+
+        fields.sort_by_key(|field| field.offset);
+        Ok(Self { name, id, fields })
+    }
+
+    /// Adds a struct describing the record to a BTF type library, named after the
+    /// tracepoint, so scripts can use it as the type of their context. Integers
+    /// and arrays get types of their own, named `<tracepoint>::<field>`, and gaps
+    /// between fields are filled with padding. Returns the struct, or `None` if
+    /// fields overlap.
+    ///
+    /// # Arguments
+    ///
+    /// * `btf` - The type library to add the struct to.
+    pub fn add_to_btf<'b>(&self, btf: &'b mut BtfTypes) -> Option<&'b Type> {
+        let byte = format!("{}::__u8", self.name);
+        let index = format!("{}::__u32", self.name);
+        btf.add_integer(&byte, 1, false)?;
+        btf.add_integer(&index, 4, false)?;
+
+        let mut members = vec![];
+        let mut end = 0;
+        for field in &self.fields {
+            if field.offset < end {
+                return None;
+            }
+
+            if field.offset > end {
+                let padding = format!("{}::__pad{}", self.name, end);
+                btf.add_array(&padding, &index, &byte, field.offset - end)?;
+                members.push((format!("__pad{}", end), padding));
+            }
+
+            let type_name = format!("{}::{}", self.name, field.name);
+            match field.array_len {
+                Some(len) => {
+                    let element = format!("{}[]", type_name);
+                    let element_size = (field.size / len).try_into().ok()?;
+                    btf.add_integer(&element, element_size, field.is_signed)?;
+                    btf.add_array(&type_name, &index, &element, len)?;
+                }
+                None if matches!(field.size, 1 | 2 | 4 | 8) => {
+                    let is_signed = field.is_signed && !field.is_pointer();
+                    btf.add_integer(&type_name, field.size as u8, is_signed)?;
+                }
+                None => {
+                    btf.add_array(&type_name, &index, &byte, field.size)?;
+                }
+            }
+
+            members.push((field.name.clone(), type_name));
+            end = field.offset + field.size;
+        }
+
+        let members: Vec<(&str, &str)> = members
+            .iter()
+            .map(|(name, type_name)| (name.as_str(), type_name.as_str()))
+            .collect();
+        btf.add_struct(&self.name, &members)
+    }
+}
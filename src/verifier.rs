@@ -0,0 +1,932 @@
+use crate::helpers::Helpers;
+
+use bpf_ins::{Instruction, MemoryOpLoadType};
+
+use std::collections::HashMap;
+
+/// Size of the stack, in bytes and in 8-byte slots.
+const STACK_SIZE: i64 = 512;
+const STACK_SLOTS: usize = STACK_SIZE as usize / 8;
+
+/// Instruction classes, the lower 3 bits of the opcode.
+const CLASS_LD: u8 = 0x00;
+const CLASS_LDX: u8 = 0x01;
+const CLASS_ST: u8 = 0x02;
+const CLASS_STX: u8 = 0x03;
+const CLASS_ALU: u8 = 0x04;
+const CLASS_JMP: u8 = 0x05;
+const CLASS_JMP32: u8 = 0x06;
+const CLASS_ALU64: u8 = 0x07;
+
+/// Memory modes of load and store instructions.
+const MODE_MEM: u8 = 0x60;
+const MODE_ATOMIC: u8 = 0xc0;
+
+/// Flag of atomic operations that return the old value in the source register.
+const ATOMIC_FETCH: i32 = 0x01;
+
+/// Source operand bit of ALU and jump instructions.
+const SOURCE_REGISTER: u8 = 0x08;
+
+/// Pseudo source register of 64-bit immediate loads that load a map.
+const PSEUDO_MAP_FD: usize = 1;
+
+/// Pseudo source register of calls to functions of the program.
+const PSEUDO_CALL: usize = 1;
+
+/// The frame pointer.
+const FRAME_POINTER: usize = 10;
+
+/// The sizes of a map's keys and values, used to check the memory passed to map
+/// helpers and accesses to map values.
+#[derive(Clone, Copy, Debug)]
+pub struct MapSizes {
+    pub key_size: u32,
+    pub value_size: u32,
+}
+
+/// An instruction that the kernel's verifier would reject, and why.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Index of the offending instruction.
+    pub instruction: usize,
+    /// Description of the problem.
+    pub reason: String,
+}
+
+/// What's known about the value of a register or stack slot. Like the kernel's
+/// verifier, pointers carry where they point and scalars carry their value when
+/// it's a known constant, or its upper bound once it's been compared with one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegType {
+    /// Never written.
+    Uninit,
+    /// An integer, with its value if it's known.
+    Scalar(Option<i64>),
+    /// An integer between 0 and the value, e.g. an array index that was compared
+    /// with the array's length.
+    Bounded(i64),
+    /// A pointer into kernel memory (e.g. an argument) or a value read from it,
+    /// which can be either an integer or a pointer.
+    Unknown,
+    /// A pointer into the stack, with its offset from R10 if it's known.
+    Stack(Option<i64>),
+    /// A pointer into the stack at an offset from R10 between the two values, e.g.
+    /// to an array element indexed with a bounded scalar.
+    StackRange(i64, i64),
+    /// A map, loaded with a 64-bit immediate load.
+    Map(u32),
+    /// A pointer into the value of a map element, with its offset if it's known.
+    MapValue { fd: u32, offset: Option<i64> },
+    /// The result of `map_lookup_elem`, which is null if the element doesn't
+    /// exist. `id` is the instruction of the lookup, so that every copy of the
+    /// result is known to be non-null once one of them is checked.
+    MapValueOrNull { fd: u32, id: usize },
+}
+
+impl RegType {
+    fn is_pointer(&self) -> bool {
+        matches!(
+            self,
+            Self::Stack(_) | Self::StackRange(..) | Self::MapValue { .. }
+        )
+    }
+
+    fn is_scalar(&self) -> bool {
+        matches!(self, Self::Scalar(_) | Self::Bounded(_))
+    }
+
+    /// Returns the range of offsets from R10 of a pointer into the stack, if it's
+    /// known.
+    fn stack_range(&self) -> Option<(i64, i64)> {
+        match *self {
+            Self::Stack(Some(offset)) => Some((offset, offset)),
+            Self::StackRange(min, max) => Some((min, max)),
+            _ => None,
+        }
+    }
+
+    /// Returns a value that's correct for both values, e.g. when two paths join.
+    fn join(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Self::Uninit, _) | (_, Self::Uninit) => Self::Uninit,
+            (a, b) if a.is_scalar() && b.is_scalar() => Self::Scalar(None),
+            (Self::Stack(_) | Self::StackRange(..), Self::Stack(_) | Self::StackRange(..)) => {
+                Self::Stack(None)
+            }
+            (Self::MapValue { fd: a, .. }, Self::MapValue { fd: b, .. }) if a == b => {
+                Self::MapValue {
+                    fd: a,
+                    offset: None,
+                }
+            }
+            (Self::MapValue { fd: a, .. }, null @ Self::MapValueOrNull { fd: b, .. })
+            | (null @ Self::MapValueOrNull { fd: b, .. }, Self::MapValue { fd: a, .. })
+                if a == b =>
+            {
+                null
+            }
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Returns the range of values of a scalar, if it's known.
+    fn scalar_range(&self) -> Option<(i64, i64)> {
+        match *self {
+            Self::Scalar(Some(value)) => Some((value, value)),
+            Self::Bounded(max) => Some((0, max)),
+            _ => None,
+        }
+    }
+
+    /// Moves a pointer by an offset anywhere in a range.
+    fn add_offset(self, offset: Option<(i64, i64)>) -> Self {
+        let exact = offset
+            .filter(|(min, max)| min == max)
+            .map(|(offset, _)| offset);
+        match (self, offset) {
+            (Self::Stack(Some(base)), Some((min, max))) if min != max => {
+                Self::StackRange(base + min, base + max)
+            }
+            (Self::Stack(current), _) => Self::Stack(current.zip(exact).map(|(a, b)| a + b)),
+            (Self::StackRange(low, high), Some((min, max))) => {
+                Self::StackRange(low + min, high + max)
+            }
+            (Self::StackRange(..), None) => Self::Stack(None),
+            (
+                Self::MapValue {
+                    fd,
+                    offset: current,
+                },
+                _,
+            ) => Self::MapValue {
+                fd,
+                offset: current.zip(exact).map(|(a, b)| a + b),
+            },
+            (value, _) => value,
+        }
+    }
+}
+
+/// An 8-byte stack slot: which of its bytes are initialized, and the value of the
+/// register spilled into it, if it holds one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Slot {
+    initialized: u8,
+    value: RegType,
+}
+
+/// The state of the registers and stack before an instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct State {
+    regs: [RegType; 11],
+    stack: [Slot; STACK_SLOTS],
+}
+
+impl State {
+    fn join(&self, other: &Self) -> Self {
+        let mut joined = self.clone();
+        for (reg, other) in joined.regs.iter_mut().zip(&other.regs) {
+            *reg = reg.join(*other);
+        }
+        for (slot, other) in joined.stack.iter_mut().zip(&other.stack) {
+            slot.initialized &= other.initialized;
+            slot.value = slot.value.join(other.value);
+        }
+        joined
+    }
+
+    /// Marks every copy of a possibly null map value as checked.
+    fn refine_null(&mut self, id: usize, value: RegType) {
+        let matches = |v: &RegType| matches!(v, RegType::MapValueOrNull { id: i, .. } if *i == id);
+        for reg in self.regs.iter_mut().filter(|reg| matches(reg)) {
+            *reg = value;
+        }
+        for slot in self.stack.iter_mut().filter(|slot| matches(&slot.value)) {
+            slot.value = value;
+        }
+    }
+}
+
+/// The fields of an instruction, decoded from its encoding. `size` is the access
+/// size of memory instructions.
+pub struct Decoded {
+    pub opcode: u8,
+    pub dst: usize,
+    pub src: usize,
+    pub offset: i64,
+    pub imm: i64,
+    pub size: i64,
+}
+
+pub fn decode(ins: &Instruction) -> Decoded {
+    let (raw, wide) = ins.encode();
+    let opcode = raw as u8;
+    let low = (raw >> 32) as u32 as u64;
+    let imm = match wide {
+        Some(high) => (low | (high & 0xffff_ffff_0000_0000)) as i64,
+        None => low as u32 as i32 as i64,
+    };
+
+    Decoded {
+        opcode,
+        dst: ((raw >> 8) & 0xf) as usize,
+        src: ((raw >> 12) & 0xf) as usize,
+        offset: (raw >> 16) as u16 as i16 as i64,
+        imm,
+        size: match opcode & 0x18 {
+            0x00 => 4,
+            0x08 => 2,
+            0x10 => 1,
+            _ => 8,
+        },
+    }
+}
+
+/// Returns the name of an ALU operation for error messages.
+fn alu_name(op: u8) -> &'static str {
+    match op {
+        0x00 => "addition",
+        0x10 => "subtraction",
+        0x20 => "multiplication",
+        0x30 => "division",
+        0x40 => "bitwise or",
+        0x50 => "bitwise and",
+        0x60 => "left shift",
+        0x70 => "right shift",
+        0x80 => "negation",
+        0x90 => "modulo",
+        0xa0 => "bitwise xor",
+        0xc0 => "arithmetic right shift",
+        _ => "byte swap",
+    }
+}
+
+/// Returns the argument and the size argument of helpers that write to memory,
+/// whose destination doesn't need to be initialized beforehand.
+fn written_memory(helper: Helpers) -> Option<(usize, usize)> {
+    match helper {
+        Helpers::ProbeRead
+        | Helpers::ProbeReadStr
+        | Helpers::ProbeReadUser
+        | Helpers::ProbeReadKernel
+        | Helpers::ProbeReadUserStr
+        | Helpers::ProbeReadKernelStr
+        | Helpers::GetCurrentComm
+        | Helpers::CopyFromUser
+        | Helpers::Snprintf => Some((1, 2)),
+        Helpers::GetStack | Helpers::DPath => Some((2, 3)),
+        _ => None,
+    }
+}
+
+struct Verifier<'a> {
+    instructions: &'a [Instruction],
+    maps: &'a HashMap<u32, MapSizes>,
+    slots: Vec<Option<usize>>,
+    pc: usize,
+}
+
+impl<'a> Verifier<'a> {
+    fn error<T>(&self, reason: String) -> Result<T, Violation> {
+        let instruction = self.slots[self.pc].unwrap_or_default();
+        Err(Violation {
+            instruction,
+            reason,
+        })
+    }
+
+    fn read(&self, state: &State, reg: usize) -> Result<RegType, Violation> {
+        match state.regs[reg] {
+            RegType::Uninit => self.error(format!("R{} is read before it's written", reg)),
+            value => Ok(value),
+        }
+    }
+
+    fn write(&self, state: &mut State, reg: usize, value: RegType) -> Result<(), Violation> {
+        if reg == FRAME_POINTER {
+            return self.error("The frame pointer R10 is read-only".to_string());
+        }
+        state.regs[reg] = value;
+        Ok(())
+    }
+
+    /// Checks that a stack access is in bounds and returns its offset from R10.
+    fn stack_bounds(&self, offset: i64, size: i64) -> Result<i64, Violation> {
+        if offset < -STACK_SIZE || offset + size > 0 {
+            return self.error(format!(
+                "Stack access of {} bytes at offset {} is outside the 512 byte stack",
+                size, offset
+            ));
+        }
+        Ok(offset)
+    }
+
+    /// Checks that a stack access through a pointer is in bounds, wherever in its
+    /// range the pointer points, and returns the range of offsets from R10 it may
+    /// start at. Accesses at offsets that aren't known at all are rejected, as
+    /// they could be anywhere in the stack.
+    fn stack_range_bounds(
+        &self,
+        pointer: RegType,
+        offset: i64,
+        size: i64,
+    ) -> Result<(i64, i64), Violation> {
+        match pointer.stack_range() {
+            Some((min, max)) => {
+                self.stack_bounds(min + offset, size)?;
+                self.stack_bounds(max + offset, size)?;
+                Ok((min + offset, max + offset))
+            }
+            None => self.error("Stack access at an unknown offset".to_string()),
+        }
+    }
+
+    /// Checks a memory access through a register and returns the range of offsets
+    /// it may start at, if it's a stack access.
+    fn check_access(
+        &self,
+        state: &State,
+        reg: usize,
+        offset: i64,
+        size: i64,
+    ) -> Result<Option<(i64, i64)>, Violation> {
+        match self.read(state, reg)? {
+            pointer @ (RegType::Stack(_) | RegType::StackRange(..)) => {
+                self.stack_range_bounds(pointer, offset, size).map(Some)
+            }
+            RegType::Unknown => Ok(None),
+            RegType::MapValue { fd, offset: base } => {
+                if let (Some(base), Some(sizes)) = (base, self.maps.get(&fd)) {
+                    let start = base + offset;
+                    if start < 0 || start + size > sizes.value_size.into() {
+                        return self.error(format!(
+                            "Access of {} bytes at offset {} is outside the {} byte map value",
+                            size, start, sizes.value_size
+                        ));
+                    }
+                }
+                Ok(None)
+            }
+            RegType::MapValueOrNull { .. } => self.error(format!(
+                "R{} may be null, the result of map_lookup_elem must be checked first",
+                reg
+            )),
+            RegType::Map(_) => self.error(format!("R{} is a map and can't be dereferenced", reg)),
+            RegType::Scalar(_) | RegType::Bounded(_) | RegType::Uninit => self.error(format!(
+                "R{} is a scalar, not a pointer, and can't be dereferenced",
+                reg
+            )),
+        }
+    }
+
+    fn load_stack(&self, state: &State, offset: i64, size: i64) -> Result<RegType, Violation> {
+        for byte in offset..offset + size {
+            let index = (byte + STACK_SIZE) as usize;
+            if state.stack[index / 8].initialized & (1 << (index % 8)) == 0 {
+                return self.error(format!(
+                    "Stack at offset {} is read before it's written",
+                    byte
+                ));
+            }
+        }
+
+        let slot = &state.stack[(offset + STACK_SIZE) as usize / 8];
+        if size == 8 && offset % 8 == 0 {
+            return Ok(slot.value);
+        }
+        Ok(RegType::Scalar(None))
+    }
+
+    fn store_stack(&self, state: &mut State, offset: i64, size: i64, value: RegType) {
+        if size == 8 && offset % 8 == 0 {
+            state.stack[(offset + STACK_SIZE) as usize / 8] = Slot {
+                initialized: 0xff,
+                value,
+            };
+            return;
+        }
+
+        for byte in offset..offset + size {
+            let index = (byte + STACK_SIZE) as usize;
+            let slot = &mut state.stack[index / 8];
+            slot.initialized |= 1 << (index % 8);
+            slot.value = RegType::Scalar(None);
+        }
+    }
+
+    /// Loads from the stack at an offset anywhere in a range. Every byte that may
+    /// be read has to be initialized, and only loads at a known offset can reload
+    /// a spilled register.
+    fn load_stack_range(
+        &self,
+        state: &State,
+        (min, max): (i64, i64),
+        size: i64,
+    ) -> Result<RegType, Violation> {
+        if min == max {
+            return self.load_stack(state, min, size);
+        }
+
+        self.load_stack(state, min, max - min + size)?;
+        Ok(RegType::Scalar(None))
+    }
+
+    /// Stores to the stack at an offset anywhere in a range. None of the bytes
+    /// are known to be written unless the offset is known, but any register
+    /// spilled in the range may be overwritten.
+    fn store_stack_range(
+        &self,
+        state: &mut State,
+        (min, max): (i64, i64),
+        size: i64,
+        value: RegType,
+    ) {
+        if min == max {
+            return self.store_stack(state, min, size, value);
+        }
+
+        for byte in min..max + size {
+            let index = (byte + STACK_SIZE) as usize;
+            state.stack[index / 8].value = RegType::Scalar(None);
+        }
+    }
+
+    /// Checks that a helper argument points to `size` bytes of memory, which
+    /// must be initialized unless the helper writes to it. Returns the range of
+    /// offsets in the stack the memory may start at, if it's on the stack.
+    fn check_memory_arg(
+        &self,
+        state: &State,
+        helper: Helpers,
+        arg: usize,
+        size: Option<i64>,
+        written: bool,
+    ) -> Result<Option<(i64, i64)>, Violation> {
+        match self.read(state, arg)? {
+            pointer @ (RegType::Stack(_) | RegType::StackRange(..)) => {
+                let size = size.unwrap_or(1).max(1);
+                let range = self.stack_range_bounds(pointer, 0, size)?;
+                if !written {
+                    self.load_stack_range(state, range, size)?;
+                }
+                Ok(Some(range))
+            }
+            RegType::MapValue { .. } | RegType::Unknown => Ok(None),
+            _ => self.error(format!(
+                "Argument {} of {:?} must point to {}memory",
+                arg,
+                helper,
+                if written { "" } else { "initialized " }
+            )),
+        }
+    }
+
+    fn call(&self, state: &mut State, id: u32) -> Result<(), Violation> {
+        let helper = match Helpers::from_id(id) {
+            Some(helper) => helper,
+            None => return self.error(format!("Unknown helper function {}", id)),
+        };
+
+        let mut map = None;
+        for (i, arg_type) in helper.get_arg_types().iter().enumerate() {
+            let arg = i + 1;
+            match arg_type {
+                MemoryOpLoadType::Map => match self.read(state, arg)? {
+                    RegType::Map(fd) => map = self.maps.get(&fd).map(|sizes| (fd, *sizes)),
+                    _ => {
+                        return self
+                            .error(format!("Argument {} of {:?} must be a map", arg, helper))
+                    }
+                },
+                MemoryOpLoadType::MapIndex => {
+                    let size = map.map(|(_, sizes)| sizes.key_size.into());
+                    self.check_memory_arg(state, helper, arg, size, false)?;
+                }
+                MemoryOpLoadType::MapValue => {
+                    let size = map.map(|(_, sizes)| sizes.value_size.into());
+                    self.check_memory_arg(state, helper, arg, size, false)?;
+                }
+                _ => {}
+            }
+        }
+
+        /*
+         * Memory the helper writes to is initialized after the call, if its size is
+         * known.
+         */
+        if let Some((arg, size_arg)) = written_memory(helper) {
+            let size = match state.regs[size_arg] {
+                RegType::Scalar(size) => size,
+                _ => None,
+            };
+            if let Some(range) = self.check_memory_arg(state, helper, arg, size, true)? {
+                if let Some(size) = size {
+                    self.store_stack_range(state, range, size, RegType::Scalar(None));
+                }
+            }
+        }
+
+        /*
+         * The arguments are clobbered by the call.
+         */
+        for reg in &mut state.regs[1..=5] {
+            *reg = RegType::Uninit;
+        }
+        state.regs[0] = match (helper, map) {
+            (Helpers::MapLookupElem, Some((fd, _))) => RegType::MapValueOrNull { fd, id: self.pc },
+            (Helpers::MapLookupElem, None) => RegType::Unknown,
+            _ => RegType::Scalar(None),
+        };
+        Ok(())
+    }
+
+    /// Calls a function of the program, which is checked on its own. It returns a
+    /// scalar, and might write to any stack it's given a pointer to.
+    fn local_call(&self, state: &mut State) {
+        for reg in 1..=5 {
+            let start = match state.regs[reg] {
+                RegType::Stack(Some(offset)) | RegType::StackRange(offset, _) => {
+                    ((offset + STACK_SIZE).max(0) / 8) as usize
+                }
+                RegType::Stack(None) => 0,
+                _ => continue,
+            };
+
+            for slot in state.stack.iter_mut().skip(start) {
+                if slot.initialized != 0xff {
+                    slot.initialized = 0xff;
+                    slot.value = RegType::Scalar(None);
+                }
+            }
+        }
+
+        for reg in &mut state.regs[1..=5] {
+            *reg = RegType::Uninit;
+        }
+        state.regs[0] = RegType::Scalar(None);
+    }
+
+    fn alu(&self, state: &mut State, ins: &Decoded, is64: bool) -> Result<(), Violation> {
+        let op = ins.opcode & 0xf0;
+        let src = if ins.opcode & SOURCE_REGISTER != 0 {
+            self.read(state, ins.src)?
+        } else {
+            RegType::Scalar(Some(ins.imm))
+        };
+
+        if op == 0xb0 {
+            let value = match src {
+                value if is64 => value,
+                RegType::Scalar(value) => RegType::Scalar(value.map(|v| v as u32 as i64)),
+                RegType::Bounded(max) if max <= u32::MAX.into() => RegType::Bounded(max),
+                RegType::Unknown => RegType::Unknown,
+                _ => RegType::Scalar(None),
+            };
+            return self.write(state, ins.dst, value);
+        }
+
+        let dst = self.read(state, ins.dst)?;
+        let value = match (dst, src) {
+            (RegType::MapValueOrNull { .. }, _) | (_, RegType::MapValueOrNull { .. }) => {
+                return self.error(format!(
+                    "Arithmetic on a map value that may be null is prohibited, check R{} first",
+                    ins.dst
+                ))
+            }
+            (RegType::Map(_), _) | (_, RegType::Map(_)) => {
+                return self.error("Arithmetic on a map is prohibited".to_string())
+            }
+            /*
+             * Values read from kernel memory may be integers, which move stack
+             * pointers somewhere unknown.
+             */
+            (pointer @ (RegType::Stack(_) | RegType::StackRange(..)), RegType::Unknown)
+                if is64 && (op == 0x00 || op == 0x10) =>
+            {
+                pointer.add_offset(None)
+            }
+            (RegType::Unknown, pointer @ (RegType::Stack(_) | RegType::StackRange(..)))
+                if is64 && op == 0x00 =>
+            {
+                pointer.add_offset(None)
+            }
+            (RegType::Unknown, _) | (_, RegType::Unknown) => RegType::Unknown,
+            (RegType::Bounded(max), RegType::Scalar(Some(imm))) if is64 && imm >= 0 => {
+                let max = match op {
+                    0x20 => max.checked_mul(imm),
+                    0x60 if imm < 32 => max.checked_mul(1 << imm),
+                    _ => None,
+                };
+                max.map_or(RegType::Scalar(None), RegType::Bounded)
+            }
+            (a, b) if a.is_scalar() && b.is_scalar() => RegType::Scalar(None),
+            (pointer, offset) if is64 && offset.is_scalar() && (op == 0x00 || op == 0x10) => {
+                let range = offset.scalar_range();
+                pointer.add_offset(match op {
+                    0x10 => range.map(|(min, max)| (-max, -min)),
+                    _ => range,
+                })
+            }
+            (offset, pointer) if is64 && offset.is_scalar() && op == 0x00 => {
+                pointer.add_offset(offset.scalar_range())
+            }
+            (a, b) if is64 && op == 0x10 && a.is_pointer() && a.join(b).is_pointer() => {
+                RegType::Scalar(None)
+            }
+            _ => {
+                return self.error(format!(
+                    "Pointer arithmetic with {} is prohibited",
+                    alu_name(op)
+                ))
+            }
+        };
+        self.write(state, ins.dst, value)
+    }
+
+    /// Executes the instruction at `self.pc` on a state and returns the states of
+    /// its successors.
+    fn step(&self, mut state: State) -> Result<Vec<(usize, State)>, Violation> {
+        let ins = decode(&self.instructions[self.slots[self.pc].unwrap_or_default()]);
+        let next = self.pc + 1;
+        if ins.dst > FRAME_POINTER || ins.src > FRAME_POINTER {
+            return self.error("Invalid register".to_string());
+        }
+
+        match ins.opcode & 0x07 {
+            CLASS_LD if ins.opcode == 0x18 => {
+                let value = match ins.src {
+                    0 => RegType::Scalar(Some(ins.imm)),
+                    PSEUDO_MAP_FD => RegType::Map(ins.imm as u32),
+                    _ => return self.error("Unsupported 64-bit load".to_string()),
+                };
+                self.write(&mut state, ins.dst, value)?;
+                return Ok(vec![(next + 1, state)]);
+            }
+            CLASS_LDX if ins.opcode & 0xe0 == MODE_MEM => {
+                let value = match self.check_access(&state, ins.src, ins.offset, ins.size)? {
+                    Some(range) => self.load_stack_range(&state, range, ins.size)?,
+                    None if matches!(state.regs[ins.src], RegType::Unknown) => RegType::Unknown,
+                    None => RegType::Scalar(None),
+                };
+                self.write(&mut state, ins.dst, value)?;
+            }
+            CLASS_ST | CLASS_STX if ins.opcode & 0xe0 == MODE_MEM => {
+                let value = match ins.opcode & 0x07 {
+                    CLASS_STX => self.read(&state, ins.src)?,
+                    _ => RegType::Scalar(Some(ins.imm)),
+                };
+                if let Some(range) = self.check_access(&state, ins.dst, ins.offset, ins.size)? {
+                    let value = if ins.size == 8 {
+                        value
+                    } else {
+                        RegType::Scalar(None)
+                    };
+                    self.store_stack_range(&mut state, range, ins.size, value);
+                }
+            }
+            CLASS_STX if ins.opcode & 0xe0 == MODE_ATOMIC => {
+                let src = self.read(&state, ins.src)?;
+                if !src.is_scalar() && src != RegType::Unknown {
+                    return self.error("Atomic operations can only use scalars".to_string());
+                }
+                if let Some(range) = self.check_access(&state, ins.dst, ins.offset, ins.size)? {
+                    self.load_stack_range(&state, range, ins.size)?;
+                    self.store_stack_range(&mut state, range, ins.size, RegType::Scalar(None));
+                }
+                if ins.imm as i32 & ATOMIC_FETCH != 0 {
+                    self.write(&mut state, ins.src, RegType::Scalar(None))?;
+                }
+            }
+            CLASS_ALU => self.alu(&mut state, &ins, false)?,
+            CLASS_ALU64 => self.alu(&mut state, &ins, true)?,
+            class @ (CLASS_JMP | CLASS_JMP32) => match ins.opcode & 0xf0 {
+                0x00 if class == CLASS_JMP => {
+                    return Ok(vec![((next as i64 + ins.offset) as usize, state)]);
+                }
+                0x80 if class == CLASS_JMP && ins.src == 0 => {
+                    self.call(&mut state, ins.imm as u32)?
+                }
+                0x80 if class == CLASS_JMP && ins.src == PSEUDO_CALL => self.local_call(&mut state),
+                0x90 if class == CLASS_JMP => {
+                    self.read(&state, 0)?;
+                    return Ok(vec![]);
+                }
+                0x80 | 0x90 => return self.error("Unsupported call".to_string()),
+                op => {
+                    let dst = self.read(&state, ins.dst)?;
+                    let src = if ins.opcode & SOURCE_REGISTER != 0 {
+                        self.read(&state, ins.src)?
+                    } else {
+                        RegType::Scalar(Some(ins.imm))
+                    };
+
+                    let target = (next as i64 + ins.offset) as usize;
+                    let mut taken = state.clone();
+                    let mut fallthrough = state;
+
+                    /*
+                     * Comparing a lookup's result against zero tells which branch
+                     * has a valid pointer.
+                     */
+                    if let (RegType::MapValueOrNull { fd, id }, RegType::Scalar(Some(0))) =
+                        (dst, src)
+                    {
+                        let valid = RegType::MapValue {
+                            fd,
+                            offset: Some(0),
+                        };
+                        let null = RegType::Scalar(Some(0));
+                        match op {
+                            0x10 => {
+                                taken.refine_null(id, null);
+                                fallthrough.refine_null(id, valid);
+                            }
+                            0x50 => {
+                                taken.refine_null(id, valid);
+                                fallthrough.refine_null(id, null);
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    /*
+                     * Unsigned comparisons with a constant bound the other side on
+                     * one of the branches, e.g. an array index compared with the
+                     * array's length.
+                     */
+                    let bounded = match (class, op, src) {
+                        (CLASS_JMP, 0x20 | 0x30 | 0xa0 | 0xb0, RegType::Scalar(Some(bound)))
+                            if bound >= 0
+                                && matches!(
+                                    dst,
+                                    RegType::Unknown | RegType::Scalar(None) | RegType::Bounded(_)
+                                ) =>
+                        {
+                            let max = match op {
+                                0x20 | 0xb0 => bound,
+                                _ => bound - 1,
+                            };
+                            let max = match dst {
+                                RegType::Bounded(current) => max.min(current),
+                                _ => max,
+                            };
+                            Some(RegType::Bounded(max)).filter(|_| max >= 0)
+                        }
+                        _ => None,
+                    };
+                    if let Some(bounded) = bounded {
+                        match op {
+                            0xa0 | 0xb0 => taken.regs[ins.dst] = bounded,
+                            _ => fallthrough.regs[ins.dst] = bounded,
+                        }
+                    }
+
+                    return Ok(vec![(next, fallthrough), (target, taken)]);
+                }
+            },
+            _ => return self.error("Unsupported instruction".to_string()),
+        }
+
+        Ok(vec![(next, state)])
+    }
+
+    /// Returns the slots that can execute after a slot, ignoring the state.
+    fn successors(&self, pc: usize) -> Vec<usize> {
+        let ins = decode(&self.instructions[self.slots[pc].unwrap_or_default()]);
+        let next = pc + 1;
+        let target = (next as i64 + ins.offset) as usize;
+        match (ins.opcode & 0x07, ins.opcode & 0xf0) {
+            (CLASS_LD, _) if ins.opcode == 0x18 => vec![next + 1],
+            (CLASS_JMP, 0x90) => vec![],
+            (CLASS_JMP, 0x00) => vec![target],
+            (CLASS_JMP, 0x80) => vec![next],
+            (CLASS_JMP | CLASS_JMP32, _) => vec![next, target],
+            _ => vec![next],
+        }
+    }
+}
+
+/// Checks a program against the main rules of the kernel's verifier, so that
+/// programs it would reject fail to compile rather than to load. Every path
+/// through the program is checked for: reads of uninitialized registers and
+/// stack, stack accesses outside the 512 bytes of stack or at offsets without a
+/// bound, dereferences and arithmetic the verifier prohibits on scalars and
+/// pointers, dereferences of `map_lookup_elem` results without a null check,
+/// helper arguments that don't match the helper's argument types, and paths that
+/// never reach an `exit`.
+///
+/// # Arguments
+///
+/// * `instructions` - The program.
+/// * `args` - The values of R1 to R5 when the program starts, missing ones are
+///   uninitialized.
+/// * `maps` - The sizes of the maps the program uses, by file descriptor.
+pub fn verify(
+    instructions: &[Instruction],
+    args: &[RegType],
+    maps: &HashMap<u32, MapSizes>,
+) -> Result<(), Violation> {
+    let mut slots = vec![];
+    for (i, ins) in instructions.iter().enumerate() {
+        slots.push(Some(i));
+        if ins.is_wide() {
+            slots.push(None);
+        }
+    }
+
+    if slots.is_empty() {
+        return Err(Violation {
+            instruction: 0,
+            reason: "The program is empty".to_string(),
+        });
+    }
+
+    let mut verifier = Verifier {
+        instructions,
+        maps,
+        slots,
+        pc: 0,
+    };
+
+    let mut entry = State {
+        regs: [RegType::Uninit; 11],
+        stack: [Slot {
+            initialized: 0,
+            value: RegType::Uninit,
+        }; STACK_SLOTS],
+    };
+    for (reg, arg) in entry.regs[1..=5].iter_mut().zip(args) {
+        *reg = *arg;
+    }
+    entry.regs[FRAME_POINTER] = RegType::Stack(Some(0));
+
+    /*
+     * States are joined where paths meet, and instructions are revisited until
+     * their states stop changing.
+     */
+    let mut states: Vec<Option<State>> = vec![None; verifier.slots.len()];
+    states[0] = Some(entry);
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        verifier.pc = pc;
+        let state = match &states[pc] {
+            Some(state) => state.clone(),
+            None => continue,
+        };
+
+        for (next, state) in verifier.step(state)? {
+            match verifier.slots.get(next) {
+                None if next == verifier.slots.len() => {
+                    return verifier
+                        .error("Execution can run past the end of the program".to_string());
+                }
+                Some(Some(_)) => {}
+                Some(None) => {
+                    return verifier.error("Jump into the middle of a 64-bit load".to_string());
+                }
+                None => return verifier.error("Jump out of bounds".to_string()),
+            }
+
+            let joined = match &states[next] {
+                Some(current) => current.join(&state),
+                None => state,
+            };
+            if states[next].as_ref() != Some(&joined) {
+                states[next] = Some(joined);
+                pending.push(next);
+            }
+        }
+    }
+
+    /*
+     * Every reachable instruction must have a path to an exit, otherwise the
+     * program can loop forever.
+     */
+    let mut predecessors = vec![vec![]; verifier.slots.len()];
+    let mut exits = vec![];
+    for pc in (0..verifier.slots.len()).filter(|pc| states[*pc].is_some()) {
+        let successors = verifier.successors(pc);
+        if successors.is_empty() {
+            exits.push(pc);
+        }
+        for next in successors {
+            predecessors[next].push(pc);
+        }
+    }
+
+    let mut exiting = vec![false; verifier.slots.len()];
+    while let Some(pc) = exits.pop() {
+        if !std::mem::replace(&mut exiting[pc], true) {
+            exits.extend(&predecessors[pc]);
+        }
+    }
+
+    if let Some(pc) = (0..verifier.slots.len()).find(|pc| states[*pc].is_some() && !exiting[*pc]) {
+        verifier.pc = pc;
+        return verifier.error("No path from here reaches an exit".to_string());
+    }
+
+    Ok(())
+}